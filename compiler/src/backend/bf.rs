@@ -1,6 +1,6 @@
 use super::common::{
 	BrainfuckBuilder, BrainfuckBuilderData, BrainfuckProgram, CellAllocator, CellAllocatorData,
-	OpcodeVariant, TapeCellVariant,
+	OpcodeEffect, OpcodeVariant, TapeCellVariant,
 };
 use crate::macros::macros::{r_assert, r_panic};
 
@@ -12,9 +12,19 @@ impl TapeCellVariant for TapeCell {
 	fn with_offset(&self, offset: i32) -> Self {
 		self + offset
 	}
+	fn with_2d_offset(&self, x: i32, _y: i32) -> Self {
+		self + x
+	}
+	fn coordinates(&self) -> (i32, i32) {
+		(*self, 0)
+	}
+	fn from_coordinates(x: i32, y: i32) -> Option<Self> {
+		// cells left of the origin are only used with location specifiers
+		(x >= 0 && y == 0).then_some(x)
+	}
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, serde::Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub enum Opcode {
 	Add,
@@ -42,6 +52,17 @@ impl OpcodeVariant for Opcode {
 			_ => None,
 		}
 	}
+	fn effect(&self) -> OpcodeEffect {
+		match self {
+			Opcode::Right => OpcodeEffect::MoveHead(1, 0),
+			Opcode::Left => OpcodeEffect::MoveHead(-1, 0),
+			Opcode::OpenLoop => OpcodeEffect::OpenLoop,
+			Opcode::CloseLoop => OpcodeEffect::CloseLoop,
+			Opcode::Add | Opcode::Subtract | Opcode::Output | Opcode::Input | Opcode::Clear => {
+				OpcodeEffect::UseCell
+			}
+		}
+	}
 }
 
 impl CellAllocator<TapeCell> for CellAllocatorData<TapeCell> {
@@ -52,11 +73,16 @@ impl CellAllocator<TapeCell> for CellAllocatorData<TapeCell> {
 				return false;
 			}
 		}
-		return true;
+		true
 	}
 
 	/// Allocate size number of cells and return the location, optionally specify a location
-	fn allocate(&mut self, location: Option<TapeCell>, size: usize) -> Result<TapeCell, String> {
+	fn allocate(
+		&mut self,
+		location: Option<TapeCell>,
+		size: usize,
+		_head: TapeCell,
+	) -> Result<TapeCell, String> {
 		if let Some(l) = location {
 			if !self.check_allocatable(&l, size) {
 				r_panic!("Location specifier @{l} conflicts with another allocation");
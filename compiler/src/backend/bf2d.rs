@@ -1,12 +1,15 @@
 use super::common::{
 	BrainfuckBuilder, BrainfuckBuilderData, BrainfuckProgram, CellAllocator, CellAllocatorData,
-	OpcodeVariant, TapeCellVariant,
+	OpcodeEffect, OpcodeVariant, TapeCellVariant,
+};
+use crate::{
+	macros::macros::{r_assert, r_panic},
+	misc::MemoryAllocationMethod,
 };
-use crate::macros::macros::{r_assert, r_panic};
 
 use std::hash::Hash;
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy, serde::Serialize)]
 pub struct TapeCell2D(pub i32, pub i32);
 impl TapeCellVariant for TapeCell2D {
 	fn origin_cell() -> TapeCell2D {
@@ -15,9 +18,18 @@ impl TapeCellVariant for TapeCell2D {
 	fn with_offset(&self, offset: i32) -> Self {
 		TapeCell2D(self.0 + offset, self.1)
 	}
+	fn with_2d_offset(&self, x: i32, y: i32) -> Self {
+		TapeCell2D(self.0 + x, self.1 + y)
+	}
+	fn coordinates(&self) -> (i32, i32) {
+		(self.0, self.1)
+	}
+	fn from_coordinates(x: i32, y: i32) -> Option<Self> {
+		Some(TapeCell2D(x, y))
+	}
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, serde::Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub enum Opcode2D {
 	Add,
@@ -49,6 +61,21 @@ impl OpcodeVariant for Opcode2D {
 			_ => None,
 		}
 	}
+	fn effect(&self) -> OpcodeEffect {
+		match self {
+			Opcode2D::Right => OpcodeEffect::MoveHead(1, 0),
+			Opcode2D::Left => OpcodeEffect::MoveHead(-1, 0),
+			Opcode2D::Up => OpcodeEffect::MoveHead(0, 1),
+			Opcode2D::Down => OpcodeEffect::MoveHead(0, -1),
+			Opcode2D::OpenLoop => OpcodeEffect::OpenLoop,
+			Opcode2D::CloseLoop => OpcodeEffect::CloseLoop,
+			Opcode2D::Add
+			| Opcode2D::Subtract
+			| Opcode2D::Output
+			| Opcode2D::Input
+			| Opcode2D::Clear => OpcodeEffect::UseCell,
+		}
+	}
 }
 
 impl BrainfuckProgram for Vec<Opcode2D> {
@@ -121,7 +148,7 @@ impl CellAllocator<TapeCell2D> for CellAllocatorData<TapeCell2D> {
 				return false;
 			}
 		}
-		return true;
+		true
 	}
 
 	/// Will either check a specific location can be allocated at the chosen size or if no location is
@@ -131,6 +158,7 @@ impl CellAllocator<TapeCell2D> for CellAllocatorData<TapeCell2D> {
 		&mut self,
 		location: Option<TapeCell2D>,
 		size: usize,
+		head: TapeCell2D,
 	) -> Result<TapeCell2D, String> {
 		let mut region_start = location.unwrap_or(TapeCell2D(0, 0));
 		//Check specified memory allocation above to ensure that this works nicely with all algorithms
@@ -139,125 +167,126 @@ impl CellAllocator<TapeCell2D> for CellAllocatorData<TapeCell2D> {
 				r_panic!("Location specifier @{l} conflicts with another allocation");
 			}
 		} else {
-			// should the region start at the current tape head?
-			if self.config.memory_allocation_method == 0 {
-				for i in region_start.0.. {
-					if self.cells.contains(&TapeCell2D(i, region_start.1)) {
-						region_start = TapeCell2D(i + 1, region_start.1);
-					} else if i - region_start.0 == (size as i32 - 1) {
-						break;
+			match self.config.memory_allocation_method {
+				// the minimal head movement layout is planned in advance, memory it doesn't place is allocated as in 1D
+				MemoryAllocationMethod::Classic | MemoryAllocationMethod::MinimiseMovement => {
+					for i in region_start.0.. {
+						if self.cells.contains(&TapeCell2D(i, region_start.1)) {
+							region_start = TapeCell2D(i + 1, region_start.1);
+						} else if i - region_start.0 == (size as i32 - 1) {
+							break;
+						}
 					}
 				}
-			} else if self.config.memory_allocation_method == 1 {
-				//Zig Zag
-				let mut found = false;
-				let mut loops = 0;
-				let mut i;
-				let mut j;
-				while !found {
-					i = region_start.0 + loops;
-					j = region_start.1;
-					for _ in 0..=loops {
-						if self.check_allocatable(&TapeCell2D(i, j), size) {
-							found = true;
-							region_start = TapeCell2D(i, j);
-							break;
+				MemoryAllocationMethod::ZigZag => {
+					let mut found = false;
+					let mut loops = 0;
+					let mut i;
+					let mut j;
+					while !found {
+						i = region_start.0 + loops;
+						j = region_start.1;
+						for _ in 0..=loops {
+							if self.check_allocatable(&TapeCell2D(i, j), size) {
+								found = true;
+								region_start = TapeCell2D(i, j);
+								break;
+							}
+							i -= 1;
+							j += 1;
 						}
-						i = i - 1;
-						j = j + 1;
+						loops += 1;
 					}
-					loops += 1;
 				}
-			} else if self.config.memory_allocation_method == 2 {
-				//Spiral
-				let mut found = false;
-				let mut loops = 1;
-				let directions = ['N', 'E', 'S', 'W'];
-				let mut i = region_start.0;
-				let mut j = region_start.1;
-				while !found {
-					for dir in directions {
-						match dir {
-							'N' => {
-								for _ in 0..loops {
-									j += 1;
-									if self.check_allocatable(&TapeCell2D(i, j), size) {
-										found = true;
-										region_start = TapeCell2D(i, j);
-										break;
+				MemoryAllocationMethod::Spiral => {
+					let mut found = false;
+					let mut loops = 1;
+					let directions = ['N', 'E', 'S', 'W'];
+					let mut i = region_start.0;
+					let mut j = region_start.1;
+					while !found {
+						for dir in directions {
+							match dir {
+								'N' => {
+									for _ in 0..loops {
+										j += 1;
+										if self.check_allocatable(&TapeCell2D(i, j), size) {
+											found = true;
+											region_start = TapeCell2D(i, j);
+											break;
+										}
 									}
 								}
-							}
-							'E' => {
-								for _ in 0..loops {
-									i += 1;
-									if self.check_allocatable(&TapeCell2D(i, j), size) {
-										found = true;
-										region_start = TapeCell2D(i, j);
-										break;
+								'E' => {
+									for _ in 0..loops {
+										i += 1;
+										if self.check_allocatable(&TapeCell2D(i, j), size) {
+											found = true;
+											region_start = TapeCell2D(i, j);
+											break;
+										}
 									}
 								}
-							}
-							'S' => {
-								for _ in 0..loops {
-									j -= 1;
-									if self.check_allocatable(&TapeCell2D(i, j), size) {
-										found = true;
-										region_start = TapeCell2D(i, j);
-										break;
+								'S' => {
+									for _ in 0..loops {
+										j -= 1;
+										if self.check_allocatable(&TapeCell2D(i, j), size) {
+											found = true;
+											region_start = TapeCell2D(i, j);
+											break;
+										}
 									}
 								}
-							}
-							'W' => {
-								for _ in 0..loops {
-									i -= 1;
-									if self.check_allocatable(&TapeCell2D(i, j), size) {
-										found = true;
-										region_start = TapeCell2D(i, j);
-										break;
+								'W' => {
+									for _ in 0..loops {
+										i -= 1;
+										if self.check_allocatable(&TapeCell2D(i, j), size) {
+											found = true;
+											region_start = TapeCell2D(i, j);
+											break;
+										}
 									}
 								}
+								_ => {}
+							}
+							if found {
+								break;
 							}
-							_ => {}
 						}
 						if found {
 							break;
 						}
+						i -= 1;
+						j -= 1;
+						loops += 2;
 					}
-					if found {
-						break;
-					}
-					i -= 1;
-					j -= 1;
-					loops += 2;
 				}
-			} else if self.config.memory_allocation_method == 3 {
-				//Tiles
-				let mut found = false;
-				let mut loops = 0;
-				while !found {
-					for i in -loops..=loops {
-						for j in -loops..=loops {
-							if self.check_allocatable(
-								&TapeCell2D(region_start.0 + i, region_start.1 + j),
-								size,
-							) {
-								found = true;
-								region_start = TapeCell2D(region_start.0 + i, region_start.1 + j);
+				MemoryAllocationMethod::Tiles => {
+					let mut found = false;
+					let mut loops = 0;
+					while !found {
+						for i in -loops..=loops {
+							for j in -loops..=loops {
+								if self.check_allocatable(
+									&TapeCell2D(region_start.0 + i, region_start.1 + j),
+									size,
+								) {
+									found = true;
+									region_start =
+										TapeCell2D(region_start.0 + i, region_start.1 + j);
+									break;
+								}
+							}
+							if found {
 								break;
 							}
 						}
-						if found {
-							break;
-						}
+						loops += 1;
 					}
-					loops += 1;
 				}
-			} else {
-				r_panic!(
-					"Memory allocation method {} not implemented.",
-					self.config.memory_allocation_method
-				);
+				MemoryAllocationMethod::Nearest => {
+					region_start = self.find_nearest(head, size);
+				}
 			}
 		}
 
@@ -306,6 +335,31 @@ impl CellAllocator<TapeCell2D> for CellAllocatorData<TapeCell2D> {
 	}
 }
 
+impl CellAllocatorData<TapeCell2D> {
+	/// Find the free region of cells closest to the head, by Manhattan distance to the nearest cell in the region
+	fn find_nearest(&mut self, head: TapeCell2D, size: usize) -> TapeCell2D {
+		let width = size.max(1) as i32;
+		for distance in 0i32.. {
+			// prefer the head's row, then those closest to it, 0 and -0 are the same row
+			for y_offset in (0..=distance).flat_map(|y| [y, -y]).skip(1) {
+				let x_distance = distance - y_offset.abs();
+				let y = head.1 + y_offset;
+				// regions starting this far to the right of the head, or ending this far to the left
+				let starts = match x_distance {
+					0 => ((head.0 - width + 1)..=head.0).collect(),
+					_ => vec![head.0 + x_distance, head.0 - x_distance - width + 1],
+				};
+				for x in starts {
+					if self.check_allocatable(&TapeCell2D(x, y), size) {
+						return TapeCell2D(x, y);
+					}
+				}
+			}
+		}
+		unreachable!();
+	}
+}
+
 impl BrainfuckProgram for BrainfuckBuilderData<TapeCell2D, Opcode2D> {
 	fn to_string(self) -> String {
 		self.opcodes.to_string()
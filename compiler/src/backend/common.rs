@@ -1,8 +1,12 @@
-use super::constants_optimiser::calculate_optimal_addition;
+use super::{
+	constants_optimiser::calculate_optimal_addition, layout::plan_layout,
+	liveness::free_dead_memory,
+};
 use crate::{
-	frontend::types::{CellLocation, Instruction, MemoryId},
+	diagnostics::Diagnostic,
+	frontend::types::{CellLocation, CellReference, Instruction, MemoryId},
 	macros::macros::{r_assert, r_panic},
-	misc::{MastermindConfig, MastermindContext},
+	misc::{MastermindConfig, MastermindContext, MemoryAllocationMethod},
 	parser::types::TapeCellLocation,
 };
 
@@ -14,12 +18,49 @@ use std::{
 type LoopDepth = usize;
 type TapeValue = u8;
 
-impl<'a> MastermindContext {
+impl MastermindContext {
 	pub fn ir_to_bf<TC: TapeCellVariant, OC: OpcodeVariant>(
 		&self,
 		instructions: Vec<Instruction<TC, OC>>,
 		return_to_cell: Option<TC>,
 	) -> Result<Vec<OC>, String>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		// embedded Mastermind blocks are left alone, as they can leave values on the tape for the surrounding Brainfuck
+		let instructions = match self.config.optimise_memory_allocation && return_to_cell.is_none()
+		{
+			true => free_dead_memory(instructions),
+			false => instructions,
+		};
+		// cells for memory without location specifiers, planned ahead to minimise head movement
+		let layout = match self.config.memory_allocation_method
+			== MemoryAllocationMethod::MinimiseMovement
+			&& return_to_cell.is_none()
+		{
+			true => plan_layout(&instructions),
+			false => HashMap::new(),
+		};
+		if layout.is_empty() {
+			return self.build_bf(instructions, &layout, return_to_cell);
+		}
+		// the plan can't account for code the backend optimises away, so only keep it if the code is shorter
+		let planned = self.build_bf(instructions.clone(), &layout, return_to_cell)?;
+		let classic = self.build_bf(instructions, &HashMap::new(), return_to_cell)?;
+		Ok(match planned.len() < classic.len() {
+			true => planned,
+			false => classic,
+		})
+	}
+
+	/// generate Brainfuck from the IR, with memory planned ahead by the layout (keyed by `Allocate` index) placed there
+	fn build_bf<TC: TapeCellVariant, OC: OpcodeVariant>(
+		&self,
+		instructions: Vec<Instruction<TC, OC>>,
+		layout: &HashMap<usize, TC>,
+		return_to_cell: Option<TC>,
+	) -> Result<Vec<OC>, String>
 	where
 		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
 		CellAllocatorData<TC>: CellAllocator<TC>,
@@ -35,11 +76,13 @@ impl<'a> MastermindContext {
 		let mut alloc_map: HashMap<MemoryId, AllocationMapEntry<TC>> = HashMap::new();
 
 		let mut loop_stack: Vec<TC> = Vec::new();
+		// values of cells allocated outside of each open loop, these are only known until the end of the current iteration
+		let mut loop_frames: Vec<LoopFrame> = Vec::new();
 		let mut current_loop_depth: LoopDepth = 0;
 		let mut skipped_loop_depth: Option<LoopDepth> = None;
 		let mut ops = BrainfuckBuilderData::new();
 
-		for instruction in instructions {
+		for (i, instruction) in instructions.into_iter().enumerate() {
 			if let Some(depth) = skipped_loop_depth {
 				// current loop is being skipped because of unreachable loop optimisations
 				match instruction {
@@ -59,8 +102,9 @@ impl<'a> MastermindContext {
 			match instruction {
 				// the ids (indices really) given by the compiler are guaranteed to be unique (at the time of writing)
 				// however they will absolutely not be very efficient if used directly as cell locations
-				Instruction::Allocate(memory, location_specifier) => {
-					let cell = allocator.allocate(location_specifier, memory.len())?;
+				Instruction::Allocate(memory, location_specifier, _name) => {
+					let location = location_specifier.or_else(|| layout.get(&i).copied());
+					let cell = allocator.allocate(location, memory.len(), ops.head_pos)?;
 					let None = alloc_map.insert(
 						memory.id(),
 						AllocationMapEntry {
@@ -95,8 +139,11 @@ which could not be found"
 					let known_value = &mut known_values[mem_idx];
 
 					// allow the user to assert that we don't know the value of the cell by clobbering when we do inline brainfuck
-					if *alloc_loop_depth == current_loop_depth || imm.is_none() {
+					if *alloc_loop_depth == current_loop_depth {
 						*known_value = imm;
+					} else if imm.is_none() {
+						*known_value = None;
+						set_loop_frame_value(&mut loop_frames, &cell_obj, None);
 					} else {
 						r_panic!(
 							"Cannot assert cell {cell_obj:#?} value \
@@ -147,25 +194,20 @@ outside of loop it was allocated"
 						"Attempted to access memory outside of allocation"
 					);
 					let cell = cell_base.with_offset(mem_idx as i32);
-					let known_value = &mut known_values[mem_idx];
-
-					let mut open = true;
-
-					if let Some(known_value) = known_value {
-						if *alloc_loop_depth == current_loop_depth
-							&& *known_value == 0 && self.config.optimise_unreachable_loops
-						{
-							open = false;
-							skipped_loop_depth = Some(current_loop_depth);
-							current_loop_depth += 1;
-						}
-					}
+					let known_value = match *alloc_loop_depth == current_loop_depth {
+						true => known_values[mem_idx],
+						false => get_loop_frame_value(&loop_frames, &cell_obj),
+					};
 
 					// skip the loop if the optimisations are turned on and we know the value is 0
-					if open {
+					if known_value == Some(0) && self.config.optimise_unreachable_loops {
+						skipped_loop_depth = Some(current_loop_depth);
+						current_loop_depth += 1;
+					} else {
 						ops.move_to_cell(cell);
 						ops.open_loop();
 						loop_stack.push(cell);
+						loop_frames.push(LoopFrame::new());
 						current_loop_depth += 1;
 					}
 				}
@@ -196,6 +238,7 @@ outside of loop it was allocated"
 					r_assert!(cell == stack_cell, "Attempted to close a loop unbalanced");
 
 					current_loop_depth -= 1;
+					loop_frames.pop();
 
 					ops.move_to_cell(cell);
 					ops.close_loop();
@@ -204,6 +247,8 @@ outside of loop it was allocated"
 					// I did encounter issues with nested loops here, interesting
 					if current_loop_depth == *alloc_loop_depth {
 						*known_value = Some(0);
+					} else {
+						set_loop_frame_value(&mut loop_frames, &cell_obj, Some(0));
 					}
 				}
 				Instruction::AddToCell(cell_obj, imm) => {
@@ -250,6 +295,9 @@ outside of loop it was allocated"
 					if imm != 0 {
 						if *alloc_loop_depth != current_loop_depth {
 							*known_value = None;
+							let frame_value = get_loop_frame_value(&loop_frames, &cell_obj)
+								.map(|value| (Wrapping(value) + Wrapping(imm)).0);
+							set_loop_frame_value(&mut loop_frames, &cell_obj, frame_value);
 						} else if let Some(known_value) = known_value {
 							*known_value = (Wrapping(*known_value) + Wrapping(imm)).0;
 						}
@@ -281,6 +329,7 @@ outside of loop it was allocated"
 					ops.input_to_current_cell();
 					// no way to know at compile time what the input to the program will be
 					*known_value = None;
+					set_loop_frame_value(&mut loop_frames, &cell_obj, None);
 				}
 				// Instruction::AssertCellValue(id, value) => {}
 				Instruction::ClearCell(cell_obj) => {
@@ -306,11 +355,14 @@ outside of loop it was allocated"
 
 					let mut clear = true;
 
-					if let Some(known_value) = known_value {
+					let current_value = match *alloc_loop_depth == current_loop_depth {
+						true => *known_value,
+						false => get_loop_frame_value(&loop_frames, &cell_obj),
+					};
+					if let Some(known_value) = current_value {
 						if self.config.optimise_cell_clearing
-							&& *alloc_loop_depth == current_loop_depth
 							// not sure if this should be 4 or 3, essentially it depends on if we prefer clears or changes [-] vs ++---
-							&& (*known_value as i8).abs() < 4
+							&& (known_value as i8).abs() < 4
 						{
 							// 	let imm = *known_value as i8;
 							// 	if imm > 0 {
@@ -322,7 +374,7 @@ outside of loop it was allocated"
 							// 			ops.push(Opcode2D::Add);
 							// 		}
 							// 	}
-							ops.add_to_current_cell(-(*known_value as i8));
+							ops.add_to_current_cell(-(known_value as i8));
 							clear = false;
 						}
 					}
@@ -334,8 +386,8 @@ outside of loop it was allocated"
 					if *alloc_loop_depth == current_loop_depth {
 						*known_value = Some(0);
 					} else {
-						// TODO: fix this for if statements
 						*known_value = None;
+						set_loop_frame_value(&mut loop_frames, &cell_obj, Some(0));
 					}
 				}
 				Instruction::OutputCell(cell_obj) => {
@@ -359,10 +411,10 @@ outside of loop it was allocated"
 					ops.move_to_cell(cell);
 					ops.output_current_cell();
 				}
-				Instruction::InsertBrainfuckAtCell(operations, location_specifier) => {
+				Instruction::InsertBrainfuckAtCell(operations, location_specifier, clobbers) => {
 					// move to the correct cell, based on the location specifier
 					match location_specifier {
-						CellLocation::FixedCell(cell) => ops.move_to_cell(cell.into()),
+						CellLocation::FixedCell(cell) => ops.move_to_cell(cell),
 						CellLocation::MemoryCell(cell_obj) => {
 							let Some(AllocationMapEntry {
 								cell_base,
@@ -384,6 +436,32 @@ outside of loop it was allocated"
 						CellLocation::Unspecified => (),
 					}
 
+					// warn about clobbered variables which the Brainfuck never uses
+					if let Some(used_cells) = find_used_cells(&operations, ops.head_pos) {
+						for clobber in clobbers {
+							let clobbered_cells = clobber.cells.iter().filter_map(|cell_obj| {
+								let entry = alloc_map.get(&cell_obj.memory_id)?;
+								Some(
+									entry
+										.cell_base
+										.with_offset(cell_obj.index.unwrap_or(0) as i32),
+								)
+							});
+							if !clobbered_cells
+								.into_iter()
+								.any(|cell| used_cells.contains(&cell))
+							{
+								self.warn(Diagnostic::warning(
+									format!(
+										"Variable \"{}\" is clobbered but never used by the Brainfuck.",
+										clobber.name
+									),
+									clobber.span,
+								));
+							}
+						}
+					}
+
 					// paste the in-line BF operations
 					ops.extend(operations);
 				}
@@ -392,13 +470,69 @@ outside of loop it was allocated"
 
 		// this is used in embedded brainfuck contexts to preserve head position
 		if let Some(origin_cell) = return_to_cell {
-			ops.move_to_cell(origin_cell.into());
+			ops.move_to_cell(origin_cell);
 		}
 
 		Ok(ops.opcodes)
 	}
 }
 
+/// follow the head through in-line Brainfuck to find every cell it uses (anything but moving the head),
+/// this is None if a loop doesn't return the head to where it started, as the head could then be anywhere
+fn find_used_cells<TC: TapeCellVariant, OC: OpcodeVariant>(
+	operations: &[OC],
+	start_cell: TC,
+) -> Option<Vec<TC>> {
+	let mut head_pos = start_cell;
+	let mut loop_stack = Vec::new();
+	let mut used_cells = Vec::new();
+	for op in operations {
+		match op.effect() {
+			OpcodeEffect::MoveHead(x, y) => {
+				head_pos = head_pos.with_2d_offset(x, y);
+				continue;
+			}
+			OpcodeEffect::OpenLoop => loop_stack.push(head_pos),
+			OpcodeEffect::CloseLoop => {
+				if loop_stack.pop() != Some(head_pos) {
+					return None;
+				}
+			}
+			OpcodeEffect::UseCell => (),
+		}
+		if !used_cells.contains(&head_pos) {
+			used_cells.push(head_pos);
+		}
+	}
+	Some(used_cells)
+}
+
+/// Known values of cells that were modified inside a loop they weren't allocated in
+type LoopFrame = HashMap<(MemoryId, usize), TapeValue>;
+
+fn get_loop_frame_value(loop_frames: &[LoopFrame], cell_obj: &CellReference) -> Option<TapeValue> {
+	loop_frames
+		.last()?
+		.get(&(cell_obj.memory_id, cell_obj.index.unwrap_or(0)))
+		.copied()
+}
+
+/// Record a change to a cell inside the innermost open loop,
+/// any outer loops can no longer rely on the cell's value as this loop may run any number of times
+fn set_loop_frame_value(
+	loop_frames: &mut [LoopFrame],
+	cell_obj: &CellReference,
+	value: Option<TapeValue>,
+) {
+	let key = (cell_obj.memory_id, cell_obj.index.unwrap_or(0));
+	for frame in loop_frames.iter_mut() {
+		frame.remove(&key);
+	}
+	if let (Some(frame), Some(value)) = (loop_frames.last_mut(), value) {
+		frame.insert(key, value);
+	}
+}
+
 /// This trait must be implemented for a cell location type for a Brainfuck variant
 /// for now this is implemented by TapeCell (i32 1D location specifier), and TapeCell2D (2D)
 pub trait TapeCellVariant
@@ -407,6 +541,12 @@ where
 {
 	fn origin_cell() -> Self;
 	fn with_offset(&self, offset: i32) -> Self;
+	/// offset along both axes, the y offset is always 0 for 1D Brainfuck
+	fn with_2d_offset(&self, x: i32, y: i32) -> Self;
+	/// the x and y position of the cell, y is always 0 for 1D Brainfuck
+	fn coordinates(&self) -> (i32, i32);
+	/// the cell at a position, None if memory can't be allocated there automatically
+	fn from_coordinates(x: i32, y: i32) -> Option<Self>;
 }
 
 /// This trait must be implemented for a Brainfuck variant
@@ -415,6 +555,16 @@ where
 	Self: Sized + Clone + Copy,
 {
 	fn try_from_char(c: char) -> Option<Self>;
+	fn effect(&self) -> OpcodeEffect;
+}
+
+/// What an opcode does to the tape head, used to follow the head through in-line Brainfuck
+pub enum OpcodeEffect {
+	MoveHead(i32, i32),
+	OpenLoop,
+	CloseLoop,
+	/// modifies, outputs or otherwise uses the current cell
+	UseCell,
 }
 
 pub struct CellAllocatorData<TC> {
@@ -432,7 +582,8 @@ impl<T> CellAllocatorData<T> {
 
 pub trait CellAllocator<TC> {
 	fn check_allocatable(&mut self, location: &TC, size: usize) -> bool;
-	fn allocate(&mut self, location: Option<TC>, size: usize) -> Result<TC, String>;
+	/// Allocate size cells, at the location if given, otherwise the allocation method may place them near the head
+	fn allocate(&mut self, location: Option<TC>, size: usize, head: TC) -> Result<TC, String>;
 	fn allocate_temp_cell(&mut self, location: TC) -> TC;
 	fn free(&mut self, cell: TC, size: usize) -> Result<(), String>;
 }
@@ -65,9 +65,8 @@ where
 				j += 1;
 			}
 
-			for j in 0..i {
+			for (j, &(a, b, c)) in best_combinations.iter().enumerate() {
 				let diff = i - j;
-				let (a, b, c) = best_combinations[j];
 				if (a + b + c + diff) < (current_best.0 + current_best.1 + current_best.2) {
 					current_best = (a, b, c + diff);
 				}
@@ -0,0 +1,514 @@
+// plans where memory is allocated so that the tape head moves as little as possible,
+// based on the order cells are used in the IR, with uses in loops weighted by how deeply nested they are
+
+use super::common::TapeCellVariant;
+use crate::frontend::types::{CellLocation, CellReference, Instruction, MemoryId};
+
+use std::collections::HashMap;
+
+/// uses of cells inside a loop are assumed to happen this many times more than outside of it
+const LOOP_WEIGHT: u64 = 8;
+/// loops nested deeper than this are weighted the same, so the weights can't overflow
+const MAX_LOOP_DEPTH: u32 = 8;
+/// how far away from the cells memory is used alongside to search for a place for it
+const SEARCH_RADIUS: i32 = 3;
+/// the number of times to try moving each memory to a better place once everything is placed
+const IMPROVEMENT_ROUNDS: usize = 4;
+
+/// Plan the base cell of each allocation without a location specifier, keyed by the index of its `Allocate` instruction.
+/// Memory is placed greedily starting with the most used, then each memory is moved to its best place given the others.
+/// The layout is empty if the classic layout is estimated to move the head at least as little.
+pub fn plan_layout<TC: TapeCellVariant, OC>(
+	instructions: &[Instruction<TC, OC>],
+) -> HashMap<usize, TC> {
+	let (lifetimes, mut layout) = Layout::new(instructions);
+
+	// place the most used memory first, as it has the most to lose from being placed badly
+	let mut order: Vec<usize> = (0..lifetimes.len())
+		.filter(|lifetime| lifetimes[*lifetime].fixed.is_none())
+		.collect();
+	let total_weights: Vec<u64> = layout
+		.edges
+		.iter()
+		.map(|edges| edges.iter().map(|(_, _, weight)| weight).sum())
+		.collect();
+	order.sort_by_key(|lifetime| (u64::MAX - total_weights[*lifetime], *lifetime));
+
+	for lifetime in order.iter() {
+		let position = layout.best_position(*lifetime);
+		layout.positions[*lifetime] = Some(position);
+	}
+
+	for _ in 0..IMPROVEMENT_ROUNDS {
+		let mut is_improved = false;
+		for lifetime in order.iter() {
+			let Some(current) = layout.positions[*lifetime].take() else {
+				unreachable!();
+			};
+			let best = layout.best_position(*lifetime);
+			if layout.cost(*lifetime, best) < layout.cost(*lifetime, current) {
+				layout.positions[*lifetime] = Some(best);
+				is_improved = true;
+			} else {
+				layout.positions[*lifetime] = Some(current);
+			}
+		}
+		if !is_improved {
+			break;
+		}
+	}
+
+	// the plan is only a heuristic, so keep the classic layout if it is estimated to be at least as good
+	let planned_cost = layout.total_cost();
+	let planned_positions = std::mem::replace(
+		&mut layout.positions,
+		classic_positions(&lifetimes, layout.is_valid_cell),
+	);
+	if layout.total_cost() <= planned_cost {
+		return HashMap::new();
+	}
+	layout.positions = planned_positions;
+
+	order
+		.into_iter()
+		.filter_map(|lifetime| {
+			let (x, y) = layout.positions[lifetime]?;
+			Some((lifetimes[lifetime].start, TC::from_coordinates(x, y)?))
+		})
+		.collect()
+}
+
+struct Lifetime {
+	/// the index of the `Allocate` instruction
+	start: usize,
+	/// the index of the `Free` instruction, or the end of the program
+	end: usize,
+	len: usize,
+	fixed: Option<(i32, i32)>,
+}
+
+/// somewhere the tape head moves to
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Target {
+	/// a fixed cell, from a location specifier on in-line Brainfuck
+	Cell(i32, i32),
+	Memory {
+		lifetime: usize,
+		index: usize,
+	},
+}
+
+/// find each memory's lifetime and the order the head moves between cells, with how often each move happens
+fn find_accesses<TC: TapeCellVariant, OC>(
+	instructions: &[Instruction<TC, OC>],
+) -> (Vec<Lifetime>, Vec<(Target, u64)>) {
+	let mut lifetimes: Vec<Lifetime> = vec![];
+	let mut active: HashMap<MemoryId, usize> = HashMap::new();
+	let (x, y) = TC::origin_cell().coordinates();
+	let mut accesses = vec![(Target::Cell(x, y), 1)];
+	let mut depth = 0u32;
+	for (i, instruction) in instructions.iter().enumerate() {
+		let target = |cell: &CellReference| {
+			let lifetime = *active.get(&cell.memory_id)?;
+			Some(Target::Memory {
+				lifetime,
+				index: cell.index.unwrap_or(0),
+			})
+		};
+		let weight = LOOP_WEIGHT.pow(depth.min(MAX_LOOP_DEPTH));
+		let access = match instruction {
+			Instruction::Allocate(memory, location_specifier, _) => {
+				active.insert(memory.id(), lifetimes.len());
+				lifetimes.push(Lifetime {
+					start: i,
+					end: instructions.len(),
+					len: memory.len(),
+					fixed: location_specifier.map(|cell| cell.coordinates()),
+				});
+				None
+			}
+			Instruction::Free(id) => {
+				if let Some(lifetime) = active.remove(id) {
+					lifetimes[lifetime].end = i;
+				}
+				None
+			}
+			Instruction::OpenLoop(cell) => {
+				let access = target(cell);
+				depth += 1;
+				access
+			}
+			Instruction::CloseLoop(cell) => {
+				let access = target(cell);
+				depth = depth.saturating_sub(1);
+				access
+			}
+			Instruction::AddToCell(cell, _)
+			| Instruction::InputToCell(cell)
+			| Instruction::ClearCell(cell)
+			| Instruction::OutputCell(cell)
+			| Instruction::InsertBrainfuckAtCell(_, CellLocation::MemoryCell(cell), _) => target(cell),
+			Instruction::InsertBrainfuckAtCell(_, CellLocation::FixedCell(cell), _) => {
+				let (x, y) = cell.coordinates();
+				Some(Target::Cell(x, y))
+			}
+			Instruction::InsertBrainfuckAtCell(_, CellLocation::Unspecified, _)
+			| Instruction::AssertCellValue(_, _) => None,
+		};
+		if let Some(access) = access {
+			accesses.push((access, weight));
+		}
+	}
+	(lifetimes, accesses)
+}
+
+/// where memory would be placed by the classic allocator, the first free cells to the right of the origin
+fn classic_positions(
+	lifetimes: &[Lifetime],
+	is_valid_cell: fn(i32, i32) -> bool,
+) -> Vec<Option<(i32, i32)>> {
+	let mut positions: Vec<Option<(i32, i32)>> = vec![];
+	let mut active: Vec<usize> = vec![];
+	for lifetime in lifetimes.iter() {
+		active.retain(|other| lifetimes[*other].end > lifetime.start);
+		let is_free = |x: i32| {
+			let len = lifetime.len as i32;
+			(0..len).all(|i| is_valid_cell(x + i, 0))
+				&& active.iter().all(|other| {
+					let Some((other_x, other_y)) = positions[*other] else {
+						return true;
+					};
+					other_y != 0
+						|| other_x + lifetimes[*other].len as i32 <= x
+						|| x + len <= other_x
+				})
+		};
+		let position = lifetime
+			.fixed
+			.unwrap_or_else(|| ((0..).find(|x| is_free(*x)).unwrap(), 0));
+		active.push(positions.len());
+		positions.push(Some(position));
+	}
+	positions
+}
+
+struct Layout {
+	/// for each memory, the moves between its cells (by index) and somewhere else, with how often they happen
+	edges: Vec<Vec<(usize, Target, u64)>>,
+	/// for each memory, the memory which is allocated at the same time
+	conflicts: Vec<Vec<usize>>,
+	positions: Vec<Option<(i32, i32)>>,
+	lens: Vec<usize>,
+	is_valid_cell: fn(i32, i32) -> bool,
+}
+
+impl Layout {
+	/// find the moves between memory in the IR, with only memory with location specifiers placed
+	fn new<TC: TapeCellVariant, OC>(
+		instructions: &[Instruction<TC, OC>],
+	) -> (Vec<Lifetime>, Layout) {
+		let (lifetimes, accesses) = find_accesses(instructions);
+
+		// the head moves between each pair of consecutive cell uses, as often as the least frequent of the two
+		let mut edge_weights: HashMap<(Target, Target), u64> = HashMap::new();
+		for pair in accesses.windows(2) {
+			let ((a, a_weight), (b, b_weight)) = (pair[0], pair[1]);
+			if a != b {
+				*edge_weights.entry((a.min(b), a.max(b))).or_default() += a_weight.min(b_weight);
+			}
+		}
+		let mut layout = Layout {
+			edges: vec![vec![]; lifetimes.len()],
+			conflicts: vec![vec![]; lifetimes.len()],
+			positions: lifetimes.iter().map(|lifetime| lifetime.fixed).collect(),
+			lens: lifetimes.iter().map(|lifetime| lifetime.len).collect(),
+			is_valid_cell: |x, y| TC::from_coordinates(x, y).is_some(),
+		};
+		let mut edge_weights: Vec<_> = edge_weights.into_iter().collect();
+		edge_weights.sort();
+		for ((a, b), weight) in edge_weights {
+			match (a, b) {
+				// the distance between cells in the same memory doesn't depend on where it is placed
+				(
+					Target::Memory {
+						lifetime: a_lifetime,
+						index: _,
+					},
+					Target::Memory {
+						lifetime: b_lifetime,
+						index: _,
+					},
+				) if a_lifetime == b_lifetime => (),
+				_ => {
+					for (own, other) in [(a, b), (b, a)] {
+						if let Target::Memory { lifetime, index } = own {
+							layout.edges[lifetime].push((index, other, weight));
+						}
+					}
+				}
+			}
+		}
+		for a in 0..lifetimes.len() {
+			for b in (a + 1)..lifetimes.len() {
+				if lifetimes[a].start <= lifetimes[b].end && lifetimes[b].start <= lifetimes[a].end
+				{
+					layout.conflicts[a].push(b);
+					layout.conflicts[b].push(a);
+				}
+			}
+		}
+		(lifetimes, layout)
+	}
+
+	/// the total distance the head would move to and from the memory if it was placed at a position
+	fn cost(&self, lifetime: usize, position: (i32, i32)) -> u64 {
+		self.edges[lifetime]
+			.iter()
+			.filter_map(|(index, other, weight)| {
+				Some(self.distance((*index, position), *other)? as u64 * weight)
+			})
+			.sum()
+	}
+
+	/// the distance between a cell of memory at a position and somewhere else, if it has been placed
+	fn distance(&self, (index, (x, y)): (usize, (i32, i32)), other: Target) -> Option<u32> {
+		let (other_x, other_y) = match other {
+			Target::Cell(x, y) => (x, y),
+			Target::Memory {
+				lifetime: other,
+				index: other_index,
+			} => {
+				let (x, y) = self.positions[other]?;
+				(x + other_index as i32, y)
+			}
+		};
+		Some((x + index as i32).abs_diff(other_x) + y.abs_diff(other_y))
+	}
+
+	/// the total distance the head would move with every memory at its current position
+	fn total_cost(&self) -> u64 {
+		(0..self.positions.len())
+			.filter_map(|lifetime| {
+				let position = self.positions[lifetime]?;
+				// moves between two memories are counted once, from the one allocated first
+				let cost: u64 = self.edges[lifetime]
+					.iter()
+					.filter(|(_, other, _)| match other {
+						Target::Cell(_, _) => true,
+						Target::Memory {
+							lifetime: other,
+							index: _,
+						} => *other > lifetime,
+					})
+					.filter_map(|(index, other, weight)| {
+						Some(self.distance((*index, position), *other)? as u64 * weight)
+					})
+					.sum();
+				Some(cost)
+			})
+			.sum()
+	}
+
+	/// whether the memory's cells would be free for its whole lifetime if it was placed at a position
+	fn is_free(&self, lifetime: usize, (x, y): (i32, i32)) -> bool {
+		let len = self.lens[lifetime] as i32;
+		(0..len).all(|i| (self.is_valid_cell)(x + i, y))
+			&& self.conflicts[lifetime].iter().all(|other| {
+				let Some((other_x, other_y)) = self.positions[*other] else {
+					return true;
+				};
+				let other_len = self.lens[*other] as i32;
+				other_y != y || other_x + other_len <= x || x + len <= other_x
+			})
+	}
+
+	/// the free position closest to where the memory's cells are used
+	fn best_position(&self, lifetime: usize) -> (i32, i32) {
+		// search around the other cells the head moves to and from, and the origin
+		let mut anchors = vec![(0, 0)];
+		for (index, other, _) in self.edges[lifetime].iter() {
+			let (x, y) = match *other {
+				Target::Cell(x, y) => (x, y),
+				Target::Memory {
+					lifetime: other,
+					index: other_index,
+				} => match self.positions[other] {
+					Some((x, y)) => (x + other_index as i32, y),
+					None => continue,
+				},
+			};
+			anchors.push((x - *index as i32, y));
+		}
+		anchors.sort();
+		anchors.dedup();
+
+		let mut candidates = vec![];
+		for (x, y) in anchors {
+			for dx in -SEARCH_RADIUS..=SEARCH_RADIUS {
+				for dy in -SEARCH_RADIUS..=SEARCH_RADIUS {
+					if dx.abs() + dy.abs() <= SEARCH_RADIUS && (self.is_valid_cell)(x + dx, y + dy)
+					{
+						candidates.push((x + dx, y + dy));
+					}
+				}
+			}
+		}
+		candidates.sort();
+		candidates.dedup();
+
+		candidates
+			.into_iter()
+			.filter(|position| self.is_free(lifetime, *position))
+			.min_by_key(|(x, y)| (self.cost(lifetime, (*x, *y)), x.abs() + y.abs(), *x, *y))
+			// otherwise fall back to the first free space to the right of the origin
+			.unwrap_or_else(|| {
+				(0..)
+					.map(|x| (x, 0))
+					.find(|position| self.is_free(lifetime, *position))
+					.unwrap()
+			})
+	}
+}
+
+#[cfg(test)]
+mod layout_tests {
+	use super::*;
+	use crate::{
+		backend::{
+			bf::{Opcode, TapeCell},
+			bf2d::{Opcode2D, TapeCell2D},
+			liveness::free_dead_memory,
+		},
+		frontend::ir::parse_ir,
+		misc::{MastermindConfig, MastermindContext},
+		parser::parser::parse_program,
+	};
+
+	#[test]
+	fn layout_1() {
+		// the cells used together in the loop are placed next to each other
+		let instructions = parse_ir::<TapeCell, Opcode>(
+			"allocate %0 cell
+allocate %1 cell
+allocate %2 cell
+add %2 5
+open_loop %2
+  add %0 1
+  add %2 -1
+close_loop %2
+output %1
+",
+		)
+		.unwrap();
+		let layout = plan_layout(&instructions);
+		assert_eq!(layout.len(), 3);
+		assert_eq!(layout[&0].abs_diff(layout[&2]), 1);
+		assert_ne!(layout[&1], layout[&0]);
+		assert_ne!(layout[&1], layout[&2]);
+	}
+
+	/// the estimated head movement of a planned layout, or the classic layout if it is empty
+	fn layout_cost<TC: TapeCellVariant, OC>(
+		instructions: &[Instruction<TC, OC>],
+		planned: &HashMap<usize, TC>,
+	) -> u64 {
+		let (lifetimes, mut layout) = Layout::new(instructions);
+		layout.positions = match planned.is_empty() {
+			true => classic_positions(&lifetimes, layout.is_valid_cell),
+			false => lifetimes
+				.iter()
+				.map(|lifetime| {
+					lifetime
+						.fixed
+						.or_else(|| Some(planned.get(&lifetime.start)?.coordinates()))
+				})
+				.collect(),
+		};
+		layout.total_cost()
+	}
+
+	#[test]
+	fn layout_2() {
+		// fixed cells are left alone and avoided, memory with several cells is kept contiguous
+		let instructions = parse_ir::<TapeCell, Opcode>(
+			"allocate %0 cell @6
+allocate %1 cells 3
+add %0 5
+open_loop %0
+  add %1[2] 1
+  add %0 -1
+close_loop %0
+",
+		)
+		.unwrap();
+		let layout = plan_layout(&instructions);
+		assert_eq!(layout.len(), 1);
+		assert_eq!(layout[&1], 3);
+	}
+
+	#[test]
+	fn layout_3() {
+		// the classic layout is kept if the plan is no better
+		let instructions = parse_ir::<TapeCell, Opcode>(
+			"allocate %0 cell
+add %0 1
+clear %0
+free %0
+allocate %0 cell
+add %0 1
+",
+		)
+		.unwrap();
+		assert!(plan_layout(&instructions).is_empty());
+	}
+
+	#[test]
+	fn layout_4() {
+		// the plan is never estimated to be worse than the classic layout
+		let program = r#"
+cell c;
+input c;
+while c {
+	output c;
+	break;
+	output 'x';
+}
+cell[4] a = "abcd";
+cell i = 3;
+while i {
+	i -= 1;
+	output a[0];
+	drain a[1] into a[0];
+	drain a[2] into a[1];
+	drain a[3] into a[2];
+}
+"#;
+		let ctx = MastermindContext::new(MastermindConfig::default());
+		let clauses = parse_program::<TapeCell, Opcode>(program).unwrap();
+		let instructions = ctx.create_ir_scope(&clauses, None).unwrap().build_ir(false);
+		for instructions in [free_dead_memory(instructions.clone()), instructions] {
+			let layout = plan_layout(&instructions);
+			assert!(
+				layout_cost(&instructions, &layout) <= layout_cost(&instructions, &HashMap::new())
+			);
+		}
+	}
+
+	#[test]
+	fn layout_2d_1() {
+		// 2D memory can be placed above or below the cells it is used with
+		let instructions = parse_ir::<TapeCell2D, Opcode2D>(
+			"allocate %0 cells 3
+allocate %1 cell
+open_loop %0[1]
+  add %1 1
+  add %0[1] -1
+close_loop %0[1]
+",
+		)
+		.unwrap();
+		let layout = plan_layout(&instructions);
+		let (TapeCell2D(x0, y0), TapeCell2D(x1, y1)) = (layout[&0], layout[&1]);
+		assert_eq!((x0 + 1).abs_diff(x1) + y0.abs_diff(y1), 1);
+	}
+}
@@ -0,0 +1,441 @@
+// liveness analysis over the IR, so that memory can be freed as soon as its values are no longer needed
+// the frontend only frees memory at the end of its scope (and never frees top level variables),
+// so temporaries and variables in long-lived scopes would otherwise keep their cells until then
+
+use crate::frontend::types::{CellReference, Instruction, Memory, MemoryId};
+
+use std::collections::{HashMap, HashSet};
+
+/// Move each `Free` to just after the last instruction that reads the memory,
+/// writes after that point are dead so they are removed, and cells which may be non-zero are cleared before freeing.
+/// Memory is only freed early if something else could be allocated in its place.
+pub fn free_dead_memory<TC, OC>(
+	instructions: Vec<Instruction<TC, OC>>,
+) -> Vec<Instruction<TC, OC>> {
+	let depths = loop_depths(&instructions);
+	let loop_ends = loop_ends(&instructions);
+
+	// the allocation and free (if any) of each memory id, ids are reused once memory is freed
+	let mut lifetimes = vec![];
+	let mut active: HashMap<MemoryId, usize> = HashMap::new();
+	for (i, instruction) in instructions.iter().enumerate() {
+		match instruction {
+			Instruction::Allocate(memory, _, _) => {
+				active.insert(memory.id(), i);
+			}
+			Instruction::Free(id) => {
+				if let Some(start) = active.remove(id) {
+					lifetimes.push((start, Some(i)));
+				}
+			}
+			_ => (),
+		}
+	}
+	lifetimes.extend(active.into_values().map(|start| (start, None)));
+	// sorted so that frees at the same position are always in the same order
+	lifetimes.sort();
+
+	let mut removed = HashSet::new();
+	let mut inserted: HashMap<usize, Vec<Instruction<TC, OC>>> = HashMap::new();
+	for (start, free) in lifetimes {
+		let Instruction::Allocate(memory, location_specifier, _) = &instructions[start] else {
+			unreachable!();
+		};
+		// memory placed at a specific cell is left alone, the programmer may be relying on it being there
+		if location_specifier.is_some() {
+			continue;
+		}
+		let Some(early_free) =
+			find_early_free(&instructions, &depths, &loop_ends, memory, start, free)
+		else {
+			continue;
+		};
+		removed.extend(early_free.removed);
+		if let Some(free) = free {
+			removed.insert(free);
+		}
+		inserted
+			.entry(early_free.position)
+			.or_default()
+			.extend(early_free.instructions);
+	}
+
+	let mut optimised = Vec::with_capacity(instructions.len());
+	for (i, instruction) in instructions.into_iter().enumerate() {
+		if !removed.contains(&i) {
+			optimised.push(instruction);
+		}
+		if let Some(instructions) = inserted.remove(&i) {
+			optimised.extend(instructions);
+		}
+	}
+	optimised
+}
+
+struct EarlyFree<TC, OC> {
+	/// the index of the instruction to free the memory after
+	position: usize,
+	/// dead writes to the memory after that position
+	removed: Vec<usize>,
+	/// clears for cells which may be non-zero, followed by the free
+	instructions: Vec<Instruction<TC, OC>>,
+}
+
+fn find_early_free<TC, OC>(
+	instructions: &[Instruction<TC, OC>],
+	depths: &[usize],
+	loop_ends: &HashMap<usize, usize>,
+	memory: &Memory,
+	start: usize,
+	free: Option<usize>,
+) -> Option<EarlyFree<TC, OC>> {
+	let id = memory.id();
+	let depth = depths[start];
+	let end = match free {
+		// frees are always in the same loop as their allocation, but check just in case
+		Some(free) if depths[free] != depth => return None,
+		Some(free) => free,
+		None => {
+			// memory which is never freed lasts until the end of its loop, only handle the top level
+			let end = (start..instructions.len())
+				.find(|i| depths[*i] < depth)
+				.unwrap_or(instructions.len());
+			if end < instructions.len() {
+				return None;
+			}
+			end
+		}
+	};
+
+	// instructions in a loop which was opened after the allocation count as being at the end of that loop,
+	// as the loop may run again and read the memory at any point
+	let mut accesses = vec![];
+	let mut loop_end = None;
+	for i in (start + 1)..end {
+		let position = match (depths[i] > depth, loop_end) {
+			(true, Some(loop_end)) => loop_end,
+			_ => {
+				loop_end = loop_ends.get(&i).copied();
+				i
+			}
+		};
+		if let Some(access) = access(&instructions[i], id) {
+			accesses.push((i, position, access));
+		}
+	}
+
+	let last_read = accesses
+		.iter()
+		.filter(|(_, _, access)| *access == Access::Read)
+		.map(|(_, position, _)| *position)
+		.max()
+		.unwrap_or(start);
+
+	// only worth freeing early if the cells could be reused
+	if !instructions[(last_read + 1)..end]
+		.iter()
+		.any(|instruction| matches!(instruction, Instruction::Allocate(_, _, _)))
+	{
+		return None;
+	}
+
+	// find which cells are definitely zero after the last read
+	let mut is_zero = vec![true; memory.len()];
+	for (i, position, _) in accesses
+		.iter()
+		.filter(|(_, position, _)| *position <= last_read)
+	{
+		let is_nested = i != position;
+		match (&instructions[*i], is_nested) {
+			(Instruction::AddToCell(_, 0), false) => (),
+			(
+				Instruction::ClearCell(cell)
+				| Instruction::CloseLoop(cell)
+				| Instruction::AssertCellValue(cell, Some(0)),
+				false,
+			) => is_zero[cell_index(cell)] = true,
+			(
+				Instruction::AddToCell(cell, _)
+				| Instruction::ClearCell(cell)
+				| Instruction::CloseLoop(cell)
+				| Instruction::AssertCellValue(cell, _)
+				| Instruction::InputToCell(cell),
+				_,
+			) => is_zero[cell_index(cell)] = false,
+			_ => (),
+		}
+	}
+
+	// the rest of the accesses are dead writes,
+	// an assertion that a cell is zero before it is written to also holds at the last read
+	let mut removed = vec![];
+	let mut is_written = vec![false; memory.len()];
+	for (i, position, _) in accesses
+		.iter()
+		.filter(|(_, position, _)| *position > last_read)
+	{
+		match &instructions[*i] {
+			Instruction::AssertCellValue(cell, Some(0)) if i == position => {
+				let index = cell_index(cell);
+				is_zero[index] |= !is_written[index];
+			}
+			Instruction::AddToCell(cell, _)
+			| Instruction::ClearCell(cell)
+			| Instruction::AssertCellValue(cell, _) => is_written[cell_index(cell)] = true,
+			_ => (),
+		}
+		removed.push(*i);
+	}
+
+	let mut early_instructions = vec![];
+	for (index, is_zero) in is_zero.into_iter().enumerate() {
+		if !is_zero {
+			early_instructions.push(Instruction::ClearCell(CellReference {
+				memory_id: id,
+				index: match memory {
+					Memory::Cell { id: _ } | Memory::MappedCell { id: _, index: _ } => None,
+					Memory::Cells { id: _, len: _ }
+					| Memory::MappedCells {
+						id: _,
+						start_index: _,
+						len: _,
+					} => Some(index),
+				},
+			}));
+		}
+	}
+	early_instructions.push(Instruction::Free(id));
+
+	Some(EarlyFree {
+		position: last_read,
+		removed,
+		instructions: early_instructions,
+	})
+}
+
+#[derive(PartialEq)]
+enum Access {
+	/// anything which depends on the value of the memory, or has a side effect
+	Read,
+	Write,
+}
+
+fn access<TC, OC>(instruction: &Instruction<TC, OC>, id: MemoryId) -> Option<Access> {
+	match instruction {
+		// in-line Brainfuck could read any cell, not just those it clobbers
+		Instruction::InsertBrainfuckAtCell(_, _, _) => Some(Access::Read),
+		Instruction::OpenLoop(cell)
+		| Instruction::CloseLoop(cell)
+		| Instruction::OutputCell(cell)
+		| Instruction::InputToCell(cell)
+			if cell.memory_id == id =>
+		{
+			Some(Access::Read)
+		}
+		Instruction::AddToCell(cell, _)
+		| Instruction::ClearCell(cell)
+		| Instruction::AssertCellValue(cell, _)
+			if cell.memory_id == id =>
+		{
+			Some(Access::Write)
+		}
+		_ => None,
+	}
+}
+
+fn cell_index(cell: &CellReference) -> usize {
+	cell.index.unwrap_or(0)
+}
+
+/// the loop depth of each instruction, loop instructions are counted as outside of their loop
+fn loop_depths<TC, OC>(instructions: &[Instruction<TC, OC>]) -> Vec<usize> {
+	let mut depth = 0usize;
+	instructions
+		.iter()
+		.map(|instruction| match instruction {
+			Instruction::OpenLoop(_) => {
+				depth += 1;
+				depth - 1
+			}
+			Instruction::CloseLoop(_) => {
+				depth = depth.saturating_sub(1);
+				depth
+			}
+			_ => depth,
+		})
+		.collect()
+}
+
+/// the index of the matching `CloseLoop` for each `OpenLoop`
+fn loop_ends<TC, OC>(instructions: &[Instruction<TC, OC>]) -> HashMap<usize, usize> {
+	let mut loop_ends = HashMap::new();
+	let mut loop_stack = vec![];
+	for (i, instruction) in instructions.iter().enumerate() {
+		match instruction {
+			Instruction::OpenLoop(_) => loop_stack.push(i),
+			Instruction::CloseLoop(_) => {
+				if let Some(start) = loop_stack.pop() {
+					loop_ends.insert(start, i);
+				}
+			}
+			_ => (),
+		}
+	}
+	loop_ends
+}
+
+#[cfg(test)]
+mod liveness_tests {
+	use super::*;
+	use crate::{
+		backend::bf::{Opcode, TapeCell},
+		frontend::ir::{ir_to_text, parse_ir},
+	};
+
+	fn optimise_ir(ir: &str) -> String {
+		ir_to_text(&free_dead_memory(parse_ir::<TapeCell, Opcode>(ir).unwrap()))
+	}
+
+	#[test]
+	fn early_free_1() {
+		// a's value is not needed after it is output, so b can use its cell
+		assert_eq!(
+			optimise_ir(
+				"allocate %0 cell a
+input %0
+output %0
+allocate %1 cell b
+input %1
+output %1
+"
+			),
+			"allocate %0 cell a
+input %0
+output %0
+clear %0
+free %0
+allocate %1 cell b
+input %1
+output %1
+"
+		);
+	}
+
+	#[test]
+	fn early_free_2() {
+		// the clear at the end of the scope is moved, and not needed after a loop on the cell
+		assert_eq!(
+			optimise_ir(
+				"allocate %0 cells 2
+add %0[0] 3
+add %0[1] 4
+open_loop %0[0]
+  add %0[0] -1
+close_loop %0[0]
+output %0[1]
+allocate %1 cell
+add %1 1
+output %1
+clear %1
+free %1
+add %0[1] 1
+clear %0[0]
+clear %0[1]
+free %0
+"
+			),
+			"allocate %0 cells 2
+add %0[0] 3
+add %0[1] 4
+open_loop %0[0]
+  add %0[0] -1
+close_loop %0[0]
+output %0[1]
+clear %0[1]
+free %0
+allocate %1 cell
+add %1 1
+output %1
+clear %1
+free %1
+"
+		);
+	}
+
+	#[test]
+	fn early_free_3() {
+		// memory read in a loop is needed until the end of the loop
+		assert_eq!(
+			optimise_ir(
+				"allocate %0 cell
+allocate %1 cell
+add %0 2
+input %1
+open_loop %1
+  output %0
+  add %0 1
+  allocate %2 cell
+  free %2
+  clear %1
+close_loop %1
+allocate %3 cell
+free %3
+"
+			),
+			"allocate %0 cell
+allocate %1 cell
+add %0 2
+input %1
+open_loop %1
+  output %0
+  add %0 1
+  allocate %2 cell
+  free %2
+  clear %1
+close_loop %1
+clear %0
+free %0
+free %1
+allocate %3 cell
+free %3
+"
+		);
+	}
+
+	#[test]
+	fn early_free_4() {
+		// an assertion that the cell is zero means it doesn't need clearing
+		assert_eq!(
+			optimise_ir(
+				"allocate %0 cell
+bf @%0 clobbers a(%0) { ,[-] }
+assert %0 unknown
+allocate %1 cell
+assert %0 equals 0
+free %1
+free %0
+"
+			),
+			"allocate %0 cell
+bf @%0 clobbers a(%0) { ,[-] }
+free %0
+allocate %1 cell
+free %1
+"
+		);
+	}
+
+	#[test]
+	fn unchanged_1() {
+		// fixed cells, and memory with nothing allocated after its last use, are left alone
+		let ir = "allocate %0 cell @3
+input %0
+allocate %1 cell
+output %0
+input %1
+output %1
+"
+		.to_string();
+		assert_eq!(optimise_ir(&ir), ir);
+	}
+}
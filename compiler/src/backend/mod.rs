@@ -4,4 +4,6 @@ pub mod bf;
 pub mod bf2d;
 
 mod constants_optimiser;
+mod layout;
+mod liveness;
 mod optimiser;
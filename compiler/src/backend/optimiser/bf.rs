@@ -83,12 +83,8 @@ fn optimise_bf_subset(run: Vec<Opcode>) -> Vec<Opcode> {
 		i += 1;
 	}
 	// always have a start and end cell
-	if !tape.contains_key(&0) {
-		tape.insert(0, Change::Add(Wrapping(0i8)));
-	}
-	if !tape.contains_key(&head) {
-		tape.insert(head, Change::Add(Wrapping(0i8)));
-	}
+	tape.entry(0).or_insert(Change::Add(Wrapping(0i8)));
+	tape.entry(head).or_insert(Change::Add(Wrapping(0i8)));
 
 	// This whole algorithm is probably really efficient and I reckon there's almost certainly a better way
 	// It's also just really poorly done in general, I don't understand what everything does and I wrote the damned thing
@@ -117,7 +113,7 @@ fn optimise_bf_subset(run: Vec<Opcode>) -> Vec<Opcode> {
 	// now combine the two arrays
 	let mut tape_arr: Vec<Change> = Vec::new();
 	tape_arr.extend(neg_arr.into_iter().rev());
-	tape_arr.extend(pos_arr.into_iter());
+	tape_arr.extend(pos_arr);
 
 	if ((start_index) + 1) >= (tape_arr.len()) {
 		tape_arr.resize(start_index + 1, Change::Add(Wrapping(0i8)));
@@ -173,9 +169,9 @@ fn optimise_bf_subset(run: Vec<Opcode>) -> Vec<Opcode> {
 
 				if cell < (tape_arr.len() - 1) {
 					output.push(Opcode::Right);
-					idx += 1;
 				}
 			}
+			idx = idx.max(tape_arr.len() - 1);
 		}
 		false => {
 			for cell in (0..=idx).rev() {
@@ -195,9 +191,9 @@ fn optimise_bf_subset(run: Vec<Opcode>) -> Vec<Opcode> {
 
 				if cell > 0 {
 					output.push(Opcode::Left);
-					idx -= 1;
 				}
 			}
+			idx = 0;
 		}
 	}
 
@@ -206,13 +202,11 @@ fn optimise_bf_subset(run: Vec<Opcode>) -> Vec<Opcode> {
 		true => {
 			for _ in idx..final_index {
 				output.push(Opcode::Right);
-				idx += 1;
 			}
 		}
 		false => {
 			for _ in final_index..idx {
 				output.push(Opcode::Left);
-				idx -= 1;
 			}
 		}
 	}
@@ -1,46 +1,41 @@
 #![cfg(test)]
 
-use std::io::Cursor;
-
 use crate::{
 	backend::{bf::*, bf2d::*, common::BrainfuckProgram},
-	brainfuck::{BrainfuckConfig, BrainfuckContext},
-	misc::{MastermindConfig, MastermindContext},
+	misc::{MastermindConfig, MastermindContext, MemoryAllocationMethod},
 };
 
-const CTX_OPT: MastermindContext = MastermindContext {
-	config: MastermindConfig {
+fn ctx_opt() -> MastermindContext {
+	MastermindContext::new(MastermindConfig {
 		optimise_generated_code: true,
 		optimise_generated_all_permutations: false,
 		optimise_cell_clearing: false,
 		optimise_unreachable_loops: false,
 
 		// optimise_variable_usage: false,
-
-		// optimise_memory_allocation: false,
+		optimise_memory_allocation: false,
 		optimise_constants: false,
 		optimise_empty_blocks: false,
-		memory_allocation_method: 0,
+		memory_allocation_method: MemoryAllocationMethod::Classic,
 		enable_2d_grid: false,
-	},
-};
+	})
+}
 
-const CTX_OPT_EXHAUSTIVE: MastermindContext = MastermindContext {
-	config: MastermindConfig {
+fn ctx_opt_exhaustive() -> MastermindContext {
+	MastermindContext::new(MastermindConfig {
 		optimise_generated_code: true,
 		optimise_generated_all_permutations: true,
 		optimise_cell_clearing: false,
 		optimise_unreachable_loops: false,
 
 		// optimise_variable_usage: false,
-
-		// optimise_memory_allocation: false,
+		optimise_memory_allocation: false,
 		optimise_constants: false,
 		optimise_empty_blocks: false,
-		memory_allocation_method: 0,
+		memory_allocation_method: MemoryAllocationMethod::Classic,
 		enable_2d_grid: false,
-	},
-};
+	})
+}
 
 // TODO: implement this, would have to refactor the BVM
 // fn get_tape_changes(code: &str) {
@@ -87,14 +82,14 @@ fn tally_opcodes(input: &str) -> [usize; 11] {
 
 fn _characteristic_test(input: &str, expected: &str) {
 	let ops: Vec<Opcode> = BrainfuckProgram::from_str(input);
-	let optimised = CTX_OPT.optimise_bf(ops).to_string();
+	let optimised = ctx_opt().optimise_bf(ops).to_string();
 	println!("OPTIMISED ({}): {}", optimised.len(), optimised);
 	println!("EXPECTED  ({}): {}", expected.len(), expected);
 	assert_eq!(optimised.len(), expected.len());
 	// TODO: implement actually running both codes, would require refactoring BVM
-	assert_eq!(tally_opcodes(&optimised), tally_opcodes(&expected));
+	assert_eq!(tally_opcodes(&optimised), tally_opcodes(expected));
 }
-fn _characteristic_test_2d(ctx: MastermindContext, input: &str, expected: &str) {
+fn _characteristic_test_2d(_ctx: MastermindContext, _input: &str, _expected: &str) {
 	todo!();
 }
 
@@ -102,14 +97,14 @@ fn _characteristic_test_2d(ctx: MastermindContext, input: &str, expected: &str)
 fn standard_0() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str("+++>><<++>--->+++<><><><><<<<<+++[>>>]");
 	//(3) 0  0 [5] -3 3
-	let o = CTX_OPT.optimise_bf(v).to_string();
+	let o = ctx_opt().optimise_bf(v).to_string();
 	let e = "+++<---<+++++<<<+++[>>>]";
 	assert_eq!(o, e);
 }
 #[test]
 fn standard_1() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str("<><><>++<+[--++>>+<<-]");
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	let e = "++<+[->>+<<]";
 	assert_eq!(o, e);
 }
@@ -121,27 +116,27 @@ fn standard_2() {
 	// [9] 0 (7) -4 0 0 2
 	// [(0)] 2
 	// -1 1
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	let e = "+++++++++>>+++++++>---->>>++<<<<[>++<]";
 	assert_eq!(o, e);
 }
 #[test]
 fn standard_3() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(".>><.");
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	let e = ".>.";
 	assert_eq!(o, e);
 }
 #[test]
 fn standard_4() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str("+++<+++>[-]+++[>.<+]");
-	let o = CTX_OPT.optimise_bf(v).to_string();
+	let o = ctx_opt().optimise_bf(v).to_string();
 	assert_eq!(o.len(), "+++>[-]+++[>.<+]".len());
 }
 #[test]
 fn standard_5() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str("+++<+++>[-]+++[-]<[-]--+>-[>,]");
-	let o = CTX_OPT.optimise_bf(v).to_string();
+	let o = ctx_opt().optimise_bf(v).to_string();
 	assert_eq!(o.len(), "[-]->[-]-[>,]".len());
 }
 #[test]
@@ -149,7 +144,7 @@ fn standard_6() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(
 		"+++++[-]+++++++++>>+++>---->>>++++--<--++<<hello<++++[[-]<+>>++<+<->]++--->+",
 	);
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	let e = "[-]+++++++++>>+++++++>---->>>++<<<<[[-]+>++<]";
 	assert_eq!(o, e);
 }
@@ -158,14 +153,14 @@ fn standard_6() {
 fn greedy_2d_0() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str("+++^^vv++^---^+++v^v^v^v^vvvvv+++[>>>>>>>]");
 	//(3) 0  0 [5] -3 3
-	let o = CTX_OPT.optimise_bf2d(v).to_string();
+	let o = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "+++++^---^+++vvvvv+++[>>>>>>>]";
 	assert_eq!(o, e);
 }
 #[test]
 fn greedy_2d_1() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str("v^v^v^++v+[--++^^+vv-]");
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "++v+[-^^+vv]";
 	assert_eq!(o, e);
 }
@@ -177,14 +172,14 @@ fn greedy_2d_2() {
 	// [9] 0 (7) -4 0 0 2
 	// [(0)] 2
 	// -1 1
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "+++++++++^^+++++++^----^^^++vvvv[^++v]";
 	assert_eq!(o, e);
 }
 #[test]
 fn greedy_2d_3() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(",^^v.");
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	let e = ",^.";
 	assert_eq!(o, e);
 }
@@ -192,7 +187,7 @@ fn greedy_2d_3() {
 fn greedy_2d_4() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str("+++v+++^[-]+++,");
 	//(3) 0  0 [5] -3 3
-	let o = CTX_OPT.optimise_bf2d(v).to_string();
+	let o = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "[-]+++v+++^,";
 	assert_eq!(o, e);
 }
@@ -200,7 +195,7 @@ fn greedy_2d_4() {
 fn greedy_2d_5() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str("+++v+++^[-]+++[-]v[-]--+^-,,,,...");
 	//(3) 0  0 [5] -3 3
-	let o = CTX_OPT.optimise_bf2d(v).to_string();
+	let o = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "[-]-v[-]-^,,,,...";
 	assert_eq!(o, e);
 }
@@ -212,7 +207,7 @@ fn greedy_2d_6() {
 	// [9] 0 (7) -4 0 0 2
 	// [(0)] 2
 	// -1 1
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "[-]+++++++++^^+++++++^----^^^++vvvv[[-]+^++v]";
 	assert_eq!(o, e);
 }
@@ -220,13 +215,13 @@ fn greedy_2d_6() {
 #[test]
 fn exhaustive_2d_0() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(",+++^^vv++^---^+++v^v^v^v^vvvvv+++,");
-	let o = CTX_OPT_EXHAUSTIVE.optimise_bf2d(v).to_string();
+	let o = ctx_opt_exhaustive().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), ",^^+++v---v+++++vvv+++,".len());
 }
 #[test]
 fn exhaustive_2d_1() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str("v^v^v^++v+[--++^^+vv-]");
-	let o: String = CTX_OPT_EXHAUSTIVE.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt_exhaustive().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), "++v+[^^+vv-]".len());
 }
 #[test]
@@ -234,27 +229,27 @@ fn exhaustive_2d_2() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(
 		"+++++++++^^+++^----^^^++++--v--++vvhellov++++[-v+^^++v+v-^]++---^+",
 	);
-	let o: String = CTX_OPT_EXHAUSTIVE.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt_exhaustive().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), "+++++++++^^+++++++^----^^^++vvvv[^++v]".len());
 }
 #[test]
 fn exhaustive_2d_3() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(".^^v.");
-	let o: String = CTX_OPT_EXHAUSTIVE.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt_exhaustive().optimise_bf2d(v).to_string();
 	let e = ".^.";
 	assert_eq!(o, e);
 }
 #[test]
 fn exhaustive_2d_4() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(",+++v+++^[-]+++.");
-	let o = CTX_OPT_EXHAUSTIVE.optimise_bf2d(v).to_string();
+	let o = ctx_opt_exhaustive().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), ",[-]+++v+++^.".len());
 }
 #[test]
 fn exhaustive_2d_5() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(",+++v+++^[-]+++[-]v[-]--+^-.");
 	//(3) 0  0 [5] -3 3
-	let o = CTX_OPT_EXHAUSTIVE.optimise_bf2d(v).to_string();
+	let o = ctx_opt_exhaustive().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), ",[-]-v[-]-^.".len());
 }
 #[test]
@@ -265,7 +260,7 @@ fn exhaustive_2d_6() {
 	// [9] 0 (7) -4 0 0 2
 	// [(0)] 2
 	// -1 1
-	let o: String = CTX_OPT_EXHAUSTIVE.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt_exhaustive().optimise_bf2d(v).to_string();
 	assert_eq!(
 		o.len(),
 		"[-]+++++++++^^^^^^++vvv----v+++++++[^++v[-]+]".len()
@@ -277,7 +272,7 @@ fn wrapping_0() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(
 			"-++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.",
 		);
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	assert_eq!(o.len(), 127 + 1);
 }
 #[test]
@@ -285,7 +280,7 @@ fn wrapping_1() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(
 			"++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++,",
 		);
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	assert_eq!(o.len(), 128 + 1);
 }
 #[test]
@@ -293,7 +288,7 @@ fn wrapping_2() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(
 			"+--------------------------------------------------------------------------------------------------------------------------------."
 		);
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	assert_eq!(o.len(), 127 + 1);
 }
 #[test]
@@ -301,7 +296,7 @@ fn wrapping_3() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(
 			"--------------------------------------------------------------------------------------------------------------------------------,"
 		);
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	assert_eq!(o.len(), 128 + 1);
 }
 #[test]
@@ -309,7 +304,7 @@ fn wrapping_3a() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(
 			"- --------------------------------------------------------------------------------------------------------------------------------."
 		);
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	assert_eq!(o.len(), 127 + 1);
 }
 #[test]
@@ -317,7 +312,7 @@ fn wrapping_4() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(
 			"[-]--------------------------------------------------------------------------------------------------------------------------------."
 		);
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	assert_eq!(o.len(), 131 + 1);
 }
 
@@ -326,7 +321,7 @@ fn wrapping_2d_0() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(
 			"-++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++,",
 		);
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), 127 + 1);
 }
 #[test]
@@ -334,7 +329,7 @@ fn wrapping_2d_1() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(
 			"++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.",
 		);
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), 128 + 1);
 }
 #[test]
@@ -342,7 +337,7 @@ fn wrapping_2d_2() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(
 			"+--------------------------------------------------------------------------------------------------------------------------------,"
 		);
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), 127 + 1);
 }
 #[test]
@@ -350,7 +345,7 @@ fn wrapping_2d_3() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(
 			"--------------------------------------------------------------------------------------------------------------------------------."
 		);
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), 128 + 1);
 }
 #[test]
@@ -358,7 +353,7 @@ fn wrapping_2d_3a() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(
 			"- --------------------------------------------------------------------------------------------------------------------------------,"
 		);
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), 127 + 1);
 }
 #[test]
@@ -366,35 +361,35 @@ fn wrapping_2d_4() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str(
 			"[-]--------------------------------------------------------------------------------------------------------------------------------."
 		);
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), 131 + 1);
 }
 
 #[test]
 fn offset_toplevel_0() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str("++>>>++++<<<-->>>.");
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	let e = "++++.";
 	assert_eq!(o, e);
 }
 #[test]
 fn offset_toplevel_0a() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str("[++>>>++++<<<-->>>.]");
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	let e = "[>>>++++.]";
 	assert_eq!(o, e);
 }
 #[test]
 fn offset_toplevel_1() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str(">>++>-+<++<[++>>>++++<<<-->>>.]<<");
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	let e = "++++<[>>>++++.]";
 	assert_eq!(o, e);
 }
 #[test]
 fn offset_toplevel_2() {
 	let v: Vec<Opcode> = BrainfuckProgram::from_str("[++>>>++++<<<-->>>.]>>>+++<<");
-	let o: String = CTX_OPT.optimise_bf(v).to_string();
+	let o: String = ctx_opt().optimise_bf(v).to_string();
 	let e = "[>>>++++.]";
 	assert_eq!(o, e);
 }
@@ -402,14 +397,14 @@ fn offset_toplevel_2() {
 #[test]
 fn offset_toplevel_2d_0() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str("++>>>vvv++++<<^^^<--vv>.");
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "++++<<^.";
 	assert_eq!(o, e);
 }
 #[test]
 fn offset_toplevel_2d_0a() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str("[++v>>vv>++++<<^^<^--vv>.]");
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "[>>>vvv++++<<^.]";
 	assert_eq!(o, e);
 }
@@ -417,13 +412,13 @@ fn offset_toplevel_2d_0a() {
 fn offset_toplevel_2d_1() {
 	let v: Vec<Opcode2D> =
 		BrainfuckProgram::from_str(">vv>++>^-+v<++<[++>vv>>++++<^^<<-->^^^>>.]<<");
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	let e = "++++<[>>>vv++++^^^^^.]";
 	assert_eq!(o, e);
 }
 #[test]
 fn offset_toplevel_2d_2() {
 	let v: Vec<Opcode2D> = BrainfuckProgram::from_str("[++>v>>++++<^<<-->v>>.]^^>>>+++<vvv<");
-	let o: String = CTX_OPT.optimise_bf2d(v).to_string();
+	let o: String = ctx_opt().optimise_bf2d(v).to_string();
 	assert_eq!(o.len(), "[v>>>++++.]".len());
 }
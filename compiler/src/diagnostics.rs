@@ -0,0 +1,380 @@
+// structured compiler errors, with enough location information to point back at the original source files
+
+use std::rc::Rc;
+
+/// A range of byte offsets into the (preprocessed) source code
+#[derive(Debug, Clone, Copy, Default, Hash, PartialEq, Eq, serde::Serialize)]
+pub struct Span {
+	pub start: usize,
+	pub end: usize,
+}
+
+impl Span {
+	pub fn new(start: usize, end: usize) -> Span {
+		Span { start, end }
+	}
+}
+
+/// A syntax node paired with the span of source code it was parsed from
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Spanned<T> {
+	pub node: T,
+	pub span: Span,
+}
+
+impl<T> Spanned<T> {
+	pub fn new(node: T, span: Span) -> Spanned<T> {
+		Spanned { node, span }
+	}
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Spanned<T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		self.node.fmt(f)
+	}
+}
+
+// spans are ignored when comparing syntax trees in tests, otherwise every expected clause would need exact offsets
+#[cfg(test)]
+impl<T: PartialEq> PartialEq for Spanned<T> {
+	fn eq(&self, other: &Self) -> bool {
+		self.node == other.node
+	}
+}
+
+// allows tests to write out expected syntax trees without spans
+#[cfg(test)]
+impl<T> From<T> for Spanned<T> {
+	fn from(node: T) -> Spanned<T> {
+		Spanned::new(node, Span::default())
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+	Error,
+	/// code which compiles but is probably a mistake
+	Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+	pub message: String,
+	/// None if the error could not be attributed to any specific source code
+	pub span: Option<Span>,
+	pub severity: Severity,
+}
+
+impl Diagnostic {
+	pub fn new(message: String, span: Span) -> Diagnostic {
+		Diagnostic {
+			message,
+			span: Some(span),
+			severity: Severity::Error,
+		}
+	}
+
+	pub fn warning(message: String, span: Span) -> Diagnostic {
+		Diagnostic {
+			message,
+			span: Some(span),
+			severity: Severity::Warning,
+		}
+	}
+
+	/// attach a span to this diagnostic, unless a more specific one was already attached
+	pub fn or_span(mut self, span: Span) -> Diagnostic {
+		self.span.get_or_insert(span);
+		self
+	}
+
+	/// format the diagnostic with its file, line and column, and a caret-underlined source snippet:
+	/// ```text
+	/// error: Expected `{` in code block.
+	///  --> main.mmi:3:7
+	///   |
+	/// 3 | while x output 'a';
+	///   |         ^^^^^^
+	/// ```
+	/// `source` must be the exact text the span offsets were calculated from
+	pub fn render(&self, source: &str, source_map: &SourceMap) -> String {
+		let mut rendered = match self.severity {
+			Severity::Error => format!("error: {}", self.message),
+			Severity::Warning => format!("warning: {}", self.message),
+		};
+		let Some(span) = self.span else {
+			return rendered;
+		};
+
+		// clamp the span to the source in case of an error at the end of the file
+		let start = floor_char_boundary(source, span.start.min(source.len()));
+		let end = floor_char_boundary(source, span.end.clamp(start, source.len()));
+
+		let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+		let line_end = source[start..]
+			.find('\n')
+			.map_or(source.len(), |i| start + i);
+		let line_index = source[..start].matches('\n').count();
+		let line_text = &source[line_start..line_end];
+
+		let column = source[line_start..start].chars().count();
+		// only underline the first line of a multi-line span
+		let underline_len = source[start..end.min(line_end)].chars().count().max(1);
+
+		let (file_name, line_number) = source_map.locate(line_index);
+		let line_label = line_number.to_string();
+		let gutter = " ".repeat(line_label.len());
+
+		rendered += &format!("\n{gutter}--> {file_name}:{line_number}:{}", column + 1);
+		rendered += &format!("\n{gutter} |");
+		rendered += &format!("\n{line_label} | {line_text}");
+		// keep tabs in the caret line so that the carets line up with the snippet
+		let padding: String = line_text
+			.chars()
+			.take(column)
+			.map(|c| if c == '\t' { '\t' } else { ' ' })
+			.collect();
+		rendered += &format!("\n{gutter} | {padding}{}", "^".repeat(underline_len));
+
+		rendered
+	}
+}
+
+impl Diagnostic {
+	/// the name of the original file this diagnostic points into, None if it has no span
+	pub fn file_name<'a>(&self, source: &str, source_map: &'a SourceMap) -> Option<&'a str> {
+		let span = self.span?;
+		let start = floor_char_boundary(source, span.start.min(source.len()));
+		Some(source_map.locate(source[..start].matches('\n').count()).0)
+	}
+}
+
+impl From<String> for Diagnostic {
+	fn from(message: String) -> Diagnostic {
+		Diagnostic {
+			message,
+			span: None,
+			severity: Severity::Error,
+		}
+	}
+}
+
+impl std::fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.message)
+	}
+}
+
+// allows tests to compare errors with their expected messages directly
+impl PartialEq<&str> for Diagnostic {
+	fn eq(&self, other: &&str) -> bool {
+		self.message == *other
+	}
+}
+
+/// Every error found while compiling a program, so that they can all be fixed in one go
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+	/// record an error, ignoring exact duplicates such as errors in a function body which is compiled once per call
+	pub fn push(&mut self, diagnostic: Diagnostic) {
+		if !self.0.contains(&diagnostic) {
+			self.0.push(diagnostic);
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// sort the errors by where they occur in the source code, errors without a span go last
+	pub fn sorted(mut self) -> Diagnostics {
+		self.0
+			.sort_by_key(|diagnostic| diagnostic.span.map_or(usize::MAX, |span| span.start));
+		self
+	}
+
+	/// drop warnings in files other than the given one, so that warnings in included libraries are not shown
+	pub fn retain_warnings_in(&mut self, file_name: &str, source: &str, source_map: &SourceMap) {
+		self.0.retain(|diagnostic| {
+			diagnostic.severity == Severity::Error
+				|| diagnostic
+					.file_name(source, source_map)
+					.is_none_or(|name| name == file_name)
+		});
+	}
+
+	/// render every error as in `Diagnostic::render`, separated by blank lines
+	pub fn render(&self, source: &str, source_map: &SourceMap) -> String {
+		self.0
+			.iter()
+			.map(|diagnostic| diagnostic.render(source, source_map))
+			.collect::<Vec<_>>()
+			.join("\n\n")
+	}
+}
+
+impl From<Diagnostic> for Diagnostics {
+	fn from(diagnostic: Diagnostic) -> Diagnostics {
+		Diagnostics(vec![diagnostic])
+	}
+}
+
+impl From<String> for Diagnostics {
+	fn from(message: String) -> Diagnostics {
+		Diagnostic::from(message).into()
+	}
+}
+
+impl std::fmt::Display for Diagnostics {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		for (i, diagnostic) in self.0.iter().enumerate() {
+			if i > 0 {
+				f.write_str("\n")?;
+			}
+			write!(f, "{diagnostic}")?;
+		}
+		Ok(())
+	}
+}
+
+// allows tests to compare a single error with its expected message directly
+impl PartialEq<&str> for Diagnostics {
+	fn eq(&self, other: &&str) -> bool {
+		matches!(self.0.as_slice(), [diagnostic] if diagnostic == other)
+	}
+}
+
+pub fn floor_char_boundary(s: &str, mut index: usize) -> usize {
+	while !s.is_char_boundary(index) {
+		index -= 1;
+	}
+	index
+}
+
+/// Maps each line of preprocessed source code back to the file and line it was originally written on
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+	lines: Vec<(Rc<str>, usize)>,
+}
+
+impl SourceMap {
+	pub fn new() -> SourceMap {
+		SourceMap { lines: Vec::new() }
+	}
+
+	/// create a source map for a program which was not preprocessed from multiple files
+	pub fn from_single_file(file_name: &str, contents: &str) -> SourceMap {
+		let mut source_map = SourceMap::new();
+		let file_name: Rc<str> = Rc::from(file_name);
+		for line_number in 1..=contents.lines().count() {
+			source_map.push_line(file_name.clone(), line_number);
+		}
+		source_map
+	}
+
+	/// record that the next line of the preprocessed output came from a given file and (1-based) line number
+	pub fn push_line(&mut self, file_name: Rc<str>, line_number: usize) {
+		self.lines.push((file_name, line_number));
+	}
+
+	pub fn extend(&mut self, other: SourceMap) {
+		self.lines.extend(other.lines);
+	}
+
+	/// find the (0-based) preprocessed line that a file's (1-based) line ended up on,
+	/// None if the line was left out, e.g. a preprocessor directive
+	pub fn find_line(&self, file_name: &str, line_number: usize) -> Option<usize> {
+		self.lines
+			.iter()
+			.position(|(name, number)| **name == *file_name && *number == line_number)
+	}
+
+	/// whether any line of the preprocessed output came from a file
+	pub fn contains_file(&self, file_name: &str) -> bool {
+		self.lines.iter().any(|(name, _)| **name == *file_name)
+	}
+
+	/// get the original file name and (1-based) line number of a (0-based) preprocessed line
+	pub fn locate(&self, line_index: usize) -> (&str, usize) {
+		match self.lines.get(line_index) {
+			Some((file_name, line_number)) => (file_name, *line_number),
+			// trailing lines with no mapping, e.g. an error at the very end of the file
+			None => match self.lines.last() {
+				Some((file_name, line_number)) => {
+					(file_name, line_number + line_index + 1 - self.lines.len())
+				}
+				None => ("<unknown>", line_index + 1),
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod diagnostics_tests {
+	use super::*;
+
+	#[test]
+	fn render_1() {
+		let source = "cell a = 5;\nwhile a output 'a';\n";
+		let diagnostic = Diagnostic::new(
+			String::from("Expected `{` in code block."),
+			Span::new(20, 26),
+		);
+		assert_eq!(
+			diagnostic.render(source, &SourceMap::from_single_file("main.mmi", source)),
+			r#"error: Expected `{` in code block.
+ --> main.mmi:2:9
+  |
+2 | while a output 'a';
+  |         ^^^^^^"#
+		);
+	}
+
+	#[test]
+	fn render_2() {
+		let source = "{\n\tcell[4 g;\n}";
+		let diagnostic = Diagnostic::new(
+			String::from("Expected `]` in array subscript."),
+			Span::new(10, 11),
+		);
+		assert_eq!(
+			diagnostic.render(source, &SourceMap::from_single_file("file", source)),
+			"error: Expected `]` in array subscript.\n --> file:2:9\n  |\n2 | \tcell[4 g;\n  | \t       ^"
+		);
+	}
+
+	#[test]
+	fn render_no_span() {
+		let diagnostic = Diagnostic::from(String::from("Something went wrong."));
+		assert_eq!(
+			diagnostic.render("", &SourceMap::new()),
+			"error: Something went wrong."
+		);
+	}
+
+	#[test]
+	fn render_end_of_file() {
+		let source = "output 'a'";
+		let diagnostic = Diagnostic::new(
+			String::from("Expected semicolon at end of output clause."),
+			Span::new(10, 10),
+		);
+		assert_eq!(
+			diagnostic.render(source, &SourceMap::from_single_file("main.mmi", source)),
+			"error: Expected semicolon at end of output clause.\n --> main.mmi:1:11\n  |\n1 | output 'a'\n  |           ^"
+		);
+	}
+
+	#[test]
+	fn source_map_includes() {
+		let mut source_map = SourceMap::from_single_file("a.mmi", "1\n2\n");
+		source_map.extend(SourceMap::from_single_file("b.mmi", "1\n2\n3\n"));
+		source_map.push_line(Rc::from("a.mmi"), 4);
+		assert_eq!(source_map.locate(0), ("a.mmi", 1));
+		assert_eq!(source_map.locate(3), ("b.mmi", 2));
+		assert_eq!(source_map.locate(5), ("a.mmi", 4));
+		assert_eq!(source_map.locate(7), ("a.mmi", 6));
+	}
+}
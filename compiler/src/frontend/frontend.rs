@@ -1,29 +1,76 @@
 // compile syntax tree into low-level instructions
 
-use super::types::*;
+use super::{types::*, warnings::find_warnings};
 use crate::{
 	backend::common::{
 		BrainfuckBuilder, BrainfuckBuilderData, CellAllocator, CellAllocatorData, OpcodeVariant,
 		TapeCellVariant,
 	},
+	diagnostics::{Diagnostic, Diagnostics, Span, Spanned},
 	macros::macros::*,
 	misc::MastermindContext,
 	parser::{
-		expressions::Expression,
+		expressions::{BinaryOperator, Expression, Sign},
 		types::{
 			Clause, ExtendedOpcode, LocationSpecifier, StructFieldTypeDefinition, VariableTarget,
 			VariableTypeDefinition, VariableTypeReference,
 		},
 	},
 };
-use std::{collections::HashMap, fmt::Display, iter::zip};
+use std::{
+	cell::{Cell, RefCell},
+	collections::{HashMap, HashSet},
+	fmt::Display,
+	iter::zip,
+};
+
+// Runtime dynamic array access, see the memory layout in types.rs.
+// These start at the array's index cell and must leave the head at the element's gap cell, or vice versa.
+/// count the index down through the gap cells leaving breadcrumbs, ends at the element's gap cell
+const DYNAMIC_SEEK_BF: &str = "[-[->>+<<]+>>]";
+/// move the element's value home into the transfer and restore cells one at a time, then move the restore cell back
+const DYNAMIC_READ_BF: &str = ">[-<<<[<<]<+>>+>[>>]>]<<<[<<]<[->>>[>>]>+<<<[<<]<]>>>[>>]";
+/// walk home and carry the transfer cell to the element one at a time
+const DYNAMIC_WRITE_BF: &str = "<<[<<]>[->[>>]>+<<<[<<]>]>[>>]";
+/// walk back along the breadcrumbs clearing them, ends at the index cell
+const DYNAMIC_RETURN_BF: &str = "<<[-<<]>>";
+
+/// convert generated Brainfuck into opcodes, used for code that moves the head at runtime
+fn bf_opcodes<OC: OpcodeVariant>(bf: &str) -> Vec<OC> {
+	bf.chars().filter_map(OC::try_from_char).collect()
+}
 
 impl MastermindContext {
+	/// compile a program (or a block within `outer_scope`), returning every error found rather than just the first,
+	/// errors within an outer scope are recorded in the outermost scope instead
 	pub fn create_ir_scope<'a, TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
 		&self,
-		clauses: &[Clause<TC, OC>],
+		clauses: &[Spanned<Clause<TC, OC>>],
+		outer_scope: Option<&'a ScopeBuilder<TC, OC>>,
+	) -> Result<ScopeBuilder<'a, TC, OC>, Diagnostics>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		if outer_scope.is_none() {
+			for warning in find_warnings(clauses) {
+				self.warn(warning);
+			}
+		}
+		let scope = self.compile_scope(clauses, outer_scope);
+		let diagnostics = scope.diagnostics.take();
+		if !diagnostics.is_empty() {
+			return Err(diagnostics.sorted());
+		}
+		Ok(scope)
+	}
+
+	/// compile a block of clauses, recording errors and carrying on with the next clause
+	fn compile_scope<'a, TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		clauses: &[Spanned<Clause<TC, OC>>],
 		outer_scope: Option<&'a ScopeBuilder<TC, OC>>,
-	) -> Result<ScopeBuilder<'a, TC, OC>, String>
+	) -> ScopeBuilder<'a, TC, OC>
 	where
 		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
 		CellAllocatorData<TC>: CellAllocator<TC>,
@@ -39,13 +86,16 @@ impl MastermindContext {
 		let mut filtered_clauses_1 = vec![];
 		// first stage: structs (these need to be defined before functions, so they can be used as arguments)
 		for clause in clauses {
-			match clause {
+			match &clause.node {
 				Clause::DefineStruct { name, fields } => {
 					// convert fields with 2D or 1D location specifiers to valid struct location specifiers
-					scope.register_struct_definition(name, fields.clone())?;
+					if let Err(err) = scope.register_struct_definition(name, fields.clone()) {
+						scope.report(Diagnostic::new(err, clause.span));
+					}
 				}
-				// also filter out None clauses (although there shouldn't be any)
-				Clause::None => (),
+				// also filter out None clauses (although there shouldn't be any),
+				// and constants which have already been substituted by the parser
+				Clause::None | Clause::DefineConstant { name: _, value: _ } => (),
 				_ => filtered_clauses_1.push(clause.clone()),
 			}
 		}
@@ -53,608 +103,1308 @@ impl MastermindContext {
 		let mut filtered_clauses_2 = vec![];
 		for clause in filtered_clauses_1 {
 			match clause {
-				Clause::DefineFunction {
-					name,
-					arguments,
-					block,
+				Spanned {
+					node:
+						Clause::DefineFunction {
+							name,
+							arguments,
+							block,
+							return_type,
+						},
+					span,
 				} => {
-					scope.register_function_definition(&name, arguments.clone(), block.clone())?;
+					// the function is still defined, so that calls to it don't report errors too
+					if let Some(return_type) = &return_type {
+						if !block.iter().any(|clause| clause.node.always_returns()) {
+							scope.report(Diagnostic::new(
+								format!(
+									"Function \"{name}\" must return a value of type `{return_type}` at the end of its body."
+								),
+								span,
+							));
+						}
+					}
+					if let Err(err) =
+						scope.register_function_definition(&name, arguments, block, return_type)
+					{
+						scope.report(Diagnostic::new(err, span));
+					}
 				}
-				_ => {
+				clause => {
 					filtered_clauses_2.push(clause);
 				}
 			}
 		}
 
-		for clause in filtered_clauses_2 {
-			match clause {
-				Clause::DeclareVariable { var } => {
-					// create an allocation in the scope
-					scope.allocate_variable(var)?;
+		let mut clauses = filtered_clauses_2.into_iter();
+		while let Some(clause) = clauses.next() {
+			let has_loop_control = clause.node.contains_loop_control(true);
+			let defined_variable = match &clause.node {
+				Clause::DeclareVariable { var } | Clause::DefineVariable { var, value: _ } => {
+					Some(var.name.clone())
+				}
+				_ => None,
+			};
+			let failed_variable_uses = scope.failed_variable_uses().get();
+			let result = self.compile_clause(&mut scope, clause.node, clause.span);
+			if let Err(err) = result {
+				// avoid reporting every later use of a variable which could not be defined
+				if !scope.is_failed_variable_use_since(failed_variable_uses) {
+					scope.report(err.or_span(clause.span));
+				}
+				if let Some(name) = defined_variable {
+					if !scope.variable_memory.contains_key(&name) {
+						scope.failed_variables.insert(name);
+					}
 				}
-				Clause::DefineVariable { var, value } => {
-					// same as above except we initialise the variable
-					let absolute_type = scope.allocate_variable(var.clone())?;
+			}
 
-					match (absolute_type, &value) {
-						(
-							ValueType::Cell,
-							Expression::NaturalNumber(_)
-							| Expression::SumExpression {
-								sign: _,
-								summands: _,
-							}
-							| Expression::VariableReference(_),
-						) => {
-							let cell = scope.get_cell(&VariableTarget::from_definition(&var))?;
-							scope._add_expr_to_cell(&value, cell)?;
+			// the rest of the block only runs if there was no `break`, `continue` or `return`
+			if has_loop_control {
+				let remaining: Vec<_> = clauses.collect();
+				if !remaining.is_empty() {
+					let failed_variable_uses = scope.failed_variable_uses().get();
+					if let Err(err) = self.compile_guarded_clauses(&mut scope, &remaining) {
+						if !scope.is_failed_variable_use_since(failed_variable_uses) {
+							scope.report(err.or_span(clause.span));
 						}
+					}
+				}
+				break;
+			}
+		}
 
-						// multi-cell arrays and (array literals or strings)
-						(ValueType::Array(_, _), Expression::ArrayLiteral(expressions)) => {
-							let cells =
-								scope.get_array_cells(&VariableTarget::from_definition(&var))?;
-							r_assert!(
-								expressions.len() == cells.len(),
-								"Variable \"{var}\" cannot be initialised to array of length {}",
-								expressions.len()
-							);
-							for (cell, expr) in zip(cells, expressions) {
-								scope._add_expr_to_cell(expr, cell)?;
-							}
-						}
-						(ValueType::Array(_, _), Expression::StringLiteral(s)) => {
-							let cells =
-								scope.get_array_cells(&VariableTarget::from_definition(&var))?;
-							r_assert!(
-								s.len() == cells.len(),
-								"Variable \"{var}\" cannot be initialised to string of length {}",
-								s.len()
-							);
-							for (cell, chr) in zip(cells, s.bytes()) {
-								scope.push_instruction(Instruction::AddToCell(cell, chr));
-							}
-						}
+		scope
+	}
 
-						(
-							ValueType::Array(_, _),
-							Expression::VariableReference(variable_target),
-						) => r_panic!(
-							"Cannot assign array \"{var}\" from variable reference \
-\"{variable_target}\". Unimplemented."
-						),
-						(
-							ValueType::Array(_, _),
-							Expression::NaturalNumber(_)
-							| Expression::SumExpression {
-								sign: _,
-								summands: _,
+	/// compile a loop body with hidden flag cells for `break` and `continue` if needed,
+	/// the loop cell must be opened before and closed after calling this
+	fn compile_loop_body<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		block: &[Spanned<Clause<TC, OC>>],
+		loop_cell: CellReference,
+	) -> Result<(), Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		if !block.iter().any(|c| c.node.contains_loop_control(true)) {
+			let loop_scope = self.compile_scope(block, Some(&*scope));
+			scope.instructions.extend(loop_scope.build_ir(true));
+			return Ok(());
+		}
+
+		// the active flag is set at the start of every iteration, and cleared by `break` or `continue`
+		let active = scope._allocate_temp_cell();
+		scope.push_instruction(Instruction::AddToCell(active, 1));
+
+		let mut control_scope = scope.open_inner();
+		control_scope.flow_control = FlowControl::Loop {
+			active,
+			loop_cell: block
+				.iter()
+				.any(|c| c.node.contains_loop_control(false))
+				.then_some(loop_cell),
+		};
+		let loop_scope = self.compile_scope(block, Some(&control_scope));
+		control_scope.instructions.extend(loop_scope.build_ir(true));
+		scope.instructions.extend(control_scope.build_ir(true));
+
+		scope.push_instruction(Instruction::ClearCell(active));
+		scope.push_instruction(Instruction::Free(active.memory_id));
+
+		Ok(())
+	}
+
+	/// compile the clauses following a `break`, `continue` or `return`,
+	/// only running them if the innermost loop's or function's active flag is still set
+	fn compile_guarded_clauses<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		clauses: &[Spanned<Clause<TC, OC>>],
+	) -> Result<(), Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		let Some(active) = scope.get_active_flag() else {
+			r_panic!("Control flow statements can only be used inside a loop or function.");
+		};
+
+		let guard_cell = scope._allocate_temp_cell();
+		scope._copy_cell(active, guard_cell, 1);
+		scope.push_instruction(Instruction::OpenLoop(guard_cell));
+		scope.push_instruction(Instruction::ClearCell(guard_cell));
+
+		let guarded_scope = self.compile_scope(clauses, Some(&*scope));
+		scope.instructions.extend(guarded_scope.build_ir(true));
+
+		scope.push_instruction(Instruction::CloseLoop(guard_cell));
+		scope.push_instruction(Instruction::Free(guard_cell.memory_id));
+
+		Ok(())
+	}
+
+	/// compile a single clause into the given scope,
+	/// errors without a span are attributed to the whole clause by the caller
+	fn compile_clause<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		clause: Clause<TC, OC>,
+		span: Span,
+	) -> Result<(), Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		let mut clause = match clause {
+			// a variable defined directly from a function call is used as the return value memory, avoiding a copy
+			Clause::DefineVariable {
+				var,
+				value:
+					Spanned {
+						node:
+							Expression::FunctionCall {
+								function_name,
+								mut arguments,
 							},
-						) => r_panic!("Cannot assign single value to array \"{var}\"."),
+						span: value_span,
+					},
+			} => {
+				let mut hoisted_values = vec![];
+				for argument in arguments.iter_mut() {
+					hoisted_values.extend(self.compile_hoisted_values(scope, argument, false)?);
+				}
+				scope.allocate_variable(var.clone())?;
+				let (var_type, memory) = scope.get_base_variable_memory(&var.name)?;
+				let result = (var_type.clone(), memory.clone());
+				self.compile_function_call(scope, function_name, arguments, Some(result))
+					.map_err(|err| err.or_span(value_span))?;
+				scope._free_hoisted_values(hoisted_values);
+				return Ok(());
+			}
+			Clause::DrainLoop {
+				source:
+					Spanned {
+						node: Expression::VariableReference(var),
+						span: source_span,
+					},
+				targets: _,
+				block: _,
+				is_copying: false,
+			} if var.split_dynamic_index().is_some() => {
+				r_panic_at!(
+					source_span,
+					"Cannot drain runtime-indexed array element \"{var}\", use `copy` instead."
+				)
+			}
+			clause => clause,
+		};
 
-						(
-							ValueType::DictStruct(_),
-							Expression::SumExpression {
-								sign: _,
-								summands: _,
-							}
-							| Expression::NaturalNumber(_)
-							| Expression::VariableReference(_)
-							| Expression::ArrayLiteral(_)
-							| Expression::StringLiteral(_),
-						) => r_panic!(
-							"Cannot assign value to struct type \"{var}\", initialise it instead."
-						),
-
-						(ValueType::Cell, Expression::ArrayLiteral(_)) => {
-							r_panic!("Cannot assign array to single-cell variable \"{var}\".")
+		// evaluate any function calls and runtime array reads within the clause's expressions first,
+		// the results are freed after the clause
+		let mut hoisted_values = vec![];
+		// function arguments and return values are type checked against the function, anything else must be a cell
+		let expects_cell = !matches!(
+			clause,
+			Clause::CallFunction {
+				function_name: _,
+				arguments: _
+			} | Clause::Return { value: _ }
+		);
+		for expr in clause.hoistable_expressions() {
+			hoisted_values.extend(self.compile_hoisted_values(scope, expr, expects_cell)?);
+		}
+
+		match clause {
+			Clause::DeclareVariable { var } => {
+				// create an allocation in the scope
+				scope.allocate_variable(var)?;
+			}
+			Clause::DefineVariable { var, value } => {
+				// same as above except we initialise the variable
+				let absolute_type = scope.allocate_variable(var.clone())?;
+
+				match (absolute_type, &value.node) {
+					(
+						ValueType::Cell,
+						Expression::NaturalNumber(_)
+						| Expression::SumExpression {
+							sign: _,
+							summands: _,
 						}
-						(ValueType::Cell, Expression::StringLiteral(_)) => {
-							r_panic!("Cannot assign string to single-cell variable \"{var}\".")
+						| Expression::BinaryExpression {
+							operator: _,
+							left: _,
+							right: _,
 						}
-					}
-				}
-				Clause::Assign {
-					var,
-					value,
-					self_referencing,
-				} => match (var.is_spread, self_referencing) {
-					(false, false) => {
-						let cell = scope.get_cell(&var)?;
-						scope.push_instruction(Instruction::ClearCell(cell.clone()));
+						| Expression::VariableReference(_),
+					) => {
+						let cell = scope.get_cell(&VariableTarget::from_definition(&var))?;
 						scope._add_expr_to_cell(&value, cell)?;
 					}
-					(false, true) => {
-						let cell = scope.get_cell(&var)?;
-						scope._add_self_referencing_expr_to_cell(value, cell, true)?;
-					}
-					(true, _) => {
-						r_panic!("Unsupported operation, assigning to spread variable: {var}");
-						// TODO: support spread assigns?
-						// let cells = scope.get_array_cells(&var)?;
-						// etc...
-					}
-				},
-				Clause::AddAssign {
-					var,
-					value,
-					self_referencing,
-				} => match (var.is_spread, self_referencing) {
-					(false, false) => {
-						let cell = scope.get_cell(&var)?;
-						scope._add_expr_to_cell(&value, cell)?;
-					}
-					(false, true) => {
-						let cell = scope.get_cell(&var)?;
-						scope._add_self_referencing_expr_to_cell(value, cell, false)?;
+
+					// multi-cell arrays and (array literals or strings)
+					(
+						ValueType::Array(_, _) | ValueType::DynamicArray(_),
+						Expression::ArrayLiteral(expressions),
+					) => {
+						let cells =
+							scope.get_array_cells(&VariableTarget::from_definition(&var))?;
+						r_assert!(
+							expressions.len() == cells.len(),
+							"Variable \"{var}\" cannot be initialised to array of length {}",
+							expressions.len()
+						);
+						for (cell, expr) in zip(cells, expressions) {
+							scope._add_expr_to_cell(expr, cell)?;
+						}
 					}
-					(true, _) => {
-						r_panic!("Unsupported operation, add-assigning to spread variable: {var}");
-						// TODO: support spread assigns?
-						// let cells = scope.get_array_cells(&var)?;
-						// etc...
+					(
+						ValueType::Array(_, _) | ValueType::DynamicArray(_),
+						Expression::StringLiteral(s),
+					) => {
+						let cells =
+							scope.get_array_cells(&VariableTarget::from_definition(&var))?;
+						r_assert!(
+							s.len() == cells.len(),
+							"Variable \"{var}\" cannot be initialised to string of length {}",
+							s.len()
+						);
+						for (cell, chr) in zip(cells, s.bytes()) {
+							scope.push_instruction(Instruction::AddToCell(cell, chr));
+						}
 					}
-				},
-				Clause::AssertVariableValue { var, value } => {
-					// unfortunately no array assertions due to a limitation with my data-structure/algorithm design
-					let imm = {
-						match value {
-							Some(expr) => {
-								let (imm, adds, subs) = expr.flatten()?;
-
-								r_assert!(
-									adds.len() == 0 && subs.len() == 0,
-									"Expected compile-time constant expression \
-in assertion for {var}"
-								);
 
-								Some(imm)
-							}
-							None => None,
+					(
+						ValueType::Array(_, _) | ValueType::DynamicArray(_),
+						Expression::VariableReference(variable_target),
+					) => {
+						r_panic!(
+							"Cannot assign array \"{var}\" from variable reference \
+\"{variable_target}\". Unimplemented."
+						)
+					}
+					(
+						ValueType::Array(_, _) | ValueType::DynamicArray(_),
+						Expression::NaturalNumber(_)
+						| Expression::SumExpression {
+							sign: _,
+							summands: _,
 						}
-					};
+						| Expression::BinaryExpression {
+							operator: _,
+							left: _,
+							right: _,
+						},
+					) => r_panic!("Cannot assign single value to array \"{var}\"."),
 
-					match var.is_spread {
-						false => {
-							let cell = scope.get_cell(&var)?;
-							scope.push_instruction(Instruction::AssertCellValue(cell, imm));
+					(
+						ValueType::DictStruct(_),
+						Expression::SumExpression {
+							sign: _,
+							summands: _,
 						}
-						true => {
-							let cells = scope.get_array_cells(&var)?;
-							for cell in cells {
-								scope.push_instruction(Instruction::AssertCellValue(cell, imm));
-							}
+						| Expression::BinaryExpression {
+							operator: _,
+							left: _,
+							right: _,
 						}
+						| Expression::NaturalNumber(_)
+						| Expression::VariableReference(_)
+						| Expression::ArrayLiteral(_)
+						| Expression::StringLiteral(_),
+					) => r_panic!(
+						"Cannot assign value to struct type \"{var}\", initialise it instead."
+					),
+
+					(ValueType::Cell, Expression::ArrayLiteral(_)) => {
+						r_panic!("Cannot assign array to single-cell variable \"{var}\".")
+					}
+					(ValueType::Cell, Expression::StringLiteral(_)) => {
+						r_panic!("Cannot assign string to single-cell variable \"{var}\".")
 					}
+					(
+						_,
+						Expression::FunctionCall {
+							function_name: _,
+							arguments: _,
+						},
+					) => unreachable!("Function calls are compiled before the clause."),
+				}
+			}
+			Clause::Assign {
+				var,
+				value,
+				self_referencing: _,
+			} if var.split_dynamic_index().is_some() => {
+				let (array_cells, index) = scope.get_dynamic_element(&var)?.unwrap();
+				self.compile_dynamic_write(scope, &array_cells, &index, Some(&value), true)?;
+			}
+			Clause::AddAssign {
+				var,
+				value,
+				self_referencing: _,
+			} if var.split_dynamic_index().is_some() => {
+				let (array_cells, index) = scope.get_dynamic_element(&var)?.unwrap();
+				self.compile_dynamic_write(scope, &array_cells, &index, Some(&value), false)?;
+			}
+			Clause::Input { var } if var.split_dynamic_index().is_some() => {
+				let (array_cells, index) = scope.get_dynamic_element(&var)?.unwrap();
+				self.compile_dynamic_write(scope, &array_cells, &index, None, true)?;
+			}
+			Clause::Assign {
+				var,
+				value,
+				self_referencing,
+			} => match (var.is_spread, self_referencing) {
+				(false, false) => {
+					let cell = scope.get_cell(&var)?;
+					scope.push_instruction(Instruction::ClearCell(cell));
+					scope._add_expr_to_cell(&value, cell)?;
+				}
+				(false, true) => {
+					let cell = scope.get_cell(&var)?;
+					scope._add_self_referencing_expr_to_cell(value, cell, true)?;
+				}
+				(true, _) => {
+					r_panic!("Unsupported operation, assigning to spread variable: {var}");
+					// TODO: support spread assigns?
+					// let cells = scope.get_array_cells(&var)?;
+					// etc...
+				}
+			},
+			Clause::AddAssign {
+				var,
+				value,
+				self_referencing,
+			} => match (var.is_spread, self_referencing) {
+				(false, false) => {
+					let cell = scope.get_cell(&var)?;
+					scope._add_expr_to_cell(&value, cell)?;
+				}
+				(false, true) => {
+					let cell = scope.get_cell(&var)?;
+					scope._add_self_referencing_expr_to_cell(value, cell, false)?;
 				}
-				Clause::Input { var } => match var.is_spread {
+				(true, _) => {
+					r_panic!("Unsupported operation, add-assigning to spread variable: {var}");
+					// TODO: support spread assigns?
+					// let cells = scope.get_array_cells(&var)?;
+					// etc...
+				}
+			},
+			Clause::AssertVariableValue { var, value } => {
+				// unfortunately no array assertions due to a limitation with my data-structure/algorithm design
+				let imm = {
+					match value {
+						Some(expr) => {
+							let (imm, adds, subs) =
+								expr.node.flatten().map_err(|err| err.or_span(expr.span))?;
+
+							r_assert_at!(
+								expr.span,
+								adds.is_empty() && subs.is_empty(),
+								"Expected compile-time constant expression \
+in assertion for {var}"
+							);
+
+							Some(imm)
+						}
+						None => None,
+					}
+				};
+
+				match var.is_spread {
 					false => {
 						let cell = scope.get_cell(&var)?;
-						scope.push_instruction(Instruction::InputToCell(cell));
+						scope.push_instruction(Instruction::AssertCellValue(cell, imm));
 					}
 					true => {
 						let cells = scope.get_array_cells(&var)?;
 						for cell in cells {
-							scope.push_instruction(Instruction::InputToCell(cell));
+							scope.push_instruction(Instruction::AssertCellValue(cell, imm));
 						}
 					}
-				},
-				Clause::Output { value } => {
-					match value {
-						Expression::VariableReference(var) => match var.is_spread {
-							false => {
-								let cell = scope.get_cell(&var)?;
+				}
+			}
+			Clause::Input { var } => match var.is_spread {
+				false => {
+					let cell = scope.get_cell(&var)?;
+					scope.push_instruction(Instruction::InputToCell(cell));
+				}
+				true => {
+					let cells = scope.get_array_cells(&var)?;
+					for cell in cells {
+						scope.push_instruction(Instruction::InputToCell(cell));
+					}
+				}
+			},
+			Clause::Output { value } => {
+				match &value.node {
+					Expression::VariableReference(var) => match var.is_spread {
+						false => {
+							let cell = scope
+								.get_cell(var)
+								.map_err(|err| Diagnostic::new(err, value.span))?;
+							scope.push_instruction(Instruction::OutputCell(cell));
+						}
+						true => {
+							let cells = scope
+								.get_array_cells(var)
+								.map_err(|err| Diagnostic::new(err, value.span))?;
+							for cell in cells {
 								scope.push_instruction(Instruction::OutputCell(cell));
 							}
-							true => {
-								let cells = scope.get_array_cells(&var)?;
-								for cell in cells {
-									scope.push_instruction(Instruction::OutputCell(cell));
-								}
-							}
-						},
-						Expression::SumExpression {
-							sign: _,
-							summands: _,
 						}
-						| Expression::NaturalNumber(_) => {
-							// allocate a temporary cell and add the expression to it, output, then clear
-							let temp_mem_id = scope.push_memory_id();
-							scope.push_instruction(Instruction::Allocate(
-								Memory::Cell { id: temp_mem_id },
-								None,
-							));
-							let cell = CellReference {
-								memory_id: temp_mem_id,
-								index: None,
-							};
+					},
+					Expression::SumExpression {
+						sign: _,
+						summands: _,
+					}
+					| Expression::BinaryExpression {
+						operator: _,
+						left: _,
+						right: _,
+					}
+					| Expression::NaturalNumber(_) => {
+						// allocate a temporary cell and add the expression to it, output, then clear
+						let temp_mem_id = scope.push_memory_id();
+						scope.push_instruction(Instruction::Allocate(
+							Memory::Cell { id: temp_mem_id },
+							None,
+							None,
+						));
+						let cell = CellReference {
+							memory_id: temp_mem_id,
+							index: None,
+						};
+
+						scope._add_expr_to_cell(&value, cell)?;
+						scope.push_instruction(Instruction::OutputCell(cell));
+						scope.push_instruction(Instruction::ClearCell(cell));
 
-							scope._add_expr_to_cell(&value, cell)?;
+						scope.push_instruction(Instruction::Free(temp_mem_id));
+					}
+					Expression::ArrayLiteral(expressions) => {
+						// same as above, except reuse the temporary cell after each output
+						let temp_mem_id = scope.push_memory_id();
+						scope.push_instruction(Instruction::Allocate(
+							Memory::Cell { id: temp_mem_id },
+							None,
+							None,
+						));
+						let cell = CellReference {
+							memory_id: temp_mem_id,
+							index: None,
+						};
+
+						for value in expressions {
+							scope._add_expr_to_cell(value, cell)?;
 							scope.push_instruction(Instruction::OutputCell(cell));
 							scope.push_instruction(Instruction::ClearCell(cell));
-
-							scope.push_instruction(Instruction::Free(temp_mem_id));
 						}
-						Expression::ArrayLiteral(expressions) => {
-							// same as above, except reuse the temporary cell after each output
-							let temp_mem_id = scope.push_memory_id();
-							scope.push_instruction(Instruction::Allocate(
-								Memory::Cell { id: temp_mem_id },
-								None,
-							));
-							let cell = CellReference {
-								memory_id: temp_mem_id,
-								index: None,
-							};
 
-							for value in expressions {
-								scope._add_expr_to_cell(&value, cell)?;
-								scope.push_instruction(Instruction::OutputCell(cell));
-								scope.push_instruction(Instruction::ClearCell(cell));
-							}
+						scope.push_instruction(Instruction::Free(temp_mem_id));
+					}
+					Expression::StringLiteral(s) => {
+						// same as above, allocate one temporary cell and reuse it for each character
+						let temp_mem_id = scope.push_memory_id();
+						scope.push_instruction(Instruction::Allocate(
+							Memory::Cell { id: temp_mem_id },
+							None,
+							None,
+						));
+						let cell = CellReference {
+							memory_id: temp_mem_id,
+							index: None,
+						};
 
-							scope.push_instruction(Instruction::Free(temp_mem_id));
-						}
-						Expression::StringLiteral(s) => {
-							// same as above, allocate one temporary cell and reuse it for each character
-							let temp_mem_id = scope.push_memory_id();
-							scope.push_instruction(Instruction::Allocate(
-								Memory::Cell { id: temp_mem_id },
-								None,
+						let mut prev = 0;
+						for c in s.bytes() {
+							scope.push_instruction(Instruction::AddToCell(
+								cell,
+								c.wrapping_sub(prev),
 							));
-							let cell = CellReference {
-								memory_id: temp_mem_id,
-								index: None,
-							};
-
-							let mut prev = 0;
-							for c in s.bytes() {
-								scope.push_instruction(Instruction::AddToCell(
-									cell,
-									c.wrapping_sub(prev),
-								));
-								scope.push_instruction(Instruction::OutputCell(cell));
-								prev = c;
-							}
-							scope.push_instruction(Instruction::ClearCell(cell));
-							scope.push_instruction(Instruction::Free(temp_mem_id));
+							scope.push_instruction(Instruction::OutputCell(cell));
+							prev = c;
 						}
+						scope.push_instruction(Instruction::ClearCell(cell));
+						scope.push_instruction(Instruction::Free(temp_mem_id));
 					}
+					Expression::FunctionCall {
+						function_name: _,
+						arguments: _,
+					} => unreachable!("Function calls are compiled before the clause."),
 				}
-				Clause::While { var, block } => {
-					let cell = scope.get_cell(&var)?;
+			}
+			Clause::While { condition, block } => {
+				let has_break = block.iter().any(|c| c.node.contains_loop_control(false));
+
+				// loop directly on the variable if possible, otherwise evaluate the condition into a temporary cell
+				// breaking clears the loop cell, so user variables can't be used directly
+				let (cell, is_temp) = match (&condition.node, has_break) {
+					(Expression::VariableReference(var), false)
+						if var.split_dynamic_index().is_none() =>
+					{
+						let cell = scope
+							.get_cell(var)
+							.map_err(|err| Diagnostic::new(err, condition.span))?;
+						(cell, false)
+					}
+					_ => {
+						let cell = scope._allocate_temp_cell();
+						self.compile_expr_to_cell(scope, &condition, cell)?;
+						(cell, true)
+					}
+				};
 
-					// open loop on variable
-					scope.push_instruction(Instruction::OpenLoop(cell));
+				// open loop on condition cell
+				scope.push_instruction(Instruction::OpenLoop(cell));
+
+				// recursively compile instructions
+				// TODO: when recursively compiling, check which things changed based on a return info value
+				self.compile_loop_body(scope, &block, cell)?;
+
+				// re-evaluate the condition before the next iteration
+				if is_temp {
+					if has_break {
+						// only re-evaluate if the loop wasn't broken out of
+						let guard_cell = scope._allocate_temp_cell();
+						scope._copy_cell(cell, guard_cell, 1);
+						scope.push_instruction(Instruction::OpenLoop(guard_cell));
+						scope.push_instruction(Instruction::ClearCell(guard_cell));
+						scope.push_instruction(Instruction::ClearCell(cell));
+						self.compile_expr_to_cell(scope, &condition, cell)?;
+						scope.push_instruction(Instruction::CloseLoop(guard_cell));
+						scope.push_instruction(Instruction::Free(guard_cell.memory_id));
+					} else {
+						scope.push_instruction(Instruction::ClearCell(cell));
+						self.compile_expr_to_cell(scope, &condition, cell)?;
+					}
+				}
 
-					// recursively compile instructions
-					// TODO: when recursively compiling, check which things changed based on a return info value
-					let loop_scope = self.create_ir_scope(&block, Some(&scope))?;
-					scope.instructions.extend(loop_scope.build_ir(true));
+				// close the loop
+				scope.push_instruction(Instruction::CloseLoop(cell));
 
-					// close the loop
-					scope.push_instruction(Instruction::CloseLoop(cell));
+				if is_temp {
+					scope.push_instruction(Instruction::Free(cell.memory_id));
 				}
-				Clause::DrainLoop {
-					source,
-					targets,
-					block,
-					is_copying,
-				} => {
-					// TODO: refactor this, there is duplicate code with copying the source value cell
-					let (source_cell, free_source_cell) = match (is_copying, &source) {
-						// draining loops can drain from an expression or a variable
-						(false, Expression::VariableReference(var)) => {
-							(scope.get_cell(var)?, false)
+			}
+			Clause::Break => {
+				let Some((active, loop_cell)) = scope.get_loop_control() else {
+					r_panic!("`break` can only be used inside a loop.");
+				};
+				scope.push_instruction(Instruction::ClearCell(active));
+				if let Some(loop_cell) = loop_cell {
+					scope.push_instruction(Instruction::ClearCell(loop_cell));
+				}
+			}
+			Clause::Continue => {
+				let Some((active, _)) = scope.get_loop_control() else {
+					r_panic!("`continue` can only be used inside a loop.");
+				};
+				scope.push_instruction(Instruction::ClearCell(active));
+			}
+			Clause::Return { value } => {
+				let Some((flag_cells, result)) = scope.get_return_control() else {
+					r_panic!("`return` can only be used inside a function.");
+				};
+				match (value, result) {
+					(Some(value), Some((result_type, result_cells))) => {
+						let value_type = scope.get_expression_type(&value)?;
+						r_assert_at!(
+							value.span,
+							value_type == result_type,
+							"Expected return value of type `{result_type}`, found `{value}` of type `{value_type}`."
+						);
+						scope._add_value_expr_to_cells(&value, &result_type, &result_cells)?;
+					}
+					(None, None) => (),
+					(Some(value), None) => {
+						r_panic_at!(
+							value.span,
+							"Cannot return `{value}` from a function without a return type."
+						)
+					}
+					(None, Some((result_type, _))) => {
+						r_panic!("Expected a return value of type `{result_type}`.")
+					}
+				}
+				// exit any loops between here and the function body, then the function itself
+				for cell in flag_cells {
+					scope.push_instruction(Instruction::ClearCell(cell));
+				}
+			}
+			Clause::DrainLoop {
+				source,
+				targets,
+				block,
+				is_copying,
+			} => {
+				// TODO: refactor this, there is duplicate code with copying the source value cell
+				let (source_cell, free_source_cell) = match (is_copying, &source.node) {
+					// draining loops can drain from an expression or a variable
+					(false, Expression::VariableReference(var)) => {
+						let cell = scope
+							.get_cell(var)
+							.map_err(|err| Diagnostic::new(err, source.span))?;
+						(cell, false)
+					}
+					(false, _) => {
+						// any other kind of expression, allocate memory for it automatically
+						let id = scope.push_memory_id();
+						scope.push_instruction(Instruction::Allocate(
+							Memory::Cell { id },
+							None,
+							None,
+						));
+						let new_cell = CellReference {
+							memory_id: id,
+							index: None,
+						};
+						scope._add_expr_to_cell(&source, new_cell)?;
+						(new_cell, true)
+					}
+					(true, Expression::VariableReference(var)) => {
+						let cell = scope
+							.get_cell(var)
+							.map_err(|err| Diagnostic::new(err, source.span))?;
+
+						let new_mem_id = scope.push_memory_id();
+						scope.push_instruction(Instruction::Allocate(
+							Memory::Cell { id: new_mem_id },
+							None,
+							None,
+						));
+
+						let new_cell = CellReference {
+							memory_id: new_mem_id,
+							index: None,
+						};
+
+						scope._copy_cell(cell, new_cell, 1);
+
+						(new_cell, true)
+					}
+					(true, _) => {
+						r_panic_at!(
+							source.span,
+							"Cannot copy from {:#?}, use a drain loop instead",
+							source.node
+						)
+					}
+				};
+				scope.push_instruction(Instruction::OpenLoop(source_cell));
+
+				// recurse
+				let block = block.unwrap_or_default();
+				self.compile_loop_body(scope, &block, source_cell)?;
+
+				// breaking clears the source cell, so the rest of the iteration is skipped
+				let guard_cell = block
+					.iter()
+					.any(|c| c.node.contains_loop_control(false))
+					.then(|| {
+						let guard_cell = scope._allocate_temp_cell();
+						scope._copy_cell(source_cell, guard_cell, 1);
+						scope.push_instruction(Instruction::OpenLoop(guard_cell));
+						scope.push_instruction(Instruction::ClearCell(guard_cell));
+						guard_cell
+					});
+
+				// copy into each target and decrement the source
+				for target in targets {
+					match target.is_spread {
+						false => {
+							let cell = scope.get_cell(&target)?;
+							scope.push_instruction(Instruction::AddToCell(cell, 1));
 						}
-						(false, _) => {
-							// any other kind of expression, allocate memory for it automatically
-							let id = scope.push_memory_id();
-							scope
-								.push_instruction(Instruction::Allocate(Memory::Cell { id }, None));
-							let new_cell = CellReference {
-								memory_id: id,
-								index: None,
-							};
-							scope._add_expr_to_cell(&source, new_cell)?;
-							(new_cell, true)
+						true => {
+							let cells = scope.get_array_cells(&target)?;
+							for cell in cells {
+								scope.push_instruction(Instruction::AddToCell(cell, 1));
+							}
 						}
-						(true, Expression::VariableReference(var)) => {
-							let cell = scope.get_cell(var)?;
+					}
+				}
 
-							let new_mem_id = scope.push_memory_id();
-							scope.push_instruction(Instruction::Allocate(
-								Memory::Cell { id: new_mem_id },
-								None,
-							));
+				scope.push_instruction(Instruction::AddToCell(source_cell, -1i8 as u8)); // 255
 
-							let new_cell = CellReference {
-								memory_id: new_mem_id,
-								index: None,
-							};
+				if let Some(guard_cell) = guard_cell {
+					scope.push_instruction(Instruction::CloseLoop(guard_cell));
+					scope.push_instruction(Instruction::Free(guard_cell.memory_id));
+				}
 
-							scope._copy_cell(cell, new_cell, 1);
+				scope.push_instruction(Instruction::CloseLoop(source_cell));
 
-							(new_cell, true)
-						}
-						(true, _) => {
-							r_panic!("Cannot copy from {source:#?}, use a drain loop instead")
-						}
-					};
-					scope.push_instruction(Instruction::OpenLoop(source_cell));
+				// free the source cell if it was a expression we just created
+				if free_source_cell {
+					scope.push_instruction(Instruction::Free(source_cell.memory_id));
+				}
+			}
+			clause @ (Clause::If {
+				condition: _,
+				if_block: _,
+			}
+			| Clause::IfNot {
+				condition: _,
+				if_not_block: _,
+			}
+			| Clause::IfElse {
+				condition: _,
+				if_block: _,
+				else_block: _,
+			}
+			| Clause::IfNotElse {
+				condition: _,
+				if_not_block: _,
+				else_block: _,
+			}) => {
+				// If-else clause types changed recently, so here is a patch to keep the original frontend code:
+				let (condition, if_block, else_block) = match clause {
+					Clause::If {
+						condition,
+						if_block,
+					} => (condition, Some(if_block), None),
+					Clause::IfNot {
+						condition,
+						if_not_block,
+					} => (condition, None, Some(if_not_block)),
+					Clause::IfElse {
+						condition,
+						if_block,
+						else_block,
+					} => (condition, Some(if_block), Some(else_block)),
+					Clause::IfNotElse {
+						condition,
+						if_not_block,
+						else_block,
+					} => (condition, Some(else_block), Some(if_not_block)),
+					_ => unreachable!(),
+				};
+				// end patch //
 
-					// recurse
-					if let Some(block) = block {
-						let loop_scope = self.create_ir_scope(&block, Some(&scope))?;
-						// TODO: refactor, make a function in scope trait to do this automatically
-						scope.instructions.extend(loop_scope.build_ir(true));
-					}
+				if if_block.is_none() && else_block.is_none() {
+					panic!("Expected block in if/else statement");
+				};
+				let mut new_scope = scope.open_inner();
 
-					// copy into each target and decrement the source
-					for target in targets {
-						match target.is_spread {
-							false => {
-								let cell = scope.get_cell(&target)?;
-								scope.push_instruction(Instruction::AddToCell(cell, 1));
-							}
-							true => {
-								let cells = scope.get_array_cells(&target)?;
-								for cell in cells {
-									scope.push_instruction(Instruction::AddToCell(cell, 1));
-								}
-							}
-						}
+				let condition_mem_id = new_scope.push_memory_id();
+				new_scope.push_instruction(Instruction::Allocate(
+					Memory::Cell {
+						id: condition_mem_id,
+					},
+					None,
+					None,
+				));
+				let condition_cell = CellReference {
+					memory_id: condition_mem_id,
+					index: None,
+				};
+
+				let else_condition_cell = match else_block {
+					Some(_) => {
+						let else_mem_id = new_scope.push_memory_id();
+						new_scope.push_instruction(Instruction::Allocate(
+							Memory::Cell { id: else_mem_id },
+							None,
+							None,
+						));
+						let else_cell = CellReference {
+							memory_id: else_mem_id,
+							index: None,
+						};
+						new_scope.push_instruction(Instruction::AddToCell(else_cell, 1));
+						Some(else_cell)
 					}
+					None => None,
+				};
+
+				// copy the condition expression to the temporary condition cell
+				new_scope._add_expr_to_cell(&condition, condition_cell)?;
 
-					scope.push_instruction(Instruction::AddToCell(source_cell, -1i8 as u8)); // 255
-					scope.push_instruction(Instruction::CloseLoop(source_cell));
+				new_scope.push_instruction(Instruction::OpenLoop(condition_cell));
+				// TODO: think about optimisations for clearing this variable, as the builder won't shorten it for safety as it doesn't know this loop is special
+				new_scope.push_instruction(Instruction::ClearCell(condition_cell));
 
-					// free the source cell if it was a expression we just created
-					if free_source_cell {
-						scope.push_instruction(Instruction::Free(source_cell.memory_id));
+				// set the else condition cell
+				// above comment about optimisations also applies here
+				if let Some(cell) = else_condition_cell {
+					new_scope.push_instruction(Instruction::ClearCell(cell));
+				};
+
+				// recursively compile if block
+				if let Some(block) = if_block {
+					let if_scope = self.compile_scope(&block, Some(&new_scope));
+					new_scope.instructions.extend(if_scope.build_ir(true));
+				};
+
+				// close if block
+				new_scope.push_instruction(Instruction::CloseLoop(condition_cell));
+				new_scope.push_instruction(Instruction::Free(condition_cell.memory_id));
+
+				// else block:
+				if let Some(cell) = else_condition_cell {
+					new_scope.push_instruction(Instruction::OpenLoop(cell));
+					// again think about how to optimise this clear in the build step
+					new_scope.push_instruction(Instruction::ClearCell(cell));
+
+					// recursively compile else block
+					// TODO: fix this bad practice unwrap
+					let block = else_block.unwrap();
+					let else_scope = self.compile_scope(&block, Some(&new_scope));
+					new_scope.instructions.extend(else_scope.build_ir(true));
+
+					new_scope.push_instruction(Instruction::CloseLoop(cell));
+					new_scope.push_instruction(Instruction::Free(cell.memory_id));
+				}
+
+				// extend the inner scopes instructions onto the outer one
+				scope.instructions.extend(new_scope.build_ir(true));
+			}
+			Clause::Block(clauses) => {
+				let new_scope = self.compile_scope(&clauses, Some(&*scope));
+				scope.instructions.extend(new_scope.build_ir(true));
+			}
+			Clause::Brainfuck {
+				location_specifier,
+				clobbered_variables,
+				operations,
+			} => {
+				// loop through the opcodes
+				let mut expanded_bf: Vec<OC> = Vec::new();
+				for op in operations {
+					match op {
+						ExtendedOpcode::Block(mm_clauses) => {
+							// create a scope object for functions from the outside scope
+							let functions_scope = scope.open_inner_templates_only();
+							// compile the block and extend the operations
+							let instructions = self
+								.compile_scope(&mm_clauses, Some(&functions_scope))
+								// compile without cleaning up top level variables, this is the brainfuck programmer's responsibility
+								.build_ir(false);
+
+							// it is also the brainfuck programmer's responsibility to return to the start position
+							let bf_code = self.ir_to_bf(instructions, Some(TC::origin_cell()))?;
+							expanded_bf.extend(bf_code);
+						}
+						ExtendedOpcode::Opcode(opcode) => expanded_bf.push(opcode),
 					}
 				}
-				clause @ (Clause::If {
-					condition: _,
-					if_block: _,
+
+				// handle the location specifier
+				let location = match location_specifier {
+					LocationSpecifier::None => CellLocation::Unspecified,
+					LocationSpecifier::Cell(cell) => CellLocation::FixedCell(cell),
+					LocationSpecifier::Variable(var) => {
+						CellLocation::MemoryCell(scope.get_target_cell_reference(&var)?)
+					}
+				};
+
+				let mut clobbers = Vec::new();
+				for var in clobbered_variables {
+					let cells = match var.is_spread {
+						false => vec![scope.get_cell(&var)?],
+						true => scope.get_array_cells(&var)?,
+					};
+					clobbers.push(ClobberedVariable {
+						name: var.to_string(),
+						cells,
+						span,
+					});
 				}
-				| Clause::IfNot {
-					condition: _,
-					if_not_block: _,
+
+				// assert that we clobbered the variables
+				// not sure whether this should go before or after the actual bf code
+				let clobbered_cells: Vec<CellReference> = clobbers
+					.iter()
+					.flat_map(|clobber| clobber.cells.iter().copied())
+					.collect();
+				scope.push_instruction(Instruction::InsertBrainfuckAtCell(
+					expanded_bf,
+					location,
+					clobbers,
+				));
+				for cell in clobbered_cells {
+					scope.push_instruction(Instruction::AssertCellValue(cell, None));
 				}
-				| Clause::IfElse {
-					condition: _,
-					if_block: _,
-					else_block: _,
+			}
+			Clause::CallFunction {
+				function_name,
+				arguments,
+			} => {
+				// discard the return value if there is one
+				if let Some((_, memory)) =
+					self.compile_function_call(scope, function_name, arguments, None)?
+				{
+					scope._clear_and_free(&memory);
 				}
-				| Clause::IfNotElse {
-					condition: _,
-					if_not_block: _,
-					else_block: _,
-				}) => {
-					// If-else clause types changed recently, so here is a patch to keep the original frontend code:
-					let (condition, if_block, else_block) = match clause {
-						Clause::If {
-							condition,
-							if_block,
-						} => (condition, Some(if_block), None),
-						Clause::IfNot {
-							condition,
-							if_not_block,
-						} => (condition, None, Some(if_not_block)),
-						Clause::IfElse {
-							condition,
-							if_block,
-							else_block,
-						} => (condition, Some(if_block), Some(else_block)),
-						Clause::IfNotElse {
-							condition,
-							if_not_block,
-							else_block,
-						} => (condition, Some(else_block), Some(if_not_block)),
-						_ => unreachable!(),
-					};
-					// end patch //
+			}
+			Clause::DefineStruct { name: _, fields: _ }
+			| Clause::DefineConstant { name: _, value: _ }
+			| Clause::DefineFunction {
+				name: _,
+				arguments: _,
+				block: _,
+				return_type: _,
+			}
+			| Clause::None => unreachable!(),
+		}
 
-					if if_block.is_none() && else_block.is_none() {
-						panic!("Expected block in if/else statement");
-					};
-					let mut new_scope = scope.open_inner();
+		scope._free_hoisted_values(hoisted_values);
 
-					let condition_mem_id = new_scope.push_memory_id();
-					new_scope.push_instruction(Instruction::Allocate(
-						Memory::Cell {
-							id: condition_mem_id,
-						},
-						None,
-					));
-					let condition_cell = CellReference {
-						memory_id: condition_mem_id,
-						index: None,
-					};
+		Ok(())
+	}
 
-					let else_condition_cell = match else_block {
-						Some(_) => {
-							let else_mem_id = new_scope.push_memory_id();
-							new_scope.push_instruction(Instruction::Allocate(
-								Memory::Cell { id: else_mem_id },
-								None,
-							));
-							let else_cell = CellReference {
-								memory_id: else_mem_id,
-								index: None,
-							};
-							new_scope.push_instruction(Instruction::AddToCell(else_cell, 1));
-							Some(else_cell)
-						}
-						None => None,
-					};
+	/// inline a function call into the given scope, the return value is written to the given memory if provided,
+	/// otherwise it is allocated and returned, and must be cleared and freed by the caller
+	fn compile_function_call<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		function_name: String,
+		arguments: Vec<Spanned<Expression>>,
+		result: Option<(ValueType, Memory)>,
+	) -> Result<Option<(ValueType, Memory)>, Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		// create variable translations and recursively compile the inner variable block
 
-					// copy the condition expression to the temporary condition cell
-					new_scope._add_expr_to_cell(&condition, condition_cell)?;
+		// get the calling arguments' types
+		let calling_argument_types: Vec<ValueType> = arguments
+			.iter()
+			.map(|arg| scope.get_expression_type(arg))
+			.collect::<Result<Vec<ValueType>, Diagnostic>>()?;
 
-					new_scope.push_instruction(Instruction::OpenLoop(condition_cell));
-					// TODO: think about optimisations for clearing this variable, as the builder won't shorten it for safety as it doesn't know this loop is special
-					new_scope.push_instruction(Instruction::ClearCell(condition_cell));
+		// find the function based on name * types
+		let function_definition = scope.get_function(&function_name, &calling_argument_types)?;
 
-					// set the else condition cell
-					// above comment about optimisations also applies here
-					if let Some(cell) = else_condition_cell {
-						new_scope.push_instruction(Instruction::ClearCell(cell));
-					};
+		// the return value is written directly into the caller's memory if given
+		let (result, is_allocated_result) = match (&function_definition.return_type, result) {
+			(Some(return_type), Some((result_type, memory))) => {
+				r_assert!(
+					*return_type == result_type,
+					"Cannot assign return value of type `{return_type}` \
+from function \"{function_name}\" to a variable of type `{result_type}`."
+				);
+				(Some((result_type, memory)), false)
+			}
+			(Some(return_type), None) => {
+				let memory = scope._allocate_temp_memory(return_type)?;
+				(Some((return_type.clone(), memory)), true)
+			}
+			(None, Some(_)) => r_panic!("Function \"{function_name}\" does not return a value."),
+			(None, None) => (None, false),
+		};
 
-					// recursively compile if block
-					if let Some(block) = if_block {
-						let if_scope = self.create_ir_scope(&block, Some(&new_scope))?;
-						new_scope.instructions.extend(if_scope.build_ir(true));
-					};
+		// evaluate any non-variable arguments into temporary memory, variables are passed by reference
+		assert_eq!(arguments.len(), function_definition.arguments.len());
+		let mut temp_arguments = vec![];
+		for (calling_expr, (_, arg_type)) in zip(&arguments, &function_definition.arguments) {
+			temp_arguments.push(match &calling_expr.node {
+				Expression::VariableReference(_) => None,
+				_ => Some(scope._allocate_temp_value(calling_expr, arg_type)?),
+			});
+		}
+
+		// the function only needs an active flag if it can return before the end of its body
+		let block = &function_definition.block;
+		let has_early_return = block.iter().enumerate().any(|(i, clause)| {
+			clause.node.contains_return()
+				&& !(i + 1 == block.len() && matches!(clause.node, Clause::Return { value: _ }))
+		});
+		let active = has_early_return.then(|| {
+			let active = scope._allocate_temp_cell();
+			scope.push_instruction(Instruction::AddToCell(active, 1));
+			active
+		});
+
+		// create mappings in a new translation scope, so mappings will be removed once scope closes
+		let mut argument_translation_scope = scope.open_inner();
+		// loops outside of the function can't be broken out of from within
+		argument_translation_scope.flow_control = FlowControl::Function {
+			active,
+			result: result
+				.as_ref()
+				.map(|(result_type, memory)| (result_type.clone(), memory.cells())),
+		};
+		for ((calling_expr, (arg_name, arg_type)), temp_memory) in zip(
+			zip(arguments, function_definition.arguments),
+			&temp_arguments,
+		) {
+			match (calling_expr.node, temp_memory) {
+				(Expression::VariableReference(var), _) => {
+					argument_translation_scope
+						.create_mapped_variable(arg_name, &var)
+						.map_err(|err| Diagnostic::new(err, calling_expr.span))?;
+				}
+				(_, Some(memory)) => {
+					argument_translation_scope
+						.create_mapped_temp_variable(arg_name, arg_type, memory)?;
+				}
+				(_, None) => unreachable!(),
+			}
+		}
 
-					// close if block
-					new_scope.push_instruction(Instruction::CloseLoop(condition_cell));
-					new_scope.push_instruction(Instruction::Free(condition_cell.memory_id));
+		// recursively compile the function block
+		let function_scope = self.compile_scope(
+			&function_definition.block,
+			Some(&argument_translation_scope),
+		);
+		argument_translation_scope
+			.instructions
+			.extend(function_scope.build_ir(true));
 
-					// else block:
-					if let Some(cell) = else_condition_cell {
-						new_scope.push_instruction(Instruction::OpenLoop(cell));
-						// again think about how to optimise this clear in the build step
-						new_scope.push_instruction(Instruction::ClearCell(cell));
+		// add the recursively compiled instructions to the current scope's built instructions
+		// TODO: figure out why this .build_ir() call uses clean_up_variables = false
+		scope
+			.instructions
+			.extend(argument_translation_scope.build_ir(false));
 
-						// recursively compile else block
-						// TODO: fix this bad practice unwrap
-						let block = else_block.unwrap();
-						let else_scope = self.create_ir_scope(&block, Some(&new_scope))?;
-						new_scope.instructions.extend(else_scope.build_ir(true));
+		if let Some(active) = active {
+			scope.push_instruction(Instruction::ClearCell(active));
+			scope.push_instruction(Instruction::Free(active.memory_id));
+		}
 
-						new_scope.push_instruction(Instruction::CloseLoop(cell));
-						new_scope.push_instruction(Instruction::Free(cell.memory_id));
-					}
+		// clean up the temporary arguments, the function may have left values in them
+		for memory in temp_arguments.into_iter().flatten() {
+			scope._clear_and_free(&memory);
+		}
 
-					// extend the inner scopes instructions onto the outer one
-					scope.instructions.extend(new_scope.build_ir(true));
+		Ok(if is_allocated_result { result } else { None })
+	}
+
+	/// compile any function calls or runtime array reads within an expression, replacing them with hidden variables holding their values,
+	/// returns the names of the hidden variables which must be freed with `_free_hoisted_values` after use,
+	/// `expects_cell` is whether the expression's value must be a single cell, e.g. a summand
+	fn compile_hoisted_values<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		expr: &mut Spanned<Expression>,
+		expects_cell: bool,
+	) -> Result<Vec<String>, Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		let mut hoisted_values = vec![];
+		let span = expr.span;
+		match &mut expr.node {
+			Expression::FunctionCall {
+				function_name: _,
+				arguments,
+			} => {
+				for argument in arguments.iter_mut() {
+					hoisted_values.extend(self.compile_hoisted_values(scope, argument, false)?);
+				}
+				let call = format!("{expr}");
+				let Expression::FunctionCall {
+					function_name,
+					arguments,
+				} = std::mem::replace(&mut expr.node, Expression::NaturalNumber(0))
+				else {
+					unreachable!();
+				};
+				let Some((value_type, memory)) = self
+					.compile_function_call(scope, function_name, arguments, None)
+					.map_err(|err| err.or_span(span))?
+				else {
+					r_panic_at!(span, "Function call `{call}` does not return a value.");
+				};
+				r_assert_at!(
+					span,
+					!expects_cell || value_type == ValueType::Cell,
+					"Expected a cell value, found function call `{call}` which returns `{value_type}`."
+				);
+				// '#' can't appear in a variable name so this can't clash with user variables
+				let name = format!("{call}#{}", memory.id());
+				scope
+					.variable_memory
+					.insert(name.clone(), (value_type, memory));
+				expr.node = Expression::VariableReference(VariableTarget {
+					name: name.clone(),
+					subfields: None,
+					is_spread: false,
+				});
+				hoisted_values.push(name);
+			}
+			Expression::SumExpression { sign: _, summands } => {
+				for summand in summands.iter_mut() {
+					hoisted_values.extend(self.compile_hoisted_values(scope, summand, true)?);
 				}
-				Clause::Block(clauses) => {
-					let new_scope = self.create_ir_scope(&clauses, Some(&scope))?;
-					scope.instructions.extend(new_scope.build_ir(true));
+			}
+			Expression::ArrayLiteral(elements) => {
+				for element in elements.iter_mut() {
+					hoisted_values.extend(self.compile_hoisted_values(scope, element, false)?);
 				}
-				Clause::Brainfuck {
-					location_specifier,
-					clobbered_variables,
-					operations,
-				} => {
-					// loop through the opcodes
-					let mut expanded_bf: Vec<OC> = Vec::new();
-					for op in operations {
-						match op {
-							ExtendedOpcode::Block(mm_clauses) => {
-								// create a scope object for functions from the outside scope
-								let functions_scope = scope.open_inner_templates_only();
-								// compile the block and extend the operations
-								let instructions = self
-									.create_ir_scope(&mm_clauses, Some(&functions_scope))?
-									// compile without cleaning up top level variables, this is the brainfuck programmer's responsibility
-									.build_ir(false);
-
-								// it is also the brainfuck programmer's responsibility to return to the start position
-								let bf_code =
-									self.ir_to_bf(instructions, Some(TC::origin_cell()))?;
-								expanded_bf.extend(bf_code);
-							}
-							ExtendedOpcode::Opcode(opcode) => expanded_bf.push(opcode),
-						}
-					}
+			}
+			Expression::BinaryExpression {
+				operator: _,
+				left,
+				right,
+			} => {
+				hoisted_values.extend(self.compile_hoisted_values(scope, left, true)?);
+				hoisted_values.extend(self.compile_hoisted_values(scope, right, true)?);
+			}
+			Expression::VariableReference(var) => {
+				let dynamic_element = scope
+					.get_dynamic_element(var)
+					.map_err(|err| Diagnostic::new(err, span))?;
+				if let Some((array_cells, index)) = dynamic_element {
+					let cell = scope._allocate_temp_cell();
+					self.compile_dynamic_read(scope, &array_cells, &index, cell)
+						.map_err(|err| err.or_span(span))?;
+					let name = format!("{var}#{}", cell.memory_id);
+					scope.variable_memory.insert(
+						name.clone(),
+						(ValueType::Cell, Memory::Cell { id: cell.memory_id }),
+					);
+					expr.node = Expression::VariableReference(VariableTarget {
+						name: name.clone(),
+						subfields: None,
+						is_spread: false,
+					});
+					hoisted_values.push(name);
+				}
+			}
+			Expression::NaturalNumber(_) | Expression::StringLiteral(_) => (),
+		}
+		Ok(hoisted_values)
+	}
+
+	/// add the runtime index of a dynamic array access to the array's index cell
+	fn compile_dynamic_index<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		array_cells: &[CellReference],
+		index: &VariableTarget,
+	) -> Result<(), Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		let index_cell = array_cells[DYNAMIC_ARRAY_INDEX_CELL];
+		match scope.get_dynamic_element(index)? {
+			Some((index_array_cells, index_index)) => {
+				self.compile_dynamic_read(scope, &index_array_cells, &index_index, index_cell)?
+			}
+			None => {
+				let cell = scope.get_cell(index)?;
+				scope._copy_cell(cell, index_cell, 1);
+			}
+		}
+		Ok(())
+	}
 
-					// handle the location specifier
-					let location = match location_specifier {
-						LocationSpecifier::None => CellLocation::Unspecified,
-						LocationSpecifier::Cell(cell) => CellLocation::FixedCell(cell),
-						LocationSpecifier::Variable(var) => {
-							CellLocation::MemoryCell(scope.get_target_cell_reference(&var)?)
-						}
-					};
+	/// add the value of a runtime-indexed dynamic array element to a cell, leaving the element unchanged
+	fn compile_dynamic_read<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		array_cells: &[CellReference],
+		index: &VariableTarget,
+		cell: CellReference,
+	) -> Result<(), Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		self.compile_dynamic_index(scope, array_cells, index)?;
+		let index_cell = array_cells[DYNAMIC_ARRAY_INDEX_CELL];
+		let transfer_cell = array_cells[DYNAMIC_ARRAY_TRANSFER_CELL];
+
+		// move the element's value home into both the transfer and restore cells, then move the restore cell back
+		let bf = [DYNAMIC_SEEK_BF, DYNAMIC_READ_BF, DYNAMIC_RETURN_BF].concat();
+		scope.push_instruction(Instruction::InsertBrainfuckAtCell(
+			bf_opcodes(&bf),
+			CellLocation::MemoryCell(index_cell),
+			vec![],
+		));
+		scope.push_instruction(Instruction::AssertCellValue(index_cell, None));
+		scope.push_instruction(Instruction::AssertCellValue(transfer_cell, None));
 
-					scope.push_instruction(Instruction::InsertBrainfuckAtCell(
-						expanded_bf,
-						location,
-					));
-					// assert that we clobbered the variables
-					// not sure whether this should go before or after the actual bf code
-					for var in clobbered_variables {
-						match var.is_spread {
-							false => {
-								let cell = scope.get_cell(&var)?;
-								scope.push_instruction(Instruction::AssertCellValue(cell, None));
-							}
-							true => {
-								let cells = scope.get_array_cells(&var)?;
-								for cell in cells {
-									scope
-										.push_instruction(Instruction::AssertCellValue(cell, None));
-								}
-							}
-						}
-					}
-				}
-				Clause::CallFunction {
-					function_name,
-					arguments,
-				} => {
-					// create variable translations and recursively compile the inner variable block
-
-					// get the calling arguments' types
-					let calling_argument_types: Vec<ValueType> = arguments
-						.iter()
-						.map(|arg| scope.get_expression_type(arg))
-						.collect::<Result<Vec<ValueType>, String>>()?;
-
-					// find the function based on name * types
-					let function_definition =
-						scope.get_function(&function_name, &calling_argument_types)?;
-
-					// create mappings in a new translation scope, so mappings will be removed once scope closes
-					let mut argument_translation_scope = scope.open_inner();
-					assert_eq!(arguments.len(), function_definition.arguments.len());
-					for (calling_expr, (arg_name, _)) in
-						zip(arguments, function_definition.arguments)
-					{
-						// TODO: allow expressions as arguments: create a new variable instead of mapping when a value needs to be computed
-						let calling_arg = match calling_expr {
-							Expression::VariableReference(var) => var,
-							expr => r_panic!(
-								"Expected variable target in function call argument, \
-found expression `{expr}`. General expressions as \
-function arguments are not supported."
-							),
-						};
-						argument_translation_scope
-							.create_mapped_variable(arg_name, &calling_arg)?;
-					}
+		scope._drain_cell(transfer_cell, cell, 1);
+		Ok(())
+	}
 
-					// recursively compile the function block
-					let function_scope = self.create_ir_scope(
-						&function_definition.block,
-						Some(&argument_translation_scope),
-					)?;
-					argument_translation_scope
-						.instructions
-						.extend(function_scope.build_ir(true));
-
-					// add the recursively compiled instructions to the current scope's built instructions
-					// TODO: figure out why this .build_ir() call uses clean_up_variables = false
-					scope
-						.instructions
-						.extend(argument_translation_scope.build_ir(false));
-				}
-				Clause::DefineStruct { name: _, fields: _ }
-				| Clause::DefineFunction {
-					name: _,
-					arguments: _,
-					block: _,
+	/// assign or add a value to a runtime-indexed dynamic array element, the value is read from input if None
+	fn compile_dynamic_write<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		array_cells: &[CellReference],
+		index: &VariableTarget,
+		value: Option<&Spanned<Expression>>,
+		is_assignment: bool,
+	) -> Result<(), Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		// the index is evaluated first, as it may read from this array using the transfer cell
+		self.compile_dynamic_index(scope, array_cells, index)?;
+		let index_cell = array_cells[DYNAMIC_ARRAY_INDEX_CELL];
+		let transfer_cell = array_cells[DYNAMIC_ARRAY_TRANSFER_CELL];
+
+		let mut bf = String::from(DYNAMIC_SEEK_BF);
+		if is_assignment {
+			bf.push_str(">[-]<");
+		}
+		match value {
+			Some(expr) if expr.node.is_constant() => {
+				// constants can be added directly to the element without carrying them from the transfer cell
+				let (imm, _, _) = expr.node.flatten().map_err(|err| err.or_span(expr.span))?;
+				bf.push('>');
+				bf.push_str(&match imm {
+					0..=128 => "+".repeat(imm as usize),
+					_ => "-".repeat(256 - imm as usize),
+				});
+				bf.push('<');
+			}
+			_ => {
+				match value {
+					Some(expr) => scope._add_expr_to_cell(expr, transfer_cell)?,
+					None => scope.push_instruction(Instruction::InputToCell(transfer_cell)),
 				}
-				| Clause::None => unreachable!(),
+				bf.push_str(DYNAMIC_WRITE_BF);
 			}
 		}
+		bf.push_str(DYNAMIC_RETURN_BF);
 
-		Ok(scope)
+		scope.push_instruction(Instruction::InsertBrainfuckAtCell(
+			bf_opcodes(&bf),
+			CellLocation::MemoryCell(index_cell),
+			vec![],
+		));
+		scope.push_instruction(Instruction::AssertCellValue(index_cell, None));
+		scope.push_instruction(Instruction::AssertCellValue(transfer_cell, None));
+		for element_cell in array_cells
+			.iter()
+			.skip(DYNAMIC_ARRAY_INDEX_CELL + 1)
+			.step_by(2)
+		{
+			scope.push_instruction(Instruction::AssertCellValue(*element_cell, None));
+		}
+		Ok(())
+	}
+
+	/// evaluate an expression into a cell, compiling any function calls within it first
+	fn compile_expr_to_cell<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		scope: &mut ScopeBuilder<'_, TC, OC>,
+		expr: &Spanned<Expression>,
+		cell: CellReference,
+	) -> Result<(), Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		let mut expr = expr.clone();
+		let hoisted_values = self.compile_hoisted_values(scope, &mut expr, true)?;
+		scope._add_expr_to_cell(&expr, cell)?;
+		scope._free_hoisted_values(hoisted_values);
+		Ok(())
 	}
 }
 
@@ -675,12 +1425,22 @@ pub struct ScopeBuilder<'a, TC, OC> {
 	variable_memory: HashMap<String, (ValueType, Memory)>,
 
 	/// Functions accessible by any code within or in the current scope
-	functions: Vec<(String, Vec<(String, ValueType)>, Vec<Clause<TC, OC>>)>,
+	functions: Vec<(String, Function<TC, OC>)>,
 	/// Struct types definitions
 	structs: HashMap<String, DictStructType>,
 
+	/// Hidden flag cells of the enclosing loop, for `break` and `continue`
+	flow_control: FlowControl,
+
 	/// Intermediate instructions generated by the compiler
 	instructions: Vec<Instruction<TC, OC>>,
+
+	/// Errors found so far, only used in the outermost scope
+	diagnostics: RefCell<Diagnostics>,
+	/// Variables in this scope whose definitions had errors, uses of these are not reported as undefined
+	failed_variables: HashSet<String>,
+	/// The number of times a variable whose definition had an error has been looked up, only used in the outermost scope
+	failed_variable_uses: Cell<usize>,
 }
 
 impl<TC, OC> ScopeBuilder<'_, TC, OC>
@@ -696,7 +1456,11 @@ where
 			variable_memory: HashMap::new(),
 			functions: Vec::new(),
 			structs: HashMap::new(),
+			flow_control: FlowControl::Inherit,
 			instructions: Vec::new(),
+			diagnostics: RefCell::default(),
+			failed_variables: HashSet::new(),
+			failed_variable_uses: Cell::new(0),
 		}
 	}
 
@@ -746,12 +1510,42 @@ where
 		self.instructions
 	}
 
+	/// record an error in the outermost scope so that compilation can carry on
+	fn report(&self, diagnostic: Diagnostic) {
+		match self.outer_scope {
+			Some(outer_scope) => outer_scope.report(diagnostic),
+			None => self.diagnostics.borrow_mut().push(diagnostic),
+		}
+	}
+
+	/// whether a variable in scope is one whose definition had an error
+	fn is_failed_variable(&self, var_name: &str) -> bool {
+		self.failed_variables.contains(var_name)
+			|| match (self.outer_scope, self.types_only) {
+				(Some(outer_scope), false) => outer_scope.is_failed_variable(var_name),
+				(None, _) | (Some(_), true) => false,
+			}
+	}
+
+	fn failed_variable_uses(&self) -> &Cell<usize> {
+		match self.outer_scope {
+			Some(outer_scope) => outer_scope.failed_variable_uses(),
+			None => &self.failed_variable_uses,
+		}
+	}
+
+	/// whether a variable whose definition had an error was used since the count was taken,
+	/// the count is restored so that the uses aren't counted again by enclosing clauses
+	fn is_failed_variable_use_since(&self, failed_variable_uses: usize) -> bool {
+		self.failed_variable_uses().replace(failed_variable_uses) > failed_variable_uses
+	}
+
 	fn push_instruction(&mut self, instruction: Instruction<TC, OC>) {
 		self.instructions.push(instruction);
 	}
 
 	/// Open a scope within the current one, any time there is a {} in Mastermind, this is called
-	fn open_inner(&self) -> ScopeBuilder<TC, OC> {
+	fn open_inner(&self) -> ScopeBuilder<'_, TC, OC> {
 		ScopeBuilder {
 			outer_scope: Some(self),
 			types_only: false,
@@ -759,13 +1553,17 @@ where
 			variable_memory: HashMap::new(),
 			functions: Vec::new(),
 			structs: HashMap::new(),
+			flow_control: FlowControl::Inherit,
 			instructions: Vec::new(),
+			diagnostics: RefCell::default(),
+			failed_variables: HashSet::new(),
+			failed_variable_uses: Cell::new(0),
 		}
 	}
 
 	// syntactic context instead of normal context
 	// used for embedded mm so that the inner mm can use outer functions
-	fn open_inner_templates_only(&self) -> ScopeBuilder<TC, OC> {
+	fn open_inner_templates_only(&self) -> ScopeBuilder<'_, TC, OC> {
 		ScopeBuilder {
 			outer_scope: Some(self),
 			types_only: true,
@@ -773,7 +1571,11 @@ where
 			variable_memory: HashMap::new(),
 			functions: Vec::new(),
 			structs: HashMap::new(),
+			flow_control: FlowControl::Boundary,
 			instructions: Vec::new(),
+			diagnostics: RefCell::default(),
+			failed_variables: HashSet::new(),
+			failed_variable_uses: Cell::new(0),
 		}
 	}
 
@@ -814,7 +1616,11 @@ target when allocating variable: {var}"
 		};
 
 		// allocate
-		self.push_instruction(Instruction::Allocate(memory.clone(), location));
+		self.push_instruction(Instruction::Allocate(
+			memory.clone(),
+			location,
+			Some(var.name.clone()),
+		));
 
 		// return a reference to the created full type
 		Ok(&self.variable_memory.get(&var.name).unwrap().0)
@@ -850,11 +1656,11 @@ target when allocating variable: {var}"
 		calling_name: &str,
 		calling_arg_types: &Vec<ValueType>,
 	) -> Result<Function<TC, OC>, String> {
-		if let Some(func) = self.functions.iter().find(|(name, args, _)| {
-			if name != calling_name || args.len() != calling_arg_types.len() {
+		if let Some((_, func)) = self.functions.iter().find(|(name, func)| {
+			if name != calling_name || func.arguments.len() != calling_arg_types.len() {
 				return false;
 			}
-			for ((_, arg_type), calling_arg_type) in zip(args, calling_arg_types) {
+			for ((_, arg_type), calling_arg_type) in zip(&func.arguments, calling_arg_types) {
 				if *arg_type != *calling_arg_type {
 					return false;
 				}
@@ -862,11 +1668,7 @@ target when allocating variable: {var}"
 			true
 		}) {
 			// TODO: stop cloning! This function overload stuff is tacked on and needs refactoring
-			let (_, arguments, block) = func;
-			return Ok(Function {
-				arguments: arguments.clone(),
-				block: block.clone(),
-			});
+			return Ok(func.clone());
 		}
 
 		if let Some(outer_scope) = self.outer_scope {
@@ -880,7 +1682,7 @@ with correct arguments in current scope"
 	}
 
 	/// Define a struct in this scope
-	fn register_struct_definition(
+	pub fn register_struct_definition(
 		&mut self,
 		struct_name: &str,
 		fields: Vec<StructFieldTypeDefinition>,
@@ -911,7 +1713,8 @@ with correct arguments in current scope"
 		&mut self,
 		new_function_name: &str,
 		new_arguments: Vec<VariableTypeDefinition<TC>>,
-		new_block: Vec<Clause<TC, OC>>,
+		new_block: Vec<Spanned<Clause<TC, OC>>>,
+		new_return_type: Option<VariableTypeReference>,
 	) -> Result<(), String> {
 		let absolute_arguments: Vec<(String, ValueType)> = new_arguments
 			.into_iter()
@@ -922,15 +1725,18 @@ with correct arguments in current scope"
 				Ok((f.name, self.create_absolute_type(&f.var_type)?))
 			})
 			.collect::<Result<Vec<(String, ValueType)>, String>>()?;
+		let return_type = new_return_type
+			.map(|type_ref| self.create_absolute_type(&type_ref))
+			.transpose()?;
 
 		// TODO: refactor this:
 		// This is some fucked C-style loop break logic, basically GOTOs
 		// basically it only gets to the panic if the functions have identical signature (except argument names)
-		'func_loop: for (name, args, _) in self.functions.iter() {
-			if name != new_function_name || args.len() != absolute_arguments.len() {
+		'func_loop: for (name, func) in self.functions.iter() {
+			if name != new_function_name || func.arguments.len() != absolute_arguments.len() {
 				continue;
 			}
-			for ((_, new_arg_type), (_, arg_type)) in zip(&absolute_arguments, args) {
+			for ((_, new_arg_type), (_, arg_type)) in zip(&absolute_arguments, &func.arguments) {
 				if *new_arg_type != *arg_type {
 					// early continue if any of the arguments are different type
 					continue 'func_loop;
@@ -942,8 +1748,14 @@ more than once in the same scope: \"{new_function_name}\""
 			);
 		}
 
-		self.functions
-			.push((new_function_name.to_string(), absolute_arguments, new_block));
+		self.functions.push((
+			new_function_name.to_string(),
+			Function {
+				arguments: absolute_arguments,
+				block: new_block,
+				return_type,
+			},
+		));
 
 		Ok(())
 	}
@@ -961,9 +1773,13 @@ more than once in the same scope: \"{new_function_name}\""
 	}
 
 	/// Construct an absolute type from a type reference
-	fn create_absolute_type(&self, type_ref: &VariableTypeReference) -> Result<ValueType, String> {
+	pub fn create_absolute_type(
+		&self,
+		type_ref: &VariableTypeReference,
+	) -> Result<ValueType, String> {
 		Ok(match type_ref {
 			VariableTypeReference::Cell => ValueType::Cell,
+			VariableTypeReference::DynamicArray(len) => ValueType::DynamicArray(*len),
 			VariableTypeReference::Struct(struct_type_name) => {
 				ValueType::from_struct(self.get_struct_definition(struct_type_name)?.clone())
 			}
@@ -990,7 +1806,7 @@ more than once in the same scope: \"{new_function_name}\""
 			}),
 			(
 				Some(subfield_chain),
-				ValueType::Array(_, _) | ValueType::DictStruct(_),
+				ValueType::Array(_, _) | ValueType::DictStruct(_) | ValueType::DynamicArray(_),
 				Memory::Cells { id, len }
 				| Memory::MappedCells {
 					id,
@@ -998,7 +1814,7 @@ more than once in the same scope: \"{new_function_name}\""
 					len,
 				},
 			) => {
-				let (subfield_type, cell_index) = full_type.get_subfield(&subfield_chain)?;
+				let (subfield_type, cell_index) = full_type.get_subfield(subfield_chain)?;
 				let ValueType::Cell = subfield_type else {
 					r_panic!("Expected cell type in variable target: {target}");
 				};
@@ -1028,7 +1844,7 @@ This should not occur."
 			) => r_panic!("Cannot get subfields of cell type: {target}"),
 			(
 				None,
-				ValueType::Array(_, _) | ValueType::DictStruct(_),
+				ValueType::Array(_, _) | ValueType::DictStruct(_) | ValueType::DynamicArray(_),
 				Memory::Cells { id: _, len: _ }
 				| Memory::MappedCells {
 					id: _,
@@ -1049,7 +1865,7 @@ This should not occur."
 			)
 			| (
 				_,
-				ValueType::Array(_, _) | ValueType::DictStruct(_),
+				ValueType::Array(_, _) | ValueType::DictStruct(_) | ValueType::DynamicArray(_),
 				Memory::Cell { id: _ } | Memory::MappedCell { id: _, index: _ },
 			) => r_panic!(
 				"Invalid memory for value type in target: {target}. This should not occur."
@@ -1059,6 +1875,14 @@ This should not occur."
 
 	/// Return a list of cell references for an array of cells (not an array of structs)
 	fn get_array_cells(&self, target: &VariableTarget) -> Result<Vec<CellReference>, String> {
+		// dynamic array elements are interleaved with gap cells
+		if let ValueType::DynamicArray(len) = self.get_target_type(target)? {
+			let cells = self.get_target_cells(target)?;
+			return Ok((0..*len)
+				.map(|i| cells[ValueType::dynamic_element_index(i)])
+				.collect());
+		}
+
 		let (full_type, memory) = self.get_base_variable_memory(&target.name)?;
 		Ok(match (&target.subfields, full_type, memory) {
 			(
@@ -1095,7 +1919,7 @@ This should not occur."
 			}
 			(
 				Some(subfields),
-				ValueType::Array(_, _) | ValueType::DictStruct(_),
+				ValueType::Array(_, _) | ValueType::DictStruct(_) | ValueType::DynamicArray(_),
 				Memory::Cells { id, len: _ }
 				| Memory::MappedCells {
 					id,
@@ -1128,7 +1952,7 @@ This should not occur."
 			}
 			(
 				None,
-				ValueType::DictStruct(_),
+				ValueType::DictStruct(_) | ValueType::DynamicArray(_),
 				Memory::Cells { id: _, len: _ }
 				| Memory::MappedCells {
 					id: _,
@@ -1162,7 +1986,7 @@ This should not occur."
 			)
 			| (
 				_,
-				ValueType::Array(_, _) | ValueType::DictStruct(_),
+				ValueType::Array(_, _) | ValueType::DictStruct(_) | ValueType::DynamicArray(_),
 				Memory::Cell { id: _ } | Memory::MappedCell { id: _, index: _ },
 			) => r_panic!(
 				"Invalid memory for value type in target: {target}. This should not occur."
@@ -1197,7 +2021,7 @@ This should not occur."
 				},
 			},
 			Some(subfield_chain) => {
-				let (_subfield_type, offset_index) = full_type.get_subfield(&subfield_chain)?;
+				let (_subfield_type, offset_index) = full_type.get_subfield(subfield_chain)?;
 				match memory {
 					Memory::Cell { id: _ } | Memory::MappedCell { id: _, index: _ } => r_panic!(
 						"Attempted to get cell reference of \
@@ -1237,21 +2061,125 @@ This should not occur. ({target})"
 
 	/// Return the absolute type and memory allocation for a variable name
 	fn get_base_variable_memory(&self, var_name: &str) -> Result<(&ValueType, &Memory), String> {
+		let Some(variable) = self.find_variable_memory(var_name) else {
+			if self.is_failed_variable(var_name) {
+				let uses = self.failed_variable_uses();
+				uses.set(uses.get() + 1);
+			}
+			r_panic!("No variable found in scope with name \"{var_name}\".");
+		};
+		Ok(variable)
+	}
+
+	fn find_variable_memory(&self, var_name: &str) -> Option<(&ValueType, &Memory)> {
 		match (
 			self.outer_scope,
 			self.types_only,
 			self.variable_memory.get(var_name),
 		) {
-			(_, _, Some((value_type, memory))) => Ok((value_type, memory)),
-			(Some(outer_scope), false, None) => outer_scope.get_base_variable_memory(var_name),
-			(None, _, None) | (Some(_), true, None) => {
-				r_panic!("No variable found in scope with name \"{var_name}\".")
+			(_, _, Some((value_type, memory))) => Some((value_type, memory)),
+			(Some(outer_scope), false, None) => outer_scope.find_variable_memory(var_name),
+			(None, _, None) | (Some(_), true, None) => None,
+		}
+	}
+
+	/// Get the flag cells of the innermost enclosing loop, as (active cell, loop cell)
+	fn get_loop_control(&self) -> Option<(CellReference, Option<CellReference>)> {
+		match (self.flow_control.clone(), self.outer_scope) {
+			(FlowControl::Loop { active, loop_cell }, _) => Some((active, loop_cell)),
+			(FlowControl::Inherit, Some(outer_scope)) => outer_scope.get_loop_control(),
+			(FlowControl::Inherit, None)
+			| (
+				FlowControl::Function {
+					active: _,
+					result: _,
+				},
+				_,
+			)
+			| (FlowControl::Boundary, _) => None,
+		}
+	}
+
+	/// Get the flag cell guarding the rest of the innermost loop iteration or function body
+	fn get_active_flag(&self) -> Option<CellReference> {
+		match (&self.flow_control, self.outer_scope) {
+			(
+				FlowControl::Loop {
+					active,
+					loop_cell: _,
+				},
+				_,
+			) => Some(*active),
+			(FlowControl::Function { active, result: _ }, _) => *active,
+			(FlowControl::Inherit, Some(outer_scope)) => outer_scope.get_active_flag(),
+			(FlowControl::Inherit, None) | (FlowControl::Boundary, _) => None,
+		}
+	}
+
+	/// Get the flag cells to clear to return from the innermost function, including any loops within it,
+	/// along with the type and cells of the function's return value
+	fn get_return_control(&self) -> Option<(Vec<CellReference>, Option<ReturnSlot>)> {
+		match (&self.flow_control, self.outer_scope) {
+			(FlowControl::Function { active, result }, _) => {
+				Some((active.iter().copied().collect(), result.clone()))
 			}
+			(FlowControl::Loop { active, loop_cell }, Some(outer_scope)) => {
+				let (mut flag_cells, result) = outer_scope.get_return_control()?;
+				flag_cells.push(*active);
+				flag_cells.extend(*loop_cell);
+				Some((flag_cells, result))
+			}
+			(FlowControl::Inherit, Some(outer_scope)) => outer_scope.get_return_control(),
+			(
+				FlowControl::Loop {
+					active: _,
+					loop_cell: _,
+				}
+				| FlowControl::Inherit,
+				None,
+			)
+			| (FlowControl::Boundary, _) => None,
 		}
 	}
 
+	/// Get every cell of a full variable target of any type, in memory order
+	fn get_target_cells(&self, target: &VariableTarget) -> Result<Vec<CellReference>, String> {
+		let (var_type, memory) = self.get_base_variable_memory(&target.name)?;
+		let (target_type, offset) = match &target.subfields {
+			None => (var_type, 0),
+			Some(subfields) => var_type.get_subfield(subfields)?,
+		};
+		Ok(memory
+			.cells()
+			.into_iter()
+			.skip(offset)
+			.take(target_type.size()?)
+			.collect())
+	}
+
+	/// Split a runtime-indexed target like `arr[i]` into the dynamic array's cells and the index target
+	fn get_dynamic_element(
+		&self,
+		target: &VariableTarget,
+	) -> Result<Option<(Vec<CellReference>, VariableTarget)>, String> {
+		let Some((array_target, index)) = target.split_dynamic_index() else {
+			return Ok(None);
+		};
+		let ValueType::DynamicArray(_) = self.get_target_type(&array_target)? else {
+			r_panic!(
+				"Runtime index in \"{target}\" can only be used with a dynamic array, \
+e.g. `dynamic cell[8]`."
+			);
+		};
+		Ok(Some((self.get_target_cells(&array_target)?, index.clone())))
+	}
+
 	/// Get the absolute type of a full variable target, not just a name like `get_base_variable_memory`
 	fn get_target_type(&self, target: &VariableTarget) -> Result<&ValueType, String> {
+		// runtime-indexed dynamic array elements are always cells
+		if self.get_dynamic_element(target)?.is_some() {
+			return Ok(&ValueType::Cell);
+		}
 		let (var_type, _memory) = self.get_base_variable_memory(&target.name)?;
 		Ok(match &target.subfields {
 			None => var_type,
@@ -1262,6 +2190,40 @@ This should not occur. ({target})"
 		})
 	}
 
+	/// Map a function argument name to temporary memory allocated in an outer scope by `_allocate_temp_value`,
+	/// used for arguments passed by value rather than by reference to an existing variable
+	fn create_mapped_temp_variable(
+		&mut self,
+		mapped_var_name: String,
+		var_type: ValueType,
+		temp_memory: &Memory,
+	) -> Result<(), String> {
+		let mapped_memory = match temp_memory {
+			Memory::Cell { id } => Memory::MappedCell {
+				id: *id,
+				index: None,
+			},
+			Memory::Cells { id, len } => Memory::MappedCells {
+				id: *id,
+				start_index: 0,
+				len: *len,
+			},
+			Memory::MappedCell { id: _, index: _ }
+			| Memory::MappedCells {
+				id: _,
+				start_index: _,
+				len: _,
+			} => r_panic!("Temporary argument \"{mapped_var_name}\" must not be mapped memory."),
+		};
+		let None = self
+			.variable_memory
+			.insert(mapped_var_name.clone(), (var_type, mapped_memory))
+		else {
+			r_panic!("Cannot map argument \"{mapped_var_name}\" twice in the same scope");
+		};
+		Ok(())
+	}
+
 	/// Create memory mapping between a pre-existing variable and a new one, used for function arguments.
 	///  This could be used for copy by reference of subfields in future.
 	fn create_mapped_variable(
@@ -1323,7 +2285,9 @@ This should not occur. ({target})"
 							index: Some(*start_index + offset_index),
 						},
 						(
-							ValueType::Array(_, _) | ValueType::DictStruct(_),
+							ValueType::Array(_, _)
+							| ValueType::DictStruct(_)
+							| ValueType::DynamicArray(_),
 							Memory::Cells { id, len: _ },
 						) => Memory::MappedCells {
 							id: *id,
@@ -1331,7 +2295,9 @@ This should not occur. ({target})"
 							len: subfield_type.size()?,
 						},
 						(
-							ValueType::Array(_, _) | ValueType::DictStruct(_),
+							ValueType::Array(_, _)
+							| ValueType::DictStruct(_)
+							| ValueType::DynamicArray(_),
 							Memory::MappedCells {
 								id,
 								start_index,
@@ -1360,12 +2326,13 @@ mapping: {mapped_var_name} -> {target}"
 
 	/// Get the final type of an expression.
 	///  (technically unnecessary right now, but can be used to implement expressions as function arguments in future)
-	fn get_expression_type(&self, expr: &Expression) -> Result<ValueType, String> {
-		Ok(match expr {
+	fn get_expression_type(&self, expr: &Spanned<Expression>) -> Result<ValueType, Diagnostic> {
+		Ok(match &expr.node {
 			Expression::NaturalNumber(_) => ValueType::Cell,
 			Expression::SumExpression { sign: _, summands } => {
 				let Some(_) = summands.first() else {
-					r_panic!(
+					r_panic_at!(
+						expr.span,
 						"Cannot infer expression type because sum \
 expression has no elements: `{expr}`."
 					);
@@ -1375,7 +2342,8 @@ expression has no elements: `{expr}`."
 					match self.get_expression_type(summand)? {
 						ValueType::Cell => (),
 						summand_type => {
-							r_panic!(
+							r_panic_at!(
+								summand.span,
 								"Sum expressions must be comprised of cell-types: \
 found `{summand_type}` in `{expr}`"
 							);
@@ -1384,11 +2352,31 @@ found `{summand_type}` in `{expr}`"
 				}
 				ValueType::Cell
 			}
-			Expression::VariableReference(var) => self.get_target_type(var)?.clone(),
+			Expression::BinaryExpression {
+				operator,
+				left,
+				right,
+			} => {
+				for operand in [left, right] {
+					let operand_type = self.get_expression_type(operand)?;
+					r_assert_at!(
+						operand.span,
+						operand_type == ValueType::Cell,
+						"Operands of `{operator}` must be cell-types: \
+found `{operand_type}` in `{expr}`"
+					);
+				}
+				ValueType::Cell
+			}
+			Expression::VariableReference(var) => self
+				.get_target_type(var)
+				.map_err(|err| Diagnostic::new(err, expr.span))?
+				.clone(),
 			Expression::ArrayLiteral(elements) => {
 				let mut elements_iter = elements.iter();
 				let Some(first_element) = elements_iter.next() else {
-					r_panic!(
+					r_panic_at!(
+						expr.span,
 						"Cannot infer expression type because \
 array literal has no elements: `{expr}`."
 					);
@@ -1396,7 +2384,8 @@ array literal has no elements: `{expr}`."
 				let first_element_type = self.get_expression_type(first_element)?;
 				for element in elements_iter {
 					let element_type = self.get_expression_type(element)?;
-					r_assert!(
+					r_assert_at!(
+						element.span,
 						element_type == first_element_type,
 						"All elements in array expressions must have the \
 same type: found `{element_type}` in `{expr}`"
@@ -1405,15 +2394,39 @@ same type: found `{element_type}` in `{expr}`"
 				ValueType::Array(elements.len(), Box::new(first_element_type))
 			}
 			Expression::StringLiteral(s) => ValueType::Array(s.len(), Box::new(ValueType::Cell)),
+			Expression::FunctionCall {
+				function_name,
+				arguments,
+			} => {
+				let argument_types = arguments
+					.iter()
+					.map(|arg| self.get_expression_type(arg))
+					.collect::<Result<Vec<ValueType>, Diagnostic>>()?;
+				let Some(return_type) = self
+					.get_function(function_name, &argument_types)
+					.map_err(|err| Diagnostic::new(err, expr.span))?
+					.return_type
+				else {
+					r_panic_at!(expr.span, "Function call `{expr}` does not return a value.");
+				};
+				return_type
+			}
 		})
 	}
 
 	/// helper function for a common use-case:
 	/// flatten an expression and add it to a specific cell (using copies and adds, etc)
-	fn _add_expr_to_cell(&mut self, expr: &Expression, cell: CellReference) -> Result<(), String> {
-		let (imm, adds, subs) = expr.flatten()?;
+	fn _add_expr_to_cell(
+		&mut self,
+		expr: &Spanned<Expression>,
+		cell: CellReference,
+	) -> Result<(), Diagnostic> {
+		if !expr.node.is_linear() {
+			return self._add_non_linear_expr_to_cell(expr, cell, false);
+		}
+		let (imm, adds, subs) = expr.node.flatten().map_err(|err| err.or_span(expr.span))?;
 
-		self.push_instruction(Instruction::AddToCell(cell.clone(), imm));
+		self.push_instruction(Instruction::AddToCell(cell, imm));
 
 		let mut adds_set = HashMap::new();
 		for var in adds {
@@ -1426,8 +2439,10 @@ same type: found `{element_type}` in `{expr}`"
 		}
 
 		for (source, constant) in adds_set {
-			let source_cell = self.get_cell(&source)?;
-			self._copy_cell(source_cell, cell.clone(), constant);
+			let source_cell = self.get_cell(&source).map_err(|err| {
+				Diagnostic::new(err, expr.node.reference_span(&source).unwrap_or(expr.span))
+			})?;
+			self._copy_cell(source_cell, cell, constant);
 		}
 
 		Ok(())
@@ -1438,15 +2453,28 @@ same type: found `{element_type}` in `{expr}`"
 	// TODO: refactor/fix underlying logic for this
 	fn _add_self_referencing_expr_to_cell(
 		&mut self,
-		expr: Expression,
+		expr: Spanned<Expression>,
 		cell: CellReference,
 		pre_clear: bool,
-	) -> Result<(), String> {
+	) -> Result<(), Diagnostic> {
+		if !expr.node.is_linear() {
+			// compute the whole expression first, as its operands are read multiple times
+			let temp_cell = self._allocate_temp_cell();
+			self._add_expr_to_cell(&expr, temp_cell)?;
+			if pre_clear {
+				self.push_instruction(Instruction::ClearCell(cell));
+			}
+			self._drain_cell(temp_cell, cell, 1);
+			self.push_instruction(Instruction::Free(temp_cell.memory_id));
+			return Ok(());
+		}
+
 		//Create a new temp cell to store the current cell value
 		let temp_mem_id = self.push_memory_id();
 		self.push_instruction(Instruction::Allocate(
 			Memory::Cell { id: temp_mem_id },
 			None,
+			None,
 		));
 		let temp_cell = CellReference {
 			memory_id: temp_mem_id,
@@ -1457,12 +2485,12 @@ same type: found `{element_type}` in `{expr}`"
 		self._copy_cell(cell, temp_cell, 1);
 		// Then if we are doing a += don't pre-clear otherwise Clear the current cell and run the same actions as _add_expr_to_cell
 		if pre_clear {
-			self.push_instruction(Instruction::ClearCell(cell.clone()));
+			self.push_instruction(Instruction::ClearCell(cell));
 		}
 
-		let (imm, adds, subs) = expr.flatten()?;
+		let (imm, adds, subs) = expr.node.flatten().map_err(|err| err.or_span(expr.span))?;
 
-		self.push_instruction(Instruction::AddToCell(cell.clone(), imm));
+		self.push_instruction(Instruction::AddToCell(cell, imm));
 
 		let mut adds_set = HashMap::new();
 		for var in adds {
@@ -1475,13 +2503,15 @@ same type: found `{element_type}` in `{expr}`"
 		}
 
 		for (source, constant) in adds_set {
-			let source_cell = self.get_cell(&source)?;
+			let source_cell = self.get_cell(&source).map_err(|err| {
+				Diagnostic::new(err, expr.node.reference_span(&source).unwrap_or(expr.span))
+			})?;
 			//If we have an instance of the original cell being added simply use our temp cell value
 			// (crucial special sauce)
 			if source_cell.memory_id == cell.memory_id && source_cell.index == cell.index {
-				self._copy_cell(temp_cell, cell.clone(), constant);
+				self._copy_cell(temp_cell, cell, constant);
 			} else {
-				self._copy_cell(source_cell, cell.clone(), constant);
+				self._copy_cell(source_cell, cell, constant);
 			}
 		}
 		//Cleanup
@@ -1491,8 +2521,323 @@ same type: found `{element_type}` in `{expr}`"
 		Ok(())
 	}
 
+	/// helper function to add an expression which can't be flattened (e.g. multiplying two variables) to a cell,
+	/// the non-linear parts are computed in temporary cells before being added
+	fn _add_non_linear_expr_to_cell(
+		&mut self,
+		expr: &Spanned<Expression>,
+		cell: CellReference,
+		negate: bool,
+	) -> Result<(), Diagnostic> {
+		match &expr.node {
+			Expression::SumExpression { sign, summands } => {
+				let negate = negate ^ matches!(sign, Sign::Negative);
+				for summand in summands {
+					self._add_non_linear_expr_to_cell(summand, cell, negate)?;
+				}
+			}
+			Expression::BinaryExpression {
+				operator,
+				left,
+				right,
+			} if !expr.node.is_linear() => {
+				let temp_cell = self._allocate_temp_cell();
+				self._add_binary_expr_to_cell(*operator, left, right, temp_cell)?;
+				self._drain_cell(temp_cell, cell, if negate { -1 } else { 1 });
+				self.push_instruction(Instruction::Free(temp_cell.memory_id));
+			}
+			_ => match negate {
+				false => self._add_expr_to_cell(expr, cell)?,
+				true => self._add_expr_to_cell(&Expression::flipped_sign(expr.clone())?, cell)?,
+			},
+		}
+
+		Ok(())
+	}
+
+	/// helper function to compute a binary operation between two expressions at runtime, adding the result to a cell
+	fn _add_binary_expr_to_cell(
+		&mut self,
+		operator: BinaryOperator,
+		left: &Spanned<Expression>,
+		right: &Spanned<Expression>,
+		cell: CellReference,
+	) -> Result<(), Diagnostic> {
+		// copy both operands into temporary cells, as the algorithms below consume them
+		let left_cell = self._allocate_temp_cell();
+		self._add_expr_to_cell(left, left_cell)?;
+		let right_cell = self._allocate_temp_cell();
+		self._add_expr_to_cell(right, right_cell)?;
+
+		match operator {
+			BinaryOperator::Multiply => {
+				// add the right operand to the result once for each unit of the left operand
+				self.push_instruction(Instruction::OpenLoop(left_cell));
+				self._copy_cell(right_cell, cell, 1);
+				self.push_instruction(Instruction::AddToCell(left_cell, -1i8 as u8));
+				self.push_instruction(Instruction::CloseLoop(left_cell));
+			}
+			BinaryOperator::Divide | BinaryOperator::Modulo => {
+				// count up the remainder one unit of the left operand at a time,
+				// every time it reaches the divisor, reset it and increment the quotient
+				let remainder_cell = self._allocate_temp_cell();
+				self.push_instruction(Instruction::OpenLoop(left_cell));
+				self.push_instruction(Instruction::AddToCell(left_cell, -1i8 as u8));
+				self.push_instruction(Instruction::AddToCell(remainder_cell, 1));
+
+				// check whether the remainder is equal to the divisor
+				let difference_cell = self._allocate_temp_cell();
+				self._copy_cell(right_cell, difference_cell, 1);
+				self._copy_cell(remainder_cell, difference_cell, -1);
+				let equal_flag_cell = self._allocate_temp_cell();
+				self.push_instruction(Instruction::AddToCell(equal_flag_cell, 1));
+				self.push_instruction(Instruction::OpenLoop(difference_cell));
+				self.push_instruction(Instruction::ClearCell(equal_flag_cell));
+				self.push_instruction(Instruction::ClearCell(difference_cell));
+				self.push_instruction(Instruction::CloseLoop(difference_cell));
+				self.push_instruction(Instruction::Free(difference_cell.memory_id));
+
+				self.push_instruction(Instruction::OpenLoop(equal_flag_cell));
+				self.push_instruction(Instruction::ClearCell(remainder_cell));
+				if let BinaryOperator::Divide = operator {
+					self.push_instruction(Instruction::AddToCell(cell, 1));
+				}
+				self.push_instruction(Instruction::ClearCell(equal_flag_cell));
+				self.push_instruction(Instruction::CloseLoop(equal_flag_cell));
+				self.push_instruction(Instruction::Free(equal_flag_cell.memory_id));
+
+				self.push_instruction(Instruction::CloseLoop(left_cell));
+
+				match operator {
+					BinaryOperator::Modulo => self._drain_cell(remainder_cell, cell, 1),
+					_ => self.push_instruction(Instruction::ClearCell(remainder_cell)),
+				}
+				self.push_instruction(Instruction::Free(remainder_cell.memory_id));
+			}
+			BinaryOperator::Equals | BinaryOperator::NotEquals => {
+				// subtract the operands, they are equal if the difference is zero
+				self._drain_cell(right_cell, left_cell, -1);
+				if let BinaryOperator::Equals = operator {
+					self.push_instruction(Instruction::AddToCell(cell, 1));
+				}
+				self.push_instruction(Instruction::OpenLoop(left_cell));
+				self.push_instruction(Instruction::AddToCell(
+					cell,
+					match operator {
+						BinaryOperator::Equals => -1i8 as u8,
+						_ => 1,
+					},
+				));
+				self.push_instruction(Instruction::ClearCell(left_cell));
+				self.push_instruction(Instruction::CloseLoop(left_cell));
+			}
+			// a > b is equivalent to b < a, and a <= b is equivalent to not b < a
+			BinaryOperator::LessThan => self._add_less_than(left_cell, right_cell, cell, 1),
+			BinaryOperator::GreaterThan => self._add_less_than(right_cell, left_cell, cell, 1),
+			BinaryOperator::LessThanOrEqual => {
+				self.push_instruction(Instruction::AddToCell(cell, 1));
+				self._add_less_than(right_cell, left_cell, cell, -1);
+			}
+			BinaryOperator::GreaterThanOrEqual => {
+				self.push_instruction(Instruction::AddToCell(cell, 1));
+				self._add_less_than(left_cell, right_cell, cell, -1);
+			}
+			BinaryOperator::And => {
+				self.push_instruction(Instruction::OpenLoop(left_cell));
+				self.push_instruction(Instruction::OpenLoop(right_cell));
+				self.push_instruction(Instruction::AddToCell(cell, 1));
+				self.push_instruction(Instruction::ClearCell(right_cell));
+				self.push_instruction(Instruction::CloseLoop(right_cell));
+				self.push_instruction(Instruction::ClearCell(left_cell));
+				self.push_instruction(Instruction::CloseLoop(left_cell));
+			}
+			BinaryOperator::Or => {
+				// move the left operand's truthiness into the right operand
+				self.push_instruction(Instruction::OpenLoop(left_cell));
+				self.push_instruction(Instruction::ClearCell(right_cell));
+				self.push_instruction(Instruction::AddToCell(right_cell, 1));
+				self.push_instruction(Instruction::ClearCell(left_cell));
+				self.push_instruction(Instruction::CloseLoop(left_cell));
+				self.push_instruction(Instruction::OpenLoop(right_cell));
+				self.push_instruction(Instruction::AddToCell(cell, 1));
+				self.push_instruction(Instruction::ClearCell(right_cell));
+				self.push_instruction(Instruction::CloseLoop(right_cell));
+			}
+		}
+
+		self.push_instruction(Instruction::ClearCell(right_cell));
+		self.push_instruction(Instruction::Free(right_cell.memory_id));
+		self.push_instruction(Instruction::Free(left_cell.memory_id));
+
+		Ok(())
+	}
+
+	/// helper function to add a constant to a cell if one cell's value is less than another's,
+	/// both cells are consumed and left zeroed
+	fn _add_less_than(
+		&mut self,
+		left_cell: CellReference,
+		right_cell: CellReference,
+		cell: CellReference,
+		constant: i32,
+	) {
+		// decrement both until the right cell is zero, if the left cell reaches zero first then left < right
+		self.push_instruction(Instruction::OpenLoop(right_cell));
+		self.push_instruction(Instruction::AddToCell(right_cell, -1i8 as u8));
+
+		let left_copy_cell = self._allocate_temp_cell();
+		self._copy_cell(left_cell, left_copy_cell, 1);
+		let left_zero_flag_cell = self._allocate_temp_cell();
+		self.push_instruction(Instruction::AddToCell(left_zero_flag_cell, 1));
+		self.push_instruction(Instruction::OpenLoop(left_copy_cell));
+		self.push_instruction(Instruction::ClearCell(left_zero_flag_cell));
+		self.push_instruction(Instruction::ClearCell(left_copy_cell));
+		self.push_instruction(Instruction::CloseLoop(left_copy_cell));
+		self.push_instruction(Instruction::Free(left_copy_cell.memory_id));
+
+		// the left cell is zero, set the result and break out of the loop
+		self.push_instruction(Instruction::OpenLoop(left_zero_flag_cell));
+		self.push_instruction(Instruction::AddToCell(cell, constant as u8));
+		self.push_instruction(Instruction::ClearCell(right_cell));
+		self.push_instruction(Instruction::ClearCell(left_zero_flag_cell));
+		self.push_instruction(Instruction::CloseLoop(left_zero_flag_cell));
+		self.push_instruction(Instruction::Free(left_zero_flag_cell.memory_id));
+
+		self.push_instruction(Instruction::AddToCell(left_cell, -1i8 as u8));
+		self.push_instruction(Instruction::CloseLoop(right_cell));
+		self.push_instruction(Instruction::ClearCell(left_cell));
+	}
+
+	/// allocate an unnamed cell for intermediate values, this must be zeroed before it is freed
+	fn _allocate_temp_cell(&mut self) -> CellReference {
+		let temp_mem_id = self.push_memory_id();
+		self.push_instruction(Instruction::Allocate(
+			Memory::Cell { id: temp_mem_id },
+			None,
+			None,
+		));
+		CellReference {
+			memory_id: temp_mem_id,
+			index: None,
+		}
+	}
+
+	/// allocate anonymous memory of the given type, this must be zeroed before it is freed
+	fn _allocate_temp_memory(&mut self, value_type: &ValueType) -> Result<Memory, String> {
+		let id = self.push_memory_id();
+		let memory = match value_type {
+			ValueType::Cell => Memory::Cell { id },
+			_ => Memory::Cells {
+				id,
+				len: value_type.size()?,
+			},
+		};
+		self.push_instruction(Instruction::Allocate(memory.clone(), None, None));
+		Ok(memory)
+	}
+
+	/// Evaluate an expression into new anonymous memory of the given type, used for passing values as function arguments
+	fn _allocate_temp_value(
+		&mut self,
+		expr: &Spanned<Expression>,
+		value_type: &ValueType,
+	) -> Result<Memory, Diagnostic> {
+		let memory = self._allocate_temp_memory(value_type)?;
+		self._add_value_expr_to_cells(expr, value_type, &memory.cells())?;
+		Ok(memory)
+	}
+
+	/// clear every cell of some anonymous memory and free it
+	fn _clear_and_free(&mut self, memory: &Memory) {
+		for cell in memory.cells() {
+			self.push_instruction(Instruction::ClearCell(cell));
+		}
+		self.push_instruction(Instruction::Free(memory.id()));
+	}
+
+	/// remove the hidden variables holding function call results from `compile_hoisted_values`, and free their memory
+	fn _free_hoisted_values(&mut self, hoisted_values: Vec<String>) {
+		for name in hoisted_values {
+			let (_, memory) = self.variable_memory.remove(&name).unwrap();
+			self._clear_and_free(&memory);
+		}
+	}
+
+	/// Add the value of a cell, array or string expression to a contiguous run of cells matching its type
+	fn _add_value_expr_to_cells(
+		&mut self,
+		expr: &Spanned<Expression>,
+		value_type: &ValueType,
+		cells: &[CellReference],
+	) -> Result<(), Diagnostic> {
+		match (value_type, &expr.node) {
+			(ValueType::Cell, _) => {
+				self._add_expr_to_cell(expr, cells[0])?;
+			}
+			(ValueType::Array(len, element_type), Expression::ArrayLiteral(elements)) => {
+				r_assert_at!(
+					expr.span,
+					elements.len() == *len,
+					"Expected array of length {len}, found `{expr}`."
+				);
+				let element_size = element_type.size()?;
+				for (element, element_cells) in zip(elements, cells.chunks(element_size)) {
+					self._add_value_expr_to_cells(element, element_type, element_cells)?;
+				}
+			}
+			(ValueType::Array(len, element_type), Expression::StringLiteral(string)) => {
+				r_assert_at!(
+					expr.span,
+					string.len() == *len && **element_type == ValueType::Cell,
+					"Expected value of type `{value_type}`, found string `{expr}`."
+				);
+				for (cell, chr) in zip(cells, string.bytes()) {
+					self.push_instruction(Instruction::AddToCell(*cell, chr));
+				}
+			}
+			(
+				ValueType::Array(_, _) | ValueType::DictStruct(_) | ValueType::DynamicArray(_),
+				Expression::VariableReference(var),
+			) => {
+				let var_type = self
+					.get_target_type(var)
+					.map_err(|err| Diagnostic::new(err, expr.span))?;
+				r_assert_at!(
+					expr.span,
+					*var_type == *value_type,
+					"Expected value of type `{value_type}`, found `{expr}` of type `{var_type}`."
+				);
+				let source_cells = self
+					.get_target_cells(var)
+					.map_err(|err| Diagnostic::new(err, expr.span))?;
+				for (source_cell, target_cell) in zip(source_cells, cells) {
+					self._copy_cell(source_cell, *target_cell, 1);
+				}
+			}
+			(ValueType::Array(_, _) | ValueType::DictStruct(_) | ValueType::DynamicArray(_), _) => {
+				r_panic_at!(
+					expr.span,
+					"Cannot evaluate `{expr}` as a value of type `{value_type}`."
+				)
+			}
+		}
+		Ok(())
+	}
+
+	/// Helper function to move a cell's value into another cell, leaving the original cell zeroed
+	fn _drain_cell(
+		&mut self,
+		source_cell: CellReference,
+		target_cell: CellReference,
+		constant: i32,
+	) {
+		self.push_instruction(Instruction::OpenLoop(source_cell));
+		self.push_instruction(Instruction::AddToCell(target_cell, constant as u8));
+		self.push_instruction(Instruction::AddToCell(source_cell, -1i8 as u8));
+		self.push_instruction(Instruction::CloseLoop(source_cell));
+	}
+
 	/// Helper function to copy a cell from one to another, leaving the original unaffected
-	// TODO: make one for draining a cell
 	fn _copy_cell(
 		&mut self,
 		source_cell: CellReference,
@@ -1507,6 +2852,7 @@ same type: found `{element_type}` in `{expr}`"
 		self.push_instruction(Instruction::Allocate(
 			Memory::Cell { id: temp_mem_id },
 			None,
+			None,
 		));
 		let temp_cell = CellReference {
 			memory_id: temp_mem_id,
@@ -1556,19 +2902,19 @@ mod scope_builder_tests {
 		let scope = ScopeBuilder::<TapeCell, Opcode>::new();
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::NaturalNumber(0))
+				.get_expression_type(&Expression::NaturalNumber(0).into())
 				.unwrap(),
 			ValueType::Cell
 		);
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::NaturalNumber(1))
+				.get_expression_type(&Expression::NaturalNumber(1).into())
 				.unwrap(),
 			ValueType::Cell
 		);
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::NaturalNumber(345678))
+				.get_expression_type(&Expression::NaturalNumber(345678).into())
 				.unwrap(),
 			ValueType::Cell
 		);
@@ -1579,40 +2925,50 @@ mod scope_builder_tests {
 		let scope = ScopeBuilder::<TapeCell, Opcode>::new();
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::SumExpression {
-					sign: Sign::Positive,
-					summands: vec![Expression::NaturalNumber(0)]
-				})
+				.get_expression_type(
+					&Expression::SumExpression {
+						sign: Sign::Positive,
+						summands: vec![Expression::NaturalNumber(0).into()]
+					}
+					.into()
+				)
 				.unwrap(),
 			ValueType::Cell
 		);
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::SumExpression {
-					sign: Sign::Negative,
-					summands: vec![
-						Expression::NaturalNumber(345678),
-						Expression::NaturalNumber(2)
-					]
-				})
+				.get_expression_type(
+					&Expression::SumExpression {
+						sign: Sign::Negative,
+						summands: vec![
+							Expression::NaturalNumber(345678).into(),
+							Expression::NaturalNumber(2).into()
+						]
+					}
+					.into()
+				)
 				.unwrap(),
 			ValueType::Cell
 		);
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::SumExpression {
-					sign: Sign::Positive,
-					summands: vec![
-						Expression::SumExpression {
-							sign: Sign::Negative,
-							summands: vec![
-								Expression::NaturalNumber(1),
-								Expression::NaturalNumber(2)
-							]
-						},
-						Expression::NaturalNumber(2)
-					]
-				})
+				.get_expression_type(
+					&Expression::SumExpression {
+						sign: Sign::Positive,
+						summands: vec![
+							Expression::SumExpression {
+								sign: Sign::Negative,
+								summands: vec![
+									Expression::NaturalNumber(1).into(),
+									Expression::NaturalNumber(2).into()
+								]
+							}
+							.into(),
+							Expression::NaturalNumber(2).into()
+						]
+					}
+					.into()
+				)
 				.unwrap(),
 			ValueType::Cell
 		);
@@ -1630,27 +2986,34 @@ mod scope_builder_tests {
 			.unwrap();
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::VariableReference(VariableTarget {
-					name: String::from("var"),
-					subfields: None,
-					is_spread: false
-				}))
+				.get_expression_type(
+					&Expression::VariableReference(VariableTarget {
+						name: String::from("var"),
+						subfields: None,
+						is_spread: false
+					})
+					.into()
+				)
 				.unwrap(),
 			ValueType::Cell
 		);
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::SumExpression {
-					sign: Sign::Positive,
-					summands: vec![
-						Expression::VariableReference(VariableTarget {
-							name: String::from("var"),
-							subfields: None,
-							is_spread: false
-						}),
-						Expression::NaturalNumber(123)
-					]
-				})
+				.get_expression_type(
+					&Expression::SumExpression {
+						sign: Sign::Positive,
+						summands: vec![
+							Expression::VariableReference(VariableTarget {
+								name: String::from("var"),
+								subfields: None,
+								is_spread: false
+							})
+							.into(),
+							Expression::NaturalNumber(123).into()
+						]
+					}
+					.into()
+				)
 				.unwrap(),
 			ValueType::Cell
 		);
@@ -1668,21 +3031,27 @@ mod scope_builder_tests {
 			.unwrap();
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::VariableReference(VariableTarget {
-					name: String::from("arr"),
-					subfields: None,
-					is_spread: false
-				}))
+				.get_expression_type(
+					&Expression::VariableReference(VariableTarget {
+						name: String::from("arr"),
+						subfields: None,
+						is_spread: false
+					})
+					.into()
+				)
 				.unwrap(),
 			ValueType::Array(3, Box::new(ValueType::Cell))
 		);
 		assert_eq!(
 			scope
-				.get_expression_type(&Expression::VariableReference(VariableTarget {
-					name: String::from("arr"),
-					subfields: Some(VariableTargetReferenceChain(vec![Reference::Index(0)])),
-					is_spread: false
-				}))
+				.get_expression_type(
+					&Expression::VariableReference(VariableTarget {
+						name: String::from("arr"),
+						subfields: Some(VariableTargetReferenceChain(vec![Reference::Index(0)])),
+						is_spread: false
+					})
+					.into()
+				)
 				.unwrap(),
 			ValueType::Cell
 		);
@@ -0,0 +1,511 @@
+// a readable text format for the intermediate representation, for inspecting the frontend's output and testing the backend
+// e.g.
+// allocate %0 cell a
+// add %0 5
+// open_loop %0
+//   add %0 -1
+//   bf @%0 clobbers a(%0) { [-]+ }
+// close_loop %0
+// free %0
+
+use super::types::{CellLocation, CellReference, ClobberedVariable, Instruction, Memory};
+use crate::{
+	backend::common::{BrainfuckProgram, OpcodeVariant},
+	diagnostics::{Diagnostic, Span},
+	macros::macros::r_panic,
+	parser::{
+		tokens::{next_token, SourceCursor, Token},
+		types::{LocationSpecifier, TapeCellLocation},
+	},
+};
+
+use std::fmt::Display;
+
+/// write IR as text, one instruction per line and indented within loops
+pub fn ir_to_text<TC: Display, OC: Clone>(instructions: &[Instruction<TC, OC>]) -> String
+where
+	Vec<OC>: BrainfuckProgram,
+{
+	let mut text = String::new();
+	let mut depth = 0usize;
+	for instruction in instructions {
+		if let Instruction::CloseLoop(_) = instruction {
+			depth = depth.saturating_sub(1);
+		}
+		text += &"  ".repeat(depth);
+		text += &instruction.to_string();
+		text.push('\n');
+		if let Instruction::OpenLoop(_) = instruction {
+			depth += 1;
+		}
+	}
+	text
+}
+
+/// parse IR written by `ir_to_text`, blank lines and `//` comments are ignored
+pub fn parse_ir<TC: TapeCellLocation, OC: OpcodeVariant>(
+	text: &str,
+) -> Result<Vec<Instruction<TC, OC>>, String> {
+	let mut instructions = vec![];
+	for (i, line) in text.lines().enumerate() {
+		let line_chars: Vec<char> = line.chars().collect();
+		let mut chars = SourceCursor::new(&line_chars);
+		match parse_instruction(&mut chars) {
+			Ok(Some(instruction)) => instructions.push(instruction),
+			Ok(None) => (),
+			Err(err) => r_panic!("{err}\n  in IR line {}", i + 1),
+		}
+	}
+	Ok(instructions)
+}
+
+fn parse_instruction<TC: TapeCellLocation, OC: OpcodeVariant>(
+	chars: &mut SourceCursor,
+) -> Result<Option<Instruction<TC, OC>>, Diagnostic> {
+	let instruction = match next_token(chars)? {
+		Token::None => return Ok(None),
+		Token::Name(name) if name == "allocate" => {
+			let memory = parse_memory(chars)?;
+			let location = match TC::parse_location_specifier(chars)? {
+				LocationSpecifier::None => None,
+				LocationSpecifier::Cell(cell) => Some(cell),
+				LocationSpecifier::Variable(_) => {
+					r_panic!("Expected a cell location for the allocation.")
+				}
+			};
+			let mut s = *chars;
+			let name = match next_token(&mut s)? {
+				Token::Name(name) => {
+					*chars = s;
+					Some(name)
+				}
+				_ => None,
+			};
+			Instruction::Allocate(memory, location, name)
+		}
+		Token::Name(name) if name == "free" => Instruction::Free(parse_memory_id(chars)?),
+		Token::Name(name) if name == "open_loop" => Instruction::OpenLoop(parse_cell(chars)?),
+		Token::Name(name) if name == "close_loop" => Instruction::CloseLoop(parse_cell(chars)?),
+		Token::Name(name) if name == "add" => {
+			Instruction::AddToCell(parse_cell(chars)?, parse_value(chars)?)
+		}
+		Token::Name(name) if name == "clear" => Instruction::ClearCell(parse_cell(chars)?),
+		Token::Input => Instruction::InputToCell(parse_cell(chars)?),
+		Token::Output => Instruction::OutputCell(parse_cell(chars)?),
+		Token::Assert => {
+			let cell = parse_cell(chars)?;
+			match next_token(chars)? {
+				Token::Unknown => Instruction::AssertCellValue(cell, None),
+				Token::Equals => Instruction::AssertCellValue(cell, Some(parse_value(chars)?)),
+				token => r_panic!("Expected `equals` or `unknown` in assertion, found `{token}`."),
+			}
+		}
+		Token::Bf => parse_brainfuck(chars)?,
+		token => r_panic!("Unknown IR instruction `{token}`."),
+	};
+
+	match next_token(chars)? {
+		Token::None => Ok(Some(instruction)),
+		token => r_panic!("Unexpected `{token}` at the end of the instruction."),
+	}
+}
+
+/// in-line Brainfuck, e.g. `bf @%0 clobbers a(%0, %1[2]) { +[-] }`
+fn parse_brainfuck<TC: TapeCellLocation, OC: OpcodeVariant>(
+	chars: &mut SourceCursor,
+) -> Result<Instruction<TC, OC>, Diagnostic> {
+	let location = {
+		let mut s = *chars;
+		match (next_token(&mut s)?, next_token(&mut s)?) {
+			(Token::At, Token::Percent) => {
+				next_token(chars)?;
+				CellLocation::MemoryCell(parse_cell(chars)?)
+			}
+			_ => match TC::parse_location_specifier(chars)? {
+				LocationSpecifier::None => CellLocation::Unspecified,
+				LocationSpecifier::Cell(cell) => CellLocation::FixedCell(cell),
+				LocationSpecifier::Variable(_) => {
+					r_panic!("Expected a cell or memory location for the Brainfuck.")
+				}
+			},
+		}
+	};
+
+	let mut clobbers = vec![];
+	let mut token = next_token(chars)?;
+	if let Token::Clobbers = token {
+		loop {
+			while chars.first().is_some_and(|c| c.is_whitespace()) {
+				chars.advance(1);
+			}
+			// clobbered variables are named by their target expression, e.g. `*a.b[2]`
+			let mut name = String::new();
+			while let Some(c) = chars.first() {
+				if *c == '(' || *c == '{' || c.is_whitespace() {
+					break;
+				}
+				name.push(*c);
+				chars.advance(1);
+			}
+			if name.is_empty() {
+				break;
+			}
+			let Token::LeftParenthesis = next_token(chars)? else {
+				r_panic!("Expected `(` after clobbered variable \"{name}\".");
+			};
+			let mut cells = vec![parse_cell(chars)?];
+			loop {
+				match next_token(chars)? {
+					Token::Comma => cells.push(parse_cell(chars)?),
+					Token::RightParenthesis => break,
+					token => r_panic!("Unexpected `{token}` in clobbered variable \"{name}\"."),
+				}
+			}
+			clobbers.push(ClobberedVariable {
+				name,
+				cells,
+				span: Span::default(),
+			});
+		}
+		token = next_token(chars)?;
+	}
+	let Token::LeftBrace = token else {
+		r_panic!("Expected `{{` in Brainfuck instruction, found `{token}`.");
+	};
+
+	let mut operations = vec![];
+	loop {
+		match chars.first() {
+			Some('}') => {
+				chars.advance(1);
+				break;
+			}
+			Some(c) if c.is_whitespace() => chars.advance(1),
+			Some(c) => {
+				let Some(operation) = OC::try_from_char(*c) else {
+					r_panic!("Unexpected character `{c}` in Brainfuck instruction.");
+				};
+				operations.push(operation);
+				chars.advance(1);
+			}
+			None => r_panic!("Expected `}}` at the end of the Brainfuck instruction."),
+		}
+	}
+
+	Ok(Instruction::InsertBrainfuckAtCell(
+		operations, location, clobbers,
+	))
+}
+
+fn parse_memory(chars: &mut SourceCursor) -> Result<Memory, Diagnostic> {
+	let id = parse_memory_id(chars)?;
+	let kind = match next_token(chars)? {
+		// `cell` is a keyword so it isn't tokenised as a name
+		Token::Cell => String::from("cell"),
+		Token::Name(kind) => kind,
+		_ => r_panic!("Expected memory kind after memory id %{id}."),
+	};
+	Ok(match kind.as_str() {
+		"cell" => Memory::Cell { id },
+		"cells" => Memory::Cells {
+			id,
+			len: parse_number(chars)?,
+		},
+		"mapped_cell" => {
+			let mut s = *chars;
+			let index = match next_token(&mut s)? {
+				Token::Number(_) => Some(parse_number(chars)?),
+				_ => None,
+			};
+			Memory::MappedCell { id, index }
+		}
+		"mapped_cells" => Memory::MappedCells {
+			id,
+			start_index: parse_number(chars)?,
+			len: parse_number(chars)?,
+		},
+		_ => r_panic!("Unknown memory kind \"{kind}\"."),
+	})
+}
+
+/// a memory id, e.g. `%3`
+fn parse_memory_id(chars: &mut SourceCursor) -> Result<usize, Diagnostic> {
+	let Token::Percent = next_token(chars)? else {
+		r_panic!("Expected memory id, e.g. `%0`.");
+	};
+	parse_number(chars)
+}
+
+/// a cell within some memory, e.g. `%3` or `%3[1]`
+fn parse_cell(chars: &mut SourceCursor) -> Result<CellReference, Diagnostic> {
+	let memory_id = parse_memory_id(chars)?;
+	let mut s = *chars;
+	let Token::LeftSquareBracket = next_token(&mut s)? else {
+		return Ok(CellReference {
+			memory_id,
+			index: None,
+		});
+	};
+	*chars = s;
+	let index = parse_number(chars)?;
+	let Token::RightSquareBracket = next_token(chars)? else {
+		r_panic!("Expected `]` after cell index.");
+	};
+	Ok(CellReference {
+		memory_id,
+		index: Some(index),
+	})
+}
+
+fn parse_number(chars: &mut SourceCursor) -> Result<usize, Diagnostic> {
+	match next_token(chars)? {
+		Token::Number(number) => Ok(number),
+		token => r_panic!("Expected number, found `{token}`."),
+	}
+}
+
+/// a cell value, negative values wrap around, e.g. `-1` is 255
+fn parse_value(chars: &mut SourceCursor) -> Result<u8, Diagnostic> {
+	let mut s = *chars;
+	let is_negative = matches!(next_token(&mut s)?, Token::Minus);
+	if is_negative {
+		*chars = s;
+	}
+	let magnitude = parse_number(chars)?;
+	match is_negative {
+		false if magnitude <= u8::MAX as usize => Ok(magnitude as u8),
+		true if magnitude <= 128 => Ok((magnitude as u8).wrapping_neg()),
+		_ => r_panic!("Cell value must be between -128 and 255."),
+	}
+}
+
+impl<TC: Display, OC: Clone> Display for Instruction<TC, OC>
+where
+	Vec<OC>: BrainfuckProgram,
+{
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Instruction::Allocate(memory, location, name) => {
+				write!(f, "allocate {memory}")?;
+				if let Some(location) = location {
+					write!(f, " @{location}")?;
+				}
+				if let Some(name) = name {
+					write!(f, " {name}")?;
+				}
+				Ok(())
+			}
+			Instruction::Free(id) => write!(f, "free %{id}"),
+			Instruction::OpenLoop(cell) => write!(f, "open_loop {cell}"),
+			Instruction::CloseLoop(cell) => write!(f, "close_loop {cell}"),
+			// values are easier to read as signed, e.g. `add %0 -1`
+			Instruction::AddToCell(cell, value) => write!(f, "add {cell} {}", *value as i8),
+			Instruction::InputToCell(cell) => write!(f, "input {cell}"),
+			Instruction::ClearCell(cell) => write!(f, "clear {cell}"),
+			Instruction::AssertCellValue(cell, None) => write!(f, "assert {cell} unknown"),
+			Instruction::AssertCellValue(cell, Some(value)) => {
+				write!(f, "assert {cell} equals {}", *value as i8)
+			}
+			Instruction::OutputCell(cell) => write!(f, "output {cell}"),
+			Instruction::InsertBrainfuckAtCell(operations, location, clobbers) => {
+				f.write_str("bf")?;
+				match location {
+					CellLocation::Unspecified => (),
+					CellLocation::FixedCell(cell) => write!(f, " @{cell}")?,
+					CellLocation::MemoryCell(cell) => write!(f, " @{cell}")?,
+				}
+				if !clobbers.is_empty() {
+					f.write_str(" clobbers")?;
+					for clobbered in clobbers {
+						let cells: Vec<String> = clobbered
+							.cells
+							.iter()
+							.map(|cell| cell.to_string())
+							.collect();
+						write!(f, " {}({})", clobbered.name, cells.join(", "))?;
+					}
+				}
+				match operations.is_empty() {
+					true => f.write_str(" { }"),
+					false => write!(f, " {{ {} }}", operations.clone().to_string()),
+				}
+			}
+		}
+	}
+}
+
+impl Display for Memory {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Memory::Cell { id } => write!(f, "%{id} cell"),
+			Memory::Cells { id, len } => write!(f, "%{id} cells {len}"),
+			Memory::MappedCell { id, index: None } => write!(f, "%{id} mapped_cell"),
+			Memory::MappedCell {
+				id,
+				index: Some(index),
+			} => write!(f, "%{id} mapped_cell {index}"),
+			Memory::MappedCells {
+				id,
+				start_index,
+				len,
+			} => write!(f, "%{id} mapped_cells {start_index} {len}"),
+		}
+	}
+}
+
+impl Display for CellReference {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "%{}", self.memory_id)?;
+		if let Some(index) = self.index {
+			write!(f, "[{index}]")?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod ir_tests {
+	use super::*;
+	use crate::{
+		backend::{
+			bf::{Opcode, TapeCell},
+			bf2d::{Opcode2D, TapeCell2D},
+		},
+		misc::{MastermindConfig, MastermindContext},
+		parser::parser::parse_program,
+	};
+
+	fn compile_to_ir(program: &str) -> String {
+		let ctx = MastermindContext::new(MastermindConfig::default());
+		let clauses = parse_program::<TapeCell, Opcode>(program).unwrap();
+		let instructions = ctx.create_ir_scope(&clauses, None).unwrap().build_ir(false);
+		ir_to_text(&instructions)
+	}
+
+	fn round_trip<TC: TapeCellLocation + Display, OC: OpcodeVariant + Clone>(text: &str) -> String
+	where
+		Vec<OC>: BrainfuckProgram,
+	{
+		ir_to_text(&parse_ir::<TC, OC>(text).unwrap())
+	}
+
+	#[test]
+	fn text_1() {
+		let text = compile_to_ir(
+			r#"
+cell a = 5;
+cell[3] b @4;
+while a {
+	a -= 1;
+	output 'x';
+}
+assert a equals 0;
+bf @b[0] clobbers *b {[-]+}
+"#,
+		);
+		assert_eq!(
+			text,
+			"allocate %0 cell a
+add %0 5
+allocate %1 cells 3 @4 b
+open_loop %0
+  add %0 -1
+  allocate %2 cell
+  add %2 120
+  output %2
+  clear %2
+  free %2
+close_loop %0
+assert %0 equals 0
+bf @%1[0] clobbers *b(%1[0], %1[1], %1[2]) { [-]+ }
+assert %1[0] unknown
+assert %1[1] unknown
+assert %1[2] unknown
+"
+		);
+	}
+
+	#[test]
+	fn round_trip_1() {
+		let text = compile_to_ir(
+			r#"
+struct pair { cell x; cell y; }
+struct pair p;
+p.x = 200;
+input p.y;
+cell c;
+drain p.x into c;
+if c { output c; } else { output 'n'; }
+bf clobbers p.y { ,. }
+"#,
+		);
+		assert_eq!(round_trip::<TapeCell, Opcode>(&text), text);
+	}
+
+	#[test]
+	fn round_trip_2() {
+		let text = "allocate %0 mapped_cells 2 3 @(1, -2) s
+allocate %1 mapped_cell 1
+allocate %2 mapped_cell
+bf @(0, 3) { ^v<> }
+bf { }
+free %0
+";
+		assert_eq!(round_trip::<TapeCell2D, Opcode2D>(text), text);
+	}
+
+	#[test]
+	fn parse_1() {
+		// comments and blank lines are skipped, values wrap around
+		let instructions = parse_ir::<TapeCell, Opcode>(
+			"// a comment
+
+allocate %0 cell
+add %0 255
+add %0 -128
+",
+		)
+		.unwrap();
+		assert_eq!(
+			ir_to_text(&instructions),
+			"allocate %0 cell\nadd %0 -1\nadd %0 -128\n"
+		);
+	}
+
+	#[test]
+	fn backend_1() {
+		// IR can be written by hand to test the backend directly
+		let instructions = parse_ir::<TapeCell, Opcode>(
+			"allocate %0 cell @2
+allocate %1 cells 2
+add %0 3
+open_loop %0
+  add %0 -1
+  add %1[1] 1
+close_loop %0
+bf @%1[1] { [-] }
+assert %1[1] equals 0
+free %0
+free %1
+",
+		)
+		.unwrap();
+		let ctx = MastermindContext::new(MastermindConfig::default());
+		let bf = ctx.ir_to_bf(instructions, None).unwrap();
+		assert_eq!(bf.to_string(), ">>+++[-<+>]<[-]");
+	}
+
+	#[test]
+	fn errors_1() {
+		assert!(parse_ir::<TapeCell, Opcode>("allocate %0")
+			.unwrap_err()
+			.contains("in IR line 1"));
+		assert!(parse_ir::<TapeCell, Opcode>("free %0\nadd %0 300")
+			.unwrap_err()
+			.contains("in IR line 2"));
+		assert!(parse_ir::<TapeCell, Opcode>("output %0 %1").is_err());
+		assert!(parse_ir::<TapeCell, Opcode>("bf { +x }").is_err());
+		assert!(parse_ir::<TapeCell, Opcode>("bf { +").is_err());
+		assert!(parse_ir::<TapeCell, Opcode>("move %0").is_err());
+	}
+}
@@ -1,2 +1,5 @@
+#[allow(clippy::module_inception)]
 pub mod frontend;
+pub mod ir;
 pub mod types;
+pub mod warnings;
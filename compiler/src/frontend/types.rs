@@ -1,4 +1,5 @@
 use crate::{
+	diagnostics::{Span, Spanned},
 	macros::macros::*,
 	parser::types::{Clause, Reference, VariableTargetReferenceChain},
 };
@@ -6,7 +7,7 @@ use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 pub enum Instruction<TC, OC> {
-	Allocate(Memory, Option<TC>),
+	Allocate(Memory, Option<TC>, Option<String>), // the variable name, if the memory belongs to a variable (for debugging)
 	Free(MemoryId), // the number indicates which cell in the allocation stack should be freed (cell 0, is the top of the stack, 1 is the second element, etc)
 	OpenLoop(CellReference), // same with other numbers here, they indicate the cell in the allocation stack to use in the instruction
 	CloseLoop(CellReference), // pass in the cell id, this originally wasn't there but may be useful later on
@@ -15,7 +16,16 @@ pub enum Instruction<TC, OC> {
 	ClearCell(CellReference), // not sure if this should be here, seems common enough that it should be
 	AssertCellValue(CellReference, Option<u8>), // allows the user to hand-tune optimisations further
 	OutputCell(CellReference),
-	InsertBrainfuckAtCell(Vec<OC>, CellLocation<TC>),
+	InsertBrainfuckAtCell(Vec<OC>, CellLocation<TC>, Vec<ClobberedVariable>),
+}
+
+#[derive(Debug, Clone)]
+/// A variable listed as clobbered by in-line Brainfuck, checked against the cells the Brainfuck uses
+pub struct ClobberedVariable {
+	pub name: String,
+	pub cells: Vec<CellReference>,
+	/// the Brainfuck clause, for warnings
+	pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -69,6 +79,35 @@ impl Memory {
 			} => *id,
 		}
 	}
+	/// references to every cell in this memory, in order
+	pub fn cells(&self) -> Vec<CellReference> {
+		match self {
+			Memory::Cell { id } => vec![CellReference {
+				memory_id: *id,
+				index: None,
+			}],
+			Memory::MappedCell { id, index } => vec![CellReference {
+				memory_id: *id,
+				index: *index,
+			}],
+			Memory::Cells { id, len } => (0..*len)
+				.map(|i| CellReference {
+					memory_id: *id,
+					index: Some(i),
+				})
+				.collect(),
+			Memory::MappedCells {
+				id,
+				start_index,
+				len,
+			} => (*start_index..(*start_index + *len))
+				.map(|i| CellReference {
+					memory_id: *id,
+					index: Some(i),
+				})
+				.collect(),
+		}
+	}
 	pub fn len(&self) -> usize {
 		match self {
 			Memory::Cell { id: _ } | Memory::MappedCell { id: _, index: _ } => 1,
@@ -82,10 +121,35 @@ impl Memory {
 	}
 }
 
+#[derive(Clone, Debug)]
+/// Hidden flag cells used to lower `break`, `continue` and `return`
+pub enum FlowControl {
+	/// not directly in a loop or function body, use the outer scope's flow control
+	Inherit,
+	/// flow control cannot reach past this scope, e.g. embedded Mastermind in Brainfuck
+	Boundary,
+	Loop {
+		/// non-zero while the rest of the current iteration should run
+		active: CellReference,
+		/// the cell the loop is opened on, cleared by `break` (None if the loop has no `break`)
+		loop_cell: Option<CellReference>,
+	},
+	Function {
+		/// non-zero until the function returns, None if the function body doesn't need it
+		active: Option<CellReference>,
+		/// the return type and the caller's result cells, None if the function has no return type
+		result: Option<ReturnSlot>,
+	},
+}
+
+/// The return type of a function, and the cells its return value is written into
+pub type ReturnSlot = (ValueType, Vec<CellReference>);
+
 #[derive(Clone, Debug)] // probably shouldn't be cloning here but whatever
 pub struct Function<TC, OC> {
 	pub arguments: Vec<(String, ValueType)>,
-	pub block: Vec<Clause<TC, OC>>,
+	pub return_type: Option<ValueType>,
+	pub block: Vec<Spanned<Clause<TC, OC>>>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -94,9 +158,25 @@ pub enum ValueType {
 	Cell,
 	Array(usize, Box<ValueType>),
 	DictStruct(Vec<(String, ValueType, Option<usize>)>),
+	/// an array of cells interleaved with gap cells, so that it can be indexed at runtime
+	DynamicArray(usize),
 	// TupleStruct(Vec<ValueType>),
 }
 
+// Dynamic array memory layout: [restore, home, transfer, gap 0, element 0, gap 1, element 1, ...]
+// To access an element at runtime, the index is counted down through the gap cells, leaving a breadcrumb in each one,
+// the head then walks along the breadcrumbs between the home cell and the element to carry values one at a time.
+// The home cell is always zero so the walk back stops there, all cells except the elements are zero outside of accesses.
+/// cell in a dynamic array used to restore an element's value after reading it
+pub const DYNAMIC_ARRAY_RESTORE_CELL: usize = 0;
+/// cell in a dynamic array used to carry values to and from an element
+pub const DYNAMIC_ARRAY_TRANSFER_CELL: usize = 2;
+/// cell in a dynamic array where the runtime index is counted down from
+pub const DYNAMIC_ARRAY_INDEX_CELL: usize = 3;
+
+/// struct subfield names mapped to their starting cell and type
+pub type SubfieldCellMap<'a> = HashMap<&'a String, (usize, &'a ValueType)>;
+
 #[derive(Clone, Debug)]
 /// equivalent to ValueType::DictStruct enum variant,
 /// Rust doesn't support enum variants as types yet so need this workaround for struct definitions in scope object
@@ -115,14 +195,20 @@ impl ValueType {
 			ValueType::Cell => 1,
 			ValueType::Array(len, value_type) => *len * value_type.size()?,
 			ValueType::DictStruct(fields) => Self::get_and_validate_subfield_cell_map(fields)?.1,
+			ValueType::DynamicArray(len) => Self::dynamic_element_index(*len),
 		})
 	}
 
+	/// the memory index of an element in a dynamic array
+	pub fn dynamic_element_index(index: usize) -> usize {
+		DYNAMIC_ARRAY_INDEX_CELL + 1 + 2 * index
+	}
+
 	/// deterministically place all struct subfields on a non-negative cell, return the positions of each and the total length
 	/// return Err() if location specified subfields overlap
 	pub fn get_and_validate_subfield_cell_map(
 		fields: &Vec<(String, ValueType, Option<usize>)>,
-	) -> Result<(HashMap<&String, (usize, &ValueType)>, usize), String> {
+	) -> Result<(SubfieldCellMap<'_>, usize), String> {
 		// (set of cells, max cell)
 		let mut cell_map = HashMap::new();
 
@@ -204,12 +290,33 @@ impl ValueType {
 					cur_field = subfield_type;
 				}
 
-				(ValueType::DictStruct(_), Reference::Index(_)) => {
+				(ValueType::DynamicArray(len), Reference::Index(index)) => {
+					r_assert!(
+						index < len,
+						"Index \"{subfield_ref}\" must be less than array length ({len})."
+					);
+					cur_index += Self::dynamic_element_index(*index);
+					cur_field = &ValueType::Cell;
+				}
+
+				(ValueType::DictStruct(_), Reference::Index(_) | Reference::DynamicIndex(_)) => {
 					r_panic!("Cannot read index subfield \"{subfield_ref}\" of struct type.")
 				}
-				(ValueType::Array(_, _), Reference::NamedField(_)) => {
+				(ValueType::Array(_, _) | ValueType::DynamicArray(_), Reference::NamedField(_)) => {
 					r_panic!("Cannot read named subfield \"{subfield_ref}\" of array type.")
 				}
+				(ValueType::Array(_, _), Reference::DynamicIndex(_)) => {
+					r_panic!(
+						"Runtime index \"{subfield_ref}\" can only be used with a dynamic array, \
+e.g. `dynamic cell[8]`."
+					)
+				}
+				(ValueType::DynamicArray(_), Reference::DynamicIndex(_)) => {
+					r_panic!(
+						"Runtime index \"{subfield_ref}\" can only be used to read, assign, \
+add to, or input into a dynamic array element."
+					)
+				}
 				(ValueType::Cell, subfield_ref) => {
 					r_panic!("Attempted to get subfield \"{subfield_ref}\" of cell type.")
 				}
@@ -228,6 +335,9 @@ impl std::fmt::Display for ValueType {
 			ValueType::Array(length, element_type) => {
 				f.write_fmt(format_args!("{element_type}[{length}]"))?;
 			}
+			ValueType::DynamicArray(length) => {
+				f.write_fmt(format_args!("dynamic cell[{length}]"))?;
+			}
 			ValueType::DictStruct(fields) => {
 				f.write_str("{")?;
 				let fields_len = fields.len();
@@ -241,6 +351,7 @@ impl std::fmt::Display for ValueType {
 						f.write_str(" ")?;
 					}
 				}
+				f.write_str("}")?;
 			}
 		}
 		Ok(())
@@ -0,0 +1,407 @@
+// warnings for code which compiles but is probably a mistake, found by walking the syntax tree before it is compiled
+
+use std::collections::HashSet;
+
+use crate::{
+	diagnostics::{Diagnostic, Span, Spanned},
+	parser::{
+		expressions::Expression,
+		types::{
+			Clause, ExtendedOpcode, LocationSpecifier, Reference, VariableTarget,
+			VariableTargetReferenceChain, VariableTypeDefinition, VariableTypeReference,
+		},
+	},
+};
+
+/// find warnings for unused variables, functions and structs, and assignments which are never read,
+/// names starting with `_` are never warned about being unused
+pub fn find_warnings<TC, OC>(clauses: &[Spanned<Clause<TC, OC>>]) -> Vec<Diagnostic> {
+	let mut linter = Linter::default();
+	linter.check_block(clauses, vec![]);
+
+	for (name, span) in &linter.functions {
+		if !linter.called_functions.contains(name) && !name.starts_with('_') {
+			linter.warnings.push(Diagnostic::warning(
+				format!("Function \"{name}\" is never called."),
+				*span,
+			));
+		}
+	}
+	for (name, span) in &linter.structs {
+		if !linter.used_structs.contains(name) && !name.starts_with('_') {
+			linter.warnings.push(Diagnostic::warning(
+				format!("Struct \"{name}\" is never used."),
+				*span,
+			));
+		}
+	}
+
+	linter.warnings
+}
+
+/// How a variable has been used so far
+struct VariableUsage {
+	name: String,
+	/// the clause defining the variable
+	span: Span,
+	read: bool,
+	/// assignments to the variable or to its fields and elements whose values haven't been read yet
+	unread_stores: Vec<UnreadStore>,
+}
+
+struct UnreadStore {
+	/// the field names and constant indices assigned to, empty for the whole variable
+	path: Vec<Reference>,
+	/// the target as written in the assignment, e.g. `a[0]`
+	target: String,
+	span: Span,
+}
+
+#[derive(Default)]
+struct Linter {
+	/// the variables defined in each open block, innermost last
+	scopes: Vec<Vec<VariableUsage>>,
+	functions: Vec<(String, Span)>,
+	structs: Vec<(String, Span)>,
+	called_functions: HashSet<String>,
+	used_structs: HashSet<String>,
+	warnings: Vec<Diagnostic>,
+}
+
+impl Linter {
+	/// check a block of clauses, with some variables already defined in it (e.g. function arguments)
+	fn check_block<TC, OC>(
+		&mut self,
+		clauses: &[Spanned<Clause<TC, OC>>],
+		variables: Vec<VariableUsage>,
+	) {
+		self.scopes.push(variables);
+		for clause in clauses {
+			self.check_clause(&clause.node, clause.span);
+		}
+		for variable in self.scopes.pop().unwrap_or_default() {
+			if !variable.read && !variable.name.starts_with('_') {
+				self.warnings.push(Diagnostic::warning(
+					format!("Variable \"{}\" is never read.", variable.name),
+					variable.span,
+				));
+			}
+		}
+	}
+
+	/// check a block which may run any number of times, the linter only follows assignments in straight-line code
+	fn check_conditional_block<TC, OC>(&mut self, clauses: &[Spanned<Clause<TC, OC>>]) {
+		self.forget_stores();
+		self.check_block(clauses, vec![]);
+		self.forget_stores();
+	}
+
+	fn check_clause<TC, OC>(&mut self, clause: &Clause<TC, OC>, span: Span) {
+		match clause {
+			Clause::None
+			| Clause::Break
+			| Clause::Continue
+			| Clause::DefineConstant { name: _, value: _ } => (),
+			Clause::DeclareVariable { var } => self.define_variable(var, span, false),
+			Clause::DefineVariable { var, value } => {
+				self.read_expression(value);
+				self.define_variable(var, span, true);
+			}
+			Clause::DefineStruct { name, fields } => {
+				self.structs.push((name.clone(), span));
+				for field in fields {
+					self.use_type(&field.field_type);
+				}
+			}
+			Clause::DefineFunction {
+				name,
+				arguments,
+				return_type,
+				block,
+			} => {
+				self.functions.push((name.clone(), span));
+				if let Some(return_type) = return_type {
+					self.use_type(return_type);
+				}
+				// arguments are passed by reference, so they may only be written to
+				let arguments = arguments
+					.iter()
+					.map(|argument| {
+						self.use_type(&argument.var_type);
+						VariableUsage {
+							name: argument.name.clone(),
+							span,
+							read: true,
+							unread_stores: vec![],
+						}
+					})
+					.collect();
+				self.forget_stores();
+				self.check_block(block, arguments);
+				self.forget_stores();
+			}
+			Clause::Assign {
+				var,
+				value,
+				self_referencing: _,
+			} => {
+				self.read_expression(value);
+				self.store(var, Some(span));
+			}
+			Clause::Input { var } => self.store(var, None),
+			Clause::AddAssign {
+				var,
+				value,
+				self_referencing: _,
+			} => {
+				self.read_expression(value);
+				self.read_indices(var);
+			}
+			Clause::AssertVariableValue { var, value } => {
+				self.read_indices(var);
+				if let Some(value) = value {
+					self.read_expression(value);
+				}
+			}
+			Clause::DrainLoop {
+				source,
+				targets,
+				block,
+				is_copying: _,
+			} => {
+				self.read_expression(source);
+				for target in targets {
+					self.read_indices(target);
+				}
+				if let Some(block) = block {
+					self.check_conditional_block(block);
+				}
+			}
+			Clause::While { condition, block } => {
+				self.read_expression(condition);
+				self.check_conditional_block(block);
+			}
+			Clause::If {
+				condition,
+				if_block: block,
+			}
+			| Clause::IfNot {
+				condition,
+				if_not_block: block,
+			} => {
+				self.read_expression(condition);
+				self.check_conditional_block(block);
+			}
+			Clause::IfElse {
+				condition,
+				if_block: block,
+				else_block,
+			}
+			| Clause::IfNotElse {
+				condition,
+				if_not_block: block,
+				else_block,
+			} => {
+				self.read_expression(condition);
+				self.check_conditional_block(block);
+				self.check_conditional_block(else_block);
+			}
+			Clause::Output { value } | Clause::Return { value: Some(value) } => {
+				self.read_expression(value)
+			}
+			Clause::Return { value: None } => (),
+			Clause::CallFunction {
+				function_name,
+				arguments,
+			} => {
+				self.called_functions.insert(function_name.clone());
+				for argument in arguments {
+					self.read_expression(argument);
+				}
+			}
+			Clause::Block(clauses) => self.check_block(clauses, vec![]),
+			Clause::Brainfuck {
+				location_specifier,
+				clobbered_variables,
+				operations,
+			} => {
+				// the Brainfuck may read and write the cells it is given
+				if let LocationSpecifier::Variable(var) = location_specifier {
+					self.read(var);
+				}
+				for var in clobbered_variables {
+					self.read(var);
+				}
+				for operation in operations {
+					if let ExtendedOpcode::Block(clauses) = operation {
+						self.check_conditional_block(clauses);
+					}
+				}
+			}
+		}
+	}
+
+	fn define_variable<TC>(
+		&mut self,
+		var: &VariableTypeDefinition<TC>,
+		span: Span,
+		initialised: bool,
+	) {
+		self.use_type(&var.var_type);
+		// variables at fixed cells are usually there to be used by Brainfuck code, which can't be checked
+		let fixed_location = match &var.location_specifier {
+			LocationSpecifier::None => false,
+			LocationSpecifier::Cell(_) => true,
+			LocationSpecifier::Variable(target) => {
+				self.read(target);
+				false
+			}
+		};
+		if let Some(scope) = self.scopes.last_mut() {
+			scope.push(VariableUsage {
+				name: var.name.clone(),
+				span,
+				read: fixed_location,
+				unread_stores: match initialised && !fixed_location {
+					true => vec![UnreadStore {
+						path: vec![],
+						target: var.name.clone(),
+						span,
+					}],
+					false => vec![],
+				},
+			});
+		}
+	}
+
+	fn use_type(&mut self, var_type: &VariableTypeReference) {
+		match var_type {
+			VariableTypeReference::Struct(name) => {
+				self.used_structs.insert(name.clone());
+			}
+			VariableTypeReference::Array(element_type, _) => self.use_type(element_type),
+			VariableTypeReference::Cell | VariableTypeReference::DynamicArray(_) => (),
+		}
+	}
+
+	/// find the innermost variable with a name
+	fn variable(&mut self, name: &str) -> Option<&mut VariableUsage> {
+		self.scopes.iter_mut().rev().find_map(|scope| {
+			scope
+				.iter_mut()
+				.rev()
+				.find(|variable| variable.name == name)
+		})
+	}
+
+	fn read(&mut self, target: &VariableTarget) {
+		self.read_indices(target);
+		let path = constant_path(target);
+		if let Some(variable) = self.variable(&target.name) {
+			variable.read = true;
+			// reading part of a variable reads any assignment overlapping that part
+			variable
+				.unread_stores
+				.retain(|store| !store.path.starts_with(path) && !path.starts_with(&store.path));
+		}
+	}
+
+	/// read the variables used as runtime array indices in a target
+	fn read_indices(&mut self, target: &VariableTarget) {
+		let Some(VariableTargetReferenceChain(references)) = &target.subfields else {
+			return;
+		};
+		for reference in references {
+			if let Reference::DynamicIndex(index) = reference {
+				self.read(index);
+			}
+		}
+	}
+
+	fn read_expression(&mut self, expr: &Spanned<Expression>) {
+		match &expr.node {
+			Expression::NaturalNumber(_) | Expression::StringLiteral(_) => (),
+			Expression::VariableReference(target) => self.read(target),
+			Expression::SumExpression {
+				sign: _,
+				summands: expressions,
+			}
+			| Expression::ArrayLiteral(expressions) => {
+				for expr in expressions {
+					self.read_expression(expr);
+				}
+			}
+			Expression::BinaryExpression {
+				operator: _,
+				left,
+				right,
+			} => {
+				self.read_expression(left);
+				self.read_expression(right);
+			}
+			Expression::FunctionCall {
+				function_name,
+				arguments,
+			} => {
+				self.called_functions.insert(function_name.clone());
+				for argument in arguments {
+					self.read_expression(argument);
+				}
+			}
+		}
+	}
+
+	/// overwrite a target, warning about previous assignments to it (or to its fields and elements) which were never read,
+	/// `span` is the assignment to track until the new value is read, if any
+	fn store(&mut self, target: &VariableTarget, span: Option<Span>) {
+		self.read_indices(target);
+		// a runtime index could be any element, so nothing is known to be overwritten
+		if target.split_dynamic_index().is_some() {
+			return;
+		}
+		let path = constant_path(target);
+		let Some(variable) = self.variable(&target.name) else {
+			return;
+		};
+		let (overwritten, unread_stores): (Vec<_>, Vec<_>) =
+			std::mem::take(&mut variable.unread_stores)
+				.into_iter()
+				.partition(|store| store.path.starts_with(path));
+		variable.unread_stores = unread_stores;
+		if let Some(span) = span {
+			variable.unread_stores.push(UnreadStore {
+				path: path.to_vec(),
+				target: target.to_string(),
+				span,
+			});
+		}
+		for store in overwritten {
+			self.warnings.push(Diagnostic::warning(
+				format!(
+					"Value assigned to \"{}\" is overwritten before it is read.",
+					store.target
+				),
+				store.span,
+			));
+		}
+	}
+
+	/// stop tracking assignments, used where control flow may skip or repeat code
+	fn forget_stores(&mut self) {
+		for variable in self.scopes.iter_mut().flatten() {
+			variable.unread_stores.clear();
+		}
+	}
+}
+
+/// the field names and constant indices of a target, up to any runtime index
+fn constant_path(target: &VariableTarget) -> &[Reference] {
+	let Some(VariableTargetReferenceChain(references)) = &target.subfields else {
+		return &[];
+	};
+	let end = references
+		.iter()
+		.position(|reference| matches!(reference, Reference::DynamicIndex(_)))
+		.unwrap_or(references.len());
+	&references[..end]
+}
@@ -0,0 +1,18 @@
+#![allow(dead_code)]
+// dead code is allowed because the language server only uses the parts of the compiler that analyse programs
+
+// project dependencies:
+mod backend;
+mod diagnostics;
+mod frontend;
+mod lsp;
+mod macros;
+mod misc;
+mod parser;
+mod preprocessor;
+mod project;
+mod sources;
+
+fn main() -> Result<(), String> {
+	lsp::server::run()
+}
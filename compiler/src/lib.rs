@@ -4,11 +4,13 @@
 // project dependencies:
 mod backend;
 mod brainfuck;
+mod diagnostics;
 mod frontend;
 mod macros;
 mod misc;
 mod parser;
-mod preprocessor;
+pub mod preprocessor;
+pub mod sources;
 mod tests;
 use crate::{
 	backend::{
@@ -17,9 +19,11 @@ use crate::{
 		common::BrainfuckProgram,
 	},
 	brainfuck::{BrainfuckConfig, BrainfuckContext},
+	diagnostics::Diagnostics,
 	misc::MastermindContext,
-	parser::parser::parse_program,
-	preprocessor::{preprocess_from_memory, strip_comments},
+	parser::parser::{ast_to_json, parse_program},
+	preprocessor::preprocess,
+	sources::{LayeredProvider, MemoryProvider},
 };
 
 // stdlib dependencies:
@@ -35,43 +39,108 @@ pub fn set_panic_hook() {
 	console_error_panic_hook::set_once();
 }
 
+/// The compiled Brainfuck of a successful compilation, along with any warnings
+#[derive(Debug, serde::Serialize)]
+pub struct CompileOutput {
+	pub code: String,
+	/// rendered warnings, empty if there are none
+	pub warnings: String,
+}
+
+/// compile a program to Brainfuck, returning a `CompileOutput` object or the rendered errors and warnings
 #[wasm_bindgen]
 pub fn wasm_compile(
 	file_contents: JsValue,
 	entry_file_name: String,
 	config: JsValue,
-) -> Result<String, String> {
+) -> Result<JsValue, String> {
 	set_panic_hook();
 
 	let file_contents: HashMap<String, String> =
 		serde_wasm_bindgen::from_value(file_contents).unwrap();
-	let ctx = MastermindContext {
-		config: serde_wasm_bindgen::from_value(config).unwrap(),
-	};
+	let ctx = MastermindContext::new(serde_wasm_bindgen::from_value(config).unwrap());
 
-	let preprocessed_file = preprocess_from_memory(&file_contents, entry_file_name)?;
-	let stripped_file = strip_comments(&preprocessed_file);
-	if ctx.config.enable_2d_grid {
-		let parsed_syntax = parse_program::<TapeCell2D, Opcode2D>(&stripped_file)?;
-		let instructions = ctx.create_ir_scope(&parsed_syntax, None)?.build_ir(false);
-		let bf_code = ctx.ir_to_bf(instructions, None)?;
-		Ok(match ctx.config.optimise_generated_code {
-			true => ctx.optimise_bf2d(bf_code),
-			false => bf_code,
+	let output = compile_files(&ctx, file_contents, &entry_file_name)?;
+	Ok(serde_wasm_bindgen::to_value(&output).unwrap())
+}
+
+/// compile an in-memory set of files, split out of `wasm_compile` so that it can be tested natively
+fn compile_files(
+	ctx: &MastermindContext,
+	file_contents: HashMap<String, String>,
+	entry_file_name: &str,
+) -> Result<CompileOutput, String> {
+	let sources = LayeredProvider::with_std_library(MemoryProvider {
+		files: file_contents,
+	});
+	let (preprocessed_file, source_map) =
+		preprocess(&sources, entry_file_name).map_err(|err| err.render())?;
+	let compile = || -> Result<String, Diagnostics> {
+		if ctx.config.enable_2d_grid {
+			let parsed_syntax = parse_program::<TapeCell2D, Opcode2D>(&preprocessed_file)?;
+			let instructions = ctx.create_ir_scope(&parsed_syntax, None)?.build_ir(false);
+			let bf_code = ctx.ir_to_bf(instructions, None)?;
+			Ok(match ctx.config.optimise_generated_code {
+				true => ctx.optimise_bf2d(bf_code),
+				false => bf_code,
+			}
+			.to_string())
+		} else {
+			let parsed_syntax = parse_program::<TapeCell, Opcode>(&preprocessed_file)?;
+			let instructions = ctx.create_ir_scope(&parsed_syntax, None)?.build_ir(false);
+			let bf_code = ctx.ir_to_bf(instructions, None)?;
+			Ok(match ctx.config.optimise_generated_code {
+				true => ctx.optimise_bf(bf_code),
+				false => bf_code,
+			}
+			.to_string())
 		}
-		.to_string())
-	} else {
-		let parsed_syntax = parse_program::<TapeCell, Opcode>(&stripped_file)?;
-		let instructions = ctx.create_ir_scope(&parsed_syntax, None)?.build_ir(false);
-		let bf_code = ctx.ir_to_bf(instructions, None)?;
-		Ok(match ctx.config.optimise_generated_code {
-			true => ctx.optimise_bf(bf_code),
-			false => bf_code,
+	};
+
+	let result = compile();
+
+	// warnings in included files (e.g. the standard library) are not shown
+	let mut diagnostics = ctx.take_warnings();
+	diagnostics.retain_warnings_in(entry_file_name, &preprocessed_file, &source_map);
+	match result {
+		Ok(code) => Ok(CompileOutput {
+			code,
+			warnings: diagnostics.render(&preprocessed_file, &source_map),
+		}),
+		Err(err) => {
+			// show warnings alongside the errors
+			diagnostics.0.extend(err.0);
+			Err(diagnostics.render(&preprocessed_file, &source_map))
 		}
-		.to_string())
 	}
 }
 
+/// parse a program without compiling it, returning the syntax tree as JSON
+#[wasm_bindgen]
+pub fn wasm_parse(
+	file_contents: JsValue,
+	entry_file_name: String,
+	enable_2d_grid: bool,
+) -> Result<String, String> {
+	set_panic_hook();
+
+	let file_contents: HashMap<String, String> =
+		serde_wasm_bindgen::from_value(file_contents).unwrap();
+
+	let sources = LayeredProvider::with_std_library(MemoryProvider {
+		files: file_contents,
+	});
+	let (preprocessed_file, source_map) =
+		preprocess(&sources, &entry_file_name).map_err(|err| err.render())?;
+	match enable_2d_grid {
+		true => parse_program::<TapeCell2D, Opcode2D>(&preprocessed_file)
+			.map(|clauses| ast_to_json(&clauses)),
+		false => parse_program::<TapeCell, Opcode>(&preprocessed_file)
+			.map(|clauses| ast_to_json(&clauses)),
+	}
+	.map_err(|err| err.render(&preprocessed_file, &source_map))
+}
+
 #[wasm_bindgen]
 pub async fn wasm_run_bf(
 	code: String,
@@ -84,7 +153,7 @@ pub async fn wasm_run_bf(
 	let ctx = BrainfuckContext {
 		config: BrainfuckConfig {
 			enable_debug_symbols: false,
-			enable_2d_grid: enable_2d_grid,
+			enable_2d_grid,
 		},
 	};
 
@@ -94,3 +163,53 @@ pub async fn wasm_run_bf(
 
 	Ok(r)
 }
+
+#[cfg(test)]
+mod wasm_tests {
+	use std::collections::HashMap;
+
+	use super::compile_files;
+	use crate::misc::{MastermindConfig, MastermindContext};
+
+	fn files(files: &[(&str, &str)]) -> HashMap<String, String> {
+		files
+			.iter()
+			.map(|(name, contents)| (name.to_string(), contents.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn compile_files_1() {
+		// warnings are returned with the compiled code, except those in included files
+		let ctx = MastermindContext::new(MastermindConfig::default());
+		let output = compile_files(
+			&ctx,
+			files(&[
+				(
+					"main.mmi",
+					"#include \"lib.mmi\"\ncell a = 1;\noutput 'x';\n",
+				),
+				("lib.mmi", "cell b = 2;\n"),
+			]),
+			"main.mmi",
+		)
+		.unwrap();
+		assert!(output.code.contains('.'));
+		assert_eq!(
+			output.warnings,
+			"warning: Variable \"a\" is never read.
+ --> main.mmi:2:1
+  |
+2 | cell a = 1;
+  | ^^^^^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn compile_files_2() {
+		let ctx = MastermindContext::new(MastermindConfig::default());
+		let output =
+			compile_files(&ctx, files(&[("main.mmi", "output 'x';\n")]), "main.mmi").unwrap();
+		assert_eq!(output.warnings, "");
+	}
+}
@@ -0,0 +1,481 @@
+// answers questions about a program's source code for the language server, such as where a name is defined,
+// offsets here are byte offsets into the preprocessed program, the server converts them to and from editor positions
+
+use crate::{
+	backend::common::{
+		BrainfuckBuilder, BrainfuckBuilderData, CellAllocator, CellAllocatorData, OpcodeVariant,
+		TapeCellVariant,
+	},
+	diagnostics::{floor_char_boundary, Diagnostics, SourceMap, Span, Spanned},
+	frontend::{frontend::ScopeBuilder, types::ValueType},
+	misc::{MastermindConfig, MastermindContext},
+	parser::{
+		parser::{parse_program_partial, parse_var_target},
+		tokens::SourceCursor,
+		types::{
+			Clause, ExtendedOpcode, StructFieldTypeDefinition, VariableTypeDefinition,
+			VariableTypeReference,
+		},
+	},
+	preprocessor::preprocess,
+	sources::SourceProvider,
+};
+
+/// A program which has been preprocessed, parsed and compiled, kept for answering questions about its source code
+pub struct Analysis<TC, OC> {
+	/// the preprocessed program, or the file with the error if preprocessing failed
+	pub program: String,
+	pub source_map: SourceMap,
+	/// as much of the program as could be parsed, clauses with syntax errors are left empty
+	pub clauses: Vec<Spanned<Clause<TC, OC>>>,
+	/// errors and warnings in the program, including those in included files
+	pub diagnostics: Diagnostics,
+}
+
+/// Something defined in a program which can be referred to by name
+#[derive(Debug, Clone)]
+pub struct Symbol<TC> {
+	pub name: String,
+	pub kind: SymbolKind<TC>,
+	/// where the name is written in its definition
+	pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum SymbolKind<TC> {
+	Variable(VariableTypeDefinition<TC>),
+	Struct(Vec<StructFieldTypeDefinition>),
+	Function {
+		arguments: Vec<VariableTypeDefinition<TC>>,
+		return_type: Option<VariableTypeReference>,
+	},
+}
+
+impl<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant> Analysis<TC, OC>
+where
+	BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+	CellAllocatorData<TC>: CellAllocator<TC>,
+{
+	/// analyse a file and everything it includes,
+	/// the program is only compiled if it has no syntax errors, like with the command-line compiler
+	pub fn new(
+		sources: &dyn SourceProvider,
+		entry_file_name: &str,
+		config: MastermindConfig,
+	) -> Analysis<TC, OC> {
+		let (program, source_map) = match preprocess(sources, entry_file_name) {
+			Ok(preprocessed) => preprocessed,
+			// the error points into the file it was found in, so that file stands in for the program
+			Err(err) => {
+				return Analysis {
+					program: err.source,
+					source_map: err.source_map,
+					clauses: vec![],
+					diagnostics: err.diagnostics,
+				}
+			}
+		};
+
+		let (clauses, mut diagnostics) = parse_program_partial(&program);
+		if diagnostics.is_empty() {
+			let ctx = MastermindContext::new(config);
+			let result = ctx.create_ir_scope(&clauses, None).and_then(|scope| {
+				ctx.ir_to_bf(scope.build_ir(false), None)
+					.map_err(Diagnostics::from)
+			});
+			if let Err(errors) = result {
+				diagnostics = errors;
+			}
+			diagnostics.0.extend(ctx.take_warnings().0);
+			diagnostics = diagnostics.sorted();
+		}
+
+		Analysis {
+			program,
+			source_map,
+			clauses,
+			diagnostics,
+		}
+	}
+
+	/// the offset of a (0-based) line and UTF-16 column in an original file,
+	/// None if the line isn't part of the preprocessed program
+	pub fn offset_at(&self, file_name: &str, line: usize, character: usize) -> Option<usize> {
+		let line_index = self.source_map.find_line(file_name, line + 1)?;
+		let line_start: usize = self
+			.program
+			.split_inclusive('\n')
+			.take(line_index)
+			.map(str::len)
+			.sum();
+		let line_text = self.program[line_start..]
+			.lines()
+			.next()
+			.unwrap_or_default();
+
+		let mut utf16_column = 0;
+		for (i, c) in line_text.char_indices() {
+			if utf16_column >= character {
+				return Some(line_start + i);
+			}
+			utf16_column += c.len_utf16();
+		}
+		Some(line_start + line_text.len())
+	}
+
+	/// the original file, (0-based) line and UTF-16 column of an offset
+	pub fn position_of(&self, offset: usize) -> (&str, usize, usize) {
+		let offset = floor_char_boundary(&self.program, offset.min(self.program.len()));
+		let line_index = self.program[..offset].matches('\n').count();
+		let line_start = self.program[..offset].rfind('\n').map_or(0, |i| i + 1);
+		let (file_name, line_number) = self.source_map.locate(line_index);
+		let character = self.program[line_start..offset].encode_utf16().count();
+		(file_name, line_number - 1, character)
+	}
+
+	/// the symbols visible at an offset, inner definitions after outer ones
+	pub fn visible_symbols(&self, offset: usize) -> Vec<Symbol<TC>> {
+		let mut symbols = vec![];
+		self.collect_symbols(&self.clauses, offset, &mut symbols);
+		symbols
+	}
+
+	fn collect_symbols(
+		&self,
+		block: &[Spanned<Clause<TC, OC>>],
+		offset: usize,
+		symbols: &mut Vec<Symbol<TC>>,
+	) {
+		// structs and functions can be used anywhere in the block they are defined in
+		for clause in block {
+			match &clause.node {
+				Clause::DefineStruct { name, fields } => symbols.push(Symbol {
+					name: name.clone(),
+					kind: SymbolKind::Struct(fields.clone()),
+					span: self.name_span(clause.span, name),
+				}),
+				Clause::DefineFunction {
+					name,
+					arguments,
+					return_type,
+					block: _,
+				} => symbols.push(Symbol {
+					name: name.clone(),
+					kind: SymbolKind::Function {
+						arguments: arguments.clone(),
+						return_type: return_type.clone(),
+					},
+					span: self.name_span(clause.span, name),
+				}),
+				_ => (),
+			}
+		}
+
+		for clause in block {
+			if clause.span.start > offset {
+				break;
+			}
+			if let Clause::DeclareVariable { var } | Clause::DefineVariable { var, value: _ } =
+				&clause.node
+			{
+				symbols.push(Symbol {
+					name: var.name.clone(),
+					kind: SymbolKind::Variable(var.clone()),
+					span: self.name_span(clause.span, &var.name),
+				});
+			}
+			if offset >= clause.span.end {
+				continue;
+			}
+
+			// the offset is in this clause, carry on into whichever of its blocks it is in
+			let (inner_blocks, hides_variables) = inner_blocks(&clause.node);
+			let Some(inner_block) = inner_blocks
+				.into_iter()
+				.filter(|inner_block| {
+					inner_block
+						.first()
+						.is_some_and(|first| first.span.start <= offset)
+				})
+				.last()
+			else {
+				return;
+			};
+			if hides_variables {
+				symbols.retain(|symbol| !matches!(symbol.kind, SymbolKind::Variable(_)));
+			}
+			if let Clause::DefineFunction {
+				name: _,
+				arguments,
+				return_type: _,
+				block: _,
+			} = &clause.node
+			{
+				// arguments are written after the function name
+				let arguments_start = self.program[clause.span.start..clause.span.end]
+					.find('(')
+					.map_or(clause.span.start, |i| clause.span.start + i);
+				for argument in arguments {
+					symbols.push(Symbol {
+						name: argument.name.clone(),
+						kind: SymbolKind::Variable(argument.clone()),
+						span: self
+							.name_span(Span::new(arguments_start, clause.span.end), &argument.name),
+					});
+				}
+			}
+			self.collect_symbols(inner_block, offset, symbols);
+			return;
+		}
+	}
+
+	/// the first place a name is written as a whole word within a span, or the whole span if it isn't found
+	fn name_span(&self, span: Span, name: &str) -> Span {
+		let text = &self.program[span.start..span.end.min(self.program.len())];
+		text.match_indices(name)
+			.find(|(i, _)| {
+				let before = text[..*i].chars().next_back();
+				let after = text[i + name.len()..].chars().next();
+				!before.is_some_and(is_identifier_char) && !after.is_some_and(is_identifier_char)
+			})
+			.map_or(span, |(i, _)| {
+				Span::new(span.start + i, span.start + i + name.len())
+			})
+	}
+
+	/// the symbol referred to by the name at an offset
+	pub fn symbol_at(&self, offset: usize) -> Option<Symbol<TC>> {
+		let (name, span) = identifier_at(&self.program, offset)?;
+		let before = self.program[..span.start].trim_end();
+		let after = self.program[span.end..].trim_start();
+		if before.ends_with('.') {
+			// struct fields aren't symbols
+			return None;
+		}
+		let is_struct = before
+			.strip_suffix("struct")
+			.is_some_and(|rest| !rest.chars().next_back().is_some_and(is_identifier_char));
+		let is_function = after.starts_with('(');
+
+		self.visible_symbols(offset)
+			.into_iter()
+			.rev()
+			.find(|symbol| {
+				symbol.name == name
+					&& match symbol.kind {
+						SymbolKind::Variable(_) => !is_struct && !is_function,
+						SymbolKind::Struct(_) => is_struct,
+						SymbolKind::Function {
+							arguments: _,
+							return_type: _,
+						} => is_function,
+					}
+			})
+	}
+
+	/// where the symbol at an offset is defined
+	pub fn definition(&self, offset: usize) -> Option<Span> {
+		Some(self.symbol_at(offset)?.span)
+	}
+
+	/// a description of the symbol or struct field at an offset in markdown, with the type and size of variables
+	pub fn hover(&self, offset: usize) -> Option<String> {
+		let (name, span) = identifier_at(&self.program, offset)?;
+		if let Some(dot) = self.program[..span.start].strip_suffix('.').map(str::len) {
+			let target = target_before(&self.program, dot)?;
+			let field_type = self.target_type(&format!("{target}.{name}"), offset)?;
+			return Some(describe(&format!("{field_type} {name}"), Ok(field_type)));
+		}
+
+		let symbol = self.symbol_at(offset)?;
+		let scope = self.type_scope(offset);
+		Some(match &symbol.kind {
+			SymbolKind::Variable(var) => {
+				describe(&var.to_string(), scope.create_absolute_type(&var.var_type))
+			}
+			SymbolKind::Struct(_) => describe(
+				&format!("struct {}", symbol.name),
+				scope.create_absolute_type(&VariableTypeReference::Struct(symbol.name.clone())),
+			),
+			SymbolKind::Function {
+				arguments,
+				return_type,
+			} => {
+				let arguments = arguments
+					.iter()
+					.map(|argument| argument.to_string())
+					.collect::<Vec<_>>()
+					.join(", ");
+				let return_type = return_type
+					.as_ref()
+					.map(|return_type| format!(" -> {return_type}"))
+					.unwrap_or_default();
+				format!(
+					"```mastermind\nfn {}({arguments}){return_type}\n```",
+					symbol.name
+				)
+			}
+		})
+	}
+
+	/// the fields of the struct before a `.` at an offset, for completing the field name being typed
+	pub fn field_completions(&self, offset: usize) -> Vec<(String, ValueType)> {
+		let offset = floor_char_boundary(&self.program, offset.min(self.program.len()));
+		// skip back over the part of the field name already typed
+		let word_start = self.program[..offset]
+			.char_indices()
+			.rev()
+			.take_while(|(_, c)| is_identifier_char(*c))
+			.last()
+			.map_or(offset, |(i, _)| i);
+		let Some(dot) = self.program[..word_start].strip_suffix('.').map(str::len) else {
+			return vec![];
+		};
+		let Some(ValueType::DictStruct(fields)) =
+			target_before(&self.program, dot).and_then(|target| self.target_type(target, offset))
+		else {
+			return vec![];
+		};
+		fields
+			.into_iter()
+			.map(|(name, field_type, _)| (name, field_type))
+			.collect()
+	}
+
+	/// the type of a variable target written out in the source code, e.g. `a.b[2]`, as seen from an offset
+	fn target_type(&self, target: &str, offset: usize) -> Option<ValueType> {
+		let chars: Vec<char> = target.chars().collect();
+		let target = parse_var_target(&mut SourceCursor::new(&chars)).ok()?;
+		let var_type = self
+			.visible_symbols(offset)
+			.into_iter()
+			.rev()
+			.find_map(|symbol| match symbol.kind {
+				SymbolKind::Variable(var) if var.name == target.name => Some(var.var_type),
+				_ => None,
+			})?;
+		let value_type = self
+			.type_scope(offset)
+			.create_absolute_type(&var_type)
+			.ok()?;
+		match &target.subfields {
+			Some(subfields) => Some(value_type.get_subfield(subfields).ok()?.0.clone()),
+			None => Some(value_type),
+		}
+	}
+
+	/// a scope with the structs visible at an offset defined, for finding the types of variables
+	fn type_scope(&self, offset: usize) -> ScopeBuilder<'static, TC, OC> {
+		let mut scope = ScopeBuilder::new();
+		for symbol in self.visible_symbols(offset) {
+			if let SymbolKind::Struct(fields) = symbol.kind {
+				// structs with errors are left out, inner structs replace outer ones with the same name
+				let _ = scope.register_struct_definition(&symbol.name, fields);
+			}
+		}
+		scope
+	}
+}
+
+type Block<TC, OC> = [Spanned<Clause<TC, OC>>];
+
+/// the blocks of clauses inside a clause in source order,
+/// and whether variables from outside are hidden within them (function bodies and embedded Mastermind)
+fn inner_blocks<TC, OC>(clause: &Clause<TC, OC>) -> (Vec<&Block<TC, OC>>, bool) {
+	match clause {
+		Clause::Block(block)
+		| Clause::While {
+			condition: _,
+			block,
+		}
+		| Clause::If {
+			condition: _,
+			if_block: block,
+		}
+		| Clause::IfNot {
+			condition: _,
+			if_not_block: block,
+		}
+		| Clause::DrainLoop {
+			source: _,
+			targets: _,
+			block: Some(block),
+			is_copying: _,
+		} => (vec![block], false),
+		Clause::IfElse {
+			condition: _,
+			if_block: block,
+			else_block,
+		}
+		| Clause::IfNotElse {
+			condition: _,
+			if_not_block: block,
+			else_block,
+		} => (vec![block, else_block], false),
+		Clause::DefineFunction {
+			name: _,
+			arguments: _,
+			return_type: _,
+			block,
+		} => (vec![block], true),
+		Clause::Brainfuck {
+			location_specifier: _,
+			clobbered_variables: _,
+			operations,
+		} => (
+			operations
+				.iter()
+				.filter_map(|operation| match operation {
+					ExtendedOpcode::Block(block) => Some(block.as_slice()),
+					ExtendedOpcode::Opcode(_) => None,
+				})
+				.collect(),
+			true,
+		),
+		_ => (vec![], false),
+	}
+}
+
+fn is_identifier_char(c: char) -> bool {
+	c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// the identifier at an offset (or ending at it), along with its span
+fn identifier_at(program: &str, offset: usize) -> Option<(&str, Span)> {
+	let offset = floor_char_boundary(program, offset.min(program.len()));
+	let start = program[..offset]
+		.char_indices()
+		.rev()
+		.take_while(|(_, c)| is_identifier_char(*c))
+		.last()
+		.map_or(offset, |(i, _)| i);
+	let end = program[offset..]
+		.find(|c| !is_identifier_char(c))
+		.map_or(program.len(), |i| offset + i);
+	let name = &program[start..end];
+	match name.chars().next() {
+		Some(c) if !c.is_ascii_digit() => Some((name, Span::new(start, end))),
+		_ => None,
+	}
+}
+
+/// the variable target written just before an offset, e.g. `a.b[2]` if the offset is at the last `.` in `a.b[2].c`
+fn target_before(program: &str, offset: usize) -> Option<&str> {
+	let start = program[..offset]
+		.char_indices()
+		.rev()
+		.take_while(|(_, c)| is_identifier_char(*c) || matches!(c, '.' | '[' | ']'))
+		.last()
+		.map(|(i, _)| i)?;
+	Some(&program[start..offset])
+}
+
+/// markdown for a definition, along with its type and size in cells if it has one
+fn describe(definition: &str, value_type: Result<ValueType, String>) -> String {
+	let description = format!("```mastermind\n{definition}\n```");
+	match value_type.and_then(|value_type| Ok((value_type.size()?, value_type))) {
+		Ok((1, value_type)) => format!("{description}\n\n`{value_type}`, 1 cell"),
+		Ok((size, value_type)) => format!("{description}\n\n`{value_type}`, {size} cells"),
+		Err(_) => description,
+	}
+}
@@ -0,0 +1,442 @@
+// the language server protocol over stdio, editors start `mastermind-lsp` and send it the documents being edited
+
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+	notification::{
+		DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, DidSaveTextDocument,
+		Notification as _, PublishDiagnostics,
+	},
+	request::{Completion, GotoDefinition, HoverRequest, Request as _},
+	CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+	DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+	DidOpenTextDocumentParams, GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents,
+	HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+	PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability,
+	TextDocumentSyncKind, Url,
+};
+
+use super::analysis::Analysis;
+use crate::{
+	backend::{
+		bf::{Opcode, TapeCell},
+		bf2d::{Opcode2D, TapeCell2D},
+		common::{
+			BrainfuckBuilder, BrainfuckBuilderData, CellAllocator, CellAllocatorData,
+			OpcodeVariant, TapeCellVariant,
+		},
+	},
+	diagnostics::{Severity, Span},
+	misc::MastermindConfig,
+	project::Project,
+	sources::{
+		include_search_path, FileSystemProvider, IncludeKind, LayeredProvider, SourceProvider,
+	},
+};
+
+/// run the language server until the editor shuts it down
+pub fn run() -> Result<(), String> {
+	let (connection, io_threads) = Connection::stdio();
+	let capabilities = ServerCapabilities {
+		text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+		definition_provider: Some(OneOf::Left(true)),
+		hover_provider: Some(HoverProviderCapability::Simple(true)),
+		completion_provider: Some(CompletionOptions {
+			trigger_characters: Some(vec![String::from(".")]),
+			..Default::default()
+		}),
+		..Default::default()
+	};
+	connection
+		.initialize(serde_json::to_value(capabilities).map_err(|err| err.to_string())?)
+		.map_err(|err| err.to_string())?;
+
+	let server = Server {
+		connection,
+		documents: HashMap::new(),
+	};
+	// this closes the connection when it finishes, so that the threads reading and writing stdio can finish
+	server.main_loop()?;
+	io_threads.join().map_err(|err| err.to_string())
+}
+
+struct Server {
+	connection: Connection,
+	/// the contents of documents open in the editor, by canonical path
+	documents: HashMap<String, String>,
+}
+
+/// How a document is compiled, from the project file found for it
+struct Workspace {
+	config: MastermindConfig,
+	include_dirs: Vec<PathBuf>,
+	/// the project's entry file, documents included by it are analysed as part of the whole project
+	entry: Option<String>,
+}
+
+impl Server {
+	fn main_loop(mut self) -> Result<(), String> {
+		while let Ok(message) = self.connection.receiver.recv() {
+			match message {
+				Message::Request(request) => {
+					if self
+						.connection
+						.handle_shutdown(&request)
+						.map_err(|err| err.to_string())?
+					{
+						return Ok(());
+					}
+					let response = self.handle_request(request);
+					self.send(Message::Response(response));
+				}
+				Message::Notification(notification) => self.handle_notification(notification),
+				Message::Response(_) => (),
+			}
+		}
+		Ok(())
+	}
+
+	fn send(&self, message: Message) {
+		// the editor has gone away if this fails, the main loop will end when the connection closes
+		let _ = self.connection.sender.send(message);
+	}
+
+	fn handle_notification(&mut self, notification: Notification) {
+		match notification.method.as_str() {
+			DidOpenTextDocument::METHOD => {
+				let Ok(params) =
+					serde_json::from_value::<DidOpenTextDocumentParams>(notification.params)
+				else {
+					return;
+				};
+				if let Some(file_name) = file_name(&params.text_document.uri) {
+					self.documents.insert(file_name, params.text_document.text);
+				}
+			}
+			DidChangeTextDocument::METHOD => {
+				let Ok(params) =
+					serde_json::from_value::<DidChangeTextDocumentParams>(notification.params)
+				else {
+					return;
+				};
+				// documents are synced in full, so the last change has the whole text
+				if let (Some(file_name), Some(change)) = (
+					file_name(&params.text_document.uri),
+					params.content_changes.into_iter().last(),
+				) {
+					self.documents.insert(file_name, change.text);
+				}
+			}
+			DidCloseTextDocument::METHOD => {
+				let Ok(params) =
+					serde_json::from_value::<DidCloseTextDocumentParams>(notification.params)
+				else {
+					return;
+				};
+				if let Some(file_name) = file_name(&params.text_document.uri) {
+					self.documents.remove(&file_name);
+				}
+				self.publish_diagnostics(params.text_document.uri, vec![]);
+			}
+			DidSaveTextDocument::METHOD => (),
+			_ => return,
+		}
+
+		// any open document could include the one which changed, so they are all checked again
+		let file_names: Vec<String> = self.documents.keys().cloned().collect();
+		for file_name in file_names {
+			let Ok(uri) = Url::from_file_path(&file_name) else {
+				continue;
+			};
+			let workspace = self.workspace(&file_name);
+			let diagnostics = match workspace.config.enable_2d_grid {
+				true => self.diagnostics::<TapeCell2D, Opcode2D>(&file_name, &workspace),
+				false => self.diagnostics::<TapeCell, Opcode>(&file_name, &workspace),
+			};
+			self.publish_diagnostics(uri, diagnostics);
+		}
+	}
+
+	fn publish_diagnostics(&self, uri: Url, diagnostics: Vec<lsp_types::Diagnostic>) {
+		let params = PublishDiagnosticsParams {
+			uri,
+			diagnostics,
+			version: None,
+		};
+		self.send(Message::Notification(Notification::new(
+			String::from(PublishDiagnostics::METHOD),
+			params,
+		)));
+	}
+
+	fn handle_request(&self, request: Request) -> Response {
+		if ![
+			GotoDefinition::METHOD,
+			HoverRequest::METHOD,
+			Completion::METHOD,
+		]
+		.contains(&request.method.as_str())
+		{
+			return Response::new_err(
+				request.id,
+				ErrorCode::MethodNotFound as i32,
+				format!("Unsupported request \"{}\"", request.method),
+			);
+		}
+
+		// every supported request is about a position in a document
+		let Some(file_name) = request.params["textDocument"]["uri"]
+			.as_str()
+			.and_then(|uri| Url::parse(uri).ok())
+			.and_then(|uri| file_name(&uri))
+		else {
+			return Response::new_err(
+				request.id,
+				ErrorCode::InvalidParams as i32,
+				String::from("Expected a file URI"),
+			);
+		};
+		let workspace = self.workspace(&file_name);
+		let result = match workspace.config.enable_2d_grid {
+			true => self.respond::<TapeCell2D, Opcode2D>(&request, &file_name, &workspace),
+			false => self.respond::<TapeCell, Opcode>(&request, &file_name, &workspace),
+		};
+		match result {
+			Ok(value) => Response::new_ok(request.id, value),
+			Err(err) => Response::new_err(request.id, ErrorCode::InvalidParams as i32, err),
+		}
+	}
+
+	fn respond<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		request: &Request,
+		file_name: &str,
+		workspace: &Workspace,
+	) -> Result<serde_json::Value, String>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		let analysis = self.analyse::<TC, OC>(file_name, workspace);
+		let offset_at = |position: Position| {
+			analysis.offset_at(
+				file_name,
+				position.line as usize,
+				position.character as usize,
+			)
+		};
+		let params = request.params.clone();
+
+		let result = match request.method.as_str() {
+			GotoDefinition::METHOD => {
+				let params: GotoDefinitionParams =
+					serde_json::from_value(params).map_err(|err| err.to_string())?;
+				offset_at(params.text_document_position_params.position)
+					.and_then(|offset| analysis.definition(offset))
+					.and_then(|span| location(&analysis, span))
+					.map(GotoDefinitionResponse::Scalar)
+					.map(serde_json::to_value)
+			}
+			HoverRequest::METHOD => {
+				let params: HoverParams =
+					serde_json::from_value(params).map_err(|err| err.to_string())?;
+				offset_at(params.text_document_position_params.position)
+					.and_then(|offset| analysis.hover(offset))
+					.map(|description| Hover {
+						contents: HoverContents::Markup(MarkupContent {
+							kind: MarkupKind::Markdown,
+							value: description,
+						}),
+						range: None,
+					})
+					.map(serde_json::to_value)
+			}
+			Completion::METHOD => {
+				let params: CompletionParams =
+					serde_json::from_value(params).map_err(|err| err.to_string())?;
+				let fields = offset_at(params.text_document_position.position)
+					.map(|offset| analysis.field_completions(offset))
+					.unwrap_or_default();
+				let items = fields
+					.into_iter()
+					.map(|(name, field_type)| CompletionItem {
+						label: name,
+						kind: Some(CompletionItemKind::FIELD),
+						detail: Some(field_type.to_string()),
+						..Default::default()
+					})
+					.collect();
+				Some(serde_json::to_value(CompletionResponse::Array(items)))
+			}
+			_ => unreachable!(),
+		};
+		match result {
+			Some(value) => value.map_err(|err| err.to_string()),
+			None => Ok(serde_json::Value::Null),
+		}
+	}
+
+	/// the errors and warnings in a document
+	fn diagnostics<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		file_name: &str,
+		workspace: &Workspace,
+	) -> Vec<lsp_types::Diagnostic>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		let analysis = self.analyse::<TC, OC>(file_name, workspace);
+		analysis
+			.diagnostics
+			.0
+			.iter()
+			.filter_map(|diagnostic| {
+				// errors that can't be placed in the code, such as a missing entry file, are shown at the top of the file
+				let range = match diagnostic.span {
+					Some(span) => {
+						let (span_file_name, line, character) = analysis.position_of(span.start);
+						if span_file_name != file_name {
+							return None;
+						}
+						let start = Position::new(line as u32, character as u32);
+						let (_, end_line, end_character) = analysis.position_of(span.end);
+						Range::new(start, Position::new(end_line as u32, end_character as u32))
+					}
+					None => Range::default(),
+				};
+				Some(lsp_types::Diagnostic {
+					range,
+					severity: Some(match diagnostic.severity {
+						Severity::Error => DiagnosticSeverity::ERROR,
+						Severity::Warning => DiagnosticSeverity::WARNING,
+					}),
+					source: Some(String::from("mastermind")),
+					message: diagnostic.message.clone(),
+					..Default::default()
+				})
+			})
+			.collect()
+	}
+
+	/// analyse a document, as part of its project if the project's entry file includes it
+	fn analyse<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+		&self,
+		file_name: &str,
+		workspace: &Workspace,
+	) -> Analysis<TC, OC>
+	where
+		BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+		CellAllocatorData<TC>: CellAllocator<TC>,
+	{
+		let sources = LayeredProvider::with_std_library(DocumentProvider {
+			documents: self.documents.clone(),
+			files: FileSystemProvider {
+				include_dirs: workspace.include_dirs.clone(),
+			},
+		});
+		if let Some(entry) = workspace
+			.entry
+			.as_deref()
+			.filter(|entry| *entry != file_name)
+		{
+			let analysis = Analysis::new(&sources, entry, workspace.config.clone());
+			if analysis.source_map.contains_file(file_name) {
+				return analysis;
+			}
+		}
+		Analysis::new(&sources, file_name, workspace.config.clone())
+	}
+
+	/// find the project settings for a document, invalid project files are ignored
+	fn workspace(&self, file_name: &str) -> Workspace {
+		let project = Path::new(file_name)
+			.parent()
+			.and_then(|directory| Project::discover(directory).ok().flatten())
+			// 2D-only allocation methods need `--2d` on the command line, which the language server doesn't have
+			.filter(|project| {
+				let config = &project.config.compiler;
+				config.enable_2d_grid || !config.memory_allocation_method.is_2d_only()
+			});
+		match project {
+			Some(project) => Workspace {
+				include_dirs: include_search_path(project.include_dirs()),
+				entry: project.entry().map(|entry| canonical_name(&entry)),
+				config: project.config.compiler,
+			},
+			None => Workspace {
+				config: MastermindConfig::default(),
+				include_dirs: include_search_path(vec![]),
+				entry: None,
+			},
+		}
+	}
+}
+
+/// Documents open in the editor (which may have unsaved changes) over the files on disk,
+/// files are named by their canonical paths so that they match the names of open documents
+struct DocumentProvider {
+	documents: HashMap<String, String>,
+	files: FileSystemProvider,
+}
+
+impl SourceProvider for DocumentProvider {
+	fn resolve(
+		&self,
+		including_file: &str,
+		include_path: &str,
+		kind: IncludeKind,
+	) -> Option<String> {
+		self.files
+			.resolve(including_file, include_path, kind)
+			.map(|file_name| canonical_name(Path::new(&file_name)))
+	}
+
+	fn contains(&self, file_name: &str) -> bool {
+		self.documents.contains_key(file_name) || self.files.contains(file_name)
+	}
+
+	fn load(&self, file_name: &str) -> Result<(String, String), String> {
+		match self.documents.get(file_name) {
+			Some(contents) => Ok((file_name.to_owned(), contents.clone())),
+			None => self.files.load(file_name),
+		}
+	}
+}
+
+/// the canonical path of a file, or the path as given if it doesn't exist on disk
+fn canonical_name(path: &Path) -> String {
+	std::fs::canonicalize(path)
+		.unwrap_or_else(|_| path.to_path_buf())
+		.to_string_lossy()
+		.into_owned()
+}
+
+/// the file name used for a document, None if it isn't a file on disk
+fn file_name(uri: &Url) -> Option<String> {
+	Some(canonical_name(&uri.to_file_path().ok()?))
+}
+
+/// the editor location of a span, None if it is in a file that can't be opened, such as the standard library
+fn location<TC, OC>(analysis: &Analysis<TC, OC>, span: Span) -> Option<Location>
+where
+	TC: 'static + TapeCellVariant,
+	OC: 'static + OpcodeVariant,
+	BrainfuckBuilderData<TC, OC>: BrainfuckBuilder<TC, OC>,
+	CellAllocatorData<TC>: CellAllocator<TC>,
+{
+	let (file_name, line, character) = analysis.position_of(span.start);
+	let (_, end_line, end_character) = analysis.position_of(span.end);
+	let uri = Url::from_file_path(file_name).ok()?;
+	Some(Location::new(
+		uri,
+		Range::new(
+			Position::new(line as u32, character as u32),
+			Position::new(end_line as u32, end_character as u32),
+		),
+	))
+}
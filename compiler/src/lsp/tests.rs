@@ -0,0 +1,254 @@
+#[cfg(test)]
+mod lsp_tests {
+	use std::collections::HashMap;
+
+	use super::super::analysis::Analysis;
+	use crate::{
+		backend::bf::{Opcode, TapeCell},
+		misc::MastermindConfig,
+		sources::{LayeredProvider, MemoryProvider},
+	};
+
+	fn analyse(files: &[(&str, &str)]) -> Analysis<TapeCell, Opcode> {
+		let sources = LayeredProvider::with_std_library(MemoryProvider {
+			files: files
+				.iter()
+				.map(|(name, contents)| (name.to_string(), contents.to_string()))
+				.collect::<HashMap<_, _>>(),
+		});
+		Analysis::new(&sources, files[0].0, MastermindConfig::default())
+	}
+
+	/// the offset of the nth occurrence of some text in a file, like placing the editor cursor at its start
+	fn offset_of(analysis: &Analysis<TapeCell, Opcode>, text: &str, n: usize) -> usize {
+		analysis.program.match_indices(text).nth(n).unwrap().0
+	}
+
+	/// where the symbol at an offset is defined, as (file name, line, column)
+	fn definition_at(
+		analysis: &Analysis<TapeCell, Opcode>,
+		offset: usize,
+	) -> Option<(String, usize, usize)> {
+		let span = analysis.definition(offset)?;
+		let (file_name, line, character) = analysis.position_of(span.start);
+		Some((file_name.to_owned(), line, character))
+	}
+
+	#[test]
+	fn positions_1() {
+		let analysis = analyse(&[
+			("main.mmi", "#include \"lib.mmi\"\ncell a = 1;\noutput a;\n"),
+			("lib.mmi", "// λ\ncell b = 2;\noutput b;\n"),
+		]);
+		let offset = analysis.offset_at("main.mmi", 2, 7).unwrap();
+		assert_eq!(&analysis.program[offset..offset + 2], "a;");
+		assert_eq!(analysis.position_of(offset), ("main.mmi", 2, 7));
+
+		// columns are in UTF-16 code units
+		let offset = analysis.offset_at("lib.mmi", 0, 3).unwrap();
+		assert_eq!(&analysis.program[offset..offset + 2], "λ");
+		assert_eq!(analysis.position_of(offset), ("lib.mmi", 0, 3));
+
+		// directives aren't part of the preprocessed program
+		assert_eq!(analysis.offset_at("main.mmi", 0, 0), None);
+	}
+
+	#[test]
+	fn definition_1() {
+		let analysis = analyse(&[(
+			"main.mmi",
+			"struct P { cell x; }
+fn f(struct P p, cell c) {
+	output c;
+}
+cell c = 5;
+struct P q;
+f(q, c);
+{
+	cell c = 6;
+	output c;
+}
+",
+		)]);
+		// the argument inside the function
+		let offset = offset_of(&analysis, "c;", 0);
+		assert_eq!(
+			definition_at(&analysis, offset),
+			Some((String::from("main.mmi"), 1, 22))
+		);
+		// the outer variable
+		let offset = offset_of(&analysis, "c);", 0);
+		assert_eq!(
+			definition_at(&analysis, offset),
+			Some((String::from("main.mmi"), 4, 5))
+		);
+		// the inner variable shadows the outer one
+		let offset = offset_of(&analysis, "c;", 1);
+		assert_eq!(
+			definition_at(&analysis, offset),
+			Some((String::from("main.mmi"), 8, 6))
+		);
+		// structs and functions
+		let offset = offset_of(&analysis, "P q", 0);
+		assert_eq!(
+			definition_at(&analysis, offset),
+			Some((String::from("main.mmi"), 0, 7))
+		);
+		let offset = offset_of(&analysis, "f(q", 0);
+		assert_eq!(
+			definition_at(&analysis, offset),
+			Some((String::from("main.mmi"), 1, 3))
+		);
+	}
+
+	#[test]
+	fn definition_2() {
+		// definitions in included files
+		let analysis = analyse(&[
+			(
+				"main.mmi",
+				"#include \"lib.mmi\"\nstruct Pair p;\nswap(p);\n",
+			),
+			(
+				"lib.mmi",
+				"struct Pair { cell a; cell b; }\nfn swap(struct Pair p) {\n\tp.a += p.b;\n}\n",
+			),
+		]);
+		let offset = offset_of(&analysis, "Pair p;", 0);
+		assert_eq!(
+			definition_at(&analysis, offset),
+			Some((String::from("lib.mmi"), 0, 7))
+		);
+		let offset = offset_of(&analysis, "swap(p)", 0);
+		assert_eq!(
+			definition_at(&analysis, offset),
+			Some((String::from("lib.mmi"), 1, 3))
+		);
+	}
+
+	#[test]
+	fn definition_3() {
+		// outer variables can't be used in functions
+		let analysis = analyse(&[(
+			"main.mmi",
+			"cell a = 1;\nfn f() {\n\toutput a;\n}\noutput a;\n",
+		)]);
+		assert_eq!(
+			definition_at(&analysis, offset_of(&analysis, "a;", 0)),
+			None
+		);
+		assert_eq!(
+			definition_at(&analysis, offset_of(&analysis, "a;", 1)),
+			Some((String::from("main.mmi"), 0, 5))
+		);
+	}
+
+	#[test]
+	fn hover_1() {
+		let analysis = analyse(&[(
+			"main.mmi",
+			"struct P { cell x; cell[3] y; }
+struct P p;
+cell[2] a;
+cell b @4;
+fn f(struct P p, cell c) -> cell {
+	return c;
+}
+output p.y[1] + a[0] + b + f(p, b);
+",
+		)]);
+		assert_eq!(
+			analysis.hover(offset_of(&analysis, "p.y", 0)).unwrap(),
+			"```mastermind\nstruct P p\n```\n\n`{cell x; cell[3] y;}`, 4 cells"
+		);
+		assert_eq!(
+			analysis.hover(offset_of(&analysis, "a[0]", 0)).unwrap(),
+			"```mastermind\ncell[2] a\n```\n\n`cell[2]`, 2 cells"
+		);
+		assert_eq!(
+			analysis.hover(offset_of(&analysis, "b +", 0)).unwrap(),
+			"```mastermind\ncell b @4\n```\n\n`cell`, 1 cell"
+		);
+		assert_eq!(
+			analysis.hover(offset_of(&analysis, "y[1]", 0)).unwrap(),
+			"```mastermind\ncell[3] y\n```\n\n`cell[3]`, 3 cells"
+		);
+		assert_eq!(
+			analysis.hover(offset_of(&analysis, "P p;", 0)).unwrap(),
+			"```mastermind\nstruct P\n```\n\n`{cell x; cell[3] y;}`, 4 cells"
+		);
+		assert_eq!(
+			analysis.hover(offset_of(&analysis, "f(p", 0)).unwrap(),
+			"```mastermind\nfn f(struct P p, cell c) -> cell\n```"
+		);
+		assert_eq!(analysis.hover(offset_of(&analysis, "output", 0)), None);
+	}
+
+	#[test]
+	fn completion_1() {
+		// programs with syntax errors are still analysed
+		let analysis = analyse(&[(
+			"main.mmi",
+			"struct Inner { cell z; }
+struct Outer { struct Inner[2] inners; cell w; }
+struct Outer o;
+output o.;
+o.inners[1].z
+",
+		)]);
+		assert!(!analysis.diagnostics.is_empty());
+
+		let fields = analysis.field_completions(offset_of(&analysis, ";", 4));
+		assert_eq!(
+			fields
+				.iter()
+				.map(|(name, field_type)| format!("{field_type} {name}"))
+				.collect::<Vec<_>>(),
+			vec!["{cell z;}[2] inners", "cell w"]
+		);
+		let fields = analysis.field_completions(analysis.program.len() - 1);
+		assert_eq!(
+			fields
+				.iter()
+				.map(|(name, _)| name.as_str())
+				.collect::<Vec<_>>(),
+			vec!["z"]
+		);
+		assert!(analysis
+			.field_completions(offset_of(&analysis, "output", 0))
+			.is_empty());
+	}
+
+	#[test]
+	fn diagnostics_1() {
+		let analysis = analyse(&[
+			("main.mmi", "#include \"lib.mmi\"\ncell a = 1;\noutput b;\n"),
+			("lib.mmi", "fn f() {}\n"),
+		]);
+		let diagnostics: Vec<_> = analysis
+			.diagnostics
+			.0
+			.iter()
+			.map(|diagnostic| {
+				let (file_name, line, _) = analysis.position_of(diagnostic.span.unwrap().start);
+				format!("{file_name}:{line}: {}", diagnostic.message)
+			})
+			.collect();
+		assert_eq!(
+			diagnostics,
+			vec![
+				"lib.mmi:0: Function \"f\" is never called.",
+				"main.mmi:1: Variable \"a\" is never read.",
+				"main.mmi:2: No variable found in scope with name \"b\"."
+			]
+		);
+
+		let analysis = analyse(&[("main.mmi", "cell a;\n#include \"missing.mmi\"\n")]);
+		let diagnostic = &analysis.diagnostics.0[0];
+		assert_eq!(diagnostic.message, "No such file \"missing.mmi\"");
+		assert_eq!(
+			analysis.position_of(diagnostic.span.unwrap().start),
+			("main.mmi", 1, 0)
+		);
+	}
+}
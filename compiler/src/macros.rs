@@ -1,19 +1,38 @@
 #[macro_use]
+#[allow(clippy::module_inception)]
 pub mod macros {
+	// these work in functions returning either a String or a Diagnostic error
 	macro_rules! r_assert {
     ($cond:expr, $($args:tt)+) => {{
       if !$cond {
-        return Err(format!($($args)+))
+        return Err(format!($($args)+).into())
       }
     }};
   }
 
 	macro_rules! r_panic {
     ($($args:tt)+) => {{
-      return Err(format!($($args)+))
+      return Err(format!($($args)+).into())
+    }};
+  }
+
+	// same as above but attach a source code span to the error
+	macro_rules! r_assert_at {
+    ($span:expr, $cond:expr, $($args:tt)+) => {{
+      if !$cond {
+        return Err($crate::diagnostics::Diagnostic::new(format!($($args)+), $span))
+      }
+    }};
+  }
+
+	macro_rules! r_panic_at {
+    ($span:expr, $($args:tt)+) => {{
+      return Err($crate::diagnostics::Diagnostic::new(format!($($args)+), $span))
     }};
   }
 
 	pub(crate) use r_assert;
+	pub(crate) use r_assert_at;
 	pub(crate) use r_panic;
+	pub(crate) use r_panic_at;
 }
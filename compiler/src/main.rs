@@ -4,12 +4,14 @@
 // project dependencies:
 mod backend;
 mod brainfuck;
+mod diagnostics;
 mod frontend;
-#[macro_use]
 mod macros;
 mod misc;
 mod parser;
 mod preprocessor;
+mod project;
+mod sources;
 mod tests;
 use crate::{
 	backend::{
@@ -18,133 +20,646 @@ use crate::{
 		common::BrainfuckProgram,
 	},
 	brainfuck::{BrainfuckConfig, BrainfuckContext},
-	misc::{MastermindConfig, MastermindContext},
-	parser::parser::parse_program,
-	preprocessor::{preprocess, strip_comments},
+	diagnostics::{Diagnostics, SourceMap},
+	frontend::ir::ir_to_text,
+	misc::{MastermindConfig, MastermindContext, MemoryAllocationMethod},
+	parser::{
+		formatter::format_program,
+		parser::{ast_to_json, parse_program},
+	},
+	preprocessor::preprocess,
+	project::{Project, PROJECT_FILE_NAME},
+	sources::{include_search_path, FileSystemProvider, LayeredProvider},
 };
 
 // stdlib dependencies:
-use std::io::{stdin, stdout, Cursor};
+use std::{
+	io::{stdin, stdout, Cursor},
+	path::PathBuf,
+};
 
 // external dependencies:
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
-#[derive(Parser, Default, Debug)]
+#[derive(Parser, Debug)]
 #[command(author = "Heathcorp", version = "0.1", about = "Mastermind: the Brainfuck interpreter and compilation tool", long_about = None)]
 struct Arguments {
-	#[arg(short, long, help = "provide a file to read a program from")]
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// compile a Mastermind program to Brainfuck
+	Compile {
+		#[command(flatten)]
+		source: SourceArgs,
+
+		#[command(flatten)]
+		options: CompileOptions,
+
+		#[arg(
+			short,
+			long,
+			help = "write the compiled Brainfuck to a file instead of stdout"
+		)]
+		output: Option<String>,
+
+		#[arg(
+			long,
+			value_enum,
+			default_value_t = Emit::Bf,
+			help = "what to output, the compiled Brainfuck or an intermediate stage of compilation"
+		)]
+		emit: Emit,
+	},
+	/// compile a Mastermind program and run it, or run a Brainfuck program directly
+	Run {
+		#[command(flatten)]
+		source: SourceArgs,
+
+		#[command(flatten)]
+		options: CompileOptions,
+
+		#[arg(
+			short,
+			long,
+			help = "provide input to the Brainfuck VM, stdin will be used if not provided"
+		)]
+		input: Option<String>,
+
+		#[arg(
+			long,
+			default_value_t = false,
+			help = "treat the program as Brainfuck and run it without compiling"
+		)]
+		brainfuck: bool,
+	},
+	/// check a Mastermind program for errors without outputting any Brainfuck
+	Check {
+		#[command(flatten)]
+		source: SourceArgs,
+
+		#[command(flatten)]
+		options: CompileOptions,
+	},
+	/// format a Mastermind source file in place
+	Fmt {
+		#[arg(help = "the file to format")]
+		file: String,
+
+		#[arg(
+			long,
+			default_value_t = false,
+			help = "exit with an error if the file is not formatted, instead of changing it"
+		)]
+		check: bool,
+	},
+}
+
+#[derive(Args, Debug)]
+struct SourceArgs {
+	#[arg(
+		conflicts_with = "program",
+		help = "the file to read a program from, defaults to the project's entry file"
+	)]
 	file: Option<String>,
 
 	#[arg(short, long, help = "provide a program via command line arguments")]
 	program: Option<String>,
 
 	#[arg(
-		short,
+		short = 'I',
+		long = "include-dir",
+		help = "add a directory to search for `#include <name>` files, searched before the built-in standard library"
+	)]
+	include_dirs: Vec<String>,
+
+	#[arg(
 		long,
-		help = "provide input to the Brainfuck VM if running, stdin will be used if not provided"
+		conflicts_with = "no_config",
+		help = "read project settings from this file instead of searching for mastermind.toml"
 	)]
-	input: Option<String>,
+	config: Option<String>,
 
 	#[arg(
-		short,
 		long,
 		default_value_t = false,
-		help = "compile the provided program to Brainfuck"
+		help = "ignore any mastermind.toml project file"
 	)]
-	compile: bool,
+	no_config: bool,
+}
 
+#[derive(Args, Debug)]
+struct CompileOptions {
 	#[arg(
-		short,
-		long,
+		long = "opt",
+		value_enum,
+		value_delimiter = ',',
+		help = "comma-separated list of optimisations to enable, replacing those in the project file"
+	)]
+	optimisations: Vec<Optimisation>,
+
+	#[arg(
+		long = "2d",
 		default_value_t = false,
-		help = "run the compiled or provided Brainfuck code"
+		conflicts_with = "one_dimensional",
+		help = "compile to and run 2D Brainfuck"
 	)]
-	run: bool,
+	two_dimensional: bool,
 
 	#[arg(
-		short,
-		long,
-		default_value_t = 0,
-		help = "specify the level of optimisation, this is a bitmask value"
+		long = "1d",
+		default_value_t = false,
+		help = "compile to and run 1D Brainfuck, even if the project file targets 2D"
 	)]
-	optimise: usize,
+	one_dimensional: bool,
+
+	#[arg(
+		long = "alloc",
+		value_enum,
+		help = "the memory allocation method, only classic and minimise-movement can be used for 1D Brainfuck"
+	)]
+	allocation_method: Option<AllocationMethod>,
 }
 
-fn main() -> Result<(), String> {
-	// TODO: clean up this crazy file, this was the first ever rust I wrote and it's messy
-	std::env::set_var("RUST_BACKTRACE", "1");
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Optimisation {
+	/// no optimisations, e.g. to turn off those in the project file
+	None,
+	/// all of the optimisations below
+	All,
+	/// clean up redundant Brainfuck operations after compiling
+	GeneratedCode,
+	/// search every permutation when optimising generated 2D Brainfuck, this is very slow
+	GeneratedAllPermutations,
+	/// track cell values and clear cells with constant addition if possible
+	CellClearing,
+	/// track cell values and skip loops which can never be entered
+	UnreachableLoops,
+	/// golf constants, useful for single characters or large numbers
+	Constants,
+	/// prune blocks which have no effect, this currently does nothing
+	EmptyBlocks,
+	/// free cells as soon as their values are no longer needed, so that they can be reused
+	MemoryAllocation,
+}
 
-	let args = Arguments::parse();
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Emit {
+	/// the compiled Brainfuck
+	Bf,
+	/// the parsed syntax tree as JSON
+	Ast,
+	/// the intermediate representation, before memory allocation
+	Ir,
+}
 
-	let ctx = MastermindContext {
-		// TODO: change this to not be a bitmask, or at least document it
-		config: MastermindConfig::new(args.optimise),
-	};
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum AllocationMethod {
+	/// the first free cells to the right of the origin, the default
+	Classic,
+	Zigzag,
+	Spiral,
+	Tiles,
+	/// the free cells closest to the tape head
+	Nearest,
+	/// plan where memory goes to minimise how far the tape head moves
+	MinimiseMovement,
+}
+
+impl CompileOptions {
+	/// override settings from the project file with those given on the command line
+	fn apply_to(&self, config: &mut MastermindConfig) {
+		if !self.optimisations.is_empty() {
+			config.optimise_generated_code = false;
+			config.optimise_generated_all_permutations = false;
+			config.optimise_cell_clearing = false;
+			config.optimise_unreachable_loops = false;
+			config.optimise_constants = false;
+			config.optimise_empty_blocks = false;
+			config.optimise_memory_allocation = false;
+		}
+		for optimisation in &self.optimisations {
+			match optimisation {
+				Optimisation::None => (),
+				Optimisation::All => {
+					config.optimise_generated_code = true;
+					config.optimise_cell_clearing = true;
+					config.optimise_unreachable_loops = true;
+					config.optimise_constants = true;
+					config.optimise_empty_blocks = true;
+					config.optimise_memory_allocation = true;
+					// exhaustive 2D permutation search is too slow to include in `all`
+				}
+				Optimisation::GeneratedCode => config.optimise_generated_code = true,
+				Optimisation::GeneratedAllPermutations => {
+					config.optimise_generated_all_permutations = true
+				}
+				Optimisation::CellClearing => config.optimise_cell_clearing = true,
+				Optimisation::UnreachableLoops => config.optimise_unreachable_loops = true,
+				Optimisation::Constants => config.optimise_constants = true,
+				Optimisation::EmptyBlocks => config.optimise_empty_blocks = true,
+				Optimisation::MemoryAllocation => config.optimise_memory_allocation = true,
+			}
+		}
+		if self.two_dimensional {
+			config.enable_2d_grid = true;
+		}
+		if self.one_dimensional {
+			config.enable_2d_grid = false;
+			// the other allocation methods are only for 2D
+			if config.memory_allocation_method.is_2d_only() {
+				config.memory_allocation_method = MemoryAllocationMethod::Classic;
+			}
+		}
+		if let Some(allocation_method) = self.allocation_method {
+			config.memory_allocation_method = match allocation_method {
+				AllocationMethod::Classic => MemoryAllocationMethod::Classic,
+				AllocationMethod::Zigzag => MemoryAllocationMethod::ZigZag,
+				AllocationMethod::Spiral => MemoryAllocationMethod::Spiral,
+				AllocationMethod::Tiles => MemoryAllocationMethod::Tiles,
+				AllocationMethod::Nearest => MemoryAllocationMethod::Nearest,
+				AllocationMethod::MinimiseMovement => MemoryAllocationMethod::MinimiseMovement,
+			};
+		}
+	}
+}
+
+/// The settings for one command, read from the project file (if any) and overridden by the command line
+struct Settings {
+	config: MastermindConfig,
+	brainfuck_config: BrainfuckConfig,
+	/// the file to read the program from, if it wasn't given with `--program`
+	file: Option<PathBuf>,
+	include_dirs: Vec<PathBuf>,
+}
+
+impl Settings {
+	fn load(source: &SourceArgs, options: &CompileOptions) -> Settings {
+		let project = match (&source.config, source.no_config) {
+			(_, true) => None,
+			(Some(config_path), false) => Some(
+				Project::load(&PathBuf::from(config_path))
+					.unwrap_or_else(|err| exit_with_error(&err)),
+			),
+			(None, false) => std::env::current_dir()
+				.map_err(|err| format!("Could not read the current directory: {err}"))
+				.and_then(|dir| Project::discover(&dir))
+				.unwrap_or_else(|err| exit_with_error(&err)),
+		};
+
+		let mut config = MastermindConfig::default();
+		let mut debug_symbols = false;
+		let mut file = source.file.as_ref().map(PathBuf::from);
+		// command line include directories are searched first
+		let mut include_dirs: Vec<PathBuf> =
+			source.include_dirs.iter().map(PathBuf::from).collect();
+		if let Some(project) = project {
+			if source.program.is_none() {
+				file = file.or_else(|| project.entry());
+			}
+			include_dirs.extend(project.include_dirs());
+			debug_symbols = project.config.interpreter.enable_debug_symbols;
+			config = project.config.compiler;
+		}
+		if file.is_none() && source.program.is_none() {
+			exit_with_error(&format!(
+				"No program given, provide a file or set `entry` in {PROJECT_FILE_NAME}."
+			));
+		}
 
-	let program = match args.file {
-		Some(file) => {
-			let file_path = std::path::PathBuf::from(file);
+		options.apply_to(&mut config);
+		if !config.enable_2d_grid && config.memory_allocation_method.is_2d_only() {
+			exit_with_error(
+				"A 2D memory allocation method was given without targeting 2D Brainfuck.",
+			);
+		}
+
+		Settings {
+			brainfuck_config: BrainfuckConfig {
+				enable_debug_symbols: debug_symbols,
+				enable_2d_grid: config.enable_2d_grid,
+			},
+			config,
+			file,
+			include_dirs: include_search_path(include_dirs),
+		}
+	}
+}
+
+/// print an error and exit, used for errors in the user's program rather than in the compiler
+fn exit_with_error(message: &str) -> ! {
+	eprintln!("{message}");
+	std::process::exit(1);
+}
 
+/// the file name given to programs passed on the command line
+const COMMAND_LINE_FILE_NAME: &str = "<program>";
+
+/// read the program from a file (running the preprocessor) or from the command line
+fn read_program(source: &SourceArgs, settings: &Settings) -> (String, SourceMap) {
+	match (&source.program, &settings.file) {
+		(Some(program), _) => {
+			let source_map = SourceMap::from_single_file(COMMAND_LINE_FILE_NAME, program);
+			(program.clone(), source_map)
+		}
+		(None, Some(file)) => {
 			// c-style preprocessor (includes and maybe some simple conditionals to avoid double includes)
-			preprocess(file_path)
+			let sources = LayeredProvider::with_std_library(FileSystemProvider {
+				include_dirs: settings.include_dirs.clone(),
+			});
+			preprocess(&sources, &file.to_string_lossy())
+				.unwrap_or_else(|err| exit_with_error(&err.render()))
+		}
+		(None, None) => unreachable!(),
+	}
+}
+
+/// the name of the file being compiled, as it appears in the source map
+fn entry_file_name(source: &SourceArgs, settings: &Settings) -> String {
+	match (&source.program, &settings.file) {
+		(Some(_), _) => String::from(COMMAND_LINE_FILE_NAME),
+		(None, Some(file)) => file.to_string_lossy().into_owned(),
+		(None, None) => unreachable!(),
+	}
+}
+
+/// compile a preprocessed program to Brainfuck (or the given stage of compilation), printing any warnings,
+/// or printing the errors and exiting if compilation fails
+fn compile(
+	ctx: &MastermindContext,
+	program: &str,
+	source_map: &SourceMap,
+	entry_file_name: &str,
+	emit: Emit,
+) -> String {
+	let compile = || -> Result<String, Diagnostics> {
+		if ctx.config.enable_2d_grid {
+			let parsed_syntax = parse_program::<TapeCell2D, Opcode2D>(program)?;
+			if emit == Emit::Ast {
+				return Ok(ast_to_json(&parsed_syntax));
+			}
+			let instructions = ctx.create_ir_scope(&parsed_syntax, None)?.build_ir(false);
+			if emit == Emit::Ir {
+				return Ok(ir_to_text(&instructions));
+			}
+			let bf_code = ctx.ir_to_bf(instructions, None)?;
+			Ok(match ctx.config.optimise_generated_code {
+				true => ctx.optimise_bf2d(bf_code),
+				false => bf_code,
+			}
+			.to_string())
+		} else {
+			let parsed_syntax = parse_program::<TapeCell, Opcode>(program)?;
+			if emit == Emit::Ast {
+				return Ok(ast_to_json(&parsed_syntax));
+			}
+			let instructions = ctx.create_ir_scope(&parsed_syntax, None)?.build_ir(false);
+			if emit == Emit::Ir {
+				return Ok(ir_to_text(&instructions));
+			}
+			let bf_code = ctx.ir_to_bf(instructions, None)?;
+			Ok(match ctx.config.optimise_generated_code {
+				true => ctx.optimise_bf(bf_code),
+				false => bf_code,
+			}
+			.to_string())
 		}
-		None => args.program.unwrap(),
 	};
+	let result = compile();
+
+	// warnings in included files (e.g. the standard library) are not shown
+	let mut warnings = ctx.take_warnings();
+	warnings.retain_warnings_in(entry_file_name, program, source_map);
+	if !warnings.is_empty() {
+		eprintln!("{}\n", warnings.render(program, source_map));
+	}
+
+	result.unwrap_or_else(|err| exit_with_error(&err.render(program, source_map)))
+}
+
+fn main() -> Result<(), String> {
+	std::env::set_var("RUST_BACKTRACE", "1");
+
+	let args = Arguments::parse();
 
-	let bf_program = match args.compile {
-		true => {
-			let stripped_program = strip_comments(&program);
-			// compile the provided file
-			if ctx.config.enable_2d_grid {
-				let parsed_syntax = parse_program::<TapeCell2D, Opcode2D>(&stripped_program)?;
-				let instructions = ctx.create_ir_scope(&parsed_syntax, None)?.build_ir(false);
-				let bf_code = ctx.ir_to_bf(instructions, None)?;
-				match ctx.config.optimise_generated_code {
-					true => ctx.optimise_bf2d(bf_code),
-					false => bf_code,
+	match args.command {
+		Command::Compile {
+			source,
+			options,
+			output,
+			emit,
+		} => {
+			let settings = Settings::load(&source, &options);
+			let ctx = MastermindContext::new(settings.config.clone());
+			let (program, source_map) = read_program(&source, &settings);
+			let bf_program = compile(
+				&ctx,
+				&program,
+				&source_map,
+				&entry_file_name(&source, &settings),
+				emit,
+			);
+			match output {
+				Some(output_path) => std::fs::write(&output_path, bf_program)
+					.map_err(|err| format!("Could not write to file \"{output_path}\": {err}"))?,
+				None => print!("{bf_program}"),
+			}
+		}
+		Command::Run {
+			source,
+			options,
+			input,
+			brainfuck,
+		} => {
+			let settings = Settings::load(&source, &options);
+			let ctx = MastermindContext::new(settings.config.clone());
+			let bf_program = match brainfuck {
+				true => match (&source.program, &settings.file) {
+					(Some(program), _) => program.clone(),
+					(None, Some(file)) => std::fs::read_to_string(file).map_err(|err| {
+						format!("Could not read file \"{}\": {err}", file.display())
+					})?,
+					(None, None) => unreachable!(),
+				},
+				false => {
+					let (program, source_map) = read_program(&source, &settings);
+					compile(
+						&ctx,
+						&program,
+						&source_map,
+						&entry_file_name(&source, &settings),
+						Emit::Bf,
+					)
 				}
-				.to_string()
-			} else {
-				let parsed_syntax = parse_program::<TapeCell, Opcode>(&stripped_program)?;
-				let instructions = ctx.create_ir_scope(&parsed_syntax, None)?.build_ir(false);
-				let bf_code = ctx.ir_to_bf(instructions, None)?;
-				match ctx.config.optimise_generated_code {
-					true => ctx.optimise_bf(bf_code),
-					false => bf_code,
+			};
+
+			// run brainfuck
+			let bvm = BrainfuckContext {
+				config: settings.brainfuck_config,
+			};
+			match input {
+				Some(input) => bvm.run(
+					bf_program.chars().collect(),
+					&mut Cursor::new(input),
+					&mut stdout(),
+					None,
+				)?,
+				None => bvm.run(
+					bf_program.chars().collect(),
+					&mut stdin(),
+					&mut stdout(),
+					None,
+				)?,
+			};
+		}
+		Command::Check { source, options } => {
+			let settings = Settings::load(&source, &options);
+			let ctx = MastermindContext::new(settings.config.clone());
+			let (program, source_map) = read_program(&source, &settings);
+			compile(
+				&ctx,
+				&program,
+				&source_map,
+				&entry_file_name(&source, &settings),
+				Emit::Bf,
+			);
+		}
+		Command::Fmt { file, check } => {
+			let source = std::fs::read_to_string(&file)
+				.map_err(|err| format!("Could not read file \"{file}\": {err}"))?;
+			let formatted = format_program(&source).unwrap_or_else(|err| {
+				exit_with_error(&err.render(&source, &SourceMap::from_single_file(&file, &source)))
+			});
+			if formatted != source {
+				match check {
+					true => exit_with_error(&format!("\"{file}\" is not formatted.")),
+					false => std::fs::write(&file, formatted)
+						.map_err(|err| format!("Could not write to file \"{file}\": {err}"))?,
 				}
-				.to_string()
 			}
 		}
-		false => program,
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod compile_options_tests {
+	use clap::Parser;
+
+	use super::{Arguments, Command};
+	use crate::{
+		misc::{MastermindConfig, MemoryAllocationMethod},
+		project::ProjectConfig,
 	};
 
-	if args.run || !args.compile {
-		// run brainfuck
-		let ctx = BrainfuckContext {
-			config: BrainfuckConfig {
-				enable_debug_symbols: false,
-				enable_2d_grid: false,
-			},
+	/// the settings from a project file, overridden by the options of a `compile` command line
+	fn apply_options(project_file: &str, args: &[&str]) -> MastermindConfig {
+		let mut config = ProjectConfig::parse(project_file).unwrap().compiler;
+		let command_line = ["mastermind", "compile", "-p", ""].iter().chain(args);
+		let Command::Compile {
+			source: _,
+			options,
+			output: _,
+			emit: _,
+		} = Arguments::try_parse_from(command_line).unwrap().command
+		else {
+			unreachable!();
 		};
+		options.apply_to(&mut config);
+		config
+	}
 
-		if args.input.is_some() {
-			ctx.run(
-				bf_program.chars().collect(),
-				&mut Cursor::new(args.input.unwrap()),
-				&mut stdout(),
-				None,
-			)?;
-		} else {
-			ctx.run(
-				bf_program.chars().collect(),
-				&mut stdin(),
-				&mut stdout(),
-				None,
-			)?;
-		}
-	} else {
-		print!("{bf_program}");
+	const PROJECT_FILE: &str = r#"
+target = "2d"
+[compiler]
+optimise_generated_code = true
+optimise_constants = true
+memory_allocation_method = "spiral"
+"#;
+
+	#[test]
+	fn compile_options_1() {
+		// without options the project file is used as is
+		let config = apply_options(PROJECT_FILE, &[]);
+		assert!(config.optimise_generated_code);
+		assert!(config.optimise_constants);
+		assert!(!config.optimise_cell_clearing);
+		assert!(config.enable_2d_grid);
+		assert_eq!(
+			config.memory_allocation_method,
+			MemoryAllocationMethod::Spiral
+		);
 	}
 
-	Ok(())
+	#[test]
+	fn compile_options_2() {
+		let config = apply_options(PROJECT_FILE, &["--opt", "none"]);
+		assert!(!config.optimise_generated_code);
+		assert!(!config.optimise_constants);
+		assert!(!config.optimise_memory_allocation);
+	}
+
+	#[test]
+	fn compile_options_3() {
+		// the given optimisations replace those in the project file rather than adding to them
+		let config = apply_options(PROJECT_FILE, &["--opt", "cell-clearing,constants"]);
+		assert!(!config.optimise_generated_code);
+		assert!(config.optimise_constants);
+		assert!(config.optimise_cell_clearing);
+
+		let config = apply_options("", &["--opt", "all"]);
+		assert!(config.optimise_generated_code);
+		assert!(config.optimise_memory_allocation);
+		assert!(!config.optimise_generated_all_permutations);
+	}
+
+	#[test]
+	fn compile_options_4() {
+		// 2D-only allocation methods from the project file are reset when targeting 1D
+		let config = apply_options(PROJECT_FILE, &["--1d"]);
+		assert!(!config.enable_2d_grid);
+		assert_eq!(
+			config.memory_allocation_method,
+			MemoryAllocationMethod::Classic
+		);
+
+		let config = apply_options(
+			"[compiler]\nmemory_allocation_method = \"minimise_movement\"",
+			&["--1d"],
+		);
+		assert_eq!(
+			config.memory_allocation_method,
+			MemoryAllocationMethod::MinimiseMovement
+		);
+	}
+
+	#[test]
+	fn compile_options_5() {
+		// values given on the command line win over the project file
+		let config = apply_options(PROJECT_FILE, &["--alloc", "tiles"]);
+		assert_eq!(
+			config.memory_allocation_method,
+			MemoryAllocationMethod::Tiles
+		);
+
+		let config = apply_options(
+			"target = \"1d\"\n[compiler]\nmemory_allocation_method = \"zig_zag\"",
+			&["--2d"],
+		);
+		assert!(config.enable_2d_grid);
+		assert_eq!(
+			config.memory_allocation_method,
+			MemoryAllocationMethod::ZigZag
+		);
+
+		let config = apply_options(PROJECT_FILE, &["--1d", "--alloc", "minimise-movement"]);
+		assert!(!config.enable_2d_grid);
+		assert_eq!(
+			config.memory_allocation_method,
+			MemoryAllocationMethod::MinimiseMovement
+		);
+	}
 }
@@ -1,4 +1,9 @@
-#[derive(Clone, serde::Deserialize)]
+use std::cell::RefCell;
+
+use crate::diagnostics::{Diagnostic, Diagnostics};
+
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 pub struct MastermindConfig {
 	// basic pure brainfuck optimisations
 	pub optimise_generated_code: bool,
@@ -14,55 +19,63 @@ pub struct MastermindConfig {
 	pub optimise_constants: bool,
 	// TODO: recursively prune if statements/loops if they do nothing
 	pub optimise_empty_blocks: bool,
-	// Memory Allocation Method
-	//'1D Mastermind'  0
-	//'2D Mastermind - ZigZag'  1
-	// '2D Mastermind - Spiral'  2
-	// '2D Mastermind - Tiles'  2
-	// '2D Mastermind - Nearest' 3
-	pub memory_allocation_method: u8,
+	pub memory_allocation_method: MemoryAllocationMethod,
 	pub enable_2d_grid: bool,
 	// TODO: prune variables that aren't needed? Maybe combine with empty blocks stuff
 	// pub optimise_variable_usage: bool,
-	// recommended to turn on these next two together
-	// pub optimise_memory_allocation: bool,
+	// free cells as soon as their values are no longer needed, so that they can be reused
+	pub optimise_memory_allocation: bool,
 }
 
-impl Default for MastermindConfig {
-	fn default() -> MastermindConfig {
-		MastermindConfig {
-			optimise_generated_code: false,
-			optimise_generated_all_permutations: false,
-			optimise_cell_clearing: false,
-			optimise_unreachable_loops: false,
-			// optimise_variable_usage: false,
-			// optimise_memory_allocation: false,
-			optimise_constants: false,
-			optimise_empty_blocks: false,
-			memory_allocation_method: 0,
-			enable_2d_grid: false,
-		}
-	}
+/// where memory without a location specifier is placed on the tape
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryAllocationMethod {
+	/// the first free cells to the right of the origin
+	#[default]
+	Classic,
+	/// diagonals outwards from the origin
+	ZigZag,
+	/// a square spiral around the origin
+	Spiral,
+	/// squares of increasing size around the origin
+	Tiles,
+	/// the free cells closest to the tape head
+	Nearest,
+	/// planned in advance to minimise how far the tape head moves
+	MinimiseMovement,
 }
 
-impl MastermindConfig {
-	// TODO: rethink this bitmask thing
-	pub fn new(optimise_bitmask: usize) -> MastermindConfig {
-		MastermindConfig {
-			optimise_generated_code: (optimise_bitmask & 0b00000001) > 0,
-			optimise_generated_all_permutations: (optimise_bitmask & 0b00001000) > 0,
-			optimise_cell_clearing: (optimise_bitmask & 0b00000010) > 0,
-			optimise_unreachable_loops: (optimise_bitmask & 0b00000100) > 0,
-			// optimise_variable_usage: false,
-			// optimise_memory_allocation: false,
-			optimise_constants: (optimise_bitmask & 0b00001000) > 0,
-			optimise_empty_blocks: false,
-			memory_allocation_method: 0,
-			enable_2d_grid: false,
-		}
+impl MemoryAllocationMethod {
+	/// whether the method can only be used for 2D Brainfuck
+	pub fn is_2d_only(&self) -> bool {
+		!matches!(
+			self,
+			MemoryAllocationMethod::Classic | MemoryAllocationMethod::MinimiseMovement
+		)
 	}
 }
 
 pub struct MastermindContext {
 	pub config: MastermindConfig,
+	/// warnings found while compiling, these don't stop compilation
+	warnings: RefCell<Diagnostics>,
+}
+
+impl MastermindContext {
+	pub const fn new(config: MastermindConfig) -> MastermindContext {
+		MastermindContext {
+			config,
+			warnings: RefCell::new(Diagnostics(Vec::new())),
+		}
+	}
+
+	pub fn warn(&self, warning: Diagnostic) {
+		self.warnings.borrow_mut().push(warning);
+	}
+
+	/// the warnings found so far in source code order, clearing them from the context
+	pub fn take_warnings(&self) -> Diagnostics {
+		self.warnings.take().sorted()
+	}
 }
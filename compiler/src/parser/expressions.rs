@@ -1,28 +1,45 @@
 use super::{
-	parser::parse_var_target,
-	tokens::{next_token, Token},
+	parser::{parse_function_call, parse_subscript, parse_var_target},
+	tokens::{next_token, SourceCursor, Token},
 	types::VariableTarget,
 };
-use crate::macros::macros::{r_assert, r_panic};
+use crate::{
+	diagnostics::{Diagnostic, Span, Spanned},
+	macros::macros::{r_assert, r_assert_at, r_panic, r_panic_at},
+};
 
 use itertools::Itertools;
 use std::num::Wrapping;
 
 // TODO: simplify expression data structure for negative sums of single values
-#[derive(Debug, Clone)]
+/// subexpressions keep their spans so that errors can point at the part of the expression at fault
+#[derive(Debug, Clone, serde::Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
+#[allow(clippy::enum_variant_names)]
 pub enum Expression {
 	SumExpression {
 		sign: Sign,
-		summands: Vec<Expression>,
+		summands: Vec<Spanned<Expression>>,
 	},
 	NaturalNumber(usize),
 	VariableReference(VariableTarget),
-	ArrayLiteral(Vec<Expression>),
+	ArrayLiteral(Vec<Spanned<Expression>>),
 	StringLiteral(String),
+	BinaryExpression {
+		operator: BinaryOperator,
+		left: Box<Spanned<Expression>>,
+		right: Box<Spanned<Expression>>,
+	},
+	FunctionCall {
+		function_name: String,
+		arguments: Vec<Spanned<Expression>>,
+	},
 }
 
-#[derive(Debug, Clone)]
+/// (constant to add, variables to add, variables to subtract)
+pub type FlattenedExpression = (u8, Vec<VariableTarget>, Vec<VariableTarget>);
+
+#[derive(Debug, Clone, serde::Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub enum Sign {
 	Positive,
@@ -37,14 +54,53 @@ impl Sign {
 	}
 }
 
+/// operators which can't be represented as a sum of values,
+/// comparison and logical operators evaluate to 0 or 1
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[cfg_attr(test, derive(PartialEq))]
+pub enum BinaryOperator {
+	Multiply,
+	Divide,
+	Modulo,
+	Equals,
+	NotEquals,
+	LessThan,
+	GreaterThan,
+	LessThanOrEqual,
+	GreaterThanOrEqual,
+	And,
+	Or,
+}
+
+impl BinaryOperator {
+	/// evaluate the operator on two constants with 8-bit wrapping arithmetic,
+	/// returns None if the result is undefined (division by zero)
+	pub fn apply(&self, left: u8, right: u8) -> Option<u8> {
+		match self {
+			BinaryOperator::Multiply => Some(left.wrapping_mul(right)),
+			BinaryOperator::Divide => left.checked_div(right),
+			BinaryOperator::Modulo => left.checked_rem(right),
+			BinaryOperator::Equals => Some((left == right) as u8),
+			BinaryOperator::NotEquals => Some((left != right) as u8),
+			BinaryOperator::LessThan => Some((left < right) as u8),
+			BinaryOperator::GreaterThan => Some((left > right) as u8),
+			BinaryOperator::LessThanOrEqual => Some((left <= right) as u8),
+			BinaryOperator::GreaterThanOrEqual => Some((left >= right) as u8),
+			BinaryOperator::And => Some((left != 0 && right != 0) as u8),
+			BinaryOperator::Or => Some((left != 0 || right != 0) as u8),
+		}
+	}
+}
+
 impl Expression {
 	// Iterators?
 	// TODO: support post/pre increment in expressions
-	pub fn parse(chars: &mut &[char]) -> Result<Expression, String> {
+	pub fn parse(chars: &mut SourceCursor) -> Result<Spanned<Expression>, Diagnostic> {
 		// parse string expressions
 		{
 			let mut s = *chars;
 			if let Token::String(literal) = next_token(&mut s)? {
+				let span = s.last_span();
 				*chars = s;
 				let (Token::RightParenthesis
 				| Token::Semicolon
@@ -54,10 +110,12 @@ impl Expression {
 				| Token::RightSquareBracket
 				| Token::None) = next_token(&mut s)?
 				else {
-					// TODO: add source snippet
-					r_panic!("String literal must entirely comprise expression.");
+					r_panic_at!(
+						s.last_span(),
+						"String literal must entirely comprise expression."
+					);
 				};
-				return Ok(Expression::StringLiteral(literal));
+				return Ok(Spanned::new(Expression::StringLiteral(literal), span));
 			}
 		}
 
@@ -65,6 +123,7 @@ impl Expression {
 		{
 			let mut s = *chars;
 			if let Ok(Token::LeftSquareBracket) = next_token(&mut s) {
+				let start = s.last_span().start;
 				*chars = s;
 				let mut expressions = vec![];
 				loop {
@@ -82,6 +141,7 @@ impl Expression {
 						_ => unreachable!(),
 					}
 				}
+				let span = chars.span_from(start);
 
 				// check for delimiters
 				{
@@ -95,15 +155,75 @@ impl Expression {
 						| Token::None,
 					) = next_token(&mut s)
 					else {
-						// TODO: add source snippet
-						r_panic!("Array literal must entirely comprise expression.");
+						r_panic_at!(
+							s.last_span(),
+							"Array literal must entirely comprise expression."
+						);
 					};
 				}
-				return Ok(Expression::ArrayLiteral(expressions));
+				return Ok(Spanned::new(Expression::ArrayLiteral(expressions), span));
 			}
 		}
 
-		// parse arithmetic or variable expressions
+		// parse arithmetic, logical or variable expressions
+		Self::parse_or(chars)
+	}
+
+	/// parse logical or, the lowest precedence operator, e.g. `a or b and c`
+	fn parse_or(chars: &mut SourceCursor) -> Result<Spanned<Expression>, Diagnostic> {
+		let mut expr = Self::parse_and(chars)?;
+		loop {
+			let mut s = *chars;
+			let Token::Or = next_token(&mut s)? else {
+				break;
+			};
+			*chars = s;
+			expr = Self::binary(BinaryOperator::Or, expr, Self::parse_and(chars)?);
+		}
+
+		Ok(expr)
+	}
+
+	/// parse logical and, e.g. `a == 1 and b`
+	fn parse_and(chars: &mut SourceCursor) -> Result<Spanned<Expression>, Diagnostic> {
+		let mut expr = Self::parse_comparison(chars)?;
+		loop {
+			let mut s = *chars;
+			let Token::And = next_token(&mut s)? else {
+				break;
+			};
+			*chars = s;
+			expr = Self::binary(BinaryOperator::And, expr, Self::parse_comparison(chars)?);
+		}
+
+		Ok(expr)
+	}
+
+	/// parse comparisons between sums, e.g. `a + 1 < b`
+	fn parse_comparison(chars: &mut SourceCursor) -> Result<Spanned<Expression>, Diagnostic> {
+		let mut expr = Self::parse_sum(chars)?;
+		loop {
+			let mut s = *chars;
+			let operator = match next_token(&mut s)? {
+				Token::EqualsEquals => BinaryOperator::Equals,
+				Token::ExclamationEquals => BinaryOperator::NotEquals,
+				Token::LessThan => BinaryOperator::LessThan,
+				Token::GreaterThan => BinaryOperator::GreaterThan,
+				Token::LessThanEquals => BinaryOperator::LessThanOrEqual,
+				Token::GreaterThanEquals => BinaryOperator::GreaterThanOrEqual,
+				_ => break,
+			};
+			*chars = s;
+			expr = Self::binary(operator, expr, Self::parse_sum(chars)?);
+		}
+
+		Ok(expr)
+	}
+
+	/// parse a sum of terms, e.g. `a + 4 * b - c`
+	fn parse_sum(chars: &mut SourceCursor) -> Result<Spanned<Expression>, Diagnostic> {
+		// the start of the current summand's minus signs, so that a negative summand's span includes them
+		let mut summand_start = None;
 		// this loop is basically a state machine based on the current sign:
 		let mut current_sign = Some(Sign::Positive); // by default the first summand is positive
 		let mut summands = Vec::new();
@@ -117,90 +237,31 @@ impl Expression {
 				(None, Token::Minus) => {
 					*chars = s;
 					current_sign = Some(Sign::Negative);
+					summand_start.get_or_insert(s.last_span().start);
 				}
 				(Some(Sign::Positive), Token::Minus) => {
 					*chars = s;
 					current_sign = Some(Sign::Negative);
+					summand_start.get_or_insert(s.last_span().start);
 				}
 				(Some(Sign::Negative), Token::Minus) => {
 					*chars = s;
 					current_sign = Some(Sign::Positive);
+					summand_start.get_or_insert(s.last_span().start);
 				}
 				(
 					Some(sign),
-					token @ (Token::Number(_) | Token::Character(_) | Token::True | Token::False),
+					Token::Number(_)
+					| Token::Character(_)
+					| Token::True
+					| Token::False
+					| Token::Name(_)
+					| Token::Asterisk
+					| Token::LeftParenthesis,
 				) => {
-					*chars = s;
-					let parsed_int = match token {
-						Token::Number(number) => number,
-						Token::Character(c) => {
-							let chr_int = c as usize;
-							r_assert!(chr_int < 0xff, "Character tokens must be single-byte: {c}");
-							chr_int
-						}
-						Token::True => 1,
-						Token::False => 0,
-						_ => unreachable!(),
-					};
-					summands.push(match sign {
-						Sign::Positive => Expression::NaturalNumber(parsed_int),
-						Sign::Negative => Expression::SumExpression {
-							sign: Sign::Negative,
-							summands: vec![Expression::NaturalNumber(parsed_int)],
-						},
-					});
-					current_sign = None;
-				}
-				(Some(sign), Token::Name(_) | Token::Asterisk) => {
-					let var = parse_var_target(chars)?;
-					summands.push(match sign {
-						Sign::Positive => Expression::VariableReference(var),
-						Sign::Negative => Expression::SumExpression {
-							sign: Sign::Negative,
-							summands: vec![Expression::VariableReference(var)],
-						},
-					});
-					current_sign = None;
-				}
-				(Some(sign), Token::LeftParenthesis) => {
-					*chars = s;
-					let inner_expr = Self::parse(chars)?;
-					// probably inefficent but everything needs to be flattened at some point anyway so won't matter
-					// TODO: make expression structure more efficient (don't use vectors every time there is a negative)
-					summands.push(match (sign, inner_expr.clone()) {
-						(
-							Sign::Negative,
-							Expression::NaturalNumber(_) | Expression::VariableReference(_),
-						) => Expression::SumExpression {
-							sign: Sign::Negative,
-							summands: vec![inner_expr],
-						},
-						(
-							Sign::Negative,
-							Expression::SumExpression {
-								sign: Sign::Negative,
-								summands,
-							},
-						) => Expression::SumExpression {
-							sign: Sign::Positive,
-							summands,
-						},
-						(
-							Sign::Negative,
-							Expression::SumExpression {
-								sign: Sign::Positive,
-								summands,
-							},
-						) => Expression::SumExpression {
-							sign: Sign::Negative,
-							summands,
-						},
-						_ => inner_expr,
-					});
-					let Token::RightParenthesis = next_token(chars)? else {
-						// TODO: add source snippet
-						r_panic!("Expected `)` after inner expression.");
-					};
+					let start = summand_start.take().unwrap_or(s.last_span().start);
+					let term = Self::parse_term(chars)?;
+					summands.push(Self::with_sign(term, sign.clone(), chars.span_from(start)));
 					current_sign = None;
 				}
 				// TODO: add delimiters here: `)` `;` `,` `{` `into`
@@ -212,54 +273,271 @@ impl Expression {
 					| Token::Comma
 					| Token::LeftBrace
 					| Token::Into
-					| Token::None,
+					| Token::None
+					| Token::EqualsEquals
+					| Token::ExclamationEquals
+					| Token::LessThan
+					| Token::GreaterThan
+					| Token::LessThanEquals
+					| Token::GreaterThanEquals
+					| Token::And
+					| Token::Or,
 				) => {
-					r_assert!(sign.is_none(), "Expected more terms in expression.");
+					r_assert_at!(
+						s.last_span(),
+						sign.is_none(),
+						"Expected more terms in expression."
+					);
 					break;
 				}
-				// TODO: add source snippet
-				(_, token) => r_panic!("Unexpected token `{token}` found in expression."),
+				(_, token) => {
+					r_panic_at!(
+						s.last_span(),
+						"Unexpected token `{token}` found in expression."
+					)
+				}
 			}
 		}
 
 		Ok(match summands.len() {
 			1 => summands.into_iter().next().unwrap(),
-			1.. => Expression::SumExpression {
+			2.. => {
+				let span = Span::new(
+					summands[0].span.start,
+					summands[summands.len() - 1].span.end,
+				);
+				Spanned::new(
+					Expression::SumExpression {
+						sign: Sign::Positive,
+						summands,
+					},
+					span,
+				)
+			}
+			_ => r_panic_at!(chars.last_span(), "Expected value in expression."),
+		})
+	}
+
+	/// parse a product of factors, e.g. `a * b / 2`,
+	/// these operators take precedence over addition and are left-associative
+	fn parse_term(chars: &mut SourceCursor) -> Result<Spanned<Expression>, Diagnostic> {
+		let mut term = Self::parse_factor(chars)?;
+		loop {
+			let mut s = *chars;
+			let operator = match next_token(&mut s)? {
+				Token::Asterisk => BinaryOperator::Multiply,
+				Token::Slash => BinaryOperator::Divide,
+				Token::Percent => BinaryOperator::Modulo,
+				_ => break,
+			};
+			*chars = s;
+			term = Self::binary(operator, term, Self::parse_factor(chars)?);
+		}
+
+		Ok(term)
+	}
+
+	/// parse a single value, variable, or parenthesised expression
+	fn parse_factor(chars: &mut SourceCursor) -> Result<Spanned<Expression>, Diagnostic> {
+		let mut s = *chars;
+		let token = next_token(&mut s)?;
+		let start = s.last_span().start;
+		let expr = match token {
+			token @ (Token::Number(_) | Token::Character(_) | Token::True | Token::False) => {
+				*chars = s;
+				Expression::NaturalNumber(match token {
+					Token::Number(number) => number,
+					Token::Character(c) => {
+						let chr_int = c as usize;
+						r_assert_at!(
+							s.last_span(),
+							chr_int < 0xff,
+							"Character tokens must be single-byte: {c}"
+						);
+						chr_int
+					}
+					Token::True => 1,
+					Token::False => 0,
+					_ => unreachable!(),
+				})
+			}
+			Token::Name(name) => match next_token(&mut s)? {
+				Token::LeftParenthesis => {
+					let (function_name, arguments) = parse_function_call(chars)?;
+					Expression::FunctionCall {
+						function_name,
+						arguments,
+					}
+				}
+				_ => match chars.get_constant(&name) {
+					Some(value) => Self::parse_constant_reference(chars, value)?,
+					None => Expression::VariableReference(parse_var_target(chars)?),
+				},
+			},
+			Token::Asterisk => Expression::VariableReference(parse_var_target(chars)?),
+			// allow negative factors in products, e.g. `a * -b`
+			Token::Minus => {
+				*chars = s;
+				let factor = Self::parse_factor(chars)?;
+				return Ok(Self::with_sign(
+					factor,
+					Sign::Negative,
+					chars.span_from(start),
+				));
+			}
+			Token::LeftParenthesis => {
+				*chars = s;
+				let inner_expr = Self::parse(chars)?;
+				let Token::RightParenthesis = next_token(chars)? else {
+					r_panic_at!(chars.last_span(), "Expected `)` after inner expression.");
+				};
+				return Ok(inner_expr);
+			}
+			token => {
+				r_panic_at!(
+					s.last_span(),
+					"Unexpected token `{token}` found in expression."
+				)
+			}
+		};
+
+		Ok(Spanned::new(expr, chars.span_from(start)))
+	}
+
+	/// substitute a named constant, array constants can be indexed with a constant subscript, e.g. `N` or `TABLE[2]`
+	fn parse_constant_reference(
+		chars: &mut SourceCursor,
+		value: Expression,
+	) -> Result<Expression, Diagnostic> {
+		let Token::Name(name) = next_token(chars)? else {
+			r_panic_at!(chars.last_span(), "Expected constant name in expression.");
+		};
+		let mut s = *chars;
+		let Token::LeftSquareBracket = next_token(&mut s)? else {
+			return Ok(value);
+		};
+		let index = parse_subscript(chars)?;
+		Ok(match value {
+			Expression::ArrayLiteral(elements) => match elements.into_iter().nth(index) {
+				Some(element) => element.node,
+				None => r_panic_at!(
+					chars.last_span(),
+					"Index {index} is out of bounds for array constant \"{name}\"."
+				),
+			},
+			Expression::StringLiteral(literal) => match literal.chars().nth(index) {
+				Some(c) => Expression::NaturalNumber(c as usize),
+				None => r_panic_at!(
+					chars.last_span(),
+					"Index {index} is out of bounds for array constant \"{name}\"."
+				),
+			},
+			_ => r_panic_at!(
+				chars.last_span(),
+				"Cannot index into numeric constant \"{name}\"."
+			),
+		})
+	}
+
+	/// combine two operands into a binary expression spanning both
+	fn binary(
+		operator: BinaryOperator,
+		left: Spanned<Expression>,
+		right: Spanned<Expression>,
+	) -> Spanned<Expression> {
+		let span = Span::new(left.span.start, right.span.end);
+		Spanned::new(
+			Expression::BinaryExpression {
+				operator,
+				left: Box::new(left),
+				right: Box::new(right),
+			},
+			span,
+		)
+	}
+
+	/// apply a sign to an expression, simplifying double negatives,
+	/// the span should include the sign
+	// probably inefficent but everything needs to be flattened at some point anyway so won't matter
+	// TODO: make expression structure more efficient (don't use vectors every time there is a negative)
+	fn with_sign(expr: Spanned<Expression>, sign: Sign, span: Span) -> Spanned<Expression> {
+		let node = match (sign, expr) {
+			(Sign::Positive, expr) => return expr,
+			(
+				Sign::Negative,
+				Spanned {
+					node:
+						Expression::SumExpression {
+							sign: Sign::Negative,
+							summands,
+						},
+					span: _,
+				},
+			) => Expression::SumExpression {
 				sign: Sign::Positive,
 				summands,
 			},
-			// TODO: add source snippet
-			_ => r_panic!("Expected value in expression."),
-		})
+			(
+				Sign::Negative,
+				Spanned {
+					node:
+						Expression::SumExpression {
+							sign: Sign::Positive,
+							summands,
+						},
+					span: _,
+				},
+			) => Expression::SumExpression {
+				sign: Sign::Negative,
+				summands,
+			},
+			(Sign::Negative, expr) => Expression::SumExpression {
+				sign: Sign::Negative,
+				summands: vec![expr],
+			},
+		};
+		Spanned::new(node, span)
 	}
 
 	/// flip the sign of an expression, equivalent to `x => -(x)`
-	pub fn flipped_sign(self) -> Result<Self, String> {
-		Ok(match self {
+	pub fn flipped_sign(expr: Spanned<Expression>) -> Result<Spanned<Expression>, Diagnostic> {
+		let span = expr.span;
+		let node = match expr.node {
 			Expression::SumExpression { sign, summands } => Expression::SumExpression {
 				sign: sign.flipped(),
 				summands,
 			},
-			Expression::NaturalNumber(_) | Expression::VariableReference(_) => {
-				Expression::SumExpression {
-					sign: Sign::Negative,
-					summands: vec![self],
-				}
+			node @ (Expression::NaturalNumber(_)
+			| Expression::VariableReference(_)
+			| Expression::BinaryExpression {
+				operator: _,
+				left: _,
+				right: _,
 			}
+			| Expression::FunctionCall {
+				function_name: _,
+				arguments: _,
+			}) => Expression::SumExpression {
+				sign: Sign::Negative,
+				summands: vec![Spanned::new(node, span)],
+			},
 			Expression::ArrayLiteral(_) | Expression::StringLiteral(_) => {
-				r_panic!(
+				r_panic_at!(
+					span,
 					"Attempted to invert sign of array or string literal, \
-	do not use += or -= on arrays or strings."
+do not use += or -= on arrays or strings."
 				);
 			}
-		})
+		};
+		Ok(Spanned::new(node, span))
 	}
 
 	// not sure if this is the compiler's concern or if it should be the parser
 	// (constant to add, variables to add, variables to subtract)
-	// currently multiplication is not supported so order of operations and flattening is very trivial
-	// If we add multiplication in future it will likely be constant multiplication only, so no variable on variable multiplication
-	pub fn flatten(&self) -> Result<(u8, Vec<VariableTarget>, Vec<VariableTarget>), String> {
+	// binary operations are folded if both sides are constant, multiplication of variables by constants is repeated addition,
+	// anything else must be computed at runtime by the frontend, see is_linear()
+	// errors in subexpressions are given their spans, errors in this expression are left for the caller to attach a span to
+	pub fn flatten(&self) -> Result<FlattenedExpression, Diagnostic> {
 		let expr = self;
 		let mut imm_sum = Wrapping(0u8);
 		let mut additions = Vec::new();
@@ -267,8 +545,10 @@ impl Expression {
 
 		match expr {
 			Expression::SumExpression { sign, summands } => {
-				let results: Result<Vec<(u8, Vec<VariableTarget>, Vec<VariableTarget>)>, String> =
-					summands.into_iter().map(|expr| expr.flatten()).collect();
+				let results: Result<Vec<FlattenedExpression>, Diagnostic> = summands
+					.iter()
+					.map(|expr| expr.node.flatten().map_err(|err| err.or_span(expr.span)))
+					.collect();
 				let flattened = results?
 					.into_iter()
 					.reduce(|acc, (imm, adds, subs)| {
@@ -299,14 +579,208 @@ impl Expression {
 			Expression::VariableReference(var) => {
 				additions.push(var.clone());
 			}
+			Expression::BinaryExpression {
+				operator,
+				left,
+				right,
+			} => {
+				let (left_imm, left_adds, left_subs) =
+					left.node.flatten().map_err(|err| err.or_span(left.span))?;
+				let (right_imm, right_adds, right_subs) = right
+					.node
+					.flatten()
+					.map_err(|err| err.or_span(right.span))?;
+				let left_constant = left_adds.is_empty() && left_subs.is_empty();
+				let right_constant = right_adds.is_empty() && right_subs.is_empty();
+
+				match (operator, left_constant, right_constant) {
+					(_, true, true) => {
+						let Some(result) = operator.apply(left_imm, right_imm) else {
+							r_panic!("Division by zero in constant expression `{expr}`.");
+						};
+						imm_sum += result;
+					}
+					// multiplying a sum by a constant, add the sum repeatedly
+					(BinaryOperator::Multiply, true, false)
+					| (BinaryOperator::Multiply, false, true) => {
+						let (constant, (imm, adds, subs)) = match left_constant {
+							true => (left_imm, (right_imm, right_adds, right_subs)),
+							false => (right_imm, (left_imm, left_adds, left_subs)),
+						};
+						imm_sum += imm.wrapping_mul(constant);
+						for _ in 0..constant {
+							additions.extend(adds.iter().cloned());
+							subtractions.extend(subs.iter().cloned());
+						}
+					}
+					_ => r_panic!(
+						"Expression `{expr}` involves operations between variables \
+and cannot be flattened."
+					),
+				}
+			}
 			Expression::ArrayLiteral(_) | Expression::StringLiteral(_) => {
 				r_panic!("Attempt to flatten an array-like expression: {expr:#?}");
 			}
+			Expression::FunctionCall {
+				function_name: _,
+				arguments: _,
+			} => {
+				r_panic!("Function call `{expr}` must be evaluated before flattening.");
+			}
 		}
 
 		Ok((imm_sum.0, additions, subtractions))
 	}
 
+	/// evaluate a `const` definition down to a literal, numbers become a single natural number,
+	/// unlike flattening this is not wrapped to 8 bits so that constants can be used as sizes and offsets
+	pub fn fold_constant(&self) -> Result<Expression, Diagnostic> {
+		Ok(match self {
+			Expression::ArrayLiteral(elements) => Expression::ArrayLiteral(
+				elements
+					.iter()
+					.map(|element| {
+						element
+							.node
+							.fold_constant()
+							.map(|node| Spanned::new(node, element.span))
+							.map_err(|err| err.or_span(element.span))
+					})
+					.collect::<Result<_, _>>()?,
+			),
+			Expression::StringLiteral(literal) => Expression::StringLiteral(literal.clone()),
+			expr => {
+				let value = expr.evaluate_constant()?;
+				r_assert!(
+					value >= 0,
+					"Constant expression evaluates to a negative value ({value})."
+				);
+				Expression::NaturalNumber(value as usize)
+			}
+		})
+	}
+
+	/// evaluate a numeric expression made up entirely of literals
+	fn evaluate_constant(&self) -> Result<isize, Diagnostic> {
+		Ok(match self {
+			Expression::NaturalNumber(number) => *number as isize,
+			Expression::SumExpression { sign, summands } => {
+				let mut sum: isize = 0;
+				for summand in summands {
+					let value = summand
+						.node
+						.evaluate_constant()
+						.map_err(|err| err.or_span(summand.span))?;
+					let Some(total) = sum.checked_add(value) else {
+						r_panic!("Constant expression `{self}` overflowed.");
+					};
+					sum = total;
+				}
+				match sign {
+					Sign::Positive => sum,
+					Sign::Negative => -sum,
+				}
+			}
+			Expression::BinaryExpression {
+				operator,
+				left,
+				right,
+			} => {
+				let (left, right) = (
+					left.node
+						.evaluate_constant()
+						.map_err(|err| err.or_span(left.span))?,
+					right
+						.node
+						.evaluate_constant()
+						.map_err(|err| err.or_span(right.span))?,
+				);
+				let result = match operator {
+					BinaryOperator::Multiply => left.checked_mul(right),
+					BinaryOperator::Divide => left.checked_div(right),
+					BinaryOperator::Modulo => left.checked_rem(right),
+					BinaryOperator::Equals => Some((left == right) as isize),
+					BinaryOperator::NotEquals => Some((left != right) as isize),
+					BinaryOperator::LessThan => Some((left < right) as isize),
+					BinaryOperator::GreaterThan => Some((left > right) as isize),
+					BinaryOperator::LessThanOrEqual => Some((left <= right) as isize),
+					BinaryOperator::GreaterThanOrEqual => Some((left >= right) as isize),
+					BinaryOperator::And => Some((left != 0 && right != 0) as isize),
+					BinaryOperator::Or => Some((left != 0 || right != 0) as isize),
+				};
+				let Some(result) = result else {
+					r_panic!(
+						"Constant expression `{self}` is undefined (division by zero or overflow)."
+					);
+				};
+				result
+			}
+			Expression::VariableReference(var) => {
+				r_panic!("Constant expressions cannot reference variable \"{var}\".")
+			}
+			Expression::FunctionCall {
+				function_name,
+				arguments: _,
+			} => r_panic!("Constant expressions cannot call function \"{function_name}\"."),
+			Expression::ArrayLiteral(_) | Expression::StringLiteral(_) => {
+				r_panic!("Array constant `{self}` cannot be used in arithmetic.")
+			}
+		})
+	}
+
+	/// check whether an expression has no variable references
+	pub fn is_constant(&self) -> bool {
+		match self {
+			Expression::NaturalNumber(_) => true,
+			Expression::VariableReference(_)
+			| Expression::FunctionCall {
+				function_name: _,
+				arguments: _,
+			} => false,
+			Expression::SumExpression { sign: _, summands }
+			| Expression::ArrayLiteral(summands) => summands.iter().all(|expr| expr.node.is_constant()),
+			Expression::StringLiteral(_) => true,
+			Expression::BinaryExpression {
+				operator: _,
+				left,
+				right,
+			} => left.node.is_constant() && right.node.is_constant(),
+		}
+	}
+
+	/// check whether an expression can be flattened into a sum of constants and variables,
+	/// otherwise the frontend must compute it with temporary cells
+	pub fn is_linear(&self) -> bool {
+		match self {
+			Expression::SumExpression { sign: _, summands } => {
+				summands.iter().all(|summand| summand.node.is_linear())
+			}
+			Expression::BinaryExpression {
+				operator,
+				left,
+				right,
+			} => {
+				left.node.is_linear()
+					&& right.node.is_linear()
+					&& match operator {
+						BinaryOperator::Multiply => {
+							left.node.is_constant() || right.node.is_constant()
+						}
+						_ => left.node.is_constant() && right.node.is_constant(),
+					}
+			}
+			Expression::NaturalNumber(_)
+			| Expression::VariableReference(_)
+			| Expression::ArrayLiteral(_)
+			| Expression::StringLiteral(_) => true,
+			Expression::FunctionCall {
+				function_name: _,
+				arguments: _,
+			} => false,
+		}
+	}
+
 	//Recursively Check If This Is Self Referencing
 	pub fn check_self_referencing(&self, parent: &VariableTarget) -> bool {
 		// TODO: make sure nested values work correctly
@@ -316,13 +790,54 @@ impl Expression {
 				summands,
 			} => summands
 				.iter()
-				.any(|summand| summand.check_self_referencing(parent)),
+				.any(|summand| summand.node.check_self_referencing(parent)),
 			Expression::VariableReference(var) => *var == *parent,
+			Expression::BinaryExpression {
+				operator: _,
+				left,
+				right,
+			} => {
+				left.node.check_self_referencing(parent)
+					|| right.node.check_self_referencing(parent)
+			}
+			Expression::FunctionCall {
+				function_name: _,
+				arguments,
+			} => arguments
+				.iter()
+				.any(|argument| argument.node.check_self_referencing(parent)),
 			Expression::ArrayLiteral(_)
 			| Expression::StringLiteral(_)
 			| Expression::NaturalNumber(_) => false,
 		}
 	}
+
+	/// find the span of the first reference to a variable within this expression's subexpressions,
+	/// used to point errors from flattened expressions at the variable involved
+	pub fn reference_span(&self, target: &VariableTarget) -> Option<Span> {
+		let subexpressions: Vec<&Spanned<Expression>> = match self {
+			Expression::SumExpression { sign: _, summands }
+			| Expression::ArrayLiteral(summands) => summands.iter().collect(),
+			Expression::BinaryExpression {
+				operator: _,
+				left,
+				right,
+			} => vec![left, right],
+			Expression::FunctionCall {
+				function_name: _,
+				arguments,
+			} => arguments.iter().collect(),
+			Expression::VariableReference(_)
+			| Expression::NaturalNumber(_)
+			| Expression::StringLiteral(_) => vec![],
+		};
+		subexpressions
+			.into_iter()
+			.find_map(|subexpression| match &subexpression.node {
+				Expression::VariableReference(var) if var == target => Some(subexpression.span),
+				node => node.reference_span(target),
+			})
+	}
 }
 
 impl std::fmt::Display for Expression {
@@ -340,7 +855,7 @@ impl std::fmt::Display for Expression {
 					f.write_fmt(format_args!("{first_expr}"))?;
 					for expr in summands_iter {
 						f.write_str(" ")?;
-						match expr {
+						match expr.node {
 							Expression::SumExpression {
 								sign: Sign::Negative,
 								summands: _,
@@ -361,8 +876,38 @@ impl std::fmt::Display for Expression {
 				f.write_fmt(format_args!("[{}]", expressions.iter().join(", ")))?;
 			}
 			Expression::StringLiteral(s) => f.write_fmt(format_args!("\"{s}\""))?,
+			Expression::BinaryExpression {
+				operator,
+				left,
+				right,
+			} => f.write_fmt(format_args!("({left} {operator} {right})"))?,
+			Expression::FunctionCall {
+				function_name,
+				arguments,
+			} => f.write_fmt(format_args!(
+				"{function_name}({})",
+				arguments.iter().join(", ")
+			))?,
 		}
 
 		Ok(())
 	}
 }
+
+impl std::fmt::Display for BinaryOperator {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			BinaryOperator::Multiply => "*",
+			BinaryOperator::Divide => "/",
+			BinaryOperator::Modulo => "%",
+			BinaryOperator::Equals => "==",
+			BinaryOperator::NotEquals => "!=",
+			BinaryOperator::LessThan => "<",
+			BinaryOperator::GreaterThan => ">",
+			BinaryOperator::LessThanOrEqual => "<=",
+			BinaryOperator::GreaterThanOrEqual => ">=",
+			BinaryOperator::And => "and",
+			BinaryOperator::Or => "or",
+		})
+	}
+}
@@ -0,0 +1,700 @@
+use super::{
+	parser::parse_program,
+	tokens::{next_token, next_token_or_comment, SourceCursor, Token},
+	types::{Clause, ExtendedOpcode},
+};
+use crate::{
+	backend::bf2d::{Opcode2D, TapeCell2D},
+	diagnostics::{Diagnostic, Diagnostics, Span, Spanned},
+	preprocessor::{expand_file, ExpandedFile},
+};
+
+use std::{iter::Peekable, slice::Iter};
+
+/// the indentation of each nested block, matching the standard library
+const INDENT: &str = "  ";
+
+/// Format a Mastermind source file: one clause per line, blocks indented and consistent spacing between tokens.
+/// The parsed clauses decide the layout, but the tokens within each clause are copied from the source,
+/// because the parser substitutes constants and character literals which should be kept as they were written.
+/// Comments and preprocessor directives are kept, and code in inactive `#ifdef`/`#ifndef` blocks is kept as it was written.
+/// Returns the syntax errors instead if the file cannot be parsed
+pub fn format_program(raw: &str) -> Result<String, Diagnostics> {
+	// the file is parsed with its defines substituted, but formatted as it was written
+	let file = expand_file(raw);
+	// 2D programs are a superset of 1D programs, so both can be formatted
+	let clauses = parse_program::<TapeCell2D, Opcode2D>(&file.expanded).map_err(|errors| {
+		Diagnostics(
+			errors
+				.0
+				.into_iter()
+				.map(|mut error| {
+					error.span = error.span.map(|span| {
+						Span::new(file.source_offset(span.start), file.source_offset(span.end))
+					});
+					error
+				})
+				.collect(),
+		)
+	})?;
+
+	let chars: Vec<char> = file.source.chars().collect();
+	let mut formatter = Formatter {
+		source: &file.source,
+		expanded_file: &file,
+		chars: SourceCursor::new(&chars),
+		hidden_lines: file.hidden_lines.iter().peekable(),
+		output: String::new(),
+		indent: 0,
+		continuation: false,
+		block_start: false,
+		newlines: 0,
+		previous: None,
+		previous_unary: false,
+	};
+	formatter.clauses(&clauses)?;
+	// comments at the end of the file
+	formatter.trivia()?;
+
+	let mut output = formatter.output.trim_end().to_owned();
+	if !output.is_empty() {
+		output.push('\n');
+	}
+	Ok(output)
+}
+
+struct Formatter<'a> {
+	/// the source code with directives and inactive conditional blocks hidden, the same length as the original
+	source: &'a str,
+	/// used to find where the clauses parsed from the expanded source end in the original
+	expanded_file: &'a ExpandedFile,
+	chars: SourceCursor<'a>,
+	/// directives and lines in inactive conditional blocks which have not been copied to the output yet
+	hidden_lines: Peekable<Iter<'a, (usize, String)>>,
+	output: String,
+	indent: usize,
+	/// whether a line break now would split a clause, continuation lines are indented once more
+	continuation: bool,
+	/// whether nothing has been written since the opening brace of a block, blank lines are not kept here
+	block_start: bool,
+	/// the number of line breaks in the source before the next token
+	newlines: usize,
+	/// the last token written, used to decide spacing
+	previous: Option<Token>,
+	/// whether the last token written was a negative sign or a spread `*`, rather than a binary operator
+	previous_unary: bool,
+}
+
+type Block = [Spanned<Clause<TapeCell2D, Opcode2D>>];
+
+impl Formatter<'_> {
+	fn clauses(&mut self, clauses: &Block) -> Result<(), Diagnostic> {
+		for clause in clauses {
+			self.clause(clause)?;
+		}
+		Ok(())
+	}
+
+	fn clause(&mut self, clause: &Spanned<Clause<TapeCell2D, Opcode2D>>) -> Result<(), Diagnostic> {
+		if let Clause::None = clause.node {
+			// function call clauses are followed by an empty clause for their semicolon,
+			// so semicolons are kept at the end of the previous line
+			self.token()?;
+			self.continuation = false;
+			return Ok(());
+		}
+		self.start_line()?;
+
+		match &clause.node {
+			Clause::Block(block) => self.block(block)?,
+			Clause::If {
+				condition: _,
+				if_block: block,
+			}
+			| Clause::IfNot {
+				condition: _,
+				if_not_block: block,
+			}
+			| Clause::While {
+				condition: _,
+				block,
+			}
+			| Clause::DrainLoop {
+				source: _,
+				targets: _,
+				block: Some(block),
+				is_copying: _,
+			}
+			| Clause::DefineFunction {
+				name: _,
+				arguments: _,
+				return_type: _,
+				block,
+			} => {
+				self.tokens_before_block()?;
+				self.block(block)?;
+			}
+			Clause::IfElse {
+				condition: _,
+				if_block,
+				else_block,
+			}
+			| Clause::IfNotElse {
+				condition: _,
+				if_not_block: if_block,
+				else_block,
+			} => {
+				self.tokens_before_block()?;
+				self.block(if_block)?;
+				// `else`
+				self.token()?;
+				self.block(else_block)?;
+			}
+			Clause::DefineStruct { name: _, fields } => {
+				self.tokens_before_block()?;
+				self.struct_fields(fields.len())?;
+			}
+			Clause::Brainfuck {
+				location_specifier: _,
+				clobbered_variables: _,
+				operations,
+			} => {
+				self.tokens_before_block()?;
+				self.brainfuck(operations)?;
+			}
+			_ => self.tokens_until(self.expanded_file.source_offset(clause.span.end))?,
+		}
+
+		self.continuation = false;
+		Ok(())
+	}
+
+	/// a block of clauses, each on their own line
+	fn block(&mut self, clauses: &Block) -> Result<(), Diagnostic> {
+		self.open_block()?;
+		self.clauses(clauses)?;
+		self.close_block()
+	}
+
+	/// the fields of a struct definition, each on their own line
+	fn struct_fields(&mut self, field_count: usize) -> Result<(), Diagnostic> {
+		self.open_block()?;
+		for _ in 0..field_count {
+			self.start_line()?;
+			while self.token()? != Token::Semicolon {}
+		}
+		self.close_block()
+	}
+
+	/// in-line Brainfuck, opcodes are kept on the lines they were written on without any spaces,
+	/// lines are indented by the depth of the loops they are in, and nested Mastermind blocks are put on their own lines
+	fn brainfuck(
+		&mut self,
+		operations: &[ExtendedOpcode<TapeCell2D, Opcode2D>],
+	) -> Result<(), Diagnostic> {
+		let mut blocks = operations.iter().filter_map(|operation| match operation {
+			ExtendedOpcode::Block(block) => Some(block),
+			ExtendedOpcode::Opcode(_) => None,
+		});
+
+		self.open_block()?;
+		let mut line_started = false;
+		let mut loop_depth = 0;
+		loop {
+			self.trivia()?;
+			if self.newlines > 0 {
+				self.line_break(self.newlines);
+				line_started = false;
+			}
+			match self.chars.first() {
+				Some('{') => {
+					self.newline();
+					self.continuation = false;
+					self.block(blocks.next().unwrap())?;
+					self.newline();
+					line_started = false;
+				}
+				Some('}') | None => break,
+				Some(c) => {
+					// loops can be unbalanced within in-line Brainfuck
+					if *c == ']' && loop_depth > 0 {
+						loop_depth -= 1;
+						self.indent -= 1;
+					}
+					if !line_started {
+						self.newline();
+						self.continuation = false;
+						line_started = true;
+					} else if self.output.ends_with("*/") {
+						self.output.push(' ');
+					}
+					self.write(&c.to_string());
+					self.chars.advance(1);
+					if *c == '[' {
+						loop_depth += 1;
+						self.indent += 1;
+					}
+				}
+			}
+		}
+		self.indent -= loop_depth;
+		self.close_block()
+	}
+
+	/// start a clause or struct field on a new line, keeping a blank line before it if there was one in the source
+	fn start_line(&mut self) -> Result<(), Diagnostic> {
+		self.trivia()?;
+		self.line_break(self.newlines);
+		self.continuation = false;
+		self.previous = None;
+		Ok(())
+	}
+
+	fn open_block(&mut self) -> Result<(), Diagnostic> {
+		// `{`
+		self.token()?;
+		self.indent += 1;
+		self.continuation = false;
+		self.block_start = true;
+		Ok(())
+	}
+
+	fn close_block(&mut self) -> Result<(), Diagnostic> {
+		// comments at the end of the block
+		self.trivia()?;
+		self.indent -= 1;
+		if !self.block_start {
+			self.newline();
+		}
+		self.continuation = false;
+		// `}`
+		self.token()?;
+		Ok(())
+	}
+
+	/// copy the tokens up to the opening brace of a block
+	fn tokens_before_block(&mut self) -> Result<(), Diagnostic> {
+		loop {
+			let mut s = self.chars;
+			if let Token::LeftBrace = next_token(&mut s)? {
+				return Ok(());
+			}
+			self.token()?;
+		}
+	}
+
+	/// copy the tokens up to an offset in the source, i.e. the end of a clause
+	fn tokens_until(&mut self, end: usize) -> Result<(), Diagnostic> {
+		loop {
+			let mut s = self.chars;
+			next_token(&mut s)?;
+			if s.last_span().start >= end {
+				return Ok(());
+			}
+			self.token()?;
+		}
+	}
+
+	/// copy the next token to the output as it was written, with consistent spacing
+	fn token(&mut self) -> Result<Token, Diagnostic> {
+		self.trivia()?;
+		let token = next_token(&mut self.chars)?;
+		let span = self.chars.last_span();
+
+		if !self.at_line_start() && self.space_before(&token) {
+			self.output.push(' ');
+		}
+		self.write(&self.source[span.start..span.end]);
+		self.continuation = true;
+
+		self.previous_unary = matches!(token, Token::Minus | Token::Asterisk)
+			&& !matches!(
+				self.previous,
+				Some(
+					Token::Name(_)
+						| Token::Number(_) | Token::Character(_)
+						| Token::String(_) | Token::True
+						| Token::False | Token::RightParenthesis
+						| Token::RightSquareBracket
+				)
+			);
+		self.previous = Some(token.clone());
+		Ok(token)
+	}
+
+	fn space_before(&self, token: &Token) -> bool {
+		match (&self.previous, token) {
+			(None, _)
+			| (
+				_,
+				Token::Semicolon
+				| Token::Comma
+				| Token::Dot
+				| Token::RightParenthesis
+				| Token::RightSquareBracket,
+			)
+			| (
+				Some(
+					Token::LeftParenthesis
+					| Token::LeftSquareBracket
+					| Token::Dot
+					| Token::At
+					| Token::PlusPlus
+					| Token::MinusMinus,
+				),
+				_,
+			)
+			| (Some(Token::LeftBrace), Token::RightBrace)
+			| (Some(Token::Name(_)), Token::LeftParenthesis)
+			| (
+				Some(Token::Name(_) | Token::Cell | Token::RightSquareBracket),
+				Token::LeftSquareBracket,
+			) => false,
+			// keep negative signs apart so that they aren't read as `--`
+			(Some(Token::Minus), Token::Minus | Token::MinusMinus | Token::MinusEquals) => true,
+			(Some(Token::Minus | Token::Asterisk), _) => !self.previous_unary,
+			_ => true,
+		}
+	}
+
+	/// copy any comments and directives before the next token to the output,
+	/// and count the line breaks between them and the next token
+	fn trivia(&mut self) -> Result<(), Diagnostic> {
+		let mut newlines = 0;
+		loop {
+			let offset = self.chars.offset();
+			if let Some((_, line)) = self.hidden_lines.next_if(|(start, _)| *start <= offset) {
+				// hidden lines are always on their own line
+				self.line_break(newlines);
+				self.output.push_str(line);
+				self.output.push('\n');
+				newlines = 0;
+				continue;
+			}
+			match (self.chars.first(), self.chars.get(1)) {
+				(Some('\n'), _) => {
+					newlines += 1;
+					self.chars.advance(1);
+				}
+				(Some(c), _) if c.is_whitespace() => self.chars.advance(1),
+				(Some('/'), Some('/' | '*')) => {
+					let Token::Comment(comment) = next_token_or_comment(&mut self.chars)? else {
+						unreachable!();
+					};
+					self.comment(&comment, newlines);
+					newlines = 0;
+				}
+				_ => break,
+			}
+		}
+		self.newlines = newlines;
+		Ok(())
+	}
+
+	/// comments on the same line as code stay at the end of that line, other comments go on their own line
+	fn comment(&mut self, comment: &str, newlines: usize) {
+		let block_start = self.block_start;
+		if newlines > 0 {
+			self.line_break(newlines);
+		} else if !self.at_line_start() {
+			self.output.push(' ');
+		}
+		self.write(comment.trim_end());
+		// a comment after the opening brace of a block doesn't count as its contents
+		if newlines == 0 {
+			self.block_start = block_start;
+		}
+		if comment.starts_with("//") {
+			self.newline();
+		}
+		self.previous = Some(Token::Comment(comment.to_owned()));
+		self.previous_unary = false;
+	}
+
+	fn write(&mut self, text: &str) {
+		if self.at_line_start() {
+			let depth = self.indent + self.continuation as usize;
+			self.output.push_str(&INDENT.repeat(depth));
+		}
+		self.output.push_str(text);
+		self.block_start = false;
+	}
+
+	fn at_line_start(&self) -> bool {
+		self.output.is_empty() || self.output.ends_with('\n')
+	}
+
+	fn newline(&mut self) {
+		if !self.at_line_start() {
+			self.output.push('\n');
+		}
+	}
+
+	/// start a new line, with a blank line before it if there was at least one in the source
+	fn line_break(&mut self, newlines: usize) {
+		self.newline();
+		if newlines > 1
+			&& !self.block_start
+			&& !self.output.is_empty()
+			&& !self.output.ends_with("\n\n")
+		{
+			self.output.push('\n');
+		}
+	}
+}
+
+#[cfg(test)]
+mod formatter_tests {
+	use super::*;
+	use crate::sources::STD_LIBRARY;
+
+	fn _formatter_test(raw: &str, expected: &str) {
+		let formatted = format_program(raw).unwrap();
+		println!("{formatted}");
+		assert_eq!(formatted, expected);
+		// formatting is idempotent
+		assert_eq!(format_program(&formatted).unwrap(), formatted);
+	}
+
+	/// formatting a program must not change what it means
+	fn _round_trip_test(raw: &str) {
+		let formatted = format_program(raw).unwrap();
+		assert_eq!(format_program(&formatted).unwrap(), formatted);
+		let parse = |program: &str| {
+			let file = expand_file(program);
+			let clauses = parse_program::<TapeCell2D, Opcode2D>(&file.expanded).unwrap();
+			(
+				clauses,
+				file.hidden_lines
+					.into_iter()
+					.map(|(_, line)| line)
+					.collect::<Vec<_>>(),
+			)
+		};
+		assert_eq!(parse(&formatted), parse(raw));
+	}
+
+	#[test]
+	fn layout_1() {
+		_formatter_test(
+			"cell a=1;cell[2]   b;\n  a+=b[0]*2;output a ;",
+			"cell a = 1;
+cell[2] b;
+a += b[0] * 2;
+output a;
+",
+		);
+	}
+
+	#[test]
+	fn layout_2() {
+		_formatter_test(
+			"fn f(cell a,struct P p)->cell{if not a{return p.x;}else{a-=1;}}
+while a{drain a into b c{output'a';}
+copy -a-1 into *p;}
+
+
+{}
+",
+			"fn f(cell a, struct P p) -> cell {
+  if not a {
+    return p.x;
+  } else {
+    a -= 1;
+  }
+}
+while a {
+  drain a into b c {
+    output 'a';
+  }
+  copy -a - 1 into *p;
+}
+
+{}
+",
+		);
+	}
+
+	#[test]
+	fn layout_3() {
+		// constants, literals and parentheses are kept as they were written
+		_formatter_test(
+			"const N=4*2;struct S{cell[N] x@N;cell y;}cell c@( -1,2 ) = (N+'\\n') / 2 == true;++c;--c;output \"a\\\"b\";",
+			"const N = 4 * 2;
+struct S {
+  cell[N] x @N;
+  cell y;
+}
+cell c @(-1, 2) = (N + '\\n') / 2 == true;
+++c;
+--c;
+output \"a\\\"b\";
+",
+		);
+	}
+
+	#[test]
+	fn comments_1() {
+		_formatter_test(
+			"// header
+
+cell a; // trailing
+/* before */ cell b;
+{ // opening
+
+
+  // inside
+
+
+  output a;
+  // closing
+}
+f(a, /* inline */ b);
+f(a, // line
+b);
+// end",
+			"// header
+
+cell a; // trailing
+/* before */
+cell b;
+{ // opening
+  // inside
+
+  output a;
+  // closing
+}
+f(a, /* inline */ b);
+f(a, // line
+  b);
+// end
+",
+		);
+	}
+
+	#[test]
+	fn directives_1() {
+		_formatter_test(
+			"#pragma once\n  #include <u8>\n/*\n#not a directive\n*/\nfn f() {\n#include \"lib.mmi\"\n}\n",
+			"#pragma once
+#include <u8>
+/*
+#not a directive
+*/
+fn f() {
+#include \"lib.mmi\"
+}
+",
+		);
+	}
+
+	#[test]
+	fn directives_2() {
+		// defines are substituted to parse the file but kept in the output
+		_formatter_test(
+			"#define N 5\n#define T cell\ncell[N] a;T b=N+1;\n#undef N\nconst N=2;\noutput a[N];\n",
+			"#define N 5
+#define T cell
+cell[N] a;
+T b = N + 1;
+#undef N
+const N = 2;
+output a[N];
+",
+		);
+	}
+
+	#[test]
+	fn directives_3() {
+		// only the active conditional block is parsed, the other is kept as it was written
+		_formatter_test(
+			"#ifndef DEBUG\nconst N=1;\n#else\n  const N = 2 ;\n  output   N;\n#endif\ncell a=N;\n",
+			"#ifndef DEBUG
+const N = 1;
+#else
+  const N = 2 ;
+  output   N;
+#endif
+cell a = N;
+",
+		);
+	}
+
+	#[test]
+	fn brainfuck_1() {
+		_formatter_test(
+			"bf @a clobbers b c {+++ [ - > + < ]
+>>   {cell d=1;}<<[
+>[-]{output d;}
+]]>[} bf{}
+bf {
+	// comment
+  ++ /* x */ --
+
+  ^v
+}",
+			"bf @a clobbers b c {
+  +++[->+<]
+  >>
+  {
+    cell d = 1;
+  }
+  <<[
+    >[-]
+    {
+      output d;
+    }
+  ]]>[
+}
+bf {}
+bf {
+  // comment
+  ++ /* x */ --
+
+  ^v
+}
+",
+		);
+	}
+
+	#[test]
+	fn errors_1() {
+		assert_eq!(
+			format_program("cell a = ;\noutput a;")
+				.unwrap_err()
+				.to_string(),
+			"Expected more terms in expression."
+		);
+	}
+
+	#[test]
+	fn errors_2() {
+		// errors are reported in the source as it was written
+		let errors = format_program("#define LONG_NAME 1\ncell[LONG_NAME] a = ;").unwrap_err();
+		assert_eq!(errors.to_string(), "Expected more terms in expression.");
+		let span = errors.0[0].span.unwrap();
+		assert_eq!((span.start, span.end), (40, 41));
+	}
+
+	#[test]
+	fn round_trip_1() {
+		for (_, contents) in STD_LIBRARY {
+			_round_trip_test(contents);
+		}
+	}
+
+	#[test]
+	fn round_trip_2() {
+		for program in [
+			include_str!("../../../programs/examples/basic_calculator.mmi"),
+			include_str!("../../../programs/examples/brainfuck.mmi"),
+			include_str!("../../../programs/examples/christmas_trees.mmi"),
+			include_str!("../../../programs/examples/divisors_example.mmi"),
+			include_str!("../../../programs/examples/hello_world.mmi"),
+			include_str!("../../../programs/examples/ifp16calculator.mmi"),
+			include_str!("../../../programs/examples/prime_1_to_100.mmi"),
+		] {
+			_round_trip_test(program);
+		}
+	}
+}
@@ -1,4 +1,6 @@
 pub mod expressions;
+pub mod formatter;
+#[allow(clippy::module_inception)]
 pub mod parser;
 pub mod tokens;
 pub mod types;
@@ -1,33 +1,142 @@
 use super::{
 	expressions::Expression,
-	tokens::{next_token, Token},
+	tokens::{next_token, skip_comment, ConstantTable, SourceCursor, Token},
 	types::{
 		Clause, ExtendedOpcode, LocationSpecifier, Reference, TapeCellLocation, VariableTarget,
 		VariableTargetReferenceChain, VariableTypeReference,
 	},
 };
+use std::cell::RefCell;
+
 use crate::{
 	backend::{bf::TapeCell, bf2d::TapeCell2D, common::OpcodeVariant},
-	macros::macros::{r_assert, r_panic},
+	diagnostics::{Diagnostic, Diagnostics, Span, Spanned},
+	macros::macros::{r_assert, r_assert_at, r_panic_at},
 	parser::types::VariableTypeDefinition,
 };
 
+/// parse a whole program, returning every syntax error found rather than just the first
 pub fn parse_program<TC: TapeCellLocation, OC: OpcodeVariant>(
 	raw: &str,
-) -> Result<Vec<Clause<TC, OC>>, String> {
+) -> Result<Vec<Spanned<Clause<TC, OC>>>, Diagnostics> {
+	let (clauses, diagnostics) = parse_program_partial(raw);
+	if !diagnostics.is_empty() {
+		return Err(diagnostics);
+	}
+	Ok(clauses)
+}
+
+/// write a parsed program as JSON, for tools which need the program structure,
+/// spans are byte offsets into the preprocessed source
+pub fn ast_to_json<TC: serde::Serialize, OC: serde::Serialize>(
+	clauses: &[Spanned<Clause<TC, OC>>],
+) -> String {
+	// the syntax tree only contains strings, numbers and enums so this can't fail
+	serde_json::to_string_pretty(clauses).unwrap()
+}
+
+/// parse as much of a program as possible, returning the clauses along with any syntax errors,
+/// clauses with syntax errors are left empty, used by tools which work on programs as they are being written
+pub fn parse_program_partial<TC: TapeCellLocation, OC: OpcodeVariant>(
+	raw: &str,
+) -> (Vec<Spanned<Clause<TC, OC>>>, Diagnostics) {
 	let program_chars: Vec<char> = raw.chars().collect();
-	let mut chars_slice = &program_chars[..];
+	let constants = ConstantTable::default();
+	let diagnostics = RefCell::new(Diagnostics::default());
+	let mut chars_slice = SourceCursor::new(&program_chars)
+		.with_constants(&constants)
+		.with_diagnostics(&diagnostics);
 	let mut clauses = vec![];
-	while let Some(clause) = parse_clause(&mut chars_slice)? {
-		clauses.push(clause);
+	loop {
+		match parse_clause_recovering(&mut chars_slice) {
+			Ok(Some(clause)) => clauses.push(clause),
+			Ok(None) => break,
+			Err(err) => {
+				diagnostics.borrow_mut().push(err);
+				break;
+			}
+		}
 	}
 
-	Ok(clauses)
+	(clauses, diagnostics.into_inner().sorted())
+}
+
+/// parse a clause, if it has a syntax error then record the error and skip past the clause,
+/// leaving an empty clause in its place so that parsing can continue
+fn parse_clause_recovering<TC: TapeCellLocation, OC: OpcodeVariant>(
+	chars: &mut SourceCursor,
+) -> Result<Option<Spanned<Clause<TC, OC>>>, Diagnostic> {
+	let start = *chars;
+	match parse_clause(chars) {
+		Err(err) => {
+			*chars = start;
+			chars.report(err)?;
+			synchronise(chars);
+			Ok(Some(Spanned::new(
+				Clause::None,
+				chars.span_from(start.offset()),
+			)))
+		}
+		result => result,
+	}
 }
 
+/// skip the rest of a clause which failed to parse, stopping after the next `;` or `{...}` block (and any `else` blocks),
+/// or before a `}` which closes the enclosing block
+fn synchronise(chars: &mut SourceCursor) {
+	let start = chars.offset();
+	let mut depth = 0usize;
+	loop {
+		let mut s = *chars;
+		let Ok(token) = next_token(&mut s) else {
+			// skip over characters which cannot be tokenised
+			chars.advance(1);
+			continue;
+		};
+		match token {
+			Token::None => break,
+			Token::RightBrace if depth == 0 => {
+				// always make progress, e.g. for a stray `}` at the top level
+				if chars.offset() == start {
+					*chars = s;
+				}
+				break;
+			}
+			Token::Semicolon if depth == 0 => {
+				*chars = s;
+				break;
+			}
+			Token::LeftBrace => depth += 1,
+			Token::RightBrace => {
+				depth -= 1;
+				if depth == 0 {
+					*chars = s;
+					if let Ok(Token::Else) = next_token(&mut s) {
+						continue;
+					}
+					break;
+				}
+			}
+			_ => (),
+		}
+		*chars = s;
+	}
+}
+
+/// parse a clause along with the span of source code it covers
 fn parse_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
-	chars: &mut &[char],
-) -> Result<Option<Clause<TC, OC>>, String> {
+	chars: &mut SourceCursor,
+) -> Result<Option<Spanned<Clause<TC, OC>>>, Diagnostic> {
+	let mut s = *chars;
+	next_token(&mut s)?;
+	let start = s.last_span().start;
+	let clause = parse_clause_inner(chars)?;
+	Ok(clause.map(|clause| Spanned::new(clause, chars.span_from(start))))
+}
+
+fn parse_clause_inner<TC: TapeCellLocation, OC: OpcodeVariant>(
+	chars: &mut SourceCursor,
+) -> Result<Option<Clause<TC, OC>>, Diagnostic> {
 	let mut s = *chars;
 	// TODO: decide whether comments should be handled in the parser or not?
 	Ok(match next_token(&mut s)? {
@@ -41,19 +150,21 @@ fn parse_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 		Token::Input => Some(parse_input_clause(chars)?),
 		Token::If => Some(parse_if_else_clause(chars)?),
 		Token::While => Some(parse_while_clause(chars)?),
+		Token::Break | Token::Continue => Some(parse_loop_control_clause(chars)?),
+		Token::Return => Some(parse_return_clause(chars)?),
 		Token::Fn => Some(parse_function_definition_clause(chars)?),
 		Token::Assert => Some(parse_assert_clause(chars)?),
+		Token::Const => Some(parse_const_clause(chars)?),
 		Token::Struct => {
 			let Token::Name(_) = next_token(&mut s)? else {
-				// TODO: add source snippet
-				r_panic!("Expected identifier after `struct` keyword.");
+				r_panic_at!(s.last_span(), "Expected identifier after `struct` keyword.");
 			};
 			match next_token(&mut s)? {
 				Token::LeftBrace => Some(parse_struct_definition_clause(chars)?),
 				_ => Some(parse_let_clause(chars)?),
 			}
 		}
-		Token::Cell => Some(parse_let_clause(chars)?),
+		Token::Cell | Token::Dynamic => Some(parse_let_clause(chars)?),
 		Token::Name(_) => match next_token(&mut s)? {
 			Token::LeftParenthesis => Some(parse_function_call_clause(chars)?),
 			_ => Some(parse_assign_clause(chars)?),
@@ -63,7 +174,7 @@ fn parse_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 			*chars = s;
 			Some(Clause::AddAssign {
 				var: parse_var_target(chars)?,
-				value: Expression::NaturalNumber(1),
+				value: Spanned::new(Expression::NaturalNumber(1), s.last_span()),
 				self_referencing: false,
 			})
 		}
@@ -71,21 +182,26 @@ fn parse_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 			*chars = s;
 			Some(Clause::AddAssign {
 				var: parse_var_target(chars)?,
-				value: Expression::NaturalNumber((-1i8 as u8) as usize),
+				value: Spanned::new(
+					Expression::NaturalNumber((-1i8 as u8) as usize),
+					s.last_span(),
+				),
 				self_referencing: false,
 			})
 		}
 		Token::Bf => Some(parse_brainfuck_clause(chars)?),
-		token => r_panic!("Invalid starting token `{token}`."),
+		token => r_panic_at!(s.last_span(), "Invalid starting token `{token}`."),
 	})
 }
 
 fn parse_block_clauses<TC: TapeCellLocation, OC: OpcodeVariant>(
-	chars: &mut &[char],
-) -> Result<Vec<Clause<TC, OC>>, String> {
+	chars: &mut SourceCursor,
+) -> Result<Vec<Spanned<Clause<TC, OC>>>, Diagnostic> {
 	let Token::LeftBrace = next_token(chars)? else {
-		r_panic!("Expected `{{` in code block.");
+		r_panic_at!(chars.last_span(), "Expected `{{` in code block.");
 	};
+	// constants defined inside the block go out of scope at the end of it
+	let outer_constants = chars.constants_snapshot();
 
 	let mut clauses = vec![];
 	loop {
@@ -96,11 +212,15 @@ fn parse_block_clauses<TC: TapeCellLocation, OC: OpcodeVariant>(
 				break;
 			}
 		}
-		let Some(clause) = parse_clause(chars)? else {
-			r_panic!("Expected clause in code block. This should not occur.");
+		let Some(clause) = parse_clause_recovering(chars)? else {
+			r_panic_at!(
+				chars.last_span(),
+				"Expected clause in code block. This should not occur."
+			);
 		};
 		clauses.push(clause);
 	}
+	chars.restore_constants(outer_constants);
 
 	Ok(clauses)
 }
@@ -111,8 +231,8 @@ fn parse_block_clauses<TC: TapeCellLocation, OC: OpcodeVariant>(
 
 impl TapeCellLocation for TapeCell {
 	fn parse_location_specifier(
-		chars: &mut &[char],
-	) -> Result<LocationSpecifier<TapeCell>, String> {
+		chars: &mut SourceCursor,
+	) -> Result<LocationSpecifier<TapeCell>, Diagnostic> {
 		let mut s = *chars;
 		let Token::At = next_token(&mut s)? else {
 			return Ok(LocationSpecifier::None);
@@ -121,10 +241,13 @@ impl TapeCellLocation for TapeCell {
 
 		match next_token(&mut s)? {
 			Token::Minus | Token::Number(_) => Ok(LocationSpecifier::Cell(parse_integer(chars)?)),
+			Token::Name(name) if chars.get_constant(&name).is_some() => {
+				Ok(LocationSpecifier::Cell(parse_integer(chars)?))
+			}
 			// variable location specifier:
 			Token::Name(_) => Ok(LocationSpecifier::Variable(parse_var_target(chars)?)),
-			// TODO: add source snippet
-			token => r_panic!(
+			token => r_panic_at!(
+				s.last_span(),
 				"Unexpected `{token}` found while parsing location specifier. (is 2D mode turned on?)"
 			),
 		}
@@ -138,8 +261,8 @@ impl TapeCellLocation for TapeCell {
 
 impl TapeCellLocation for TapeCell2D {
 	fn parse_location_specifier(
-		chars: &mut &[char],
-	) -> Result<LocationSpecifier<TapeCell2D>, String> {
+		chars: &mut SourceCursor,
+	) -> Result<LocationSpecifier<TapeCell2D>, Diagnostic> {
 		let mut s = *chars;
 		let Token::At = next_token(&mut s)? else {
 			return Ok(LocationSpecifier::None);
@@ -156,11 +279,16 @@ impl TapeCellLocation for TapeCell2D {
 				parse_integer(chars)?,
 				0,
 			))),
+			Token::Name(name) if chars.get_constant(&name).is_some() => Ok(
+				LocationSpecifier::Cell(TapeCell2D(parse_integer(chars)?, 0)),
+			),
 			// variable location specifier:
 			Token::Name(_) => Ok(LocationSpecifier::Variable(parse_var_target(chars)?)),
-			// TODO: add source snippet
 			token => {
-				r_panic!("Unexpected `{token}` found while parsing 2D location specifier.")
+				r_panic_at!(
+					s.last_span(),
+					"Unexpected `{token}` found while parsing 2D location specifier."
+				)
 			}
 		}
 	}
@@ -174,22 +302,66 @@ impl TapeCellLocation for TapeCell2D {
 	}
 }
 
+/// parse a type and name, e.g. `cell[4] x @2`,
+/// struct fields can share a name with a constant as they are only reachable through the struct, e.g. `s.N`
 fn parse_var_type_definition<TC: TapeCellLocation>(
-	chars: &mut &[char],
-) -> Result<VariableTypeDefinition<TC>, String> {
+	chars: &mut SourceCursor,
+	is_struct_field: bool,
+) -> Result<VariableTypeDefinition<TC>, Diagnostic> {
+	let var_type = parse_var_type_reference(chars)?;
+
+	let Token::Name(name) = next_token(chars)? else {
+		r_panic_at!(chars.last_span(), "Expected name in variable definition.");
+	};
+	r_assert_at!(
+		chars.last_span(),
+		is_struct_field || chars.get_constant(&name).is_none(),
+		"Cannot define variable \"{name}\", a constant with that name already exists."
+	);
+
+	Ok(VariableTypeDefinition {
+		var_type,
+		name,
+		location_specifier: TC::parse_location_specifier(chars)?,
+	})
+}
+
+/// parse a type without a variable name, e.g. `cell[4]`, `struct Name` or `dynamic cell[8]`
+fn parse_var_type_reference(chars: &mut SourceCursor) -> Result<VariableTypeReference, Diagnostic> {
 	let mut var_type = match next_token(chars)? {
+		Token::Dynamic => {
+			let start_span = chars.last_span();
+			let VariableTypeReference::Array(element_type, len) = parse_var_type_reference(chars)?
+			else {
+				r_panic_at!(
+					start_span,
+					"Dynamic arrays must be arrays of cells, e.g. `dynamic cell[8]`."
+				);
+			};
+			let VariableTypeReference::Cell = *element_type else {
+				r_panic_at!(
+					start_span,
+					"Dynamic arrays must be arrays of cells, e.g. `dynamic cell[8]`."
+				);
+			};
+			return Ok(VariableTypeReference::DynamicArray(len));
+		}
 		Token::Cell => VariableTypeReference::Cell,
 		Token::Struct => {
 			let Token::Name(struct_name) = next_token(chars)? else {
-				// TODO: add source snippet
-				r_panic!("Expected struct type name in variable definition.");
+				r_panic_at!(
+					chars.last_span(),
+					"Expected struct type name in variable definition."
+				);
 			};
 
 			VariableTypeReference::Struct(struct_name)
 		}
 		token => {
-			// TODO: add source snippet
-			r_panic!("Unexpected `{token}` found in variable type definition.");
+			r_panic_at!(
+				chars.last_span(),
+				"Unexpected `{token}` found in variable type definition."
+			);
 		}
 	};
 
@@ -198,42 +370,57 @@ fn parse_var_type_definition<TC: TapeCellLocation>(
 		let mut s = *chars;
 		while let Token::LeftSquareBracket = next_token(&mut s)? {
 			var_type = VariableTypeReference::Array(Box::new(var_type), parse_subscript(chars)?);
-			s = chars;
+			s = *chars;
 		}
 	}
 
-	let Token::Name(name) = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected name in variable definition.");
-	};
-
-	Ok(VariableTypeDefinition {
-		var_type,
-		name,
-		location_specifier: TC::parse_location_specifier(chars)?,
-	})
+	Ok(var_type)
 }
 
-/// parse the subscript of an array variable, e.g. [4] [6] [0]
+/// parse the subscript of an array variable, e.g. [4] [6] [0] [N]
 /// must be compile-time constant
-fn parse_subscript(chars: &mut &[char]) -> Result<usize, String> {
+pub fn parse_subscript(chars: &mut SourceCursor) -> Result<usize, Diagnostic> {
 	let Token::LeftSquareBracket = next_token(chars)? else {
-		// TODO: add program snippet
-		r_panic!("Expected `[` in array subscript.");
+		r_panic_at!(chars.last_span(), "Expected `[` in array subscript.");
 	};
-	let Token::Number(number) = next_token(chars)? else {
-		// TODO: add program snippet
-		r_panic!("Expected natural number in array subscript.");
+	let token = next_token(chars)?;
+	let Some(number) = constant_number(chars, &token)? else {
+		r_panic_at!(
+			chars.last_span(),
+			"Expected natural number in array subscript."
+		);
 	};
 	let Token::RightSquareBracket = next_token(chars)? else {
-		// TODO: add program snippet
-		r_panic!("Expected `]` in array subscript.");
+		r_panic_at!(chars.last_span(), "Expected `]` in array subscript.");
 	};
 	// TODO: handle errors here
 	Ok(number)
 }
 
-pub fn parse_var_target(chars: &mut &[char]) -> Result<VariableTarget, String> {
+/// parse the subscript of an array variable target, either a constant index or a runtime index variable, e.g. [4] [i]
+fn parse_target_subscript(chars: &mut SourceCursor) -> Result<Reference, Diagnostic> {
+	let mut s = *chars;
+	let Token::LeftSquareBracket = next_token(&mut s)? else {
+		r_panic_at!(s.last_span(), "Expected `[` in array subscript.");
+	};
+	let Token::Name(name) = next_token(&mut s)? else {
+		return Ok(Reference::Index(parse_subscript(chars)?));
+	};
+	if chars.get_constant(&name).is_some() {
+		return Ok(Reference::Index(parse_subscript(chars)?));
+	}
+
+	let Token::LeftSquareBracket = next_token(chars)? else {
+		unreachable!();
+	};
+	let index_var = parse_var_target(chars)?;
+	let Token::RightSquareBracket = next_token(chars)? else {
+		r_panic_at!(chars.last_span(), "Expected `]` in array subscript.");
+	};
+	Ok(Reference::DynamicIndex(Box::new(index_var)))
+}
+
+pub fn parse_var_target(chars: &mut SourceCursor) -> Result<VariableTarget, Diagnostic> {
 	let is_spread = {
 		let mut s = *chars;
 		if let Token::Asterisk = next_token(&mut s)? {
@@ -245,8 +432,10 @@ pub fn parse_var_target(chars: &mut &[char]) -> Result<VariableTarget, String> {
 	};
 
 	let Token::Name(base_var_name) = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected identifier in variable target identifier.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected identifier in variable target identifier."
+		);
 	};
 
 	let mut ref_chain = vec![];
@@ -254,14 +443,15 @@ pub fn parse_var_target(chars: &mut &[char]) -> Result<VariableTarget, String> {
 		let mut s = *chars;
 		match next_token(&mut s)? {
 			Token::LeftSquareBracket => {
-				let index = parse_subscript(chars)?;
-				ref_chain.push(Reference::Index(index));
+				ref_chain.push(parse_target_subscript(chars)?);
 			}
 			Token::Dot => {
 				*chars = s;
 				let Token::Name(subfield_name) = next_token(chars)? else {
-					// TODO: add source snippet
-					r_panic!("Expected subfield name in variable target identifier.");
+					r_panic_at!(
+						chars.last_span(),
+						"Expected subfield name in variable target identifier."
+					);
 				};
 				ref_chain.push(Reference::NamedField(subfield_name));
 			}
@@ -271,7 +461,7 @@ pub fn parse_var_target(chars: &mut &[char]) -> Result<VariableTarget, String> {
 
 	Ok(VariableTarget {
 		name: base_var_name,
-		subfields: if ref_chain.len() > 0 {
+		subfields: if !ref_chain.is_empty() {
 			Some(VariableTargetReferenceChain(ref_chain))
 		} else {
 			None
@@ -280,16 +470,15 @@ pub fn parse_var_target(chars: &mut &[char]) -> Result<VariableTarget, String> {
 	})
 }
 
-fn parse_integer(chars: &mut &[char]) -> Result<i32, String> {
+fn parse_integer(chars: &mut SourceCursor) -> Result<i32, Diagnostic> {
 	let mut token = next_token(chars)?;
 	let mut is_negative = false;
 	if let Token::Minus = token {
 		is_negative = true;
 		token = next_token(chars)?;
 	}
-	let Token::Number(magnitude) = token else {
-		// TODO: add source snippet
-		r_panic!("Expected integer.")
+	let Some(magnitude) = constant_number(chars, &token)? else {
+		r_panic_at!(chars.last_span(), "Expected integer.")
 	};
 	// TODO: handle errors here
 	Ok(match is_negative {
@@ -299,10 +488,30 @@ fn parse_integer(chars: &mut &[char]) -> Result<i32, String> {
 	})
 }
 
-fn parse_integer_tuple<const LENGTH: usize>(chars: &mut &[char]) -> Result<[i32; LENGTH], String> {
+/// get the value of a number token or the name of a numeric constant, returns None for any other token
+fn constant_number(chars: &SourceCursor, token: &Token) -> Result<Option<usize>, Diagnostic> {
+	Ok(match token {
+		Token::Number(number) => Some(*number),
+		Token::Name(name) => match chars.get_constant(name) {
+			Some(Expression::NaturalNumber(number)) => Some(number),
+			Some(_) => r_panic_at!(
+				chars.last_span(),
+				"Array constant \"{name}\" cannot be used as a number."
+			),
+			None => None,
+		},
+		_ => None,
+	})
+}
+
+fn parse_integer_tuple<const LENGTH: usize>(
+	chars: &mut SourceCursor,
+) -> Result<[i32; LENGTH], Diagnostic> {
 	let Token::LeftParenthesis = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected opening parenthesis in {LENGTH}-tuple.")
+		r_panic_at!(
+			chars.last_span(),
+			"Expected opening parenthesis in {LENGTH}-tuple."
+		)
 	};
 
 	let mut tuple = [0; LENGTH];
@@ -311,14 +520,15 @@ fn parse_integer_tuple<const LENGTH: usize>(chars: &mut &[char]) -> Result<[i32;
 
 		if j < LENGTH - 1 {
 			let Token::Comma = next_token(chars)? else {
-				// TODO: add source snippet
-				r_panic!("Expected comma in {LENGTH}-tuple.");
+				r_panic_at!(chars.last_span(), "Expected comma in {LENGTH}-tuple.");
 			};
 		}
 	}
 	let Token::RightParenthesis = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected closing parenthesis in {LENGTH}-tuple.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected closing parenthesis in {LENGTH}-tuple."
+		);
 	};
 
 	Ok(tuple)
@@ -329,11 +539,10 @@ fn parse_integer_tuple<const LENGTH: usize>(chars: &mut &[char]) -> Result<[i32;
 ////////////////////////////
 
 fn parse_if_else_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
-	chars: &mut &[char],
-) -> Result<Clause<TC, OC>, String> {
+	chars: &mut SourceCursor,
+) -> Result<Clause<TC, OC>, Diagnostic> {
 	let Token::If = next_token(chars)? else {
-		// TODO: add program snippet
-		r_panic!("Expected \"if\" in if-else clause.");
+		r_panic_at!(chars.last_span(), "Expected \"if\" in if-else clause.");
 	};
 
 	let is_not = {
@@ -349,7 +558,7 @@ fn parse_if_else_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 	{
 		let mut s = *chars;
 		let Token::LeftBrace = next_token(&mut s)? else {
-			r_panic!("Expected code block in if-else clause.");
+			r_panic_at!(s.last_span(), "Expected code block in if-else clause.");
 		};
 	}
 	let block_one = parse_block_clauses(chars)?;
@@ -387,50 +596,96 @@ fn parse_if_else_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 }
 
 fn parse_while_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
-	chars: &mut &[char],
-) -> Result<Clause<TC, OC>, String> {
+	chars: &mut SourceCursor,
+) -> Result<Clause<TC, OC>, Diagnostic> {
 	let Token::While = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected `while` in while clause.");
+		r_panic_at!(chars.last_span(), "Expected `while` in while clause.");
 	};
 
 	let condition = Expression::parse(chars)?;
-	// TODO: make while loops support expressions
-	let Expression::VariableReference(condition_variable) = condition else {
-		r_panic!("While clause expected variable target condition.");
-	};
 
 	{
 		let mut s = *chars;
 		let Token::LeftBrace = next_token(&mut s)? else {
-			r_panic!("Expected code block in while clause.");
+			r_panic_at!(s.last_span(), "Expected code block in while clause.");
 		};
 	}
 	let loop_block = parse_block_clauses(chars)?;
 
 	Ok(Clause::While {
-		var: condition_variable,
+		condition,
 		block: loop_block,
 	})
 }
 
+fn parse_loop_control_clause<T, O>(chars: &mut SourceCursor) -> Result<Clause<T, O>, Diagnostic> {
+	let clause = match next_token(chars)? {
+		Token::Break => Clause::Break,
+		Token::Continue => Clause::Continue,
+		token => r_panic_at!(
+			chars.last_span(),
+			"Unexpected `{token}` in loop control clause."
+		),
+	};
+
+	let Token::Semicolon = next_token(chars)? else {
+		r_panic_at!(
+			chars.last_span(),
+			"Expected semicolon at end of loop control clause."
+		);
+	};
+
+	Ok(clause)
+}
+
+fn parse_return_clause<T, O>(chars: &mut SourceCursor) -> Result<Clause<T, O>, Diagnostic> {
+	let Token::Return = next_token(chars)? else {
+		r_panic_at!(chars.last_span(), "Expected `return` in return clause.");
+	};
+
+	let value = {
+		let mut s = *chars;
+		match next_token(&mut s)? {
+			Token::Semicolon => None,
+			_ => Some(Expression::parse(chars)?),
+		}
+	};
+
+	let Token::Semicolon = next_token(chars)? else {
+		r_panic_at!(
+			chars.last_span(),
+			"Expected semicolon at end of return clause."
+		);
+	};
+
+	Ok(Clause::Return { value })
+}
+
 fn parse_function_definition_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
-	chars: &mut &[char],
-) -> Result<Clause<TC, OC>, String> {
+	chars: &mut SourceCursor,
+) -> Result<Clause<TC, OC>, Diagnostic> {
 	let Token::Fn = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected `fn` in function definition clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected `fn` in function definition clause."
+		);
 	};
 
 	let Token::Name(function_name) = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected name in function definition clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected name in function definition clause."
+		);
 	};
 
 	let Token::LeftParenthesis = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected argument list in function definition clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected argument list in function definition clause."
+		);
 	};
+	// arguments are only visible inside the function
+	let outer_constants = chars.constants_snapshot();
 	let mut arguments = vec![];
 	loop {
 		{
@@ -440,32 +695,65 @@ fn parse_function_definition_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 				break;
 			}
 		}
-		arguments.push(parse_var_type_definition(chars)?);
+		let argument = parse_var_type_definition(chars, false)?;
+		chars.define_variable(argument.name.clone());
+		arguments.push(argument);
 
 		match next_token(chars)? {
 			Token::RightParenthesis => break,
 			Token::Comma => (),
-			// TODO: add source snippet
-			_ => r_panic!("Unexpected token in function definition arguments."),
+			_ => r_panic_at!(
+				chars.last_span(),
+				"Unexpected token in function definition arguments."
+			),
 		}
 	}
 
+	// optional return type, e.g. `-> cell[4]`
+	let return_type = {
+		let mut s = *chars;
+		match next_token(&mut s)? {
+			Token::Arrow => {
+				*chars = s;
+				Some(parse_var_type_reference(chars)?)
+			}
+			_ => None,
+		}
+	};
+
+	let block = parse_block_clauses(chars)?;
+	chars.restore_constants(outer_constants);
+
 	Ok(Clause::DefineFunction {
 		name: function_name,
 		arguments,
-		block: parse_block_clauses(chars)?,
+		return_type,
+		block,
 	})
 }
 
-fn parse_function_call_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>, String> {
+fn parse_function_call_clause<T, O>(chars: &mut SourceCursor) -> Result<Clause<T, O>, Diagnostic> {
+	let (function_name, arguments) = parse_function_call(chars)?;
+
+	Ok(Clause::CallFunction {
+		function_name,
+		arguments,
+	})
+}
+
+/// parse a function name and its arguments, used in both call clauses and expressions
+pub fn parse_function_call(
+	chars: &mut SourceCursor,
+) -> Result<(String, Vec<Spanned<Expression>>), Diagnostic> {
 	let Token::Name(function_name) = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected function name in function call clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected function name in function call."
+		);
 	};
 
 	let Token::LeftParenthesis = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected `(` in function call clause.");
+		r_panic_at!(chars.last_span(), "Expected `(` in function call.");
 	};
 
 	let mut arguments = vec![];
@@ -482,43 +770,51 @@ fn parse_function_call_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>,
 		match next_token(chars)? {
 			Token::RightParenthesis => break,
 			Token::Comma => (),
-			// TODO: add source snippet
-			_ => r_panic!("Unexpected token in function call arguments."),
+			_ => r_panic_at!(
+				chars.last_span(),
+				"Unexpected token in function call arguments."
+			),
 		}
 	}
 
-	Ok(Clause::CallFunction {
-		function_name,
-		arguments,
-	})
+	Ok((function_name, arguments))
 }
 
 /// Parse tokens representing a struct definition into a clause
 fn parse_struct_definition_clause<TC: TapeCellLocation, O>(
-	chars: &mut &[char],
-) -> Result<Clause<TC, O>, String> {
+	chars: &mut SourceCursor,
+) -> Result<Clause<TC, O>, Diagnostic> {
 	let Token::Struct = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected `struct` in struct definition.");
+		r_panic_at!(chars.last_span(), "Expected `struct` in struct definition.");
 	};
 
 	let Token::Name(name) = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected name in struct definition.");
+		r_panic_at!(chars.last_span(), "Expected name in struct definition.");
 	};
 
 	let Token::LeftBrace = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected `{{` in struct clause.");
+		r_panic_at!(chars.last_span(), "Expected `{{` in struct clause.");
 	};
 
 	let mut fields = vec![];
 	loop {
-		let field = parse_var_type_definition::<TC>(chars)?;
-		fields.push(field.try_into()?);
+		let field_start = {
+			let mut s = *chars;
+			next_token(&mut s)?;
+			s.last_span().start
+		};
+		let field = parse_var_type_definition::<TC>(chars, true)?;
+		let field_span = chars.span_from(field_start);
+		fields.push(
+			field
+				.try_into()
+				.map_err(|err| Diagnostic::new(err, field_span))?,
+		);
 		let Token::Semicolon = next_token(chars)? else {
-			// TODO: add source snippet
-			r_panic!("Expected semicolon after struct definition field.");
+			r_panic_at!(
+				chars.last_span(),
+				"Expected semicolon after struct definition field."
+			);
 		};
 		{
 			let mut s = *chars;
@@ -532,72 +828,141 @@ fn parse_struct_definition_clause<TC: TapeCellLocation, O>(
 	Ok(Clause::DefineStruct { name, fields })
 }
 
+/// parse a compile-time constant definition, the value is folded immediately, e.g. `const N = 4 * 2;`
+fn parse_const_clause<T, O>(chars: &mut SourceCursor) -> Result<Clause<T, O>, Diagnostic> {
+	let Token::Const = next_token(chars)? else {
+		r_panic_at!(
+			chars.last_span(),
+			"Expected `const` in constant definition."
+		);
+	};
+	let Token::Name(name) = next_token(chars)? else {
+		r_panic_at!(
+			chars.last_span(),
+			"Expected identifier after `const` keyword."
+		);
+	};
+	let name_span = chars.last_span();
+	let Token::EqualsSign = next_token(chars)? else {
+		r_panic_at!(chars.last_span(), "Expected `=` in constant definition.");
+	};
+
+	let expr = Expression::parse(chars)?;
+	let value = Spanned::new(
+		expr.node
+			.fold_constant()
+			.map_err(|err| err.or_span(expr.span))?,
+		expr.span,
+	);
+	let Token::Semicolon = next_token(chars)? else {
+		r_panic_at!(
+			chars.last_span(),
+			"Expected semicolon after constant definition."
+		);
+	};
+
+	r_assert_at!(
+		name_span,
+		!chars.is_variable(&name),
+		"Cannot define constant \"{name}\", a variable with that name already exists."
+	);
+	r_assert_at!(
+		name_span,
+		chars.define_constant(name.clone(), value.node.clone()),
+		"Constant \"{name}\" is already defined."
+	);
+	Ok(Clause::DefineConstant { name, value })
+}
+
 /// parse variable declarations and definitions.
 /// e.g. `cell x = 0;` or `struct DummyStruct y;`
-fn parse_let_clause<TC: TapeCellLocation, O>(chars: &mut &[char]) -> Result<Clause<TC, O>, String> {
-	let var = parse_var_type_definition(chars)?;
+fn parse_let_clause<TC: TapeCellLocation, O>(
+	chars: &mut SourceCursor,
+) -> Result<Clause<TC, O>, Diagnostic> {
+	let var = parse_var_type_definition(chars, false)?;
+	chars.define_variable(var.name.clone());
 
 	let mut s = *chars;
 	if let Token::EqualsSign = next_token(&mut s)? {
 		*chars = s;
 		let expr = Expression::parse(chars)?;
 		let Token::Semicolon = next_token(chars)? else {
-			r_panic!("Expected semicolon after variable definition.");
+			r_panic_at!(
+				chars.last_span(),
+				"Expected semicolon after variable definition."
+			);
 		};
 		return Ok(Clause::DefineVariable { var, value: expr });
 	}
 	let Token::Semicolon = next_token(chars)? else {
-		r_panic!("Expected semicolon after variable declaration.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected semicolon after variable declaration."
+		);
 	};
 	Ok(Clause::DeclareVariable { var })
 }
 
-fn parse_output_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>, String> {
+fn parse_output_clause<T, O>(chars: &mut SourceCursor) -> Result<Clause<T, O>, Diagnostic> {
 	let Token::Output = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected keyword `output` in output clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected keyword `output` in output clause."
+		);
 	};
 
 	let expr = Expression::parse(chars)?;
 
 	let Token::Semicolon = next_token(chars)? else {
-		r_panic!("Expected semicolon at end of output clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected semicolon at end of output clause."
+		);
 	};
 
 	Ok(Clause::Output { value: expr })
 }
 
-fn parse_input_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>, String> {
+fn parse_input_clause<T, O>(chars: &mut SourceCursor) -> Result<Clause<T, O>, Diagnostic> {
 	let Token::Input = next_token(chars)? else {
-		// TODO: add source snippet
-		r_panic!("Expected keyword `input` in input clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected keyword `input` in input clause."
+		);
 	};
 
 	let var = parse_var_target(chars)?;
 
 	let Token::Semicolon = next_token(chars)? else {
-		r_panic!("Expected semicolon at end of input clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected semicolon at end of input clause."
+		);
 	};
 
 	Ok(Clause::Input { var })
 }
 
-fn parse_assign_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>, String> {
+fn parse_assign_clause<T, O>(chars: &mut SourceCursor) -> Result<Clause<T, O>, Diagnostic> {
 	let var = parse_var_target(chars)?;
 
 	let operator = next_token(chars)?;
 	match operator {
 		Token::EqualsSign | Token::PlusEquals | Token::MinusEquals => (),
-		token => r_panic!("Unexpected `{token}` in assignment clause."),
+		token => r_panic_at!(
+			chars.last_span(),
+			"Unexpected `{token}` in assignment clause."
+		),
 	}
 
 	let expr = Expression::parse(chars)?;
 
 	// TODO: refactor this, at the very least make it nuanced per-cell, as this was added before subfields were added
-	let self_referencing = expr.check_self_referencing(&var);
+	let self_referencing = expr.node.check_self_referencing(&var);
 
 	let Token::Semicolon = next_token(chars)? else {
-		r_panic!(
+		r_panic_at!(
+			chars.last_span(),
 			"Expected semicolon at end of {} clause.",
 			match operator {
 				Token::EqualsSign => "assignment",
@@ -621,7 +986,7 @@ fn parse_assign_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>, String
 		},
 		Token::MinusEquals => Clause::AddAssign {
 			var,
-			value: expr.flipped_sign()?,
+			value: Expression::flipped_sign(expr)?,
 			self_referencing,
 		},
 		_ => unreachable!(),
@@ -633,12 +998,15 @@ fn parse_assign_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>, String
 /// `drain g into j;`
 /// `copy foo into bar {g += 2; etc;};`
 fn parse_drain_copy_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
-	chars: &mut &[char],
-) -> Result<Clause<TC, OC>, String> {
+	chars: &mut SourceCursor,
+) -> Result<Clause<TC, OC>, Diagnostic> {
 	let is_copying = match next_token(chars)? {
 		Token::Copy => true,
 		Token::Drain => false,
-		token => r_panic!("Unexpected `{token}` in drain/copy clause."),
+		token => r_panic_at!(
+			chars.last_span(),
+			"Unexpected `{token}` in drain/copy clause."
+		),
 	};
 
 	let source = Expression::parse(chars)?;
@@ -669,7 +1037,7 @@ fn parse_drain_copy_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 				*chars = s;
 				None
 			}
-			token => r_panic!("Unexpected `{token}` in drain/copy clause."),
+			token => r_panic_at!(s.last_span(), "Unexpected `{token}` in drain/copy clause."),
 		}
 	};
 
@@ -681,9 +1049,9 @@ fn parse_drain_copy_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 	})
 }
 
-fn parse_assert_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>, String> {
+fn parse_assert_clause<T, O>(chars: &mut SourceCursor) -> Result<Clause<T, O>, Diagnostic> {
 	let Token::Assert = next_token(chars)? else {
-		r_panic!("Expected `assert` in assert clause.");
+		r_panic_at!(chars.last_span(), "Expected `assert` in assert clause.");
 	};
 
 	let var = parse_var_target(chars)?;
@@ -691,21 +1059,27 @@ fn parse_assert_clause<T, O>(chars: &mut &[char]) -> Result<Clause<T, O>, String
 	let value = match next_token(chars)? {
 		Token::Unknown => None,
 		Token::Equals => Some(Expression::parse(chars)?),
-		token => r_panic!("Unexpected `{token}` in assert clause."),
+		token => r_panic_at!(chars.last_span(), "Unexpected `{token}` in assert clause."),
 	};
 
 	let Token::Semicolon = next_token(chars)? else {
-		r_panic!("Expected semicolon at end of assert clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected semicolon at end of assert clause."
+		);
 	};
 
 	Ok(Clause::AssertVariableValue { var, value })
 }
 
 fn parse_brainfuck_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
-	chars: &mut &[char],
-) -> Result<Clause<TC, OC>, String> {
+	chars: &mut SourceCursor,
+) -> Result<Clause<TC, OC>, Diagnostic> {
 	let Token::Bf = next_token(chars)? else {
-		r_panic!("Expected `bf` in in-line Brainfuck clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected `bf` in in-line Brainfuck clause."
+		);
 	};
 
 	let location_specifier = TC::parse_location_specifier(chars)?;
@@ -728,17 +1102,24 @@ fn parse_brainfuck_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 	}
 
 	let Token::LeftBrace = next_token(chars)? else {
-		r_panic!("Expected `{{` in in-line Brainfuck clause.");
+		r_panic_at!(
+			chars.last_span(),
+			"Expected `{{` in in-line Brainfuck clause."
+		);
 	};
 
 	// tokenise and parse in-line brainfuck:
 	// totally different tokenisation to mastermind
 	let mut operations = vec![];
 	loop {
-		match chars.get(0) {
+		let char_span = Span::new(
+			chars.offset(),
+			chars.offset() + chars.first().map_or(0, |c| c.len_utf8()),
+		);
+		match chars.first() {
 			Some(c) => match OC::try_from_char(*c) {
 				Some(opcode) => {
-					*chars = &chars[1..];
+					chars.advance(1);
 					operations.push(ExtendedOpcode::Opcode(opcode));
 				}
 				None => match c {
@@ -747,18 +1128,18 @@ fn parse_brainfuck_clause<TC: TapeCellLocation, OC: OpcodeVariant>(
 						operations.push(ExtendedOpcode::Block(parse_block_clauses(chars)?));
 					}
 					'}' => {
-						*chars = &chars[1..];
+						chars.advance(1);
 						break;
 					}
 					c if c.is_whitespace() => {
-						*chars = &chars[1..];
+						chars.advance(1);
 					}
-					c => r_panic!("Unexpected character `{c}` in Brainfuck clause."),
+					'/' if skip_comment(chars)? => (),
+					c => r_panic_at!(char_span, "Unexpected character `{c}` in Brainfuck clause."),
 				},
 			},
 			None => {
-				// TODO: add source snippet
-				r_panic!("Unexpected end of file in Brainfuck clause.");
+				r_panic_at!(char_span, "Unexpected end of file in Brainfuck clause.");
 			}
 		}
 	}
@@ -1,24 +1,37 @@
 #[cfg(test)]
 mod parser_tests {
 	use super::super::{
-		expressions::{Expression, Sign},
-		parser::parse_program,
+		expressions::{BinaryOperator, Expression, Sign},
+		parser::{ast_to_json, parse_program},
 		types::{
-			Clause, ExtendedOpcode, LocationSpecifier, VariableTarget, VariableTypeDefinition,
-			VariableTypeReference,
+			Clause, ExtendedOpcode, LocationSpecifier, Reference, VariableTarget,
+			VariableTargetReferenceChain, VariableTypeDefinition, VariableTypeReference,
 		},
 	};
-	use crate::backend::{
-		bf::{Opcode, TapeCell},
-		bf2d::{Opcode2D, TapeCell2D},
+	use crate::{
+		backend::{
+			bf::{Opcode, TapeCell},
+			bf2d::{Opcode2D, TapeCell2D},
+		},
+		diagnostics::Span,
 	};
 
 	fn _parser_test(raw: &str, expected: &[Clause<TapeCell, Opcode>]) {
-		assert_eq!(parse_program(raw).unwrap(), expected);
+		let clauses: Vec<_> = parse_program(raw)
+			.unwrap()
+			.into_iter()
+			.map(|clause| clause.node)
+			.collect();
+		assert_eq!(clauses, expected);
 	}
 
 	fn _parser_test_2d(raw: &str, expected: &[Clause<TapeCell2D, Opcode2D>]) {
-		assert_eq!(parse_program(raw).unwrap(), expected);
+		let clauses: Vec<_> = parse_program(raw)
+			.unwrap()
+			.into_iter()
+			.map(|clause| clause.node)
+			.collect();
+		assert_eq!(clauses, expected);
 	}
 
 	#[test]
@@ -26,8 +39,8 @@ mod parser_tests {
 		_parser_test(
 			"if true {{}}",
 			&[Clause::If {
-				condition: Expression::NaturalNumber(1),
-				if_block: vec![Clause::<TapeCell, Opcode>::Block(vec![])],
+				condition: Expression::NaturalNumber(1).into(),
+				if_block: vec![Clause::<TapeCell, Opcode>::Block(vec![]).into()],
 			}],
 		);
 	}
@@ -53,16 +66,237 @@ mod parser_tests {
 		_parser_test(
 			"while x {{}}",
 			&[Clause::While {
-				var: VariableTarget {
+				condition: Expression::VariableReference(VariableTarget {
 					name: String::from("x"),
 					subfields: None,
 					is_spread: false,
-				},
-				block: vec![Clause::Block(vec![])],
+				})
+				.into(),
+				block: vec![Clause::Block(vec![]).into()],
 			}],
 		);
 	}
 
+	#[test]
+	fn while_condition_2() {
+		_parser_test(
+			"while x < 5 {}",
+			&[Clause::While {
+				condition: Expression::BinaryExpression {
+					operator: BinaryOperator::LessThan,
+					left: Box::new(
+						Expression::VariableReference(VariableTarget {
+							name: String::from("x"),
+							subfields: None,
+							is_spread: false,
+						})
+						.into(),
+					),
+					right: Box::new(Expression::NaturalNumber(5).into()),
+				}
+				.into(),
+				block: vec![],
+			}],
+		);
+	}
+
+	#[test]
+	fn loop_control_1() {
+		_parser_test(
+			"while x { break; continue; }",
+			&[Clause::While {
+				condition: Expression::VariableReference(VariableTarget {
+					name: String::from("x"),
+					subfields: None,
+					is_spread: false,
+				})
+				.into(),
+				block: vec![Clause::Break.into(), Clause::Continue.into()],
+			}],
+		);
+	}
+
+	#[test]
+	fn loop_control_2() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("while x { break }").unwrap_err(),
+			"Expected semicolon at end of loop control clause."
+		);
+	}
+
+	#[test]
+	fn function_returns_1() {
+		_parser_test(
+			"fn f(cell x) -> cell[2] { return [x, 1]; }",
+			&[Clause::DefineFunction {
+				name: String::from("f"),
+				arguments: vec![VariableTypeDefinition {
+					name: String::from("x"),
+					var_type: VariableTypeReference::Cell,
+					location_specifier: LocationSpecifier::None,
+				}],
+				return_type: Some(VariableTypeReference::Array(
+					Box::new(VariableTypeReference::Cell),
+					2,
+				)),
+				block: vec![Clause::Return {
+					value: Some(
+						Expression::ArrayLiteral(vec![
+							Expression::VariableReference(VariableTarget {
+								name: String::from("x"),
+								subfields: None,
+								is_spread: false,
+							})
+							.into(),
+							Expression::NaturalNumber(1).into(),
+						])
+						.into(),
+					),
+				}
+				.into()],
+			}],
+		);
+	}
+
+	#[test]
+	fn function_returns_2() {
+		_parser_test(
+			"output f(2) + 1;",
+			&[Clause::Output {
+				value: Expression::SumExpression {
+					sign: Sign::Positive,
+					summands: vec![
+						Expression::FunctionCall {
+							function_name: String::from("f"),
+							arguments: vec![Expression::NaturalNumber(2).into()],
+						}
+						.into(),
+						Expression::NaturalNumber(1).into(),
+					],
+				}
+				.into(),
+			}],
+		);
+	}
+
+	#[test]
+	fn function_returns_3() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("fn f() -> { return; }").unwrap_err(),
+			"Unexpected `{` found in variable type definition."
+		);
+	}
+
+	#[test]
+	fn dynamic_arrays_1() {
+		_parser_test(
+			"dynamic cell[3] a; a[i] = 1;",
+			&[
+				Clause::DeclareVariable {
+					var: VariableTypeDefinition {
+						name: String::from("a"),
+						var_type: VariableTypeReference::DynamicArray(3),
+						location_specifier: LocationSpecifier::None,
+					},
+				},
+				Clause::Assign {
+					var: VariableTarget {
+						name: String::from("a"),
+						subfields: Some(VariableTargetReferenceChain(vec![
+							Reference::DynamicIndex(Box::new(VariableTarget {
+								name: String::from("i"),
+								subfields: None,
+								is_spread: false,
+							})),
+						])),
+						is_spread: false,
+					},
+					value: Expression::NaturalNumber(1).into(),
+					self_referencing: false,
+				},
+			],
+		);
+	}
+
+	#[test]
+	fn dynamic_arrays_2() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("dynamic struct S[4] a;").unwrap_err(),
+			"Dynamic arrays must be arrays of cells, e.g. `dynamic cell[8]`."
+		);
+	}
+
+	#[test]
+	fn constants_1() {
+		_parser_test(
+			"const N = 2 * 3; cell[N] a @N;",
+			&[
+				Clause::DefineConstant {
+					name: String::from("N"),
+					value: Expression::NaturalNumber(6).into(),
+				},
+				Clause::DeclareVariable {
+					var: VariableTypeDefinition {
+						name: String::from("a"),
+						var_type: VariableTypeReference::Array(
+							Box::new(VariableTypeReference::Cell),
+							6,
+						),
+						location_specifier: LocationSpecifier::Cell(6),
+					},
+				},
+			],
+		);
+	}
+
+	#[test]
+	fn constants_2() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("const N = 1; const N = 2;").unwrap_err(),
+			"Constant \"N\" is already defined."
+		);
+	}
+
+	#[test]
+	fn constants_3() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("const N = 1; cell N;").unwrap_err(),
+			"Cannot define variable \"N\", a constant with that name already exists."
+		);
+	}
+
+	#[test]
+	fn constants_4() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("cell x; const x = 5;").unwrap_err(),
+			"Cannot define constant \"x\", a variable with that name already exists."
+		);
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("cell x; { const x = 5; }").unwrap_err(),
+			"Cannot define constant \"x\", a variable with that name already exists."
+		);
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("fn f(cell x) { const x = 5; }").unwrap_err(),
+			"Cannot define constant \"x\", a variable with that name already exists."
+		);
+	}
+
+	#[test]
+	fn constants_5() {
+		// variables are scoped to their block or function
+		assert!(parse_program::<TapeCell, Opcode>("{ cell x; } const x = 5;").is_ok());
+		assert!(parse_program::<TapeCell, Opcode>("fn f(cell x) {} const x = 5;").is_ok());
+	}
+
+	#[test]
+	fn constants_6() {
+		// struct fields are only reachable through the struct, so they can't shadow a constant
+		assert!(parse_program::<TapeCell, Opcode>(
+			"const N = 66; struct S { cell N; cell[N] b; } struct S s; s.N = N;"
+		)
+		.is_ok());
+	}
+
 	#[test]
 	fn two_dimensional_1() {
 		assert_eq!(
@@ -150,7 +384,8 @@ mod parser_tests {
 								var_type: VariableTypeReference::Cell,
 								location_specifier: LocationSpecifier::None,
 							},
-						}]),
+						}
+						.into()]),
 						ExtendedOpcode::Opcode(Opcode::Subtract),
 					],
 				},
@@ -181,7 +416,8 @@ mod parser_tests {
 								var_type: VariableTypeReference::Cell,
 								location_specifier: LocationSpecifier::None,
 							},
-						}]),
+						}
+						.into()]),
 						ExtendedOpcode::Opcode(Opcode2D::Up),
 					],
 				},
@@ -230,7 +466,7 @@ cell[5] ggghh = "hello";
 					),
 					location_specifier: LocationSpecifier::None,
 				},
-				value: Expression::StringLiteral(String::from("hello")),
+				value: Expression::StringLiteral(String::from("hello")).into(),
 			}],
 		);
 	}
@@ -250,7 +486,7 @@ cell[0] ggghh = "";
 					),
 					location_specifier: LocationSpecifier::None,
 				},
-				value: Expression::StringLiteral(String::from("")),
+				value: Expression::StringLiteral(String::from("")).into(),
 			}],
 		);
 	}
@@ -270,7 +506,7 @@ cell[1] ggghh = "hello";
 					),
 					location_specifier: LocationSpecifier::None,
 				},
-				value: Expression::StringLiteral(String::from("hello")),
+				value: Expression::StringLiteral(String::from("hello")).into(),
 			}],
 		);
 	}
@@ -290,7 +526,7 @@ cell[6] ggghh = "hel'lo";
 					),
 					location_specifier: LocationSpecifier::None,
 				},
-				value: Expression::StringLiteral(String::from("hel'lo")),
+				value: Expression::StringLiteral(String::from("hel'lo")).into(),
 			}],
 		);
 	}
@@ -310,7 +546,7 @@ cell[7] ggghh = "\"hello\"";
 					),
 					location_specifier: LocationSpecifier::None,
 				},
-				value: Expression::StringLiteral(String::from("\"hello\"")),
+				value: Expression::StringLiteral(String::from("\"hello\"")).into(),
 			}],
 		);
 	}
@@ -330,7 +566,7 @@ cell[0] ggghh = [];
 					),
 					location_specifier: LocationSpecifier::None,
 				},
-				value: Expression::ArrayLiteral(vec![]),
+				value: Expression::ArrayLiteral(vec![]).into(),
 			}],
 		);
 	}
@@ -351,9 +587,10 @@ cell[333] arr = [45, 53];
 					location_specifier: LocationSpecifier::None,
 				},
 				value: Expression::ArrayLiteral(vec![
-					Expression::NaturalNumber(45),
-					Expression::NaturalNumber(53),
-				]),
+					Expression::NaturalNumber(45).into(),
+					Expression::NaturalNumber(53).into(),
+				])
+				.into(),
 			}],
 		);
 	}
@@ -377,12 +614,14 @@ cell[333] arr = [45 + 123, 53];
 					Expression::SumExpression {
 						sign: Sign::Positive,
 						summands: vec![
-							Expression::NaturalNumber(45),
-							Expression::NaturalNumber(123),
+							Expression::NaturalNumber(45).into(),
+							Expression::NaturalNumber(123).into(),
 						],
-					},
-					Expression::NaturalNumber(53),
-				]),
+					}
+					.into(),
+					Expression::NaturalNumber(53).into(),
+				])
+				.into(),
 			}],
 		);
 	}
@@ -406,23 +645,27 @@ cell[333] arr = [45 + 123, -(53 + 0+78-9)];
 					Expression::SumExpression {
 						sign: Sign::Positive,
 						summands: vec![
-							Expression::NaturalNumber(45),
-							Expression::NaturalNumber(123),
+							Expression::NaturalNumber(45).into(),
+							Expression::NaturalNumber(123).into(),
 						],
-					},
+					}
+					.into(),
 					Expression::SumExpression {
 						sign: Sign::Negative,
 						summands: vec![
-							Expression::NaturalNumber(53),
-							Expression::NaturalNumber(0),
-							Expression::NaturalNumber(78),
+							Expression::NaturalNumber(53).into(),
+							Expression::NaturalNumber(0).into(),
+							Expression::NaturalNumber(78).into(),
 							Expression::SumExpression {
 								sign: Sign::Negative,
-								summands: vec![Expression::NaturalNumber(9)],
-							},
+								summands: vec![Expression::NaturalNumber(9).into()],
+							}
+							.into(),
 						],
-					},
-				]),
+					}
+					.into(),
+				])
+				.into(),
 			}],
 		);
 	}
@@ -443,10 +686,11 @@ cell[3] arr = ['h', 53, (((4)))];
 					location_specifier: LocationSpecifier::None,
 				},
 				value: Expression::ArrayLiteral(vec![
-					Expression::NaturalNumber(104),
-					Expression::NaturalNumber(53),
-					Expression::NaturalNumber(4),
-				]),
+					Expression::NaturalNumber(104).into(),
+					Expression::NaturalNumber(53).into(),
+					Expression::NaturalNumber(4).into(),
+				])
+				.into(),
 			}],
 		);
 	}
@@ -467,14 +711,16 @@ struct nonsense[39] arr @-56 = ["hello!", 53, [4,5,6]];
 					location_specifier: LocationSpecifier::Cell(-56),
 				},
 				value: Expression::ArrayLiteral(vec![
-					Expression::StringLiteral(String::from("hello!")),
-					Expression::NaturalNumber(53),
+					Expression::StringLiteral(String::from("hello!")).into(),
+					Expression::NaturalNumber(53).into(),
 					Expression::ArrayLiteral(vec![
-						Expression::NaturalNumber(4),
-						Expression::NaturalNumber(5),
-						Expression::NaturalNumber(6),
-					]),
-				]),
+						Expression::NaturalNumber(4).into(),
+						Expression::NaturalNumber(5).into(),
+						Expression::NaturalNumber(6).into(),
+					])
+					.into(),
+				])
+				.into(),
 			}],
 		);
 	}
@@ -495,14 +741,16 @@ struct nonsense[39] arr @-56 = ["hello!", ',', [4,"hello comma: ,",6]];
 					location_specifier: LocationSpecifier::Cell(-56),
 				},
 				value: Expression::ArrayLiteral(vec![
-					Expression::StringLiteral(String::from("hello!")),
-					Expression::NaturalNumber(44),
+					Expression::StringLiteral(String::from("hello!")).into(),
+					Expression::NaturalNumber(44).into(),
 					Expression::ArrayLiteral(vec![
-						Expression::NaturalNumber(4),
-						Expression::StringLiteral(String::from("hello comma: ,")),
-						Expression::NaturalNumber(6),
-					]),
-				]),
+						Expression::NaturalNumber(4).into(),
+						Expression::StringLiteral(String::from("hello comma: ,")).into(),
+						Expression::NaturalNumber(6).into(),
+					])
+					.into(),
+				])
+				.into(),
 			}],
 		);
 	}
@@ -525,38 +773,201 @@ struct nonsense[39] arr @-56 = 56 - ( 4+3+( -7-5 +(6)-(((( (0) )))) ) );
 				value: Expression::SumExpression {
 					sign: Sign::Positive,
 					summands: vec![
-						Expression::NaturalNumber(56),
+						Expression::NaturalNumber(56).into(),
 						Expression::SumExpression {
 							sign: Sign::Negative,
 							summands: vec![
-								Expression::NaturalNumber(4),
-								Expression::NaturalNumber(3),
+								Expression::NaturalNumber(4).into(),
+								Expression::NaturalNumber(3).into(),
 								Expression::SumExpression {
 									sign: Sign::Positive,
 									summands: vec![
 										Expression::SumExpression {
 											sign: Sign::Negative,
-											summands: vec![Expression::NaturalNumber(7)],
-										},
+											summands: vec![Expression::NaturalNumber(7).into()],
+										}
+										.into(),
 										Expression::SumExpression {
 											sign: Sign::Negative,
-											summands: vec![Expression::NaturalNumber(5)],
-										},
-										Expression::NaturalNumber(6),
+											summands: vec![Expression::NaturalNumber(5).into()],
+										}
+										.into(),
+										Expression::NaturalNumber(6).into(),
 										Expression::SumExpression {
 											sign: Sign::Negative,
-											summands: vec![Expression::NaturalNumber(0)],
-										},
+											summands: vec![Expression::NaturalNumber(0).into()],
+										}
+										.into(),
 									],
-								},
+								}
+								.into(),
 							],
-						},
+						}
+						.into(),
 					],
-				},
+				}
+				.into(),
+			}],
+		);
+	}
+
+	#[test]
+	fn products_1() {
+		let var = |name: &str| {
+			Box::new(
+				Expression::VariableReference(VariableTarget {
+					name: String::from(name),
+					subfields: None,
+					is_spread: false,
+				})
+				.into(),
+			)
+		};
+		_parser_test(
+			"output a + b * 2 - c / d % 3;",
+			&[Clause::Output {
+				value: Expression::SumExpression {
+					sign: Sign::Positive,
+					summands: vec![
+						*var("a"),
+						Expression::BinaryExpression {
+							operator: BinaryOperator::Multiply,
+							left: var("b"),
+							right: Box::new(Expression::NaturalNumber(2).into()),
+						}
+						.into(),
+						Expression::SumExpression {
+							sign: Sign::Negative,
+							summands: vec![Expression::BinaryExpression {
+								operator: BinaryOperator::Modulo,
+								left: Box::new(
+									Expression::BinaryExpression {
+										operator: BinaryOperator::Divide,
+										left: var("c"),
+										right: var("d"),
+									}
+									.into(),
+								),
+								right: Box::new(Expression::NaturalNumber(3).into()),
+							}
+							.into()],
+						}
+						.into(),
+					],
+				}
+				.into(),
+			}],
+		);
+	}
+
+	#[test]
+	fn products_2() {
+		_parser_test(
+			"output (1 + 2) * -3;",
+			&[Clause::Output {
+				value: Expression::BinaryExpression {
+					operator: BinaryOperator::Multiply,
+					left: Box::new(
+						Expression::SumExpression {
+							sign: Sign::Positive,
+							summands: vec![
+								Expression::NaturalNumber(1).into(),
+								Expression::NaturalNumber(2).into(),
+							],
+						}
+						.into(),
+					),
+					right: Box::new(
+						Expression::SumExpression {
+							sign: Sign::Negative,
+							summands: vec![Expression::NaturalNumber(3).into()],
+						}
+						.into(),
+					),
+				}
+				.into(),
+			}],
+		);
+	}
+
+	#[test]
+	fn products_3() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("output 3 * ;").unwrap_err(),
+			"Unexpected token `;` found in expression."
+		);
+	}
+
+	#[test]
+	fn comparisons_1() {
+		let var = |name: &str| {
+			Box::new(
+				Expression::VariableReference(VariableTarget {
+					name: String::from(name),
+					subfields: None,
+					is_spread: false,
+				})
+				.into(),
+			)
+		};
+		_parser_test(
+			"output a == 1 or b * 2 < c + 1 and d;",
+			&[Clause::Output {
+				value: Expression::BinaryExpression {
+					operator: BinaryOperator::Or,
+					left: Box::new(
+						Expression::BinaryExpression {
+							operator: BinaryOperator::Equals,
+							left: var("a"),
+							right: Box::new(Expression::NaturalNumber(1).into()),
+						}
+						.into(),
+					),
+					right: Box::new(
+						Expression::BinaryExpression {
+							operator: BinaryOperator::And,
+							left: Box::new(
+								Expression::BinaryExpression {
+									operator: BinaryOperator::LessThan,
+									left: Box::new(
+										Expression::BinaryExpression {
+											operator: BinaryOperator::Multiply,
+											left: var("b"),
+											right: Box::new(Expression::NaturalNumber(2).into()),
+										}
+										.into(),
+									),
+									right: Box::new(
+										Expression::SumExpression {
+											sign: Sign::Positive,
+											summands: vec![
+												*var("c"),
+												Expression::NaturalNumber(1).into(),
+											],
+										}
+										.into(),
+									),
+								}
+								.into(),
+							),
+							right: var("d"),
+						}
+						.into(),
+					),
+				}
+				.into(),
 			}],
 		);
 	}
 
+	#[test]
+	fn comparisons_2() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("cell x = 1 + == 2;").unwrap_err(),
+			"Expected more terms in expression."
+		);
+	}
+
 	#[test]
 	fn empty_clauses_1() {
 		_parser_test(";", &[Clause::None]);
@@ -577,11 +988,12 @@ struct nonsense[39] arr @-56 = 56 - ( 4+3+( -7-5 +(6)-(((( (0) )))) ) );
 				Clause::None,
 				Clause::None,
 				Clause::Block(vec![
-					Clause::None,
+					Clause::None.into(),
 					Clause::Output {
-						value: Expression::NaturalNumber(3),
-					},
-					Clause::None,
+						value: Expression::NaturalNumber(3).into(),
+					}
+					.into(),
+					Clause::None.into(),
 				]),
 				Clause::None,
 				Clause::None,
@@ -597,7 +1009,7 @@ struct nonsense[39] arr @-56 = 56 - ( 4+3+( -7-5 +(6)-(((( (0) )))) ) );
 			&[
 				Clause::None,
 				Clause::None,
-				Clause::Block(vec![Clause::None, Clause::None]),
+				Clause::Block(vec![Clause::None.into(), Clause::None.into()]),
 				Clause::None,
 				Clause::None,
 			],
@@ -624,9 +1036,10 @@ struct nonsense[39] arr @-56 = 56 - ( 4+3+( -7-5 +(6)-(((( (0) )))) ) );
 			&[
 				Clause::Block(vec![]),
 				Clause::Block(vec![Clause::Block(vec![
-					Clause::Block(vec![Clause::Block(vec![])]),
-					Clause::Block(vec![]),
-				])]),
+					Clause::Block(vec![Clause::Block(vec![]).into()]).into(),
+					Clause::Block(vec![]).into(),
+				])
+				.into()]),
 				Clause::Block(vec![]),
 				Clause::Block(vec![]),
 			],
@@ -640,19 +1053,201 @@ struct nonsense[39] arr @-56 = 56 - ( 4+3+( -7-5 +(6)-(((( (0) )))) ) );
 			&[
 				Clause::Block(vec![
 					Clause::Output {
-						value: Expression::NaturalNumber(1),
-					},
+						value: Expression::NaturalNumber(1).into(),
+					}
+					.into(),
 					Clause::Output {
-						value: Expression::NaturalNumber(2),
-					},
+						value: Expression::NaturalNumber(2).into(),
+					}
+					.into(),
 				]),
 				Clause::Block(vec![Clause::Block(vec![
-					Clause::Block(vec![]),
+					Clause::Block(vec![]).into(),
 					Clause::Output {
-						value: Expression::NaturalNumber(3),
-					},
-				])]),
+						value: Expression::NaturalNumber(3).into(),
+					}
+					.into(),
+				])
+				.into()]),
 			],
 		);
 	}
+
+	#[test]
+	fn spans_1() {
+		let clauses = parse_program::<TapeCell, Opcode>("output 1;\n  cell x = 2;  {\n;}").unwrap();
+		let spans: Vec<Span> = clauses.iter().map(|clause| clause.span).collect();
+		assert_eq!(
+			spans,
+			[Span::new(0, 9), Span::new(12, 23), Span::new(25, 29)]
+		);
+	}
+
+	#[test]
+	fn spans_2() {
+		let Clause::Block(inner_clauses) =
+			&parse_program::<TapeCell, Opcode>("{ output 1; }").unwrap()[0].node
+		else {
+			panic!("Expected block clause.");
+		};
+		assert_eq!(inner_clauses[0].span, Span::new(2, 11));
+	}
+
+	#[test]
+	fn error_spans_1() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("cell[4 g;")
+				.unwrap_err()
+				.0[0]
+				.span,
+			Some(Span::new(7, 8))
+		);
+	}
+
+	#[test]
+	fn error_spans_2() {
+		assert_eq!(
+			parse_program::<TapeCell, Opcode>("output 'a';\noutput $;")
+				.unwrap_err()
+				.0[0]
+				.span,
+			Some(Span::new(19, 20))
+		);
+	}
+
+	#[test]
+	fn multiple_errors_1() {
+		let errors = parse_program::<TapeCell, Opcode>(
+			"cell a = ;\noutput a;\ncell[4 g;\nwhile a { output b }\noutput 'c';\n",
+		)
+		.unwrap_err();
+		assert_eq!(
+			errors.to_string(),
+			"Expected more terms in expression.
+Expected `]` in array subscript.
+Unexpected token `}` found in expression."
+		);
+	}
+
+	#[test]
+	fn multiple_errors_2() {
+		// errors in blocks are recovered from within the block, a stray `}` is skipped
+		let errors = parse_program::<TapeCell, Opcode>(
+			"if a { cell = 1; output a; } else { output; }\n}\nfn f() { cell; }\noutput $;",
+		)
+		.unwrap_err();
+		assert_eq!(
+			errors
+				.0
+				.iter()
+				.map(|err| err.message.as_str())
+				.collect::<Vec<_>>(),
+			[
+				"Expected name in variable definition.",
+				"Expected more terms in expression.",
+				"Invalid starting token `}`.",
+				"Expected name in variable definition.",
+				"Invalid token found: `$`.",
+			]
+		);
+	}
+
+	#[test]
+	fn multiple_errors_3() {
+		// a clause with a broken header is skipped along with its block and any else blocks
+		let errors = parse_program::<TapeCell, Opcode>(
+			"if { output 1; } else { output 2; }\nwhile a b { }\noutput 1 2;",
+		)
+		.unwrap_err();
+		assert_eq!(errors.0.len(), 3);
+	}
+
+	#[test]
+	fn expression_spans_1() {
+		let clauses = parse_program::<TapeCell, Opcode>("output a + -(b * 2);").unwrap();
+		let Clause::Output { value } = &clauses[0].node else {
+			panic!("Expected output clause.");
+		};
+		assert_eq!(value.span, Span::new(7, 19));
+		let Expression::SumExpression {
+			sign: Sign::Positive,
+			summands,
+		} = &value.node
+		else {
+			panic!("Expected sum expression.");
+		};
+		let spans: Vec<_> = summands.iter().map(|summand| summand.span).collect();
+		assert_eq!(spans, [Span::new(7, 8), Span::new(11, 19)]);
+		let Expression::SumExpression {
+			sign: Sign::Negative,
+			summands,
+		} = &summands[1].node
+		else {
+			panic!("Expected negative sum expression.");
+		};
+		assert_eq!(summands[0].span, Span::new(13, 18));
+	}
+
+	#[test]
+	fn ast_json_1() {
+		let clauses = parse_program::<TapeCell, Opcode>(
+			"cell a @2 = 'a';
+bf { + }",
+		)
+		.unwrap();
+		let json: serde_json::Value = serde_json::from_str(&ast_to_json(&clauses)).unwrap();
+		assert_eq!(
+			json,
+			serde_json::json!([
+				{
+					"node": {
+						"DefineVariable": {
+							"var": {
+								"name": "a",
+								"var_type": "Cell",
+								"location_specifier": { "Cell": 2 }
+							},
+							"value": {
+								"node": { "NaturalNumber": 97 },
+								"span": { "start": 12, "end": 15 }
+							}
+						}
+					},
+					"span": { "start": 0, "end": 16 }
+				},
+				{
+					"node": {
+						"Brainfuck": {
+							"location_specifier": "None",
+							"clobbered_variables": [],
+							"operations": [{ "Opcode": "Add" }]
+						}
+					},
+					"span": { "start": 17, "end": 25 }
+				}
+			])
+		);
+	}
+
+	#[test]
+	fn ast_json_2() {
+		let clauses = parse_program::<TapeCell2D, Opcode2D>(
+			"struct s { cell[2] x @1; }
+bf @(1, -1) { ^ }",
+		)
+		.unwrap();
+		let json: serde_json::Value = serde_json::from_str(&ast_to_json(&clauses)).unwrap();
+		assert_eq!(
+			json[0]["node"]["DefineStruct"]["fields"][0],
+			serde_json::json!({
+				"name": "x",
+				"field_type": { "Array": ["Cell", 2] },
+				"location_offset_specifier": 1
+			})
+		);
+		assert_eq!(
+			json[1]["node"]["Brainfuck"]["location_specifier"],
+			serde_json::json!({ "Cell": [1, -1] })
+		);
+	}
 }
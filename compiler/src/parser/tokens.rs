@@ -1,30 +1,246 @@
 // TODO: make an impl for a tokeniser, inverse-builder pattern?
 // have a function to peek, then accept changes, so we don't double hangle tokens
 
-use crate::macros::macros::r_panic;
+use std::{
+	cell::RefCell,
+	collections::{HashMap, HashSet},
+};
+
+use super::expressions::Expression;
+use crate::{
+	diagnostics::{Diagnostic, Diagnostics, Span, Spanned},
+	macros::macros::r_panic,
+};
+
+/// A read position in the source code, used in place of a raw `&[char]` slice so that tokens know their byte offsets.
+/// This is cheap to copy, the parser copies it to peek at tokens before accepting them
+#[derive(Debug, Clone, Copy)]
+pub struct SourceCursor<'a> {
+	chars: &'a [char],
+	/// byte offset of the first remaining character
+	offset: usize,
+	/// the span of the most recently read token, used to locate errors
+	last_span: Span,
+	/// named compile-time constants visible at this point, shared between copies of the cursor
+	constants: Option<&'a ConstantTable>,
+	/// errors recovered from so far, shared between copies of the cursor
+	diagnostics: Option<&'a RefCell<Diagnostics>>,
+}
+
+/// Compile-time constants defined with `const`, already folded to literal expressions
+pub type ConstantTable = RefCell<ScopeNames>;
+
+/// The constants and variable names visible at a point in the program,
+/// variables are tracked so that constants can't share a name with them
+#[derive(Debug, Clone, Default)]
+pub struct ScopeNames {
+	constants: HashMap<String, Expression>,
+	variables: HashSet<String>,
+}
+
+impl<'a> SourceCursor<'a> {
+	pub fn new(chars: &'a [char]) -> SourceCursor<'a> {
+		SourceCursor {
+			chars,
+			offset: 0,
+			last_span: Span::default(),
+			constants: None,
+			diagnostics: None,
+		}
+	}
+
+	/// attach a constant table so that `const` definitions can be recorded and substituted
+	pub fn with_constants(mut self, constants: &'a ConstantTable) -> SourceCursor<'a> {
+		self.constants = Some(constants);
+		self
+	}
+
+	/// attach a list of errors so that the parser can record an error and carry on parsing
+	pub fn with_diagnostics(mut self, diagnostics: &'a RefCell<Diagnostics>) -> SourceCursor<'a> {
+		self.diagnostics = Some(diagnostics);
+		self
+	}
+
+	/// record an error to recover from, returns the error instead if there is nowhere to record it
+	pub fn report(&self, diagnostic: Diagnostic) -> Result<(), Diagnostic> {
+		let Some(diagnostics) = self.diagnostics else {
+			return Err(diagnostic);
+		};
+		diagnostics.borrow_mut().push(diagnostic);
+		Ok(())
+	}
+
+	/// look up the folded value of a named constant
+	pub fn get_constant(&self, name: &str) -> Option<Expression> {
+		self.constants?.borrow().constants.get(name).cloned()
+	}
+
+	/// record a folded constant, returns false if the name is already taken
+	pub fn define_constant(&self, name: String, value: Expression) -> bool {
+		let Some(constants) = self.constants else {
+			return false;
+		};
+		let mut constants = constants.borrow_mut();
+		if constants.constants.contains_key(&name) {
+			return false;
+		}
+		constants.constants.insert(name, value);
+		true
+	}
+
+	/// check whether a variable with the given name is visible
+	pub fn is_variable(&self, name: &str) -> bool {
+		self.constants
+			.is_some_and(|c| c.borrow().variables.contains(name))
+	}
+
+	/// record a variable name so that constants can't be defined with it
+	pub fn define_variable(&self, name: String) {
+		if let Some(constants) = self.constants {
+			constants.borrow_mut().variables.insert(name);
+		}
+	}
+
+	/// copy the currently defined constants and variable names, used to scope them to blocks
+	pub fn constants_snapshot(&self) -> Option<ScopeNames> {
+		self.constants.map(|c| c.borrow().clone())
+	}
+
+	/// restore a previous set of constants and variable names when leaving a block
+	pub fn restore_constants(&self, snapshot: Option<ScopeNames>) {
+		if let (Some(constants), Some(snapshot)) = (self.constants, snapshot) {
+			*constants.borrow_mut() = snapshot;
+		}
+	}
+
+	pub fn get(&self, index: usize) -> Option<&'a char> {
+		self.chars.get(index)
+	}
+
+	pub fn first(&self) -> Option<&'a char> {
+		self.chars.first()
+	}
+
+	/// consume a number of characters
+	pub fn advance(&mut self, len: usize) {
+		assert!(len <= self.chars.len());
+		self.offset += self.chars[..len]
+			.iter()
+			.map(|c| c.len_utf8())
+			.sum::<usize>();
+		self.chars = &self.chars[len..];
+	}
+
+	pub fn offset(&self) -> usize {
+		self.offset
+	}
+
+	pub fn last_span(&self) -> Span {
+		self.last_span
+	}
+
+	/// the span between a previous offset and the current position
+	pub fn span_from(&self, start: usize) -> Span {
+		Span::new(start, self.offset)
+	}
+}
 
 /// Get the next token from chars, advance the passed in pointer
-pub fn next_token(chars: &mut &[char]) -> Result<Token, String> {
-	// skip any whitespace
+pub fn next_token(chars: &mut SourceCursor) -> Result<Token, Diagnostic> {
+	// skip any comments
 	loop {
-		match chars.get(0) {
-			Some(c) => {
-				if !c.is_whitespace() {
+		match next_token_or_comment(chars)? {
+			Token::Comment(_) => (),
+			token => return Ok(token),
+		}
+	}
+}
+
+/// Get the next token from chars, returning comments as tokens rather than skipping them,
+/// used by the formatter so that comments are kept
+pub fn next_token_or_comment(chars: &mut SourceCursor) -> Result<Token, Diagnostic> {
+	// skip any whitespace
+	while let Some(c) = chars.first() {
+		if !c.is_whitespace() {
+			break;
+		}
+		chars.advance(1);
+	}
+
+	let start = chars.offset();
+	let comment_chars = chars.chars;
+	if skip_comment(chars)? {
+		let len = comment_chars.len() - chars.chars.len();
+		chars.last_span = chars.span_from(start);
+		return Ok(Token::Comment(comment_chars[..len].iter().collect()));
+	}
+
+	let token = read_token(chars).map_err(|err| {
+		let len = chars.first().map_or(0, |c| c.len_utf8());
+		Diagnostic::new(err, Span::new(start, start + len))
+	})?;
+	chars.last_span = chars.span_from(start);
+
+	Ok(token)
+}
+
+/// Skip a `// line comment` or a `/* block comment */` if there is one at the start of chars,
+/// block comments can be nested. Comments are skipped rather than removed so source offsets stay intact
+pub fn skip_comment(chars: &mut SourceCursor) -> Result<bool, Diagnostic> {
+	match (chars.get(0), chars.get(1)) {
+		(Some('/'), Some('/')) => {
+			while let Some(c) = chars.first() {
+				if *c == '\n' {
 					break;
 				}
+				chars.advance(1);
 			}
-			None => break,
+			Ok(true)
 		}
-		*chars = &chars[1..];
+		(Some('/'), Some('*')) => {
+			let start = chars.offset();
+			chars.advance(2);
+			let mut depth = 1;
+			while depth > 0 {
+				match (chars.get(0), chars.get(1)) {
+					(Some('/'), Some('*')) => {
+						chars.advance(2);
+						depth += 1;
+					}
+					(Some('*'), Some('/')) => {
+						chars.advance(2);
+						depth -= 1;
+					}
+					(Some(_), _) => chars.advance(1),
+					(None, _) => {
+						return Err(Diagnostic::new(
+							String::from("Unterminated block comment."),
+							Span::new(start, start + 2),
+						));
+					}
+				}
+			}
+			Ok(true)
+		}
+		_ => Ok(false),
 	}
+}
 
+/// Get the next token along with its location in the source code
+pub fn next_spanned_token(chars: &mut SourceCursor) -> Result<Spanned<Token>, Diagnostic> {
+	let token = next_token(chars)?;
+	Ok(Spanned::new(token, chars.last_span()))
+}
+
+fn read_token(chars: &mut SourceCursor) -> Result<Token, String> {
 	// read the first character and branch from there
-	let Some(c) = chars.get(0) else {
+	let Some(c) = chars.first() else {
 		return Ok(Token::None);
 	};
 	Ok(match *c {
-		c @ (';' | '{' | '}' | '(' | ')' | '[' | ']' | '.' | ',' | '*' | '@' | '=' | '+' | '-') => {
-			*chars = &chars[1..];
+		c @ (';' | '{' | '}' | '(' | ')' | '[' | ']' | '.' | ',' | '*' | '/' | '%' | '@' | '='
+		| '!' | '<' | '>' | '+' | '-') => {
+			chars.advance(1);
 			match c {
 				';' => Token::Semicolon,
 				'{' => Token::LeftBrace,
@@ -36,28 +252,61 @@ pub fn next_token(chars: &mut &[char]) -> Result<Token, String> {
 				'.' => Token::Dot,
 				',' => Token::Comma,
 				'*' => Token::Asterisk,
+				'/' => Token::Slash,
+				'%' => Token::Percent,
 				'@' => Token::At,
-				'=' => Token::EqualsSign,
-				'+' => match chars.get(0) {
+				'=' => match chars.first() {
+					Some('=') => {
+						chars.advance(1);
+						Token::EqualsEquals
+					}
+					_ => Token::EqualsSign,
+				},
+				'!' => match chars.first() {
+					Some('=') => {
+						chars.advance(1);
+						Token::ExclamationEquals
+					}
+					_ => r_panic!("Expected `=` after `!`, use `not` for negation."),
+				},
+				'<' => match chars.first() {
+					Some('=') => {
+						chars.advance(1);
+						Token::LessThanEquals
+					}
+					_ => Token::LessThan,
+				},
+				'>' => match chars.first() {
+					Some('=') => {
+						chars.advance(1);
+						Token::GreaterThanEquals
+					}
+					_ => Token::GreaterThan,
+				},
+				'+' => match chars.first() {
 					Some('+') => {
-						*chars = &chars[1..];
+						chars.advance(1);
 						Token::PlusPlus
 					}
 					Some('=') => {
-						*chars = &chars[1..];
+						chars.advance(1);
 						Token::PlusEquals
 					}
 					_ => Token::Plus,
 				},
-				'-' => match chars.get(0) {
+				'-' => match chars.first() {
 					Some('-') => {
-						*chars = &chars[1..];
+						chars.advance(1);
 						Token::MinusMinus
 					}
 					Some('=') => {
-						*chars = &chars[1..];
+						chars.advance(1);
 						Token::MinusEquals
 					}
+					Some('>') => {
+						chars.advance(1);
+						Token::Arrow
+					}
 					_ => Token::Minus,
 				},
 				_ => unreachable!(),
@@ -91,6 +340,13 @@ pub fn next_token(chars: &mut &[char]) -> Result<Token, String> {
 				"unknown" => Token::Unknown,
 				"true" => Token::True,
 				"false" => Token::False,
+				"and" => Token::And,
+				"or" => Token::Or,
+				"break" => Token::Break,
+				"continue" => Token::Continue,
+				"return" => Token::Return,
+				"dynamic" => Token::Dynamic,
+				"const" => Token::Const,
 				_ => Token::Name(word),
 			}
 		}
@@ -100,9 +356,9 @@ pub fn next_token(chars: &mut &[char]) -> Result<Token, String> {
 	})
 }
 
-fn parse_number(chars: &mut &[char]) -> Result<usize, String> {
+fn parse_number(chars: &mut SourceCursor) -> Result<usize, String> {
 	// parse hexadecimal and binary
-	// if let Some('0') = chars.get(0) {
+	// if let Some('0') = chars.first() {
 	// 	match chars.get(1) {
 	// 		// Some('x') => {
 	// 		// 	let mut i = 2;
@@ -117,10 +373,7 @@ fn parse_number(chars: &mut &[char]) -> Result<usize, String> {
 	// parse decimal natural number
 	let mut i = 0;
 	let mut n = 0;
-	loop {
-		let Some(digit) = chars.get(i) else {
-			break;
-		};
+	while let Some(digit) = chars.get(i) {
 		match digit {
 			c @ ('0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9') => {
 				n *= 10;
@@ -129,29 +382,27 @@ fn parse_number(chars: &mut &[char]) -> Result<usize, String> {
 			// '_' => {
 			// 	// TODO: support underscores in number literals?
 			// }
-			';' | '{' | '}' | '(' | ')' | '[' | ']' | '.' | ',' | '*' | '@' | '+' | '-' => break,
+			';' | '{' | '}' | '(' | ')' | '[' | ']' | '.' | ',' | '*' | '/' | '%' | '@' | '+'
+			| '-' | '=' | '!' | '<' | '>' => break,
 			c if c.is_whitespace() => break,
 			'a' | 'b' | 'c' | 'd' | 'e' | 'f' | 'g' | 'h' | 'i' | 'j' | 'k' | 'l' | 'm' | 'n'
 			| 'o' | 'p' | 'q' | 'r' | 's' | 't' | 'u' | 'v' | 'w' | 'x' | 'y' | 'z' | 'A' | 'B'
 			| 'C' | 'D' | 'E' | 'F' | 'G' | 'H' | 'I' | 'J' | 'K' | 'L' | 'M' | 'N' | 'O' | 'P'
 			| 'Q' | 'R' | 'S' | 'T' | 'U' | 'V' | 'W' | 'X' | 'Y' | 'Z' | '_' => {
-				// TODO: add source snippet
 				r_panic!("Unexpected word character in number token.")
 			}
-			// TODO: add source snippet
 			_ => r_panic!("Unknown character found while parsing number token."),
 		}
 		i += 1;
 	}
 
 	// update used characters
-	assert!(i <= chars.len());
-	*chars = &chars[i..];
+	chars.advance(i);
 
 	Ok(n)
 }
 
-fn parse_word(chars: &mut &[char]) -> Result<String, String> {
+fn parse_word(chars: &mut SourceCursor) -> Result<String, String> {
 	let mut i = 0;
 	let mut parsed_word = String::new();
 
@@ -182,14 +433,13 @@ fn parse_word(chars: &mut &[char]) -> Result<String, String> {
 	}
 
 	// update used characters
-	assert!(i <= chars.len());
-	*chars = &chars[i..];
+	chars.advance(i);
 
 	Ok(parsed_word)
 }
 
 /// handle character escape sequences, supports Rust ASCII escapes
-fn parse_character_literal(chars: &mut &[char]) -> Result<char, String> {
+fn parse_character_literal(chars: &mut SourceCursor) -> Result<char, String> {
 	let mut i = 0;
 	let Some('\'') = chars.get(i) else {
 		r_panic!("Expected `'` at start of character literal.");
@@ -206,7 +456,6 @@ fn parse_character_literal(chars: &mut &[char]) -> Result<char, String> {
 					't' => '\t',
 					'\\' => '\\',
 					'0' => '\0',
-					// TODO: add source snippet
 					_ => r_panic!("Invalid escape sequence in character literal."),
 				},
 				None => r_panic!("Expected escape sequence in character literal."),
@@ -224,14 +473,13 @@ fn parse_character_literal(chars: &mut &[char]) -> Result<char, String> {
 	i += 1;
 
 	// update used characters
-	assert!(i <= chars.len());
-	*chars = &chars[i..];
+	chars.advance(i);
 
 	Ok(c)
 }
 
 /// handle string escape sequences, supports Rust ASCII escapes
-fn parse_string_literal(chars: &mut &[char]) -> Result<String, String> {
+fn parse_string_literal(chars: &mut SourceCursor) -> Result<String, String> {
 	let mut parsed_string = String::new();
 	let mut i = 0;
 	let Some('"') = chars.get(i) else {
@@ -250,7 +498,6 @@ fn parse_string_literal(chars: &mut &[char]) -> Result<String, String> {
 					Some('t') => '\t',
 					Some('\\') => '\\',
 					Some('0') => '\0',
-					// TODO: add source snippet
 					_ => r_panic!("Invalid escape sequence in string literal."),
 				});
 			}
@@ -266,8 +513,7 @@ fn parse_string_literal(chars: &mut &[char]) -> Result<String, String> {
 	i += 1;
 
 	// update used characters
-	assert!(i <= chars.len());
-	*chars = &chars[i..];
+	chars.advance(i);
 
 	Ok(parsed_string)
 }
@@ -294,6 +540,13 @@ pub enum Token {
 	Unknown,
 	True,
 	False,
+	And,
+	Or,
+	Break,
+	Continue,
+	Return,
+	Dynamic,
+	Const,
 	LeftBrace,
 	RightBrace,
 	LeftSquareBracket,
@@ -303,6 +556,8 @@ pub enum Token {
 	Comma,
 	Dot,
 	Asterisk,
+	Slash,
+	Percent,
 	At,
 	Plus,
 	Minus,
@@ -312,10 +567,19 @@ pub enum Token {
 	MinusMinus,
 	PlusEquals,
 	MinusEquals,
+	Arrow,
+	EqualsEquals,
+	ExclamationEquals,
+	LessThan,
+	GreaterThan,
+	LessThanEquals,
+	GreaterThanEquals,
 	Name(String),
 	Number(usize),
 	String(String),
 	Character(char),
+	/// a `// line comment` or `/* block comment */`, only returned by `next_token_or_comment`
+	Comment(String),
 }
 
 impl std::fmt::Display for Token {
@@ -340,6 +604,13 @@ impl std::fmt::Display for Token {
 			| Token::Unknown
 			| Token::True
 			| Token::False
+			| Token::And
+			| Token::Or
+			| Token::Break
+			| Token::Continue
+			| Token::Return
+			| Token::Dynamic
+			| Token::Const
 			| Token::LeftBrace
 			| Token::RightBrace
 			| Token::LeftSquareBracket
@@ -349,6 +620,8 @@ impl std::fmt::Display for Token {
 			| Token::Comma
 			| Token::Dot
 			| Token::Asterisk
+			| Token::Slash
+			| Token::Percent
 			| Token::At
 			| Token::Plus
 			| Token::Minus
@@ -357,7 +630,14 @@ impl std::fmt::Display for Token {
 			| Token::PlusPlus
 			| Token::MinusMinus
 			| Token::PlusEquals
-			| Token::MinusEquals => f.write_str(match self {
+			| Token::MinusEquals
+			| Token::Arrow
+			| Token::EqualsEquals
+			| Token::ExclamationEquals
+			| Token::LessThan
+			| Token::GreaterThan
+			| Token::LessThanEquals
+			| Token::GreaterThanEquals => f.write_str(match self {
 				Token::Output => "output",
 				Token::Input => "input",
 				Token::Fn => "fn",
@@ -377,6 +657,13 @@ impl std::fmt::Display for Token {
 				Token::Unknown => "unknown",
 				Token::True => "true",
 				Token::False => "false",
+				Token::And => "and",
+				Token::Or => "or",
+				Token::Break => "break",
+				Token::Continue => "continue",
+				Token::Return => "return",
+				Token::Dynamic => "dynamic",
+				Token::Const => "const",
 				Token::LeftBrace => "{",
 				Token::RightBrace => "}",
 				Token::LeftSquareBracket => "[",
@@ -386,6 +673,8 @@ impl std::fmt::Display for Token {
 				Token::Comma => ",",
 				Token::Dot => ".",
 				Token::Asterisk => "*",
+				Token::Slash => "/",
+				Token::Percent => "%",
 				Token::At => "@",
 				Token::Plus => "+",
 				Token::Minus => "-",
@@ -395,12 +684,20 @@ impl std::fmt::Display for Token {
 				Token::MinusMinus => "--",
 				Token::PlusEquals => "+=",
 				Token::MinusEquals => "-=",
+				Token::Arrow => "->",
+				Token::EqualsEquals => "==",
+				Token::ExclamationEquals => "!=",
+				Token::LessThan => "<",
+				Token::GreaterThan => ">",
+				Token::LessThanEquals => "<=",
+				Token::GreaterThanEquals => ">=",
 				_ => unreachable!(),
 			}),
 			Token::Name(name) => f.write_str(name),
 			Token::Number(number) => f.write_fmt(format_args!("{number}")),
 			Token::String(s) => f.write_fmt(format_args!("\"{s}\"")),
 			Token::Character(c) => f.write_fmt(format_args!("'{c}'")),
+			Token::Comment(comment) => f.write_str(comment),
 			Token::None => Ok(()),
 		}
 	}
@@ -410,9 +707,9 @@ impl std::fmt::Display for Token {
 mod tokeniser_tests {
 	use super::*;
 
-	fn tokenise(input_str: &str) -> Result<Vec<Token>, String> {
+	fn tokenise(input_str: &str) -> Result<Vec<Token>, Diagnostic> {
 		let chars_vec: Vec<char> = input_str.chars().collect();
-		let mut chars_slice = &chars_vec[..];
+		let mut chars_slice = SourceCursor::new(&chars_vec);
 		let mut tokens = vec![];
 		loop {
 			let token = next_token(&mut chars_slice)?;
@@ -431,6 +728,83 @@ mod tokeniser_tests {
 		assert!(actual_output.iter().eq(desired_output));
 	}
 
+	#[test]
+	fn comments_1() {
+		_tokenisation_test(
+			"output \"http://x\"; // comment\ncell/* a */b;",
+			&[
+				Token::Output,
+				Token::String(String::from("http://x")),
+				Token::Semicolon,
+				Token::Cell,
+				Token::Name(String::from("b")),
+				Token::Semicolon,
+			],
+		);
+	}
+
+	#[test]
+	fn comments_2() {
+		_tokenisation_test(
+			"a /* outer /* inner */ still a comment // */ b\n/**/c '/' /",
+			&[
+				Token::Name(String::from("a")),
+				Token::Name(String::from("b")),
+				Token::Name(String::from("c")),
+				Token::Character('/'),
+				Token::Slash,
+			],
+		);
+	}
+
+	#[test]
+	fn comments_3() {
+		let chars_vec: Vec<char> = "a\n/* /* */".chars().collect();
+		let mut chars_slice = SourceCursor::new(&chars_vec);
+		next_token(&mut chars_slice).unwrap();
+		let err = next_token(&mut chars_slice).unwrap_err();
+		assert_eq!(err, "Unterminated block comment.");
+		assert_eq!(err.span, Some(Span::new(2, 4)));
+	}
+
+	#[test]
+	fn comments_4() {
+		let chars_vec: Vec<char> = "/* é\n */ // ü\nx".chars().collect();
+		let mut chars_slice = SourceCursor::new(&chars_vec);
+		assert_eq!(
+			next_token(&mut chars_slice).unwrap(),
+			Token::Name(String::from("x"))
+		);
+		assert_eq!(chars_slice.last_span(), Span::new(16, 17));
+	}
+
+	#[test]
+	fn comments_5() {
+		let chars_vec: Vec<char> = "a; // é\n/* b /* c */ */;".chars().collect();
+		let mut chars_slice = SourceCursor::new(&chars_vec);
+		let mut tokens = vec![];
+		loop {
+			let token = next_token_or_comment(&mut chars_slice).unwrap();
+			if let Token::None = token {
+				break;
+			}
+			tokens.push((token, chars_slice.last_span()));
+		}
+		assert_eq!(
+			tokens,
+			[
+				(Token::Name(String::from("a")), Span::new(0, 1)),
+				(Token::Semicolon, Span::new(1, 2)),
+				(Token::Comment(String::from("// é")), Span::new(3, 8)),
+				(
+					Token::Comment(String::from("/* b /* c */ */")),
+					Span::new(9, 24)
+				),
+				(Token::Semicolon, Span::new(24, 25)),
+			]
+		);
+	}
+
 	#[test]
 	fn empty_1() {
 		_tokenisation_test("", &[]);
@@ -444,14 +818,14 @@ mod tokeniser_tests {
 	#[test]
 	fn empty_2() {
 		let chars_vec: Vec<char> = "".chars().collect();
-		let mut chars_slice = &chars_vec[..];
+		let mut chars_slice = SourceCursor::new(&chars_vec);
 		assert_eq!(next_token(&mut chars_slice).unwrap(), Token::None);
 	}
 
 	#[test]
 	fn empty_2a() {
 		let chars_vec: Vec<char> = "\n    \t \n  ".chars().collect();
-		let mut chars_slice = &chars_vec[..];
+		let mut chars_slice = SourceCursor::new(&chars_vec);
 		assert_eq!(next_token(&mut chars_slice).unwrap(), Token::None);
 	}
 
@@ -483,8 +857,71 @@ mod tokeniser_tests {
 			Token::Comma,
 			Token::Comma,
 		];
-		_tokenisation_test("==;;**@@[[{{((]]}}))..,,", &desired_output);
-		_tokenisation_test(" == ; ;**@ @[[ {{ ( (] ]}} )). ., ,", &desired_output);
+		_tokenisation_test("= =;;**@@[[{{((]]}}))..,,", &desired_output);
+		_tokenisation_test(" = = ; ;**@ @[[ {{ ( (] ]}} )). ., ,", &desired_output);
+	}
+
+	#[test]
+	fn single_1() {
+		_tokenisation_test(
+			"a*b/ 2%c",
+			&[
+				Token::Name(String::from("a")),
+				Token::Asterisk,
+				Token::Name(String::from("b")),
+				Token::Slash,
+				Token::Number(2),
+				Token::Percent,
+				Token::Name(String::from("c")),
+			],
+		);
+		_tokenisation_test(
+			"4*3/2%1",
+			&[
+				Token::Number(4),
+				Token::Asterisk,
+				Token::Number(3),
+				Token::Slash,
+				Token::Number(2),
+				Token::Percent,
+				Token::Number(1),
+			],
+		);
+	}
+
+	#[test]
+	fn comparisons_1() {
+		_tokenisation_test(
+			"a==b != 3<4>5<= c>=d = e and f or",
+			&[
+				Token::Name(String::from("a")),
+				Token::EqualsEquals,
+				Token::Name(String::from("b")),
+				Token::ExclamationEquals,
+				Token::Number(3),
+				Token::LessThan,
+				Token::Number(4),
+				Token::GreaterThan,
+				Token::Number(5),
+				Token::LessThanEquals,
+				Token::Name(String::from("c")),
+				Token::GreaterThanEquals,
+				Token::Name(String::from("d")),
+				Token::EqualsSign,
+				Token::Name(String::from("e")),
+				Token::And,
+				Token::Name(String::from("f")),
+				Token::Or,
+			],
+		);
+	}
+
+	#[test]
+	fn comparisons_2() {
+		assert_eq!(
+			tokenise("a ! b").unwrap_err(),
+			"Expected `=` after `!`, use `not` for negation."
+		);
 	}
 
 	#[test]
@@ -901,11 +1338,7 @@ if fn{output)true)false -while*  @copy@+=@drain-=into=][bf.cell"#,
 	fn numbers_dec_1() {
 		_tokenisation_test(
 			"1 123 000098763",
-			&[
-				Token::Number(1),
-				Token::Number(123),
-				Token::Number(000098763),
-			],
+			&[Token::Number(1), Token::Number(123), Token::Number(98763)],
 		);
 	}
 
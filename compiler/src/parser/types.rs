@@ -1,10 +1,14 @@
-use super::expressions::Expression;
-use crate::macros::macros::r_panic;
+use super::{expressions::Expression, tokens::SourceCursor};
+use crate::{
+	diagnostics::{Diagnostic, Spanned},
+	macros::macros::r_panic,
+};
 
+/// Blocks of clauses keep the span of each clause, so that later compiler errors can point at the source code
 /// Clause type type variables:
 /// - TC: TapeCell can be changed to implement 2D brainfuck, or other modifications
 /// - OC: Opcode represents the valid Brainfuck Opcodes that we're generating (also used for 2D or other BF variants)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub enum Clause<TC, OC> {
 	None,
@@ -13,41 +17,51 @@ pub enum Clause<TC, OC> {
 	},
 	DefineVariable {
 		var: VariableTypeDefinition<TC>,
-		value: Expression,
+		value: Spanned<Expression>,
 	},
 	DefineStruct {
 		name: String,
 		fields: Vec<StructFieldTypeDefinition>,
 	},
+	/// constants are substituted by the parser, this is kept so that the definition is still visible in the AST
+	DefineConstant {
+		name: String,
+		value: Spanned<Expression>,
+	},
 	AddAssign {
 		var: VariableTarget,
-		value: Expression,
+		value: Spanned<Expression>,
 		self_referencing: bool,
 	},
 	Assign {
 		var: VariableTarget,
-		value: Expression,
+		value: Spanned<Expression>,
 		self_referencing: bool,
 	},
 	AssertVariableValue {
 		var: VariableTarget,
 		// Some(constant) indicates we know the value, None indicates we don't know the value
 		// typically will either be used for assert unknown or assert 0
-		value: Option<Expression>,
+		value: Option<Spanned<Expression>>,
 	},
 	DrainLoop {
-		source: Expression,
+		source: Spanned<Expression>,
 		targets: Vec<VariableTarget>,
-		block: Option<Vec<Clause<TC, OC>>>,
+		block: Option<Vec<Spanned<Clause<TC, OC>>>>,
 		// TODO: reassess this syntax
 		is_copying: bool,
 	},
 	While {
-		var: VariableTarget,
-		block: Vec<Clause<TC, OC>>,
+		condition: Spanned<Expression>,
+		block: Vec<Spanned<Clause<TC, OC>>>,
+	},
+	Break,
+	Continue,
+	Return {
+		value: Option<Spanned<Expression>>,
 	},
 	Output {
-		value: Expression,
+		value: Spanned<Expression>,
 	},
 	Input {
 		var: VariableTarget,
@@ -56,31 +70,32 @@ pub enum Clause<TC, OC> {
 		name: String,
 		// TODO: fix the type here, as function definitions don't actually need location specifiers and therefore don't need a tape cell type
 		arguments: Vec<VariableTypeDefinition<TC>>,
-		block: Vec<Clause<TC, OC>>,
+		return_type: Option<VariableTypeReference>,
+		block: Vec<Spanned<Clause<TC, OC>>>,
 	},
 	CallFunction {
 		function_name: String,
-		arguments: Vec<Expression>,
+		arguments: Vec<Spanned<Expression>>,
 	},
 	If {
-		condition: Expression,
-		if_block: Vec<Clause<TC, OC>>,
+		condition: Spanned<Expression>,
+		if_block: Vec<Spanned<Clause<TC, OC>>>,
 	},
 	IfNot {
-		condition: Expression,
-		if_not_block: Vec<Clause<TC, OC>>,
+		condition: Spanned<Expression>,
+		if_not_block: Vec<Spanned<Clause<TC, OC>>>,
 	},
 	IfElse {
-		condition: Expression,
-		if_block: Vec<Clause<TC, OC>>,
-		else_block: Vec<Clause<TC, OC>>,
+		condition: Spanned<Expression>,
+		if_block: Vec<Spanned<Clause<TC, OC>>>,
+		else_block: Vec<Spanned<Clause<TC, OC>>>,
 	},
 	IfNotElse {
-		condition: Expression,
-		if_not_block: Vec<Clause<TC, OC>>,
-		else_block: Vec<Clause<TC, OC>>,
+		condition: Spanned<Expression>,
+		if_not_block: Vec<Spanned<Clause<TC, OC>>>,
+		else_block: Vec<Spanned<Clause<TC, OC>>>,
 	},
-	Block(Vec<Clause<TC, OC>>),
+	Block(Vec<Spanned<Clause<TC, OC>>>),
 	Brainfuck {
 		location_specifier: LocationSpecifier<TC>,
 		clobbered_variables: Vec<VariableTarget>,
@@ -88,6 +103,163 @@ pub enum Clause<TC, OC> {
 	},
 }
 
+impl<TC, OC> Clause<TC, OC> {
+	/// Whether this clause contains a `break`, `return` (or a `continue` if `include_continue`) that exits the enclosing loop,
+	/// nested loops are only searched for `return`s, function definitions are not searched
+	pub fn contains_loop_control(&self, include_continue: bool) -> bool {
+		let block_contains = |block: &[Spanned<Clause<TC, OC>>]| {
+			block
+				.iter()
+				.any(|clause| clause.node.contains_loop_control(include_continue))
+		};
+		match self {
+			Clause::Break | Clause::Return { value: _ } => true,
+			Clause::Continue => include_continue,
+			Clause::While {
+				condition: _,
+				block,
+			} => block.iter().any(|clause| clause.node.contains_return()),
+			Clause::DrainLoop {
+				source: _,
+				targets: _,
+				block: Some(block),
+				is_copying: _,
+			} => block.iter().any(|clause| clause.node.contains_return()),
+			Clause::Block(block)
+			| Clause::If {
+				condition: _,
+				if_block: block,
+			}
+			| Clause::IfNot {
+				condition: _,
+				if_not_block: block,
+			} => block_contains(block),
+			Clause::IfElse {
+				condition: _,
+				if_block,
+				else_block,
+			} => block_contains(if_block) || block_contains(else_block),
+			Clause::IfNotElse {
+				condition: _,
+				if_not_block,
+				else_block,
+			} => block_contains(if_not_block) || block_contains(else_block),
+			_ => false,
+		}
+	}
+
+	/// Whether this clause contains a `return` for the enclosing function, including inside nested loops
+	pub fn contains_return(&self) -> bool {
+		let block_contains = |block: &[Spanned<Clause<TC, OC>>]| {
+			block.iter().any(|clause| clause.node.contains_return())
+		};
+		match self {
+			Clause::Return { value: _ } => true,
+			Clause::Block(block)
+			| Clause::While {
+				condition: _,
+				block,
+			}
+			| Clause::DrainLoop {
+				source: _,
+				targets: _,
+				block: Some(block),
+				is_copying: _,
+			}
+			| Clause::If {
+				condition: _,
+				if_block: block,
+			}
+			| Clause::IfNot {
+				condition: _,
+				if_not_block: block,
+			} => block_contains(block),
+			Clause::IfElse {
+				condition: _,
+				if_block,
+				else_block,
+			} => block_contains(if_block) || block_contains(else_block),
+			Clause::IfNotElse {
+				condition: _,
+				if_not_block,
+				else_block,
+			} => block_contains(if_not_block) || block_contains(else_block),
+			_ => false,
+		}
+	}
+
+	/// Whether this clause returns from the enclosing function however it runs, loops may not run so they don't count
+	pub fn always_returns(&self) -> bool {
+		let block_returns = |block: &[Spanned<Clause<TC, OC>>]| {
+			block.iter().any(|clause| clause.node.always_returns())
+		};
+		match self {
+			Clause::Return { value: _ } => true,
+			Clause::Block(block) => block_returns(block),
+			Clause::IfElse {
+				condition: _,
+				if_block,
+				else_block,
+			} => block_returns(if_block) && block_returns(else_block),
+			Clause::IfNotElse {
+				condition: _,
+				if_not_block,
+				else_block,
+			} => block_returns(if_not_block) && block_returns(else_block),
+			_ => false,
+		}
+	}
+
+	/// The expressions evaluated exactly once when this clause runs,
+	/// function calls within these can be compiled before the clause itself
+	pub fn hoistable_expressions(&mut self) -> Vec<&mut Spanned<Expression>> {
+		match self {
+			Clause::DefineVariable { var: _, value }
+			| Clause::AddAssign {
+				var: _,
+				value,
+				self_referencing: _,
+			}
+			| Clause::Assign {
+				var: _,
+				value,
+				self_referencing: _,
+			}
+			| Clause::Output { value }
+			| Clause::Return { value: Some(value) }
+			| Clause::DrainLoop {
+				source: value,
+				targets: _,
+				block: _,
+				is_copying: _,
+			}
+			| Clause::If {
+				condition: value,
+				if_block: _,
+			}
+			| Clause::IfNot {
+				condition: value,
+				if_not_block: _,
+			}
+			| Clause::IfElse {
+				condition: value,
+				if_block: _,
+				else_block: _,
+			}
+			| Clause::IfNotElse {
+				condition: value,
+				if_not_block: _,
+				else_block: _,
+			} => vec![value],
+			Clause::CallFunction {
+				function_name: _,
+				arguments,
+			} => arguments.iter_mut().collect(),
+			_ => vec![],
+		}
+	}
+}
+
 pub trait TapeCellLocation
 where
 	Self: Sized + std::fmt::Display,
@@ -96,7 +268,9 @@ where
 	/// let g @(4,2) = 68;
 	/// or
 	/// let p @3 = 68;
-	fn parse_location_specifier(chars: &mut &[char]) -> Result<LocationSpecifier<Self>, String>;
+	fn parse_location_specifier(
+		chars: &mut SourceCursor,
+	) -> Result<LocationSpecifier<Self>, Diagnostic>;
 
 	/// safely cast a 2D or 1D location specifier into a 1D non-negative cell offset,
 	///  for use with struct fields
@@ -104,22 +278,24 @@ where
 }
 
 // extended brainfuck opcodes to include mastermind code blocks
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub enum ExtendedOpcode<TC, OC> {
 	Opcode(OC),
-	Block(Vec<Clause<TC, OC>>),
+	Block(Vec<Spanned<Clause<TC, OC>>>),
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, serde::Serialize)]
 /// the type of a variable according to the user, not validated yet as the parser does not keep track of types
 pub enum VariableTypeReference {
 	Cell,
 	Struct(String),
 	Array(Box<VariableTypeReference>, usize),
+	/// an array of cells with gap cells for runtime indexing
+	DynamicArray(usize),
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, serde::Serialize)]
 pub enum LocationSpecifier<TC> {
 	None,
 	Cell(TC),
@@ -131,7 +307,7 @@ impl<T> LocationSpecifier<T> {
 	}
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, serde::Serialize)]
 pub struct VariableTypeDefinition<TC> {
 	pub name: String,
 	pub var_type: VariableTypeReference,
@@ -139,7 +315,7 @@ pub struct VariableTypeDefinition<TC> {
 	// Infinite {name: String, pattern: ???},
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
 pub struct StructFieldTypeDefinition {
 	pub name: String,
 	pub field_type: VariableTypeReference,
@@ -189,18 +365,20 @@ must be relative, not variable."
 	}
 }
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, serde::Serialize)]
 pub enum Reference {
 	NamedField(String),
 	Index(usize),
+	/// an index into a dynamic array read from a cell at runtime
+	DynamicIndex(Box<VariableTarget>),
 }
 
 /// Represents a list of subfield references after the `.` or `[x]` operators, e.g. `obj.h[6]` would have `['h', '[6]']`
 // a bit verbose, not quite sure about this
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, serde::Serialize)]
 pub struct VariableTargetReferenceChain(pub Vec<Reference>);
 /// Represents a target variable in an expression, this has no type informatino
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, serde::Serialize)]
 pub struct VariableTarget {
 	pub name: String,
 	pub subfields: Option<VariableTargetReferenceChain>,
@@ -215,6 +393,23 @@ impl VariableTarget {
 			is_spread: false,
 		}
 	}
+
+	/// split a target ending in a runtime index into the dynamic array target and the index target, e.g. `a.b[i]` -> (`a.b`, `i`)
+	pub fn split_dynamic_index(&self) -> Option<(VariableTarget, &VariableTarget)> {
+		let VariableTargetReferenceChain(subfields) = self.subfields.as_ref()?;
+		let Some((Reference::DynamicIndex(index), array_subfields)) = subfields.split_last() else {
+			return None;
+		};
+		Some((
+			VariableTarget {
+				name: self.name.clone(),
+				subfields: (!array_subfields.is_empty())
+					.then(|| VariableTargetReferenceChain(array_subfields.to_vec())),
+				is_spread: false,
+			},
+			index,
+		))
+	}
 }
 
 impl std::fmt::Display for VariableTypeReference {
@@ -227,6 +422,9 @@ impl std::fmt::Display for VariableTypeReference {
 			VariableTypeReference::Array(element_type, len) => {
 				f.write_fmt(format_args!("{element_type}[{len}]"))
 			}
+			VariableTypeReference::DynamicArray(len) => {
+				f.write_fmt(format_args!("dynamic cell[{len}]"))
+			}
 		}
 	}
 }
@@ -263,6 +461,7 @@ impl std::fmt::Display for Reference {
 		match self {
 			Reference::NamedField(subfield_name) => f.write_str(&format!(".{subfield_name}"))?,
 			Reference::Index(index) => f.write_str(&format!("[{index}]"))?,
+			Reference::DynamicIndex(index) => f.write_str(&format!("[{index}]"))?,
 		}
 
 		Ok(())
@@ -1,132 +1,812 @@
 // take in a file, read includes and simple conditionals and output a file with those includes pasted in
 // C-style
 
-// TODO: add tests for this!
-
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+	collections::{HashMap, HashSet},
+	rc::Rc,
+};
 
 use itertools::Itertools;
 
-use crate::macros::macros::r_assert;
-
-pub fn preprocess(file_path: PathBuf) -> String {
-	let file_contents = std::fs::read_to_string(&file_path).unwrap();
-	let mut dir_path = file_path.clone();
-	dir_path.pop();
-
-	file_contents
-		.lines()
-		.map(|line| {
-			if line.starts_with("#include") {
-				// TODO: refactor and deduplicate code, currently doesn't care if "" or <> or jk or any set of two characters
-				let split: Vec<&str> = line.split_whitespace().collect();
-				assert!(
-					split.len() == 2,
-					"Malformed #include preprocessor directive {line}"
-				);
-				let mut substring = split[1];
-				assert!(
-					substring.len() > 2,
-					"Expected path string in #include preprocessor directive {line}"
-				);
-				substring = &substring[1..(substring.len() - 1)];
-
-				let rel_include_path = PathBuf::from(substring);
-				let include_path = dir_path.join(rel_include_path);
-				preprocess(include_path)
-			} else {
-				line.to_owned()
+use crate::{
+	diagnostics::{Diagnostic, Diagnostics, SourceMap, Span},
+	macros::macros::{r_assert, r_panic},
+	sources::{IncludeKind, SourceProvider},
+};
+
+/// preprocess a file and everything it includes, reading files from the given source provider,
+/// returns the preprocessed program along with a map of where each of its lines came from
+pub fn preprocess(
+	sources: &dyn SourceProvider,
+	entry_file_name: &str,
+) -> Result<(String, SourceMap), PreprocessorError> {
+	Preprocessor::default().process_file(entry_file_name, sources)
+}
+
+/// An error found while preprocessing, along with the file its span points into,
+/// as there is no preprocessed program to render it against
+#[derive(Debug)]
+pub struct PreprocessorError {
+	pub diagnostics: Diagnostics,
+	/// the contents of the file the error was found in, empty if it has no span
+	pub source: String,
+	pub source_map: SourceMap,
+}
+
+impl PreprocessorError {
+	/// an error which could not be attributed to a line, e.g. a missing entry file
+	fn new(message: String) -> PreprocessorError {
+		PreprocessorError {
+			diagnostics: Diagnostic::from(message).into(),
+			source: String::new(),
+			source_map: SourceMap::new(),
+		}
+	}
+
+	fn has_span(&self) -> bool {
+		self.diagnostics
+			.0
+			.iter()
+			.any(|diagnostic| diagnostic.span.is_some())
+	}
+
+	pub fn render(&self) -> String {
+		self.diagnostics.render(&self.source, &self.source_map)
+	}
+}
+
+/// State shared by every file included in one preprocessor run.
+#[derive(Default)]
+struct Preprocessor {
+	/// object-like `#define` constants, substituted as whole identifiers in later lines
+	defines: HashMap<String, String>,
+	/// files which contained `#pragma once`, identified by the key returned when loading them
+	once_files: HashSet<String>,
+	/// the files currently being processed, the entry file first, used for cycle detection and error messages
+	include_stack: Vec<IncludeFrame>,
+}
+
+struct IncludeFrame {
+	name: Rc<str>,
+	key: String,
+	/// the (1-based) line currently being processed
+	line: usize,
+}
+
+/// an open `#ifdef`/`#ifndef` block
+struct Conditional {
+	condition: bool,
+	in_else: bool,
+}
+
+enum Directive<'a> {
+	Include { path: &'a str, kind: IncludeKind },
+	Define { name: &'a str, value: String },
+	Undef(&'a str),
+	IfDef(&'a str),
+	IfNotDef(&'a str),
+	Else,
+	EndIf,
+	PragmaOnce,
+}
+
+impl Preprocessor {
+	/// preprocess a file and anything it includes
+	fn process_file(
+		&mut self,
+		file_name: &str,
+		sources: &dyn SourceProvider,
+	) -> Result<(String, SourceMap), PreprocessorError> {
+		let (file_key, file_contents) = sources.load(file_name).map_err(PreprocessorError::new)?;
+		if self.once_files.contains(&file_key) {
+			return Ok((String::new(), SourceMap::new()));
+		}
+		if let Some(i) = self
+			.include_stack
+			.iter()
+			.position(|frame| frame.key == file_key)
+		{
+			let cycle = self.include_stack[i..]
+				.iter()
+				.map(|frame| format!("\"{}\"", frame.name))
+				.chain([format!("\"{file_name}\"")])
+				.join(" -> ");
+			return Err(PreprocessorError::new(format!(
+				"Include cycle detected: {cycle}"
+			)));
+		}
+
+		self.include_stack.push(IncludeFrame {
+			name: Rc::from(file_name),
+			key: file_key,
+			line: 0,
+		});
+		let result = self.process_lines(&file_contents, sources);
+		self.include_stack.pop();
+		result
+	}
+
+	/// preprocess the lines of the file on top of the include stack, errors in the file itself are given the span of their line,
+	/// errors from `process_file` without a span are about the `#include` line which loaded the file
+	fn process_lines(
+		&mut self,
+		file_contents: &str,
+		sources: &dyn SourceProvider,
+	) -> Result<(String, SourceMap), PreprocessorError> {
+		let frame = self.include_stack.last().unwrap();
+		let (source_name, file_key) = (frame.name.clone(), frame.key.clone());
+
+		let mut acc = String::new();
+		let mut source_map = SourceMap::new();
+		let mut conditionals: Vec<Conditional> = vec![];
+		// comments are skipped by the tokeniser, but directives inside block comments must be ignored here
+		let mut comment_depth = 0;
+		let mut line_start = 0;
+		for (i, raw_line) in file_contents.split_inclusive('\n').enumerate() {
+			self.include_stack.last_mut().unwrap().line = i + 1;
+			let line = raw_line
+				.strip_suffix('\n')
+				.map_or(raw_line, |line| line.strip_suffix('\r').unwrap_or(line));
+			// errors on this line point at the line without its indentation
+			let line_span = Span::new(
+				line_start + line.len() - line.trim_start().len(),
+				line_start + line.trim_end().len(),
+			);
+			line_start += raw_line.len();
+			let is_active = conditionals
+				.iter()
+				.all(|conditional| conditional.condition != conditional.in_else);
+			let in_comment = comment_depth > 0;
+			comment_depth = block_comment_depth(line, comment_depth);
+
+			let Some(directive) = line.trim_start().strip_prefix('#').filter(|_| !in_comment)
+			else {
+				if is_active {
+					acc += &self.substitute_defines(line).0;
+					acc.push('\n');
+					source_map.push_line(source_name.clone(), i + 1);
+				}
+				continue;
+			};
+			let directive = match parse_directive(directive) {
+				Ok(directive) => directive,
+				// malformed or unknown directives are ignored inside inactive conditional blocks
+				Err(_) if !is_active => continue,
+				Err(err) => return Err(self.error(err, line_span, file_contents)),
+			};
+
+			match directive {
+				Directive::IfDef(name) | Directive::IfNotDef(name) => {
+					conditionals.push(Conditional {
+						condition: self.defines.contains_key(name)
+							== matches!(directive, Directive::IfDef(_)),
+						in_else: false,
+					});
+				}
+				Directive::Else => match conditionals.last_mut() {
+					Some(conditional) if !conditional.in_else => conditional.in_else = true,
+					Some(_) => {
+						return Err(self.error(
+							String::from("Duplicate #else preprocessor directive"),
+							line_span,
+							file_contents,
+						))
+					}
+					None => {
+						return Err(self.error(
+							String::from("Unexpected #else preprocessor directive"),
+							line_span,
+							file_contents,
+						))
+					}
+				},
+				Directive::EndIf => {
+					if conditionals.pop().is_none() {
+						return Err(self.error(
+							String::from("Unexpected #endif preprocessor directive"),
+							line_span,
+							file_contents,
+						));
+					}
+				}
+				// other directives are ignored inside inactive conditional blocks
+				_ if !is_active => (),
+				Directive::Define { name, value } => {
+					self.defines.insert(name.to_owned(), value);
+				}
+				Directive::Undef(name) => {
+					self.defines.remove(name);
+				}
+				Directive::PragmaOnce => {
+					self.once_files.insert(file_key.clone());
+				}
+				Directive::Include { path, kind } => {
+					let Some(include_name) = sources.resolve(&source_name, path, kind) else {
+						let message = match kind {
+							IncludeKind::Quoted => format!("No such file \"{path}\""),
+							IncludeKind::Angled => {
+								format!("No such file <{path}> in the include search path")
+							}
+						};
+						return Err(self.error(message, line_span, file_contents));
+					};
+					let (included, included_source_map) = self
+						.process_file(&include_name, sources)
+						.map_err(|err| match err.has_span() {
+							true => err,
+							false => {
+								self.error(err.diagnostics.to_string(), line_span, file_contents)
+							}
+						})?;
+					acc += &included;
+					source_map.extend(included_source_map);
+				}
+			}
+		}
+		if !conditionals.is_empty() {
+			return Err(self.error(
+				String::from("Expected #endif preprocessor directive before the end of the file"),
+				Span::new(file_contents.len(), file_contents.len()),
+				file_contents,
+			));
+		}
+
+		Ok((acc, source_map))
+	}
+
+	/// an error in the file being processed, with the chain of files which included it appended to the message
+	fn error(&self, mut message: String, span: Span, file_contents: &str) -> PreprocessorError {
+		let frame = self.include_stack.last().unwrap();
+		for frame in self.include_stack.iter().rev().skip(1) {
+			message += &format!("\n  included from \"{}\" line {}", frame.name, frame.line);
+		}
+		PreprocessorError {
+			diagnostics: Diagnostic::new(message, span).into(),
+			source: file_contents.to_owned(),
+			source_map: SourceMap::from_single_file(&frame.name, file_contents),
+		}
+	}
+
+	/// replace whole identifiers which have been `#define`d, ignoring string and character literals and comments,
+	/// also returns where each substitution was made as (offset in the result, length of the name, length of the value)
+	fn substitute_defines(&self, line: &str) -> (String, Vec<(usize, usize, usize)>) {
+		let mut substitutions = vec![];
+		if self.defines.is_empty() {
+			return (line.to_owned(), substitutions);
+		}
+
+		let mut result = String::with_capacity(line.len());
+		let mut chars = line.char_indices().peekable();
+		while let Some((i, c)) = chars.next() {
+			match c {
+				'"' | '\'' => {
+					result.push(c);
+					while let Some((_, next)) = chars.next() {
+						result.push(next);
+						match next {
+							'\\' => result.extend(chars.next().map(|(_, escaped)| escaped)),
+							_ if next == c => break,
+							_ => (),
+						}
+					}
+				}
+				'/' if line[i..].starts_with("//") => {
+					result += &line[i..];
+					break;
+				}
+				c if c.is_ascii_alphanumeric() || c == '_' => {
+					let mut end = i + c.len_utf8();
+					while let Some((j, next)) =
+						chars.next_if(|(_, next)| next.is_ascii_alphanumeric() || *next == '_')
+					{
+						end = j + next.len_utf8();
+					}
+					let word = &line[i..end];
+					match self.defines.get(word) {
+						Some(value) if !c.is_ascii_digit() => {
+							substitutions.push((result.len(), word.len(), value.len()));
+							result += value;
+						}
+						_ => result += word,
+					}
+				}
+				c => result.push(c),
 			}
-		})
-		.fold(String::new(), |acc, e| acc + &e + "\n")
+		}
+		(result, substitutions)
+	}
+}
+
+/// A single file as the preprocessor sees it without following includes, for tools which work on the source as written
+pub struct ExpandedFile {
+	/// the file with directives and lines in inactive conditional blocks replaced with spaces, so that offsets are kept
+	pub source: String,
+	/// the lines which were replaced, without trailing whitespace, along with their offsets
+	pub hidden_lines: Vec<(usize, String)>,
+	/// the source with defines substituted
+	pub expanded: String,
+	/// where each define was substituted, as (offset in the expanded source, length of the name, length of the value)
+	substitutions: Vec<(usize, usize, usize)>,
 }
 
-// utility function so that files can be compiled from javascript strings in browser
-
-pub fn preprocess_from_memory(
-	file_map: &HashMap<String, String>,
-	entry_file_name: String,
-) -> Result<String, String> {
-	let file_contents = file_map
-		.get(&entry_file_name)
-		.expect(&format!("No such file \"{entry_file_name}\" exists"));
-
-	let mut acc = String::new();
-	for line in file_contents.lines() {
-		if line.starts_with("#include") {
-			// TODO: refactor and deduplicate code, currently doesn't care if "" or <> or jk or any set of two characters
-			let split: Vec<&str> = line.split_whitespace().collect();
+impl ExpandedFile {
+	/// map an offset in the expanded source back to the source, offsets inside a substituted value map to the end of the name
+	pub fn source_offset(&self, offset: usize) -> usize {
+		let mut source_offset = offset;
+		for (start, name_len, value_len) in self.substitutions.iter().copied() {
+			if offset <= start {
+				break;
+			}
+			if offset < start + value_len {
+				return source_offset - (offset - start) + name_len;
+			}
+			source_offset = source_offset + name_len - value_len;
+		}
+		source_offset
+	}
+}
+
+/// expand the defines in a single file and hide its directives and inactive conditional blocks,
+/// malformed directives are hidden but otherwise ignored, as are included files
+pub fn expand_file(raw: &str) -> ExpandedFile {
+	let mut preprocessor = Preprocessor::default();
+	let mut file = ExpandedFile {
+		source: String::with_capacity(raw.len()),
+		hidden_lines: vec![],
+		expanded: String::with_capacity(raw.len()),
+		substitutions: vec![],
+	};
+	let mut conditionals: Vec<Conditional> = vec![];
+	let mut comment_depth = 0;
+	for line in raw.split_inclusive('\n') {
+		let is_active = conditionals
+			.iter()
+			.all(|conditional| conditional.condition != conditional.in_else);
+		let in_comment = comment_depth > 0;
+		comment_depth = block_comment_depth(line, comment_depth);
+		let content = line.trim_end();
+
+		let directive = content
+			.trim_start()
+			.strip_prefix('#')
+			.filter(|_| !in_comment);
+		if directive.is_none() && is_active {
+			let (expanded, substitutions) = preprocessor.substitute_defines(line);
+			file.substitutions.extend(substitutions.into_iter().map(
+				|(start, name_len, value_len)| (file.expanded.len() + start, name_len, value_len),
+			));
+			file.source.push_str(line);
+			file.expanded.push_str(&expanded);
+			continue;
+		}
+
+		match directive.map(parse_directive) {
+			Some(Ok(Directive::IfDef(name))) => conditionals.push(Conditional {
+				condition: preprocessor.defines.contains_key(name),
+				in_else: false,
+			}),
+			Some(Ok(Directive::IfNotDef(name))) => conditionals.push(Conditional {
+				condition: !preprocessor.defines.contains_key(name),
+				in_else: false,
+			}),
+			Some(Ok(Directive::Else)) => {
+				if let Some(conditional) = conditionals.last_mut() {
+					conditional.in_else = true;
+				}
+			}
+			Some(Ok(Directive::EndIf)) => {
+				conditionals.pop();
+			}
+			Some(Ok(Directive::Define { name, value })) if is_active => {
+				preprocessor.defines.insert(name.to_owned(), value);
+			}
+			Some(Ok(Directive::Undef(name))) if is_active => {
+				preprocessor.defines.remove(name);
+			}
+			_ => (),
+		}
+		// directives are always put at the start of their line, code in inactive blocks is kept as it was written
+		let hidden_line = match directive {
+			Some(_) => content.trim_start(),
+			None => content,
+		};
+		file.hidden_lines
+			.push((file.source.len(), hidden_line.to_owned()));
+		let blank = " ".repeat(content.len()) + &line[content.len()..];
+		file.source.push_str(&blank);
+		file.expanded.push_str(&blank);
+	}
+	file
+}
+
+/// parse a preprocessor directive line, without the leading `#`
+fn parse_directive(directive: &str) -> Result<Directive<'_>, String> {
+	let directive = directive.trim();
+	let (directive_name, rest) = directive
+		.split_once(char::is_whitespace)
+		.unwrap_or((directive, ""));
+	let rest = rest.trim_start();
+	let mut words = rest.split_whitespace();
+
+	Ok(match directive_name {
+		"include" => {
+			let (kind, closing) = match rest.chars().next() {
+				Some('"') => (IncludeKind::Quoted, '"'),
+				Some('<') => (IncludeKind::Angled, '>'),
+				_ => r_panic!("Expected \"path\" or <path> in #include preprocessor directive"),
+			};
+			let Some((path, trailing)) = rest[1..].split_once(closing) else {
+				r_panic!("Expected closing `{closing}` in #include preprocessor directive");
+			};
 			r_assert!(
-				split.len() == 2,
-				"Malformed #include preprocessor directive {line}"
+				!path.is_empty(),
+				"Expected path string in #include preprocessor directive"
 			);
-			let mut substring = split[1];
+			let trailing = trailing.trim_start();
 			r_assert!(
-				substring.len() > 2,
-				"Expected path string in #include preprocessor directive {line}"
+				trailing.is_empty() || trailing.starts_with("//"),
+				"Unexpected `{trailing}` after path in #include preprocessor directive"
 			);
-			substring = &substring[1..(substring.len() - 1)];
+			Directive::Include { path, kind }
+		}
+		"define" => {
+			let Some(name) = words.next() else {
+				r_panic!("Expected name in #define preprocessor directive");
+			};
+			r_assert!(
+				is_identifier(name),
+				"Invalid name \"{name}\" in #define preprocessor directive"
+			);
+			Directive::Define {
+				name,
+				value: words.join(" "),
+			}
+		}
+		"undef" | "ifdef" | "ifndef" => {
+			let Some(name) = words.next() else {
+				r_panic!("Expected name in #{directive_name} preprocessor directive");
+			};
+			match directive_name {
+				"undef" => Directive::Undef(name),
+				"ifdef" => Directive::IfDef(name),
+				_ => Directive::IfNotDef(name),
+			}
+		}
+		"else" => Directive::Else,
+		"endif" => Directive::EndIf,
+		"pragma" => match words.next() {
+			Some("once") => Directive::PragmaOnce,
+			_ => r_panic!("Unknown #pragma preprocessor directive"),
+		},
+		_ => r_panic!("Unknown preprocessor directive"),
+	})
+}
 
-			acc += &preprocess_from_memory(file_map, substring.to_owned())?;
-		} else {
-			acc += line;
+/// the nesting depth of block comments at the end of a line, given the depth at the start of it,
+/// this mirrors the tokeniser so that string literals and line comments are respected
+pub fn block_comment_depth(line: &str, mut depth: usize) -> usize {
+	let mut chars = line.chars().peekable();
+	while let Some(c) = chars.next() {
+		match (depth, c, chars.peek()) {
+			(_, '/', Some('*')) => {
+				chars.next();
+				depth += 1;
+			}
+			(1.., '*', Some('/')) => {
+				chars.next();
+				depth -= 1;
+			}
+			(0, '/', Some('/')) => break,
+			(0, '"' | '\'', _) => {
+				while let Some(next) = chars.next() {
+					match next {
+						'\\' => {
+							chars.next();
+						}
+						_ if next == c => break,
+						_ => (),
+					}
+				}
+			}
+			_ => (),
 		}
-		acc.push('\n');
 	}
-
-	Ok(acc)
+	depth
 }
 
-/// strips comments from input program, does not support anything else
-pub fn strip_comments(raw_program: &str) -> String {
-	let mut stripped = raw_program
-		.lines()
-		.map(|line| line.split_once("//").map_or_else(|| line, |(left, _)| left))
-		.join("\n");
-	// join doesn't add a newline to the end, here we re-add it, this is probably unnecessary
-	if raw_program.ends_with("\n") {
-		stripped.push_str("\n");
-	}
-	stripped
+/// whether a `#define` name is a valid Mastermind identifier
+fn is_identifier(name: &str) -> bool {
+	name.chars()
+		.next()
+		.is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+		&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
 }
 
 #[cfg(test)]
 pub mod preprocessor_tests {
-	use crate::preprocessor::strip_comments;
+	use std::collections::HashMap;
+
+	use itertools::Itertools;
+
+	use crate::{
+		diagnostics::SourceMap,
+		preprocessor::preprocess,
+		sources::{FileSystemProvider, LayeredProvider, MemoryProvider},
+	};
+
+	fn _preprocess_test(
+		file_map: HashMap<String, String>,
+		entry_file_name: &str,
+	) -> Result<(String, SourceMap), String> {
+		let sources = LayeredProvider::with_std_library(MemoryProvider { files: file_map });
+		preprocess(&sources, entry_file_name).map_err(|err| err.render())
+	}
 
 	#[test]
-	fn comments_0() {
-		assert_eq!(strip_comments(""), "");
-		assert_eq!(strip_comments("\n\t\t\n"), "\n\t\t\n");
+	fn source_map_1() {
+		let file_map = HashMap::from([
+			(
+				String::from("main.mmi"),
+				String::from("cell a;\n#include \"lib.mmi\"\noutput a;\n"),
+			),
+			(String::from("lib.mmi"), String::from("cell b;\ncell c;\n")),
+		]);
+		let (program, source_map) = _preprocess_test(file_map, "main.mmi").unwrap();
+		assert_eq!(program, "cell a;\ncell b;\ncell c;\noutput a;\n");
+		assert_eq!(source_map.locate(0), ("main.mmi", 1));
+		assert_eq!(source_map.locate(1), ("lib.mmi", 1));
+		assert_eq!(source_map.locate(2), ("lib.mmi", 2));
+		assert_eq!(source_map.locate(3), ("main.mmi", 3));
 	}
 
 	#[test]
-	fn comments_1() {
-		assert_eq!(strip_comments("hi//hello"), "hi");
+	fn header_guards_1() {
+		let file_map = HashMap::from([
+			(
+				String::from("main.mmi"),
+				String::from("#include \"lib.mmi\"\n#include \"lib.mmi\"\noutput a;\n"),
+			),
+			(
+				String::from("lib.mmi"),
+				String::from("#ifndef LIB\n#define LIB\ncell a;\n#endif\n"),
+			),
+		]);
+		let (program, source_map) = _preprocess_test(file_map, "main.mmi").unwrap();
+		assert_eq!(program, "cell a;\noutput a;\n");
+		assert_eq!(source_map.locate(0), ("lib.mmi", 3));
+		assert_eq!(source_map.locate(1), ("main.mmi", 3));
+	}
+
+	#[test]
+	fn header_guards_2() {
+		let file_map = HashMap::from([
+			(
+				String::from("main.mmi"),
+				String::from("#include \"a.mmi\"\n#include \"b.mmi\"\n"),
+			),
+			(
+				String::from("a.mmi"),
+				String::from("#pragma once\n#include \"b.mmi\"\ncell a;\n"),
+			),
+			(
+				String::from("b.mmi"),
+				String::from("#pragma once\n#include \"a.mmi\"\ncell b;\n"),
+			),
+		]);
+		let (program, _) = _preprocess_test(file_map, "main.mmi").unwrap();
+		assert_eq!(program, "cell b;\ncell a;\n");
+	}
+
+	#[test]
+	fn conditionals_1() {
+		let file_map = HashMap::from([(
+			String::from("main.mmi"),
+			String::from(
+				"#define A\n#ifdef A\n1\n#ifndef A\n2\n#else\n3\n#endif\n#else\n4\n#endif\n#undef A\n#ifdef A\n5\n#endif\n",
+			),
+		)]);
+		let (program, source_map) = _preprocess_test(file_map, "main.mmi").unwrap();
+		assert_eq!(program, "1\n3\n");
+		assert_eq!(source_map.locate(1), ("main.mmi", 7));
+	}
+
+	#[test]
+	fn conditionals_2() {
+		let file_map = HashMap::from([(
+			String::from("main.mmi"),
+			String::from("#ifndef A\ncell a;\n"),
+		)]);
+		assert_eq!(
+			_preprocess_test(file_map, "main.mmi").unwrap_err(),
+			"error: Expected #endif preprocessor directive before the end of the file\n --> main.mmi:3:1\n  |\n3 | \n  | ^"
+		);
+	}
+
+	#[test]
+	fn conditionals_3() {
+		let file_map = HashMap::from([(String::from("main.mmi"), String::from("#endif\n"))]);
+		assert_eq!(
+			_preprocess_test(file_map, "main.mmi").unwrap_err(),
+			"error: Unexpected #endif preprocessor directive\n --> main.mmi:1:1\n  |\n1 | #endif\n  | ^^^^^^"
+		);
+	}
+
+	#[test]
+	fn includes_1() {
+		let file_map = HashMap::from([
+			(
+				String::from("main.mmi"),
+				String::from("#include <std lib>\n#include \"a.mmi\" // comment\n"),
+			),
+			(String::from("std lib"), String::from("cell a;\n")),
+			(String::from("a.mmi"), String::from("cell b;\n")),
+		]);
+		let (program, _) = _preprocess_test(file_map, "main.mmi").unwrap();
+		assert_eq!(program, "cell a;\ncell b;\n");
+	}
+
+	#[test]
+	fn includes_2() {
+		let file_map = HashMap::from([(
+			String::from("main.mmi"),
+			String::from("cell a;\n#include <a.mmi\"\n"),
+		)]);
+		assert_eq!(
+			_preprocess_test(file_map, "main.mmi").unwrap_err(),
+			"error: Expected closing `>` in #include preprocessor directive\n --> main.mmi:2:1\n  |\n2 | #include <a.mmi\"\n  | ^^^^^^^^^^^^^^^^"
+		);
 	}
 
 	#[test]
-	fn comments_2() {
-		assert_eq!(strip_comments("h//i // hello"), "h");
+	fn includes_3() {
+		let file_map = HashMap::from([
+			(
+				String::from("main.mmi"),
+				String::from("\n#include \"a.mmi\"\n"),
+			),
+			(
+				String::from("a.mmi"),
+				String::from("cell a;\n#include \"b.mmi\"\n"),
+			),
+		]);
+		assert_eq!(
+			_preprocess_test(file_map, "main.mmi").unwrap_err(),
+			"error: No such file \"b.mmi\"\n  included from \"main.mmi\" line 2\n --> a.mmi:2:1\n  |\n2 | #include \"b.mmi\"\n  | ^^^^^^^^^^^^^^^^"
+		);
 	}
 
 	#[test]
-	fn comments_3() {
+	fn include_cycles_1() {
+		let file_map = HashMap::from([(
+			String::from("main.mmi"),
+			String::from("#include \"main.mmi\"\n"),
+		)]);
 		assert_eq!(
-			strip_comments(
-				r#"
-hello // don't talk to me
-second line
-// third line comment
-fourth line
-"#
+			_preprocess_test(file_map, "main.mmi").unwrap_err(),
+			"error: Include cycle detected: \"main.mmi\" -> \"main.mmi\"\n --> main.mmi:1:1\n  |\n1 | #include \"main.mmi\"\n  | ^^^^^^^^^^^^^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn include_cycles_2() {
+		let file_map = HashMap::from([
+			(
+				String::from("main.mmi"),
+				String::from("#include \"a.mmi\"\n"),
+			),
+			(String::from("a.mmi"), String::from("#include \"b.mmi\"\n")),
+			(
+				String::from("b.mmi"),
+				String::from("#ifndef B\n#include \"a.mmi\"\n#endif\n"),
 			),
-			r#"
-hello 
-second line
+		]);
+		assert_eq!(
+			_preprocess_test(file_map, "main.mmi").unwrap_err(),
+			"error: Include cycle detected: \"a.mmi\" -> \"b.mmi\" -> \"a.mmi\"\n  included from \"a.mmi\" line 1\n  included from \"main.mmi\" line 1\n --> b.mmi:2:1\n  |\n2 | #include \"a.mmi\"\n  | ^^^^^^^^^^^^^^^^"
+		);
+	}
 
-fourth line
-"#
+	#[test]
+	fn std_library_1() {
+		let file_map = HashMap::from([(
+			String::from("main.mmi"),
+			String::from("#include <ifp16>\n#include <u8>\n#include \"u16\"\n"),
+		)]);
+		let (program, source_map) = _preprocess_test(file_map, "main.mmi").unwrap();
+		let std_files = (0..program.lines().count())
+			.map(|line| source_map.locate(line).0)
+			.unique()
+			.collect::<Vec<_>>();
+		assert_eq!(
+			std_files,
+			[
+				"<std>/ifp16",
+				"<std>/u16",
+				"<std>/i8",
+				"<std>/bitops",
+				"<std>/u8"
+			]
 		);
 	}
+
+	#[test]
+	fn std_library_2() {
+		let file_map = HashMap::from([
+			(String::from("main.mmi"), String::from("#include <u8>\n")),
+			(String::from("u8"), String::from("cell a;\n")),
+		]);
+		let (program, _) = _preprocess_test(file_map, "main.mmi").unwrap();
+		assert_eq!(program, "cell a;\n");
+	}
+
+	#[test]
+	fn sources_1() {
+		let sources = LayeredProvider {
+			layers: vec![
+				Box::new(MemoryProvider {
+					files: HashMap::from([(
+						String::from("main.mmi"),
+						String::from("#include \"lib.mmi\"\n#include <u8>\n"),
+					)]),
+				}),
+				Box::new(MemoryProvider {
+					files: HashMap::from([
+						(String::from("main.mmi"), String::from("hidden\n")),
+						(String::from("lib.mmi"), String::from("cell b;\n")),
+						(String::from("u8"), String::from("cell c;\n")),
+					]),
+				}),
+			],
+		};
+		let (program, _) = preprocess(&sources, "main.mmi").unwrap();
+		assert_eq!(program, "cell b;\ncell c;\n");
+	}
+
+	#[test]
+	fn sources_2() {
+		let file_map = HashMap::from([(
+			String::from("main.mmi"),
+			String::from("#include <u8>\n#include \"i8\"\n"),
+		)]);
+		let sources = LayeredProvider {
+			layers: vec![
+				Box::new(MemoryProvider { files: file_map }),
+				Box::new(FileSystemProvider {
+					include_dirs: vec!["../programs/std".into()],
+				}),
+			],
+		};
+		let (_, source_map) = preprocess(&sources, "main.mmi").unwrap();
+		assert_eq!(source_map.locate(0), ("../programs/std/u8", 2));
+	}
+
+	#[test]
+	fn comments_1() {
+		let file_map = HashMap::from([
+			(
+				String::from("main.mmi"),
+				String::from(
+					"/* \"*/ /* /* */\n#include \"a.mmi\"\n*/ output '/*'; // /*\n#include \"a.mmi\"\n",
+				),
+			),
+			(String::from("a.mmi"), String::from("cell a;\n")),
+		]);
+		let (program, _) = _preprocess_test(file_map, "main.mmi").unwrap();
+		assert_eq!(
+			program,
+			"/* \"*/ /* /* */\n#include \"a.mmi\"\n*/ output '/*'; // /*\ncell a;\n"
+		);
+	}
+
+	#[test]
+	fn defines_1() {
+		let file_map = HashMap::from([(
+			String::from("main.mmi"),
+			String::from(
+				"#define SIZE 4\n#define CHAR 'x'\ncell[SIZE] SIZE_2 = \"SIZE\";\noutput CHAR; // SIZE\n",
+			),
+		)]);
+		let (program, _) = _preprocess_test(file_map, "main.mmi").unwrap();
+		assert_eq!(program, "cell[4] SIZE_2 = \"SIZE\";\noutput 'x'; // SIZE\n");
+	}
 }
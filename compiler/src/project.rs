@@ -0,0 +1,229 @@
+// project configuration files, so that compiler settings can be shared with a project rather than
+// passed on the command line each time
+
+use std::path::{Path, PathBuf};
+
+use serde::{de::Error, Deserialize};
+
+use crate::misc::MastermindConfig;
+
+/// the file name searched for in the current directory and its ancestors
+pub const PROJECT_FILE_NAME: &str = "mastermind.toml";
+
+/// Settings read from a `mastermind.toml` file, any of which can be left out
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+	/// the file compiled when no file is given on the command line
+	pub entry: Option<PathBuf>,
+	/// directories searched for `#include <name>` files, before those given by the environment
+	pub include_dirs: Vec<PathBuf>,
+	/// whether to target 1D or 2D Brainfuck, overrides `compiler.enable_2d_grid` when given
+	pub target: Option<Target>,
+	/// the same settings as the web IDE's settings panel
+	#[serde(deserialize_with = "deserialize_compiler_config")]
+	pub compiler: MastermindConfig,
+	pub interpreter: InterpreterConfig,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, serde::Deserialize)]
+pub enum Target {
+	#[serde(rename = "1d")]
+	OneDimensional,
+	#[serde(rename = "2d")]
+	TwoDimensional,
+}
+
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InterpreterConfig {
+	pub enable_debug_symbols: bool,
+}
+
+/// read the `[compiler]` table, rejecting unknown keys like the rest of the project file,
+/// `MastermindConfig` itself accepts them because the web IDE may have saved settings which no longer exist
+fn deserialize_compiler_config<'de, D: serde::Deserializer<'de>>(
+	deserializer: D,
+) -> Result<MastermindConfig, D::Error> {
+	let table = toml::Table::deserialize(deserializer)?;
+	let known_keys =
+		toml::Table::try_from(MastermindConfig::default()).map_err(D::Error::custom)?;
+	if let Some(key) = table.keys().find(|key| !known_keys.contains_key(*key)) {
+		return Err(D::Error::custom(format!(
+			"unknown field `{key}` in [compiler], expected one of {}",
+			known_keys
+				.keys()
+				.map(|key| format!("`{key}`"))
+				.collect::<Vec<_>>()
+				.join(", ")
+		)));
+	}
+	table.try_into().map_err(D::Error::custom)
+}
+
+/// A project file along with the directory it was found in
+pub struct Project {
+	pub directory: PathBuf,
+	pub config: ProjectConfig,
+}
+
+impl Project {
+	/// find and read the closest project file in a directory or any of its ancestors
+	pub fn discover(start_directory: &Path) -> Result<Option<Project>, String> {
+		for directory in start_directory.ancestors() {
+			let file_path = directory.join(PROJECT_FILE_NAME);
+			if file_path.is_file() {
+				return Project::load(&file_path).map(Some);
+			}
+		}
+		Ok(None)
+	}
+
+	/// read a project file, paths in the file are relative to the directory containing it
+	pub fn load(file_path: &Path) -> Result<Project, String> {
+		let contents = std::fs::read_to_string(file_path)
+			.map_err(|err| format!("Could not read file \"{}\": {err}", file_path.display()))?;
+		let config = ProjectConfig::parse(&contents)
+			.map_err(|err| format!("Invalid project file \"{}\": {err}", file_path.display()))?;
+		let directory = file_path
+			.parent()
+			.map(Path::to_path_buf)
+			.unwrap_or_default();
+		Ok(Project { directory, config })
+	}
+
+	/// the entry file, relative to the current directory
+	pub fn entry(&self) -> Option<PathBuf> {
+		self.config
+			.entry
+			.as_ref()
+			.map(|entry| self.directory.join(entry))
+	}
+
+	/// the include directories, relative to the current directory
+	pub fn include_dirs(&self) -> Vec<PathBuf> {
+		self.config
+			.include_dirs
+			.iter()
+			.map(|dir| self.directory.join(dir))
+			.collect()
+	}
+}
+
+impl ProjectConfig {
+	pub fn parse(contents: &str) -> Result<ProjectConfig, String> {
+		let mut config: ProjectConfig = toml::from_str(contents).map_err(|err| err.to_string())?;
+		if let Some(target) = config.target {
+			config.compiler.enable_2d_grid = target == Target::TwoDimensional;
+		}
+		// 2D-only allocation methods are checked once command line overrides have been applied, e.g. `--2d`
+		Ok(config)
+	}
+}
+
+#[cfg(test)]
+pub mod project_tests {
+	use std::path::PathBuf;
+
+	use super::{Project, ProjectConfig, Target};
+	use crate::misc::MemoryAllocationMethod;
+
+	#[test]
+	fn project_config_1() {
+		let config = ProjectConfig::parse("").unwrap();
+		assert_eq!(config.entry, None);
+		assert!(config.include_dirs.is_empty());
+		assert!(!config.compiler.optimise_generated_code);
+		assert!(!config.compiler.enable_2d_grid);
+		assert!(!config.interpreter.enable_debug_symbols);
+	}
+
+	#[test]
+	fn project_config_2() {
+		let config = ProjectConfig::parse(
+			r#"
+entry = "src/main.mmi"
+include_dirs = ["lib", "vendor/std"]
+target = "2d"
+
+[compiler]
+optimise_generated_code = true
+optimise_constants = true
+memory_allocation_method = "spiral"
+
+[interpreter]
+enable_debug_symbols = true
+"#,
+		)
+		.unwrap();
+		assert_eq!(config.entry, Some(PathBuf::from("src/main.mmi")));
+		assert_eq!(
+			config.include_dirs,
+			vec![PathBuf::from("lib"), PathBuf::from("vendor/std")]
+		);
+		assert_eq!(config.target, Some(Target::TwoDimensional));
+		assert!(config.compiler.optimise_generated_code);
+		assert!(config.compiler.optimise_constants);
+		assert!(!config.compiler.optimise_cell_clearing);
+		assert_eq!(
+			config.compiler.memory_allocation_method,
+			MemoryAllocationMethod::Spiral
+		);
+		assert!(config.compiler.enable_2d_grid);
+		assert!(config.interpreter.enable_debug_symbols);
+	}
+
+	#[test]
+	fn project_config_3() {
+		// the target overrides the compiler setting
+		let config = ProjectConfig::parse(
+			r#"
+target = "1d"
+[compiler]
+enable_2d_grid = true
+"#,
+		)
+		.unwrap();
+		assert!(!config.compiler.enable_2d_grid);
+	}
+
+	#[test]
+	fn project_config_4() {
+		assert!(ProjectConfig::parse("entry_file = \"main.mmi\"").is_err());
+		assert!(ProjectConfig::parse("target = \"3d\"").is_err());
+		assert!(ProjectConfig::parse("[compiler]\nmemory_allocation_method = 3").is_err());
+		assert!(ProjectConfig::parse("[compiler]\noptimize_cell_clearing = true").is_err());
+		assert!(ProjectConfig::parse("[interpreter]\nenable_debug = true").is_err());
+	}
+
+	#[test]
+	fn project_config_5() {
+		// 2D-only allocation methods are allowed, as the command line can still choose to target 2D
+		let config =
+			ProjectConfig::parse("[compiler]\nmemory_allocation_method = \"spiral\"").unwrap();
+		assert!(!config.compiler.enable_2d_grid);
+		assert_eq!(
+			config.compiler.memory_allocation_method,
+			MemoryAllocationMethod::Spiral
+		);
+	}
+
+	#[test]
+	fn project_discovery_1() {
+		let root = std::env::temp_dir().join(format!("mastermind_project_{}", std::process::id()));
+		let nested = root.join("src").join("nested");
+		std::fs::create_dir_all(&nested).unwrap();
+		std::fs::write(
+			root.join("mastermind.toml"),
+			"entry = \"src/main.mmi\"\ninclude_dirs = [\"lib\"]\n",
+		)
+		.unwrap();
+
+		let project = Project::discover(&nested).unwrap().unwrap();
+		assert_eq!(project.directory, root);
+		assert_eq!(project.entry(), Some(root.join("src/main.mmi")));
+		assert_eq!(project.include_dirs(), vec![root.join("lib")]);
+
+		std::fs::remove_dir_all(&root).unwrap();
+	}
+}
@@ -0,0 +1,205 @@
+// where the preprocessor reads source files from, so that the same preprocessor can run on the filesystem,
+// on files sent from the web IDE, or on anything else a tool embedding the compiler can provide
+
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::macros::macros::r_panic;
+
+/// How an `#include` path was written, `"..."` paths are relative to the including file,
+/// `<...>` paths are searched for in the include search path and standard library
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IncludeKind {
+	Quoted,
+	Angled,
+}
+
+/// A source of files for the preprocessor.
+/// File names are whatever the provider chooses, they are shown in error messages and source maps
+pub trait SourceProvider {
+	/// find the file an `#include` directive refers to, given the name of the including file,
+	/// returns None if this provider has no such file
+	fn resolve(
+		&self,
+		including_file: &str,
+		include_path: &str,
+		kind: IncludeKind,
+	) -> Option<String>;
+
+	/// whether this provider can load a file name
+	fn contains(&self, file_name: &str) -> bool;
+
+	/// read a file, returning a key identifying the file (used by `#pragma once`) along with its contents
+	fn load(&self, file_name: &str) -> Result<(String, String), String>;
+}
+
+/// Reads files from disk, `<...>` includes are searched for in a list of directories
+pub struct FileSystemProvider {
+	pub include_dirs: Vec<PathBuf>,
+}
+
+impl SourceProvider for FileSystemProvider {
+	fn resolve(
+		&self,
+		including_file: &str,
+		include_path: &str,
+		kind: IncludeKind,
+	) -> Option<String> {
+		// quoted includes are relative to the including file, falling back to the search path like in C
+		let relative_path = (kind == IncludeKind::Quoted).then(|| {
+			let mut dir_path = PathBuf::from(including_file);
+			dir_path.pop();
+			dir_path.join(include_path)
+		});
+		relative_path
+			.into_iter()
+			.chain(self.include_dirs.iter().map(|dir| dir.join(include_path)))
+			.find(|path| path.is_file())
+			.map(|path| path.to_string_lossy().into_owned())
+	}
+
+	fn contains(&self, file_name: &str) -> bool {
+		PathBuf::from(file_name).is_file()
+	}
+
+	fn load(&self, file_name: &str) -> Result<(String, String), String> {
+		let contents = std::fs::read_to_string(file_name)
+			.map_err(|err| format!("Could not read file \"{file_name}\": {err}"))?;
+		// identify files by their canonical path so that `#pragma once` works across relative includes
+		let canonical = std::fs::canonicalize(file_name).map_or_else(
+			|_| file_name.to_owned(),
+			|path| path.to_string_lossy().into_owned(),
+		);
+		Ok((canonical, contents))
+	}
+}
+
+/// Files held in memory, e.g. sent from the web IDE or open in an editor.
+/// There are no directories, include paths are used as file names directly
+pub struct MemoryProvider {
+	pub files: HashMap<String, String>,
+}
+
+impl SourceProvider for MemoryProvider {
+	fn resolve(
+		&self,
+		_including_file: &str,
+		include_path: &str,
+		_kind: IncludeKind,
+	) -> Option<String> {
+		self.contains(include_path).then(|| include_path.to_owned())
+	}
+
+	fn contains(&self, file_name: &str) -> bool {
+		self.files.contains_key(file_name)
+	}
+
+	fn load(&self, file_name: &str) -> Result<(String, String), String> {
+		let Some(contents) = self.files.get(file_name) else {
+			r_panic!("No such file \"{file_name}\" exists");
+		};
+		Ok((file_name.to_owned(), contents.clone()))
+	}
+}
+
+/// the standard library files, embedded so that `#include <name>` works without any search path configured
+pub const STD_LIBRARY: [(&str, &str); 6] = [
+	("bitops", include_str!("../../programs/std/bitops")),
+	("i8", include_str!("../../programs/std/i8")),
+	("ifp16", include_str!("../../programs/std/ifp16")),
+	("stack", include_str!("../../programs/std/stack")),
+	("u16", include_str!("../../programs/std/u16")),
+	("u8", include_str!("../../programs/std/u8")),
+];
+/// file name prefix given to embedded standard library files
+const STD_LIBRARY_PREFIX: &str = "<std>/";
+
+/// The standard library built into the compiler, its files are named e.g. `<std>/u16`
+pub struct StdLibraryProvider;
+
+impl StdLibraryProvider {
+	fn get(file_name: &str) -> Option<&'static str> {
+		let name = file_name.strip_prefix(STD_LIBRARY_PREFIX)?;
+		STD_LIBRARY
+			.iter()
+			.find(|(std_name, _)| *std_name == name)
+			.map(|(_, contents)| *contents)
+	}
+}
+
+impl SourceProvider for StdLibraryProvider {
+	fn resolve(
+		&self,
+		_including_file: &str,
+		include_path: &str,
+		_kind: IncludeKind,
+	) -> Option<String> {
+		let file_name = format!("{STD_LIBRARY_PREFIX}{include_path}");
+		self.contains(&file_name).then_some(file_name)
+	}
+
+	fn contains(&self, file_name: &str) -> bool {
+		Self::get(file_name).is_some()
+	}
+
+	fn load(&self, file_name: &str) -> Result<(String, String), String> {
+		let Some(contents) = Self::get(file_name) else {
+			r_panic!("No such standard library file \"{file_name}\" exists");
+		};
+		Ok((file_name.to_owned(), contents.to_owned()))
+	}
+}
+
+/// Overlays several providers, earlier layers take priority when resolving and loading files
+pub struct LayeredProvider {
+	pub layers: Vec<Box<dyn SourceProvider>>,
+}
+
+impl LayeredProvider {
+	/// layer a provider over the built-in standard library
+	pub fn with_std_library(provider: impl SourceProvider + 'static) -> LayeredProvider {
+		LayeredProvider {
+			layers: vec![Box::new(provider), Box::new(StdLibraryProvider)],
+		}
+	}
+}
+
+impl SourceProvider for LayeredProvider {
+	fn resolve(
+		&self,
+		including_file: &str,
+		include_path: &str,
+		kind: IncludeKind,
+	) -> Option<String> {
+		self.layers
+			.iter()
+			.find_map(|layer| layer.resolve(including_file, include_path, kind))
+	}
+
+	fn contains(&self, file_name: &str) -> bool {
+		self.layers.iter().any(|layer| layer.contains(file_name))
+	}
+
+	fn load(&self, file_name: &str) -> Result<(String, String), String> {
+		match self.layers.iter().find(|layer| layer.contains(file_name)) {
+			Some(layer) => layer.load(file_name),
+			// let the top layer report the error
+			None => match self.layers.first() {
+				Some(layer) => layer.load(file_name),
+				None => r_panic!("No such file \"{file_name}\" exists"),
+			},
+		}
+	}
+}
+
+/// environment variable listing extra directories to search for `#include <name>`, separated like `PATH`
+pub const INCLUDE_PATH_ENV_VAR: &str = "MASTERMIND_INCLUDE_PATH";
+
+/// the directories searched for includes, in order:
+/// directories given on the command line, then those in the include path environment variable
+pub fn include_search_path(cli_dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+	let mut dirs = cli_dirs;
+	if let Some(env_dirs) = std::env::var_os(INCLUDE_PATH_ENV_VAR) {
+		dirs.extend(std::env::split_paths(&env_dirs).filter(|dir| !dir.as_os_str().is_empty()));
+	}
+	dirs
+}
@@ -13,9 +13,9 @@ pub mod black_box_tests {
 			},
 		},
 		brainfuck::{bvm_tests::run_code, BrainfuckConfig},
-		misc::{MastermindConfig, MastermindContext},
+		diagnostics::SourceMap,
+		misc::{MastermindConfig, MastermindContext, MemoryAllocationMethod},
 		parser::parser::parse_program,
-		preprocessor::strip_comments,
 	};
 	// TODO: run test suite with different optimisations turned on
 	const OPT_NONE: MastermindConfig = MastermindConfig {
@@ -23,11 +23,11 @@ pub mod black_box_tests {
 		optimise_generated_all_permutations: false,
 		optimise_cell_clearing: false,
 		// optimise_variable_usage: false,
-		// optimise_memory_allocation: false,
+		optimise_memory_allocation: false,
 		optimise_unreachable_loops: false,
 		optimise_constants: false,
 		optimise_empty_blocks: false,
-		memory_allocation_method: 0,
+		memory_allocation_method: MemoryAllocationMethod::Classic,
 		enable_2d_grid: false,
 	};
 
@@ -36,11 +36,11 @@ pub mod black_box_tests {
 		optimise_generated_all_permutations: false,
 		optimise_cell_clearing: true,
 		// optimise_variable_usage: true,
-		// optimise_memory_allocation: true,
+		optimise_memory_allocation: false,
 		optimise_unreachable_loops: true,
 		optimise_constants: true,
 		optimise_empty_blocks: true,
-		memory_allocation_method: 0,
+		memory_allocation_method: MemoryAllocationMethod::Classic,
 		enable_2d_grid: false,
 	};
 
@@ -49,11 +49,11 @@ pub mod black_box_tests {
 		optimise_generated_all_permutations: false,
 		optimise_cell_clearing: false,
 		// optimise_variable_usage: false,
-		// optimise_memory_allocation: false,
+		optimise_memory_allocation: false,
 		optimise_unreachable_loops: false,
 		optimise_constants: false,
 		optimise_empty_blocks: false,
-		memory_allocation_method: 3,
+		memory_allocation_method: MemoryAllocationMethod::Tiles,
 		enable_2d_grid: true,
 	};
 
@@ -62,11 +62,11 @@ pub mod black_box_tests {
 		optimise_generated_all_permutations: false,
 		optimise_cell_clearing: false,
 		// optimise_variable_usage: false,
-		// optimise_memory_allocation: false,
+		optimise_memory_allocation: false,
 		optimise_unreachable_loops: false,
 		optimise_constants: false,
 		optimise_empty_blocks: false,
-		memory_allocation_method: 2,
+		memory_allocation_method: MemoryAllocationMethod::Spiral,
 		enable_2d_grid: true,
 	};
 
@@ -75,11 +75,24 @@ pub mod black_box_tests {
 		optimise_generated_all_permutations: false,
 		optimise_cell_clearing: false,
 		// optimise_variable_usage: false,
-		// optimise_memory_allocation: false,
+		optimise_memory_allocation: false,
 		optimise_unreachable_loops: false,
 		optimise_constants: false,
 		optimise_empty_blocks: false,
-		memory_allocation_method: 1,
+		memory_allocation_method: MemoryAllocationMethod::ZigZag,
+		enable_2d_grid: true,
+	};
+
+	const OPT_NONE_2D_NEAREST: MastermindConfig = MastermindConfig {
+		optimise_generated_code: false,
+		optimise_generated_all_permutations: false,
+		optimise_cell_clearing: false,
+		// optimise_variable_usage: false,
+		optimise_memory_allocation: false,
+		optimise_unreachable_loops: false,
+		optimise_constants: false,
+		optimise_empty_blocks: false,
+		memory_allocation_method: MemoryAllocationMethod::Nearest,
 		enable_2d_grid: true,
 	};
 
@@ -95,7 +108,7 @@ pub mod black_box_tests {
 
 	const TESTING_BVM_MAX_STEPS: usize = 100_000_000;
 
-	fn compile_and_run<'a, TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+	fn compile_and_run<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
 		raw_program: &str,
 		input: &str,
 	) -> Result<String, String>
@@ -104,10 +117,12 @@ pub mod black_box_tests {
 		CellAllocatorData<TC>: CellAllocator<TC>,
 		Vec<OC>: BrainfuckProgram,
 	{
-		let ctx = MastermindContext { config: OPT_NONE };
-		let stripped_program = strip_comments(raw_program);
-		let clauses = parse_program::<TC, OC>(&stripped_program)?;
-		let instructions = ctx.create_ir_scope(&clauses, None)?.build_ir(false);
+		let ctx = MastermindContext::new(OPT_NONE);
+		let clauses = parse_program::<TC, OC>(raw_program).map_err(|err| err.to_string())?;
+		let instructions = ctx
+			.create_ir_scope(&clauses, None)
+			.map_err(|err| err.to_string())?
+			.build_ir(false);
 		let bf_program = ctx.ir_to_bf(instructions, None)?;
 		let bfs = bf_program.to_string();
 
@@ -115,7 +130,7 @@ pub mod black_box_tests {
 		run_code(BVM_CONFIG_1D, &bfs, input, Some(TESTING_BVM_MAX_STEPS))
 	}
 
-	fn compile_program<'a, TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
+	fn compile_program<TC: 'static + TapeCellVariant, OC: 'static + OpcodeVariant>(
 		raw_program: &str,
 		config: Option<MastermindConfig>,
 	) -> Result<String, String>
@@ -124,17 +139,38 @@ pub mod black_box_tests {
 		CellAllocatorData<TC>: CellAllocator<TC>,
 		Vec<OC>: BrainfuckProgram,
 	{
-		let ctx = MastermindContext {
-			config: config.unwrap_or(OPT_NONE),
-		};
-		let stripped_program = strip_comments(raw_program);
-		let clauses = parse_program::<TC, OC>(&stripped_program)?;
-		let instructions = ctx.create_ir_scope(&clauses, None)?.build_ir(false);
+		let ctx = MastermindContext::new(config.unwrap_or(OPT_NONE));
+		let clauses = parse_program::<TC, OC>(raw_program).map_err(|err| err.to_string())?;
+		let instructions = ctx
+			.create_ir_scope(&clauses, None)
+			.map_err(|err| err.to_string())?
+			.build_ir(false);
 		let bf_code = ctx.ir_to_bf(instructions, None)?;
 
 		Ok(bf_code.to_string())
 	}
 
+	/// compile a program expecting an error, and render the error as the command-line would
+	fn compile_error_rendered(raw_program: &str) -> String {
+		let ctx = MastermindContext::new(OPT_NONE);
+		let source_map = SourceMap::from_single_file("main.mmi", raw_program);
+		let err = match parse_program::<TapeCell, Opcode>(raw_program) {
+			Ok(clauses) => ctx.create_ir_scope(&clauses, None).unwrap_err(),
+			Err(err) => err,
+		};
+		err.render(raw_program, &source_map)
+	}
+
+	/// compile a program expecting no errors, and return its warnings rendered as the command-line would
+	fn compile_warnings_rendered(raw_program: &str) -> String {
+		let ctx = MastermindContext::new(OPT_NONE);
+		let source_map = SourceMap::from_single_file("main.mmi", raw_program);
+		let clauses = parse_program::<TapeCell, Opcode>(raw_program).unwrap();
+		let instructions = ctx.create_ir_scope(&clauses, None).unwrap().build_ir(false);
+		ctx.ir_to_bf(instructions, None).unwrap();
+		ctx.take_warnings().render(raw_program, &source_map)
+	}
+
 	#[test]
 	fn empty_program_1() {
 		assert_eq!(compile_and_run::<TapeCell, Opcode>("", "").unwrap(), "");
@@ -383,6 +419,209 @@ output A;
 		);
 	}
 
+	#[test]
+	fn expressions_5() {
+		let program = r#"
+output '0' + 2 * 3 + 7 % 4 - 8 / 4;
+output '0' + 3 * (1 + 2) / 4;
+output 10 * 10 / 20 + 'a';
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"72f"
+		);
+	}
+
+	#[test]
+	fn expressions_6() {
+		let program = r#"
+cell x = 6 * 7;
+output x;
+"#;
+		let code = compile_program::<TapeCell, Opcode>(program, None).unwrap();
+		assert!(!code.contains('['));
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "", None).unwrap(), "*");
+	}
+
+	#[test]
+	fn expressions_7() {
+		let program = r#"
+cell a;
+cell b;
+input a;
+input b;
+a -= '0';
+b -= '0';
+
+output '0' + a * b;
+output ' ';
+output '0' + a / b;
+output ' ';
+output '0' + a % b;
+output ' ';
+output 'A' + a * b - b * 3 + a * 2;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "73").unwrap(),
+			"E 2 1 ["
+		);
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "29").unwrap(),
+			"B 0 2 <"
+		);
+	}
+
+	#[test]
+	fn expressions_8() {
+		let program = r#"
+cell x;
+cell y = 3;
+input x;
+x = x * y - 2 * x;
+output x;
+x += x % 10;
+output x;
+cell z = x / (y - 1) * 2;
+output z;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "a").unwrap(),
+			"ahh"
+		);
+	}
+
+	#[test]
+	fn expressions_9() {
+		let program = r#"
+output 'a' + 5 / (3 - 3);
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap_err(),
+			"Division by zero in constant expression `(5 / (3 - (3)))`."
+		);
+	}
+
+	#[test]
+	fn expressions_10() {
+		let program = r#"
+cell a;
+input a;
+cell b = 7;
+output 'A' + a / b;
+output '0' + a % b;
+output a * b;
+"#;
+		let code = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "z", None).unwrap(), "R3V");
+	}
+
+	#[test]
+	fn comparisons_1() {
+		let program = r#"
+cell a;
+cell b;
+input a;
+input b;
+output '0' + (a == b);
+output '0' + (a != b);
+output '0' + (a < b);
+output '0' + (a > b);
+output '0' + (a <= b);
+output '0' + (a >= b);
+"#;
+		for (input, expected) in [
+			("ab", "011010"),
+			("ba", "010101"),
+			("cc", "100011"),
+			("\0\0", "100011"),
+			("\0\u{7f}", "011010"),
+			("\u{7f}\0", "010101"),
+		] {
+			assert_eq!(
+				compile_and_run::<TapeCell, Opcode>(program, input).unwrap(),
+				expected
+			);
+		}
+	}
+
+	#[test]
+	fn comparisons_2() {
+		let program = r#"
+cell a;
+cell b;
+input a;
+input b;
+a -= '0';
+b -= '0';
+output '0' + (a and b);
+output '0' + (a or b);
+output '0' + (a == 1 or b == 1 and a < b);
+"#;
+		for (input, expected) in [("00", "000"), ("01", "011"), ("10", "011"), ("23", "110")] {
+			assert_eq!(
+				compile_and_run::<TapeCell, Opcode>(program, input).unwrap(),
+				expected
+			);
+		}
+	}
+
+	#[test]
+	fn comparisons_3() {
+		let program = r#"
+cell x = 3 < 4;
+x += (2 == 2) + (5 >= 6) + (1 and 0) + (0 or 7);
+output '0' + x;
+"#;
+		let code = compile_program::<TapeCell, Opcode>(program, None).unwrap();
+		assert!(code.starts_with("+++>"));
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "", None).unwrap(), "3");
+	}
+
+	#[test]
+	fn comparisons_4() {
+		let program = r#"
+cell n;
+input n;
+if n >= 'a' and n <= 'z' {
+	output "lower";
+} else {
+	output "other";
+}
+cell count = 0;
+drain n > 'b' + 4 {
+	count += 1;
+}
+output '0' + count;
+cell flag;
+flag = n != 0;
+output '0' + flag;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "q").unwrap(),
+			"lower11"
+		);
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "Q").unwrap(),
+			"other01"
+		);
+	}
+
+	#[test]
+	fn comparisons_5() {
+		let program = r#"
+cell a;
+cell b;
+input a;
+input b;
+output '0' + (a == b);
+output '0' + (a < b);
+output '0' + (a >= b or a == 'x');
+"#;
+		let code = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "xy", None).unwrap(), "011");
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "yy", None).unwrap(), "101");
+	}
+
 	#[test]
 	fn assignments_1() {
 		let program = r#"
@@ -730,6 +969,193 @@ output 'h';
 		)
 	}
 
+	#[test]
+	fn loops_4() {
+		let program = r#"
+cell n = 0;
+while n < 5 {
+	output '0' + n;
+	n += 1;
+}
+output 10;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"01234\n"
+		);
+	}
+
+	#[test]
+	fn loops_5() {
+		let program = r#"
+cell c;
+input c;
+while c != '.' {
+	output c + 1;
+	input c;
+}
+while c - '.' {
+	output 'X';
+}
+cell[2] pair = [3, 1];
+while pair[0] * pair[1] < 20 {
+	pair[1] += 2;
+}
+output '0' + pair[1];
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "HAL.").unwrap(),
+			"IBM7"
+		);
+		let code = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		assert_eq!(
+			run_code(BVM_CONFIG_1D, &code, "abc.", None).unwrap(),
+			"bcd7"
+		);
+	}
+
+	#[test]
+	fn loop_control_1() {
+		let program = r#"
+cell n = 0;
+while n < 10 {
+	n += 1;
+	if n == 3 {
+		continue;
+	}
+	if n == 7 {
+		break;
+	}
+	output '0' + n;
+}
+output 10;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"12456\n"
+		);
+		let code = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "", None).unwrap(), "12456\n");
+	}
+
+	#[test]
+	fn loop_control_2() {
+		let program = r#"
+cell x = 5;
+cell y = 0;
+drain x into y {
+	if x == 2 {
+		break;
+	}
+	output 'a' + x;
+}
+output '0' + x;
+output '0' + y;
+cell z;
+input z;
+z -= '0';
+copy z {
+	output 'c';
+	continue;
+	output 'd';
+}
+output '0' + z;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "3").unwrap(),
+			"fed03ccc3"
+		);
+	}
+
+	#[test]
+	fn loop_control_3() {
+		let program = r#"
+cell i = 0;
+while i < 3 {
+	cell j = 0;
+	while true {
+		j += 1;
+		if j > i {
+			break;
+		}
+		output '0' + j;
+	}
+	output ';';
+	i += 1;
+}
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			";1;12;"
+		);
+	}
+
+	#[test]
+	fn loop_control_4() {
+		let program = r#"
+cell c;
+input c;
+while c {
+	output c;
+	break;
+	output 'x';
+}
+"#;
+		let code_1 = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		let code_2 =
+			compile_program::<TapeCell, Opcode>(&program.replace("output 'x';", ""), Some(OPT_ALL))
+				.unwrap();
+		assert_eq!(code_1, code_2);
+		assert_eq!(run_code(BVM_CONFIG_1D, &code_1, "a", None).unwrap(), "a");
+	}
+
+	#[test]
+	fn loop_control_5() {
+		let program = r#"
+cell c;
+input c;
+while c {
+	if c == 'b' {
+		break;
+		output 'x';
+	}
+	output c;
+	input c;
+}
+"#;
+		let code_1 = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		let code_2 =
+			compile_program::<TapeCell, Opcode>(&program.replace("output 'x';", ""), Some(OPT_ALL))
+				.unwrap();
+		assert_eq!(code_1, code_2);
+		assert_eq!(
+			run_code(BVM_CONFIG_1D, &code_1, "aab\0", None).unwrap(),
+			"aa"
+		);
+	}
+
+	#[test]
+	fn loop_control_6() {
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>("cell a = 1; if a { break; }", None).unwrap_err(),
+			"`break` can only be used inside a loop."
+		);
+		let program = r#"
+fn f() {
+	continue;
+}
+cell a = 1;
+while a {
+	f();
+	a = 0;
+}
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"`continue` can only be used inside a loop."
+		);
+	}
+
 	#[test]
 	fn ifs_1() {
 		let program = r#"
@@ -1183,6 +1609,290 @@ output 10;
 		);
 	}
 
+	#[test]
+	fn functions_5() {
+		let program = r#"
+fn print_zeros(cell num) {
+	drain num {
+		output '0';
+	}
+	output 10;
+}
+print_zeros(3);
+cell g = 2;
+print_zeros(g + 2);
+print_zeros(g * 3 - 1);
+output '0' + g;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"000\n0000\n00000\n2"
+		);
+	}
+
+	#[test]
+	fn functions_5a() {
+		let program = r#"
+fn print_all(cell[3] arr) {
+	output *arr;
+	arr[1] = '-';
+	output arr[1];
+}
+fn add(cell a, cell b, cell out) {
+	out += a + b;
+}
+cell c = 'x';
+print_all("abc");
+print_all([c, c + 1, 'z']);
+cell r;
+add(c - 'x', 4, r);
+output '0' + r;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"abc-xyz-4"
+		);
+		let code = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		assert_eq!(
+			run_code(BVM_CONFIG_1D, &code, "", None).unwrap(),
+			"abc-xyz-4"
+		);
+	}
+
+	#[test]
+	fn functions_5b() {
+		let program = r#"
+fn f(cell[2] arr) {}
+f("abc");
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Could not find function \"f\" with correct arguments in current scope"
+		);
+	}
+
+	#[test]
+	fn function_returns_1() {
+		let program = r#"
+fn add(cell a, cell b) -> cell {
+	return a + b;
+}
+cell c = add('0', 3);
+output c;
+output add(c, 1);
+output add(1, add(2, '0')) + 1;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"344"
+		);
+	}
+
+	#[test]
+	fn function_returns_2() {
+		let program = r#"
+struct Pair {
+	cell a;
+	cell b;
+}
+fn make_pair(cell x) -> struct Pair {
+	struct Pair p;
+	p.a = x;
+	p.b = x + 1;
+	return p;
+}
+fn letters() -> cell[3] {
+	return "xyz";
+}
+struct Pair p = make_pair('a');
+output p.a;
+output p.b;
+cell[3] l = letters();
+output *l;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"abxyz"
+		);
+	}
+
+	#[test]
+	fn function_returns_3() {
+		let program = r#"
+fn classify(cell n) -> cell {
+	if not n {
+		return 'z';
+	}
+	cell i = 5;
+	while i {
+		if not n {
+			return 's';
+		}
+		n -= 1;
+		i -= 1;
+	}
+	return 'l';
+}
+output classify(0);
+output classify(3);
+output classify(8);
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"zsl"
+		);
+	}
+
+	#[test]
+	fn function_returns_4() {
+		let program = r#"
+fn print_digit(cell d) {
+	if not d < 10 {
+		output '?';
+		return;
+	}
+	output '0' + d;
+}
+fn double(cell x) -> cell {
+	return x * 2;
+}
+print_digit(4);
+print_digit(12);
+double(3);
+cell i = 3;
+while i {
+	print_digit(double(i));
+	i -= 1;
+}
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"4?642"
+		);
+	}
+
+	#[test]
+	fn function_returns_5() {
+		let program = r#"
+fn f() {
+	return 4;
+}
+f();
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Cannot return `4` from a function without a return type."
+		);
+	}
+
+	#[test]
+	fn function_returns_5a() {
+		let program = r#"
+fn f() {}
+cell c = 1 + f();
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Function call `f()` does not return a value."
+		);
+	}
+
+	#[test]
+	fn function_returns_5b() {
+		let program = r#"
+cell c = 5;
+return c;
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"`return` can only be used inside a function."
+		);
+	}
+
+	#[test]
+	fn function_returns_5c() {
+		let program = r#"
+fn f() -> cell[2] {
+	return 3;
+}
+cell[2] c = f();
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Expected return value of type `cell[2]`, found `3` of type `cell`."
+		);
+	}
+
+	#[test]
+	fn function_returns_5d() {
+		let program = r#"
+fn f() -> cell[2] {
+	cell[2] r = [1, 2];
+	return r;
+}
+output f();
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Expected a cell value, found function call `f()` which returns `cell[2]`."
+		);
+	}
+
+	#[test]
+	fn function_returns_5e() {
+		let program = r#"
+struct P { cell a; cell b; }
+fn f() -> struct P {
+	struct P p;
+	return p;
+}
+cell x = f() + 1;
+output x;
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Expected a cell value, found function call `f()` which returns `{cell a; cell b;}`."
+		);
+	}
+
+	#[test]
+	fn function_returns_5f() {
+		let program = r#"
+fn f(cell a) -> cell {
+	if a {
+		return 1;
+	}
+}
+output f(1);
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Function \"f\" must return a value of type `cell` at the end of its body."
+		);
+	}
+
+	#[test]
+	fn function_returns_5g() {
+		let program = r#"
+fn f(cell a) -> cell {
+	if a {
+		return 'y';
+	} else {
+		output 'n';
+		{
+			return 'o';
+		}
+	}
+}
+output f(1);
+output f(0);
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"yno"
+		);
+	}
+
 	#[test]
 	fn function_overloads_1() {
 		let program = r#"
@@ -1395,10 +2105,239 @@ output g[2][3];
 "#;
 		assert_eq!(
 			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
-			"543112320003"
+			"543112320003"
+		);
+	}
+
+	#[test]
+	fn dynamic_arrays_1() {
+		let program = r#"
+dynamic cell[5] arr = "hello";
+cell i = 4;
+output arr[i];
+i = 1;
+output arr[i];
+output arr[0];
+output *arr;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"oehhello"
+		);
+	}
+
+	#[test]
+	fn dynamic_arrays_2() {
+		let program = r#"
+dynamic cell[6] arr;
+cell i = 6;
+while i {
+	i -= 1;
+	arr[i] = 'a' + i;
+}
+cell j = 2;
+arr[j] += 5;
+arr[j] -= 1;
+++arr[j];
+i = 4;
+arr[i] = arr[j] + 1;
+output *arr;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"abhdif"
+		);
+	}
+
+	#[test]
+	fn dynamic_arrays_3() {
+		let program = r#"
+dynamic cell[4] lookup = ['z', 'y', 'x', 'w'];
+dynamic cell[3] indices = [3, 0, 2];
+cell i = 3;
+cell j = 0;
+while i {
+	i -= 1;
+	output lookup[indices[j]];
+	j += 1;
+}
+input lookup[j];
+input lookup[indices[1]];
+output *lookup;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "AB").unwrap(),
+			"wzxByxA"
+		);
+	}
+
+	#[test]
+	fn dynamic_arrays_4() {
+		let program = r#"
+struct Buffer {
+	cell len;
+	dynamic cell[4] data;
+}
+fn push(struct Buffer buf, cell value) {
+	buf.data[buf.len] = value;
+	buf.len += 1;
+}
+struct Buffer b;
+push(b, '1');
+push(b, '2');
+push(b, '3');
+cell i = b.len;
+while i {
+	i -= 1;
+	output b.data[i];
+}
+"#;
+		let code = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "", None).unwrap(), "321");
+		let code =
+			compile_program::<TapeCell2D, Opcode2D>(program, Some(OPT_NONE_2D_SPIRAL)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_2D, &code, "", None).unwrap(), "321");
+	}
+
+	#[test]
+	fn dynamic_arrays_5() {
+		let program = r#"
+cell[4] arr;
+cell i;
+output arr[i];
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Runtime index in \"arr[i]\" can only be used with a dynamic array, e.g. `dynamic cell[8]`."
+		);
+	}
+
+	#[test]
+	fn dynamic_arrays_5a() {
+		let program = r#"
+dynamic cell[4] arr;
+cell i;
+drain arr[i] into i;
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Cannot drain runtime-indexed array element \"arr[i]\", use `copy` instead."
+		);
+	}
+
+	#[test]
+	fn constants_1() {
+		let program = r#"
+const N = 3;
+const M = N * 2 + 1;
+cell[N] arr = [1, 2, M];
+output '0' + arr[2];
+drain N {
+	output '0' + M - 1;
+}
+output 10;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"7666\n"
+		);
+	}
+
+	#[test]
+	fn constants_2() {
+		let program = r#"
+const OFFSET = 2;
+struct Pair {
+	cell a @OFFSET;
+	cell b @0;
+}
+struct Pair p;
+p.a = 'a';
+p.b = 'b';
+const X_POS = OFFSET + 2;
+bf @OFFSET {.<<.>>}
+cell x @X_POS = 'x';
+bf @4 {.}
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"abx"
+		);
+	}
+
+	#[test]
+	fn constants_3() {
+		let program = r#"
+const GREETING = "hi!";
+const DIGITS = [3, 1, 4];
+cell[3] s = GREETING;
+output *s;
+output GREETING[2];
+output '0' + DIGITS[0] + DIGITS[2];
+{
+	const N = 1;
+	output '0' + N;
+}
+const N = 2;
+output '0' + N;
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"hi!!712"
+		);
+	}
+
+	#[test]
+	fn constants_4() {
+		let program = r#"
+const N = 300;
+const M = N - 299;
+cell x = N;
+output '0' + M;
+output x;
+"#;
+		let code = compile_program::<TapeCell, Opcode>(program, Some(OPT_ALL)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "", None).unwrap(), "1,");
+	}
+
+	#[test]
+	fn constants_5() {
+		let program = r#"
+cell x;
+const N = x + 1;
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Constant expressions cannot reference variable \"x\"."
+		);
+	}
+
+	#[test]
+	fn constants_5a() {
+		let program = r#"
+const N = 2 - 3;
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"Constant expression evaluates to a negative value (-1)."
 		);
 	}
 
+	#[test]
+	fn constants_6() {
+		let program = r#"
+const X = 1;
+const Y = 2;
+cell a @(X, Y) = 'y';
+cell b @(Y, 0) = 'x';
+bf @(X, Y) {.}
+bf @Y {.}
+"#;
+		let code =
+			compile_program::<TapeCell2D, Opcode2D>(program, Some(OPT_NONE_2D_SPIRAL)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_2D, &code, "", None).unwrap(), "yx");
+	}
+
 	#[test]
 	fn structs_1() {
 		let program = r#"
@@ -2607,31 +3546,6 @@ input z;
 		assert_eq!(run_code(BVM_CONFIG_1D, &code, "   ", None).unwrap(), "");
 	}
 
-	// TODO: remove the need for this
-	#[test]
-	fn unimplemented_memory_allocation() {
-		let program = r#"
-cell[15] arr @1;
-cell a = 'G';
-"#;
-		let cfg = MastermindConfig {
-			optimise_generated_code: false,
-			optimise_generated_all_permutations: false,
-			optimise_cell_clearing: false,
-			// optimise_variable_usage: false,
-			// optimise_memory_allocation: false,
-			optimise_unreachable_loops: false,
-			optimise_constants: false,
-			optimise_empty_blocks: false,
-			memory_allocation_method: 128,
-			enable_2d_grid: false,
-		};
-		assert_eq!(
-			compile_program::<TapeCell2D, Opcode2D>(program, Some(cfg)).unwrap_err(),
-			"Memory allocation method 128 not implemented."
-		);
-	}
-
 	#[test]
 	fn memory_specifiers_2d_1() {
 		let program = r#"
@@ -2956,4 +3870,545 @@ output a;
 		assert!(code.contains("v") || code.contains("^"));
 		assert_eq!(run_code(BVM_CONFIG_2D, &code, "", None).unwrap(), "12345");
 	}
+
+	#[test]
+	fn nearest_memory_allocation_1() {
+		let program = r#"
+cell a = 1;
+cell b = 1;
+cell c = 1;
+"#;
+		assert_eq!(
+			compile_program::<TapeCell2D, Opcode2D>(program, Some(OPT_NONE_2D_NEAREST)).unwrap(),
+			"+>+>+"
+		);
+	}
+
+	#[test]
+	fn nearest_memory_allocation_2() {
+		let program = r#"
+cell a @(0, 3) = 1;
+cell b = 2;
+cell[3] c @(0, 2);
+cell d = 3;
+"#;
+		// b and d are allocated next to the head rather than at the origin
+		assert_eq!(
+			compile_program::<TapeCell2D, Opcode2D>(program, Some(OPT_NONE_2D_NEAREST)).unwrap(),
+			"^^^+>++>+++"
+		);
+	}
+
+	#[test]
+	fn nearest_memory_allocation_3() {
+		let program = r#"
+cell[4] a = "abcd";
+cell[2] b @(1, 1);
+cell[3] c = "efg";
+output a[3];
+output c[0];
+output c[1];
+output c[2];
+"#;
+		let code =
+			compile_program::<TapeCell2D, Opcode2D>(program, Some(OPT_NONE_2D_NEAREST)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_2D, &code, "", None).unwrap(), "defg");
+	}
+
+	#[test]
+	fn diagnostics_1() {
+		let program = r#"cell a = 1;
+while a {
+	output b;
+}
+"#;
+		assert_eq!(
+			compile_error_rendered(program),
+			"error: No variable found in scope with name \"b\".
+ --> main.mmi:3:9
+  |
+3 | 	output b;
+  | 	       ^"
+		);
+	}
+
+	#[test]
+	fn diagnostics_2() {
+		let program = r#"
+cell[4] x;
+x[2 = 5;
+"#;
+		assert_eq!(
+			compile_error_rendered(program),
+			"error: Expected `]` in array subscript.
+ --> main.mmi:3:5
+  |
+3 | x[2 = 5;
+  |     ^"
+		);
+	}
+
+	#[test]
+	fn diagnostics_3() {
+		let program = r#"
+struct A {
+	cell a @x;
+}
+"#;
+		assert_eq!(
+			compile_error_rendered(program),
+			"error: Location specifiers in struct definitions must be relative, not variable.
+ --> main.mmi:3:2
+  |
+3 | 	cell a @x;
+  | 	^^^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn diagnostics_4() {
+		let program = r#"/* a block comment
+   over /* nested */ several lines */ cell a = 1;
+// output b;
+output b; // comment
+"#;
+		assert_eq!(
+			compile_error_rendered(program),
+			"error: No variable found in scope with name \"b\".
+ --> main.mmi:4:8
+  |
+4 | output b; // comment
+  |        ^"
+		);
+	}
+
+	#[test]
+	fn diagnostics_5() {
+		// errors within an expression point at the part of the expression at fault
+		let program = r#"cell a = 1;
+output a + 2 * b;
+"#;
+		assert_eq!(
+			compile_error_rendered(program),
+			"error: No variable found in scope with name \"b\".
+ --> main.mmi:2:16
+  |
+2 | output a + 2 * b;
+  |                ^"
+		);
+	}
+
+	#[test]
+	fn diagnostics_6() {
+		let program = r#"fn f(cell x) -> cell { return x; }
+cell a = 3 + f(1, 2);
+output a;
+"#;
+		assert_eq!(
+			compile_error_rendered(program),
+			"error: Could not find function \"f\" with correct arguments in current scope
+ --> main.mmi:2:14
+  |
+2 | cell a = 3 + f(1, 2);
+  |              ^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn comments_1() {
+		let program = r#"
+output "http://x"; // line comment
+/* block /* nested */
+output "no"; */
+cell c = '/';
+output c;
+cell newline;
+bf @newline {
+	// comments in Brainfuck
+	++++++++++ /* more */ .
+	[-]
+}
+"#;
+		assert_eq!(
+			compile_and_run::<TapeCell, Opcode>(program, "").unwrap(),
+			"http://x/\n"
+		);
+	}
+
+	#[test]
+	fn multiple_errors_1() {
+		let program = r#"cell a = 1;
+output b;
+struct P { cell x; }
+struct P p;
+p.y = a;
+fn f(cell q) { output z; }
+f(a);
+f(a);
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"No variable found in scope with name \"b\".
+Could not find subfield \".y\" in struct type
+No variable found in scope with name \"z\"."
+		);
+	}
+
+	#[test]
+	fn multiple_errors_2() {
+		// uses of a variable whose definition had an error are not reported again
+		let program = r#"struct Q q;
+output q;
+q.x = 4;
+cell a = c;
+output a;
+"#;
+		assert_eq!(
+			compile_error_rendered(program),
+			"error: No definition found for struct \"Q\".
+ --> main.mmi:1:1
+  |
+1 | struct Q q;
+  | ^^^^^^^^^^^
+
+error: No variable found in scope with name \"c\".
+ --> main.mmi:4:10
+  |
+4 | cell a = c;
+  |          ^"
+		);
+	}
+
+	#[test]
+	fn multiple_errors_3() {
+		// other errors around uses of a failed variable are still reported
+		let program = r#"cell a = c;
+{
+	output a;
+	output d;
+	{
+		a += 1;
+	}
+	output e;
+}
+"#;
+		assert_eq!(
+			compile_program::<TapeCell, Opcode>(program, None).unwrap_err(),
+			"No variable found in scope with name \"c\".
+No variable found in scope with name \"d\".
+No variable found in scope with name \"e\"."
+		);
+	}
+
+	#[test]
+	fn warnings_1() {
+		let program = r#"cell a = 4;
+cell b = 5;
+output b;
+"#;
+		assert_eq!(
+			compile_warnings_rendered(program),
+			"warning: Variable \"a\" is never read.
+ --> main.mmi:1:1
+  |
+1 | cell a = 4;
+  | ^^^^^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn warnings_2() {
+		let program = r#"cell a = 4;
+a = 5;
+output a;
+a = 6;
+if a {
+	a = 7;
+}
+a = 8;
+output a;
+"#;
+		assert_eq!(
+			compile_warnings_rendered(program),
+			"warning: Value assigned to \"a\" is overwritten before it is read.
+ --> main.mmi:1:1
+  |
+1 | cell a = 4;
+  | ^^^^^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn warnings_3() {
+		let program = r#"struct P { cell x; }
+struct Q { cell y; }
+fn f(cell x) { x += 1; }
+fn g(struct Q q) { output q.y; }
+fn _h() {}
+cell _c;
+cell d @3;
+"#;
+		assert_eq!(
+			compile_warnings_rendered(program),
+			"warning: Struct \"P\" is never used.
+ --> main.mmi:1:1
+  |
+1 | struct P { cell x; }
+  | ^^^^^^^^^^^^^^^^^^^^
+
+warning: Function \"f\" is never called.
+ --> main.mmi:3:1
+  |
+3 | fn f(cell x) { x += 1; }
+  | ^^^^^^^^^^^^^^^^^^^^^^^^
+
+warning: Function \"g\" is never called.
+ --> main.mmi:4:1
+  |
+4 | fn g(struct Q q) { output q.y; }
+  | ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn warnings_4() {
+		let program = r#"cell a = 1;
+cell b = 2;
+bf @a clobbers a b {
+	[-]
+}
+bf @a clobbers b {
+	>[-]<
+}
+bf @a clobbers a b {
+	[>]
+}
+"#;
+		assert_eq!(
+			compile_warnings_rendered(program),
+			"warning: Variable \"b\" is clobbered but never used by the Brainfuck.
+ --> main.mmi:3:1
+  |
+3 | bf @a clobbers a b {
+  | ^^^^^^^^^^^^^^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn warnings_5() {
+		// warnings are kept alongside errors
+		let program = r#"cell a = 1;
+output b;
+"#;
+		let ctx = MastermindContext::new(OPT_NONE);
+		let clauses = parse_program::<TapeCell, Opcode>(program).unwrap();
+		assert!(ctx.create_ir_scope(&clauses, None).is_err());
+		assert_eq!(
+			ctx.take_warnings().to_string(),
+			"Variable \"a\" is never read."
+		);
+	}
+
+	#[test]
+	fn warnings_6() {
+		// stores to fields and elements are tracked separately, reading the whole variable reads all of them
+		let program = r#"cell[3] a;
+a[0] = 1;
+a[1] = 2;
+a[0] = 3;
+output a[0];
+output a[1];
+a[2] = 4;
+output *a;
+a[2] = 5;
+struct P { cell x; cell y; }
+struct P p;
+p.x = 1;
+output p.y;
+p.x = 2;
+output p.x;
+"#;
+		assert_eq!(
+			compile_warnings_rendered(program),
+			"warning: Value assigned to \"a[0]\" is overwritten before it is read.
+ --> main.mmi:2:1
+  |
+2 | a[0] = 1;
+  | ^^^^^^^^^
+
+warning: Value assigned to \"p.x\" is overwritten before it is read.
+  --> main.mmi:12:1
+   |
+12 | p.x = 1;
+   | ^^^^^^^^"
+		);
+	}
+
+	#[test]
+	fn memory_allocation_1() {
+		let program = r#"
+cell a;
+input a;
+output a;
+cell b;
+input b;
+output b;
+"#;
+		let config = MastermindConfig {
+			optimise_memory_allocation: true,
+			..OPT_NONE
+		};
+		let code = compile_program::<TapeCell, Opcode>(program, Some(config)).unwrap();
+		// b reuses a's cell
+		assert_eq!(code, ",.[-],.");
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "xy", None).unwrap(), "xy");
+	}
+
+	#[test]
+	fn memory_allocation_2() {
+		let program = r#"
+fn count(cell n) {
+	cell i = '0';
+	while n {
+		output i;
+		i += 1;
+		n -= 1;
+	}
+}
+
+cell[3] nums = [2, 3, 0];
+cell total;
+cell j = 3;
+while j {
+	j -= 1;
+	count(nums[0]);
+	copy nums[1] into total;
+	drain nums[1] into nums[0];
+	drain nums[2] into nums[1];
+	output '\n';
+}
+total += '0';
+output total;
+"#;
+		let config = MastermindConfig {
+			optimise_memory_allocation: true,
+			..OPT_NONE
+		};
+		let code_1 = compile_program::<TapeCell, Opcode>(program, Some(config)).unwrap();
+		let code_2 = compile_program::<TapeCell, Opcode>(program, None).unwrap();
+		assert_eq!(
+			run_code(BVM_CONFIG_1D, &code_1, "", None).unwrap(),
+			run_code(BVM_CONFIG_1D, &code_2, "", None).unwrap()
+		);
+		assert_eq!(
+			run_code(BVM_CONFIG_1D, &code_1, "", None).unwrap(),
+			"01\n012\n\n3"
+		);
+	}
+
+	#[test]
+	fn minimise_movement_1() {
+		let program = r#"
+cell a = 3;
+cell[4] unused;
+cell b;
+while a {
+	b += 2;
+	a -= 1;
+}
+b += '0';
+output b;
+"#;
+		let config = MastermindConfig {
+			memory_allocation_method: MemoryAllocationMethod::MinimiseMovement,
+			..OPT_NONE
+		};
+		let code_1 = compile_program::<TapeCell, Opcode>(program, Some(config)).unwrap();
+		let code_2 = compile_program::<TapeCell, Opcode>(program, None).unwrap();
+		// b is placed next to a instead of after the unused cells
+		assert!(code_1.len() < code_2.len());
+		assert_eq!(run_code(BVM_CONFIG_1D, &code_1, "", None).unwrap(), "6");
+	}
+
+	#[test]
+	fn minimise_movement_2() {
+		let program = r#"
+cell x @2 = 'h';
+cell[3] y = "ey\n";
+output x;
+output y[0];
+output y[1];
+output y[2];
+"#;
+		let config = MastermindConfig {
+			memory_allocation_method: MemoryAllocationMethod::MinimiseMovement,
+			optimise_memory_allocation: true,
+			..OPT_NONE
+		};
+		let code = compile_program::<TapeCell, Opcode>(program, Some(config)).unwrap();
+		assert_eq!(run_code(BVM_CONFIG_1D, &code, "", None).unwrap(), "hey\n");
+	}
+
+	#[test]
+	fn minimise_movement_3() {
+		// the planned layout is never used if it generates more code than the classic layout
+		let program = r#"
+cell c;
+input c;
+while c {
+	output c;
+	break;
+	output 'x';
+}
+"#;
+		let config = MastermindConfig {
+			memory_allocation_method: MemoryAllocationMethod::MinimiseMovement,
+			optimise_memory_allocation: true,
+			..OPT_ALL
+		};
+		let code_1 = compile_program::<TapeCell, Opcode>(program, Some(config)).unwrap();
+		let code_2 = compile_program::<TapeCell, Opcode>(
+			program,
+			Some(MastermindConfig {
+				optimise_memory_allocation: true,
+				..OPT_ALL
+			}),
+		)
+		.unwrap();
+		assert!(code_1.len() <= code_2.len());
+		assert_eq!(run_code(BVM_CONFIG_1D, &code_1, "a", None).unwrap(), "a");
+	}
+
+	#[test]
+	fn minimise_movement_2d_1() {
+		let program = r#"
+fn count(cell n) {
+	cell i = '0';
+	while n {
+		output i;
+		i += 1;
+		n -= 1;
+	}
+}
+
+cell[3] nums = [2, 3, 0];
+cell j = 3;
+while j {
+	j -= 1;
+	count(nums[0]);
+	drain nums[1] into nums[0];
+	drain nums[2] into nums[1];
+	output '\n';
+}
+"#;
+		let config = MastermindConfig {
+			memory_allocation_method: MemoryAllocationMethod::MinimiseMovement,
+			..OPT_NONE_2D_SPIRAL
+		};
+		let code = compile_program::<TapeCell2D, Opcode2D>(program, Some(config)).unwrap();
+		assert_eq!(
+			run_code(BVM_CONFIG_2D, &code, "", None).unwrap(),
+			"01\n012\n\n"
+		);
+	}
 }
@@ -68,6 +68,15 @@ const App: Component = () => {
     createSignal<MastermindConfig>(DEFAULT_MASTERMIND_CONFIG),
     { name: "mastermind_config" }
   );
+  // memory allocation methods used to be saved as numbers
+  const savedAllocationMethod: unknown = config().memory_allocation_method;
+  if (typeof savedAllocationMethod === "number") {
+    const methods = ["classic", "zig_zag", "spiral", "tiles"] as const;
+    setConfig({
+      ...config(),
+      memory_allocation_method: methods[savedAllocationMethod] ?? "classic",
+    });
+  }
   createEffect(
     on([version], () => {
       const v = version();
@@ -336,7 +345,12 @@ const App: Component = () => {
       // surely there is a library for this kind of thing, transactionify messages or something, maybe make one?
       const transaction = uuidv4();
       const callback = (e: {
-        data: { transaction: string; success: boolean; message: string };
+        data: {
+          transaction: string;
+          success: boolean;
+          message: string;
+          warnings?: string;
+        };
       }) => {
         if (transaction !== e.data.transaction) return;
 
@@ -346,7 +360,9 @@ const App: Component = () => {
           setBrainfuck({ text: e.data.message, amountRead: null });
           setOutput({
             type: "OUTPUT",
-            content: "Successfully Compiled Program",
+            content: e.data.warnings
+              ? `Successfully Compiled Program\n\n${e.data.warnings}`
+              : "Successfully Compiled Program",
           });
           setStatus("IDLE");
           resolve(e.data.message);
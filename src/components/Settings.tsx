@@ -5,12 +5,16 @@ import { useAppContext } from "../App";
 
 // TODO: FIX THIS SO WE DON'T HAVE 2 PERSISTED VALUES ONLY ONE
 const SettingsModal: Component<{ style?: JSX.CSSProperties }> = () => {
-  // TODO: refactor this
-  const MemoryAllocationOptions: string[] = [
-    "Classic",
-    "2D Zig Zag",
-    "2D Spiral",
-    "2D Tiles",
+  const MemoryAllocationOptions: {
+    method: MemoryAllocationMethod;
+    label: string;
+  }[] = [
+    { method: "classic", label: "Classic" },
+    { method: "zig_zag", label: "2D Zig Zag" },
+    { method: "spiral", label: "2D Spiral" },
+    { method: "tiles", label: "2D Tiles" },
+    { method: "nearest", label: "2D Nearest" },
+    { method: "minimise_movement", label: "Minimise Head Movement" },
   ];
 
   const tickboxKeys: (keyof OptimisationSettings)[] = [
@@ -20,7 +24,7 @@ const SettingsModal: Component<{ style?: JSX.CSSProperties }> = () => {
     "optimise_generated_code",
     "optimise_generated_all_permutations",
     "optimise_unreachable_loops",
-    // "optimise_memory_allocation",
+    "optimise_memory_allocation",
     // "optimise_variable_usage",
   ];
 
@@ -99,9 +103,13 @@ const SettingsModal: Component<{ style?: JSX.CSSProperties }> = () => {
                       app.setConfig({
                         ...app.config(),
                         enable_2d_grid: isChecked,
-                        memory_allocation_method: !isChecked
-                          ? 0
-                          : app.config().memory_allocation_method,
+                        memory_allocation_method:
+                          !isChecked &&
+                          isAllocationMethod2DOnly(
+                            app.config().memory_allocation_method
+                          )
+                            ? "classic"
+                            : app.config().memory_allocation_method,
                       });
                     }}
                   />
@@ -110,20 +118,25 @@ const SettingsModal: Component<{ style?: JSX.CSSProperties }> = () => {
                 <label class="row">Memory Allocation</label>
                 <select
                   value={app.config().memory_allocation_method}
-                  disabled={!app.config().enable_2d_grid}
                   onChange={(event) => {
-                    const value = parseInt(
-                      (event.target as HTMLSelectElement).value,
-                      10
-                    );
+                    const value = (event.target as HTMLSelectElement)
+                      .value as MemoryAllocationMethod;
                     app.setConfig({
                       ...app.config(),
                       memory_allocation_method: value,
                     });
                   }}
                 >
-                  {MemoryAllocationOptions.map((option, index) => (
-                    <option value={index}>{option}</option>
+                  {MemoryAllocationOptions.map(({ method, label }) => (
+                    <option
+                      value={method}
+                      disabled={
+                        !app.config().enable_2d_grid &&
+                        isAllocationMethod2DOnly(method)
+                      }
+                    >
+                      {label}
+                    </option>
                   ))}
                 </select>
               </form>
@@ -155,13 +168,25 @@ interface OptimisationSettings {
   optimise_generated_code: boolean;
   optimise_generated_all_permutations: boolean;
   optimise_unreachable_loops: boolean;
-  //   optimise_memory_allocation: boolean;
+  optimise_memory_allocation: boolean;
   //   optimise_variable_usage: boolean;
 }
 
+export type MemoryAllocationMethod =
+  | "classic"
+  | "zig_zag"
+  | "spiral"
+  | "tiles"
+  | "nearest"
+  | "minimise_movement";
+
+// only the classic and minimise movement methods work for 1D Brainfuck
+const isAllocationMethod2DOnly = (method: MemoryAllocationMethod) =>
+  method !== "classic" && method !== "minimise_movement";
+
 interface TwoDimensionalSettings {
   enable_2d_grid: boolean;
-  memory_allocation_method: number;
+  memory_allocation_method: MemoryAllocationMethod;
 }
 
 export interface MastermindConfig
@@ -175,19 +200,19 @@ const optimisationLabels: Record<keyof OptimisationSettings, string> = {
   optimise_unreachable_loops: "unreachable loops",
   optimise_generated_code: "generated code",
   optimise_generated_all_permutations: "generated code permutations",
-  // optimise_memory_allocation: "memory allocations",
+  optimise_memory_allocation: "memory allocations",
   // optimise_variable_usage: "variable usage",
 };
 
-export const DEFAULT_MASTERMIND_CONFIG = {
+export const DEFAULT_MASTERMIND_CONFIG: MastermindConfig = {
   optimise_cell_clearing: false,
   optimise_constants: false,
   optimise_empty_blocks: false,
   optimise_generated_code: false,
   optimise_generated_all_permutations: false,
   optimise_unreachable_loops: false,
-  // optimise_memory_allocation: false,
+  optimise_memory_allocation: false,
   // optimise_variable_usage: false,
-  memory_allocation_method: 0,
+  memory_allocation_method: "classic",
   enable_2d_grid: false,
 };
@@ -76,12 +76,16 @@ function mastermindLanguageSupport() {
           styleTags({
             "FnClause/Fn": tags.function(tags.definitionKeyword),
             "FnClause/Name": tags.function(tags.definition(tags.variableName)),
-            "CallClause/Name": tags.function(tags.variableName),
+            "CallClause/Name Call/Name": tags.function(tags.variableName),
             "LocationSpecifier/At": tags.annotation,
-            "VariableType/Cell VariableType/Struct": tags.definitionKeyword,
+            "VariableType/Cell VariableType/Struct VariableType/Dynamic":
+              tags.definitionKeyword,
             "VariableType/Name": tags.className,
             "VariableDefinition/Name": tags.variableName,
 
+            "ConstClause/Const": tags.definitionKeyword,
+            "ConstClause/Name": tags.constant(tags.definition(tags.variableName)),
+
             "StructClause/Struct": tags.definitionKeyword,
             "StructClause/Name": tags.className,
 
@@ -90,11 +94,17 @@ function mastermindLanguageSupport() {
             "DrainCopyClause/DrainCopy DrainCopyClause/Into":
               tags.controlKeyword,
             "WhileClause/While": tags.controlKeyword,
+            "LoopControlClause/Break LoopControlClause/Continue ReturnClause/Return":
+              tags.controlKeyword,
             "IfElseClause/If IfElseClause/Not IfElseClause/Else":
               tags.controlKeyword,
 
             Comment: tags.lineComment,
+            BlockComment: tags.blockComment,
             Include: tags.moduleKeyword,
+            Directive: tags.processingInstruction,
+            "DefineDirective/Name UndefDirective/Name ConditionalDirective/Name":
+              tags.constant(tags.variableName),
             IncludePath: tags.string,
 
             Boolean: tags.bool,
@@ -49,16 +49,18 @@ self.addEventListener("message", ({ data }: MessageEvent<
         console.error = old_error;
       };
       try {
-        const compiledCode = wasm_compile(
-          data.arguments.fileMap,
-          data.arguments.entryFileName,
-          data.arguments.config
-        );
+        const { code, warnings }: { code: string; warnings: string } =
+          wasm_compile(
+            data.arguments.fileMap,
+            data.arguments.entryFileName,
+            data.arguments.config
+          );
 
         postMessage({
           transaction: data.transaction,
           success: true,
-          message: compiledCode,
+          message: code,
+          warnings,
         });
       } catch (e) {
         // rust function returned Err(string), didn't panic